@@ -0,0 +1,63 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use nalgebra::Point3;
+use pcd_lod::{
+    prelude::{write_unit, Color, EncoderOptions, FileSystemSink, Point, TileSink},
+    process_lod_with_meta, write_points_to_txt, LODOptions,
+};
+
+/// 200k points on a wavy surface
+fn points() -> Vec<Point> {
+    (0..200_000)
+        .map(|i| {
+            let (x, y) = ((i % 500) as f64 * 0.1, (i / 500) as f64 * 0.1);
+            let mut p = Point::from(Point3::new(x, y, (x * 0.3).sin() + (y * 0.7).cos()));
+            p.color = Some(Color::new((i % 256) as u8, (i / 500 % 256) as u8, 128));
+            p
+        })
+        .collect()
+}
+
+/// process the text file to tiles like the CLI, with the images encoded in parallel by the build (`parallel`)
+/// or one by one in `write_unit` without `LODOptions::encoder`
+fn process_to_files(input: &std::path::Path, parallel: bool) {
+    let dir = tempfile::tempdir().unwrap();
+    let sink = &FileSystemSink::new(dir.path().to_path_buf());
+    let encoder = EncoderOptions::new();
+    let options = LODOptions {
+        point_count_threshold: 4096,
+        native_input: true,
+        encoder: parallel.then(|| encoder.clone()),
+        ..Default::default()
+    };
+    let encoder = &encoder;
+    tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(process_lod_with_meta(
+            None,
+            &[input],
+            |unit| async move { write_unit(sink, &unit, encoder) },
+            |meta| async move { sink.write_meta(&meta) },
+            false,
+            &options,
+        ))
+        .unwrap();
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("points.txt");
+    write_points_to_txt(&input, &points(), 3).unwrap();
+
+    let mut group = c.benchmark_group("process lod to files");
+    group.sample_size(10);
+    group.bench_function("encode serially", |b| {
+        b.iter(|| process_to_files(&input, false))
+    });
+    group.bench_function("encode in parallel", |b| {
+        b.iter(|| process_to_files(&input, true))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
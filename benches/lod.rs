@@ -0,0 +1,54 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use nalgebra::Point3;
+use pcd_lod::{
+    build_lod_with_meta,
+    prelude::{write_unit, Color, EncoderOptions, FileSystemSink, Point, TileSink},
+    LODOptions,
+};
+
+/// 200k points on a wavy surface
+fn points() -> Vec<Point> {
+    (0..200_000)
+        .map(|i| {
+            let f = i as f64;
+            let (x, y) = ((i % 500) as f64 * 0.1, (i / 500) as f64 * 0.1);
+            let mut p = Point::from(Point3::new(x, y, (x * 0.3).sin() + (y * 0.7).cos()));
+            p.color = Some(Color::new((i % 256) as u8, (f * 0.01) as u8, 128));
+            p
+        })
+        .collect()
+}
+
+fn build_to_files(points: &[Point], pipeline: bool) {
+    let dir = tempfile::tempdir().unwrap();
+    let sink = &FileSystemSink::new(dir.path().to_path_buf());
+    let encoder = EncoderOptions::new();
+    let options = LODOptions {
+        point_count_threshold: 4096,
+        encoder: Some(encoder.clone()),
+        pipeline,
+        ..Default::default()
+    };
+    let encoder = &encoder;
+    tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(build_lod_with_meta(
+            points.to_vec(),
+            |unit| async move { write_unit(sink, &unit, encoder) },
+            |meta| async move { sink.write_meta(&meta) },
+            &options,
+        ))
+        .unwrap();
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let points = points();
+    let mut group = c.benchmark_group("build lod to files");
+    group.sample_size(10);
+    group.bench_function("serial", |b| b.iter(|| build_to_files(&points, false)));
+    group.bench_function("pipeline", |b| b.iter(|| build_to_files(&points, true)));
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
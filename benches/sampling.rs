@@ -17,18 +17,44 @@ fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("non parallels", |b| {
         b.iter(|| {
             let sampler = PoissonDiskSampling::new();
-            sampler.sample(&points, radius);
+            sampler.sample(&points, radius).unwrap();
         })
     });
 
     c.bench_function("parallels", |b| {
         b.iter(|| {
-            let mut sampler = ParallelPoissonDiskSampling::new(points.iter().collect(), radius);
+            let mut sampler =
+                ParallelPoissonDiskSampling::new(points.iter().collect(), radius).unwrap();
             for _ in 0..=sampler.max_iterations() {
                 let _ = sampler.step();
             }
         })
     });
+
+    // two copies of the cloud far apart: a dense grid over the bounding box would be mostly empty
+    let sparse = points
+        .iter()
+        .cloned()
+        .chain(points.iter().map(|p| {
+            let q = p.position;
+            Point::new(q.x + 1e5, q.y + 1e5, q.z + 1e5)
+        }))
+        .collect_vec();
+
+    c.bench_function("sparse non parallels", |b| {
+        b.iter(|| {
+            let sampler = PoissonDiskSampling::new();
+            sampler.sample(&sparse, radius).unwrap();
+        })
+    });
+
+    c.bench_function("sparse parallels", |b| {
+        b.iter(|| {
+            let mut sampler =
+                ParallelPoissonDiskSampling::new(sparse.iter().collect(), radius).unwrap();
+            let _ = sampler.sample();
+        })
+    });
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -128,8 +128,7 @@ fn setup(
                         let fy = iy as f64 / 255.;
                         let fz = iz as f64 / 255.;
                         let v = Vector3::new(fx, fy, fz);
-                        let p = bb.size().component_mul(&v) + bb.min().coords;
-                        p.cast::<f32>().into()
+                        bb.denormalize(&v).coords.cast::<f32>().into()
                     })
                     .collect();
                 commands.spawn(MaterialMeshBundle {
@@ -139,7 +138,7 @@ fn setup(
                     }),
                     material: materials.add(PointsMaterial {
                         settings: PointsShaderSettings {
-                            point_size: 1.0,
+                            point_size: meta.point_size(level) as f32,
                             opacity: 1.0,
                             color: Color::WHITE.into(),
                             ..Default::default()
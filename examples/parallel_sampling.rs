@@ -91,7 +91,7 @@ fn setup(
         ..Default::default()
     });
 
-    let mut sampler = ParallelPoissonDiskSampling::new(points.iter().collect(), RADIUS);
+    let mut sampler = ParallelPoissonDiskSampling::new(points.iter().collect(), RADIUS).unwrap();
     for _ in 0..sampler.max_iterations() {
         let _ = sampler.step();
     }
@@ -93,7 +93,7 @@ fn setup(
 
     let sampler = PoissonDiskSampling::default();
 
-    let samples = sampler.sample(&points, RADIUS);
+    let samples = sampler.sample(&points, RADIUS).unwrap();
     commands.spawn(MaterialMeshBundle {
         mesh: meshes.add(PointsMesh {
             vertices: samples
@@ -0,0 +1,190 @@
+use anyhow::bail;
+use image::{DynamicImage, GenericImage};
+use serde::{Deserialize, Serialize};
+
+use crate::LODKey;
+
+/// sub-rectangle of a tile in the atlas image
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AtlasRect {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+    pub left: u32,
+    pub top: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl AtlasRect {
+    pub fn key(&self) -> LODKey {
+        (self.x, self.y, self.z)
+    }
+}
+
+/// Layout of the tiles of a level packed into a single image (`<level>/atlas<suffix>.json`)
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Atlas {
+    pub width: u32,
+    pub height: u32,
+    pub tiles: Vec<AtlasRect>,
+}
+
+impl Atlas {
+    /// sub-rectangle of the tile at `key`
+    pub fn rect(&self, key: &LODKey) -> Option<&AtlasRect> {
+        self.tiles.iter().find(|r| r.key() == *key)
+    }
+
+    /// cut the image of the tile at `key` out of the atlas image
+    pub fn crop(&self, image: &DynamicImage, key: &LODKey) -> Option<DynamicImage> {
+        self.rect(key)
+            .map(|r| image.crop_imm(r.left, r.top, r.width, r.height))
+    }
+
+    /// cut the images of all the tiles out of the atlas image
+    pub fn unpack(&self, image: &DynamicImage) -> Vec<(LODKey, DynamicImage)> {
+        self.tiles
+            .iter()
+            .map(|r| (r.key(), image.crop_imm(r.left, r.top, r.width, r.height)))
+            .collect()
+    }
+}
+
+/// Pack the tile images into a single image row by row (tallest first).
+/// The tiles must have the same color type, and their pixels are copied as is.
+pub fn pack_atlas(tiles: &[(LODKey, DynamicImage)]) -> anyhow::Result<(DynamicImage, Atlas)> {
+    let Some((_, first)) = tiles.first() else {
+        return Ok((
+            DynamicImage::new(0, 0, image::ColorType::Rgba8),
+            Atlas::default(),
+        ));
+    };
+
+    let mut order: Vec<&(LODKey, DynamicImage)> = tiles.iter().collect();
+    order.sort_by(|(k0, i0), (k1, i1)| i1.height().cmp(&i0.height()).then(k0.cmp(k1)));
+
+    // aim for a square atlas, wide enough for the widest tile
+    let area: u64 = tiles
+        .iter()
+        .map(|(_, i)| i.width() as u64 * i.height() as u64)
+        .sum();
+    let widest = tiles.iter().map(|(_, i)| i.width()).max().unwrap_or(0);
+    let width = ((area as f64).sqrt().ceil() as u32).max(widest);
+
+    let (mut left, mut top, mut row_height) = (0, 0, 0);
+    let mut rects = vec![];
+    for ((x, y, z), image) in order.iter() {
+        if left + image.width() > width {
+            left = 0;
+            top += row_height;
+            row_height = 0;
+        }
+        rects.push(AtlasRect {
+            x: *x,
+            y: *y,
+            z: *z,
+            left,
+            top,
+            width: image.width(),
+            height: image.height(),
+        });
+        left += image.width();
+        row_height = row_height.max(image.height());
+    }
+    let height = top + row_height;
+
+    let mut atlas = DynamicImage::new(width, height, first.color());
+    for (rect, (_, image)) in rects.iter().zip(order.iter()) {
+        copy_into(&mut atlas, image, rect.left, rect.top)?;
+    }
+
+    Ok((
+        atlas,
+        Atlas {
+            width,
+            height,
+            tiles: rects,
+        },
+    ))
+}
+
+/// copy the pixels of the tile without converting the color type
+fn copy_into(
+    atlas: &mut DynamicImage,
+    tile: &DynamicImage,
+    left: u32,
+    top: u32,
+) -> anyhow::Result<()> {
+    match (atlas, tile) {
+        (DynamicImage::ImageLuma8(a), DynamicImage::ImageLuma8(t)) => a.copy_from(t, left, top)?,
+        (DynamicImage::ImageLuma16(a), DynamicImage::ImageLuma16(t)) => {
+            a.copy_from(t, left, top)?
+        }
+        (DynamicImage::ImageRgb8(a), DynamicImage::ImageRgb8(t)) => a.copy_from(t, left, top)?,
+        (DynamicImage::ImageRgba8(a), DynamicImage::ImageRgba8(t)) => a.copy_from(t, left, top)?,
+        (DynamicImage::ImageRgba16(a), DynamicImage::ImageRgba16(t)) => {
+            a.copy_from(t, left, top)?
+        }
+        (DynamicImage::ImageRgba32F(a), DynamicImage::ImageRgba32F(t)) => {
+            a.copy_from(t, left, top)?
+        }
+        (a, t) => bail!(
+            "Tile of {:?} can not be packed into the atlas of {:?}",
+            t.color(),
+            a.color()
+        ),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, Luma, RgbaImage};
+
+    use super::*;
+
+    #[test]
+    fn pack_and_unpack_tiles() {
+        let tiles: Vec<(LODKey, DynamicImage)> = [(0, 0, 0), (1, 0, 0), (0, 1, 1), (1, 1, 1)]
+            .into_iter()
+            .enumerate()
+            .map(|(i, key)| {
+                let side = 3 + i as u32;
+                let image = RgbaImage::from_fn(side, side, |x, y| {
+                    image::Rgba([i as u8, x as u8, y as u8, 255])
+                });
+                (key, DynamicImage::from(image))
+            })
+            .collect();
+
+        let (image, atlas) = pack_atlas(&tiles).unwrap();
+        assert_eq!((image.width(), image.height()), (atlas.width, atlas.height));
+        assert_eq!(atlas.tiles.len(), tiles.len());
+
+        let rgba = image.to_rgba8();
+        for (key, tile) in tiles.iter() {
+            let rect = atlas.rect(key).unwrap();
+            let tile = tile.to_rgba8();
+            for (x, y, p) in tile.enumerate_pixels() {
+                assert_eq!(rgba.get_pixel(rect.left + x, rect.top + y), p);
+            }
+        }
+        for (key, tile) in atlas.unpack(&image) {
+            let (_, expected) = tiles.iter().find(|(k, _)| *k == key).unwrap();
+            assert_eq!(&tile, expected);
+        }
+    }
+
+    #[test]
+    fn mixed_color_types_are_rejected() {
+        let tiles = vec![
+            ((0, 0, 0), DynamicImage::from(RgbaImage::new(2, 2))),
+            (
+                (1, 0, 0),
+                DynamicImage::from(GrayImage::from_pixel(2, 2, Luma([1]))),
+            ),
+        ];
+        assert!(pack_atlas(&tiles).is_err());
+    }
+}
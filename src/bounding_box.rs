@@ -1,12 +1,16 @@
 use std::iter::FromIterator;
 
-use nalgebra::{zero, Point3, Vector3};
+use nalgebra::{zero, Isometry3, Point3, Vector3};
+use rayon::{iter::ParallelIterator, slice::ParallelSlice};
 use serde::{Deserialize, Serialize};
 
-use crate::prelude::Point;
+use crate::{prelude::Point, LODKey};
+
+/// normalized coordinate of the axes of zero size in the bounding box
+pub const DEFAULT_FLAT_AXIS: f64 = 0.5;
 
 /// A bounding box struct that holds the minimum and maximum points of a box.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct BoundingBox {
     pub min: Point3<f64>,
     pub max: Point3<f64>,
@@ -39,6 +43,66 @@ impl BoundingBox {
         self.max - self.min
     }
 
+    /// Index of the cell containing `p` in the grid of `divisions` cubic cells per axis of the max size.
+    /// Each cell covers the half-open interval `[min + k * unit, min + (k + 1) * unit)` on each axis,
+    /// so a point exactly on a boundary belongs to the upper cell, and the max side to the last cell.
+    pub fn cell(&self, p: &Point3<f64>, divisions: usize) -> LODKey {
+        let unit = self.max_size() / divisions as f64;
+        self.grid_cell(p, [divisions; 3], Vector3::repeat(unit))
+    }
+
+    /// Index of the cell containing `p` in the grid of `divisions` cells of `unit` size along each axis
+    /// from the min of the box, with the boundaries assigned as `cell` (0 on an axis of zero unit)
+    pub fn grid_cell(&self, p: &Point3<f64>, divisions: [usize; 3], unit: Vector3<f64>) -> LODKey {
+        let index = |i: usize| {
+            let (v, min, unit, div) = (p[i], self.min[i], unit[i], divisions[i] as i32);
+            if unit <= 0. {
+                return 0;
+            }
+            let mut k = ((v - min) / unit).floor() as i32;
+            // the division can round across an edge, so compare with the edges themselves
+            if min + (k + 1) as f64 * unit <= v {
+                k += 1;
+            } else if v < min + k as f64 * unit {
+                k -= 1;
+            }
+            k.clamp(0, div - 1)
+        };
+        (index(0), index(1), index(2))
+    }
+
+    /// Coordinate of `p` relative to the box, 0.0 ~ 1.0 from the min to the max on each axis
+    /// (`DEFAULT_FLAT_AXIS` on an axis of zero size instead of NaN)
+    pub fn normalize(&self, p: &Point3<f64>) -> Vector3<f64> {
+        (p - self.min).zip_map(
+            &self.size(),
+            |v, s| {
+                if s > 0. {
+                    v / s
+                } else {
+                    DEFAULT_FLAT_AXIS
+                }
+            },
+        )
+    }
+
+    /// Point of the coordinate relative to the box, the inverse of `normalize` (the min on an axis of zero size)
+    pub fn denormalize(&self, v: &Vector3<f64>) -> Point3<f64> {
+        self.min + self.size().component_mul(v)
+    }
+
+    /// volume of the box (0 if any axis has no extent)
+    pub fn volume(&self) -> f64 {
+        let size = self.size().map(|v| v.max(0.));
+        size.x * size.y * size.z
+    }
+
+    /// surface area of the box (0 if the box is empty)
+    pub fn surface_area(&self) -> f64 {
+        let size = self.size().map(|v| v.max(0.));
+        2. * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+
     #[allow(unused)]
     pub fn min(&self) -> &Point3<f64> {
         &self.min
@@ -55,12 +119,76 @@ impl BoundingBox {
         Point3::from(p)
     }
 
+    /// Extent of each axis from the min of the box to the farthest of the positions (at most the size of the box),
+    /// the scale of the positions of a tile whose box is larger than its points (e.g. a cube)
+    pub fn extent_of<'a>(&self, positions: impl Iterator<Item = &'a Point3<f64>>) -> Vector3<f64> {
+        positions
+            .fold(Vector3::zeros(), |extent: Vector3<f64>, p| {
+                extent.sup(&(p - self.min))
+            })
+            .inf(&self.size())
+    }
+
+    /// Expand the box to a cube whose side is the max size, keeping the min corner
+    pub fn cube(&self) -> BoundingBox {
+        let side = self.max_size();
+        BoundingBox {
+            min: self.min,
+            max: self.min + Vector3::from_element(side),
+        }
+    }
+
+    /// Check if the point is inside the box (boundaries included)
+    pub fn contains(&self, p: &Point3<f64>) -> bool {
+        self.min.x <= p.x
+            && p.x <= self.max.x
+            && self.min.y <= p.y
+            && p.y <= self.max.y
+            && self.min.z <= p.z
+            && p.z <= self.max.z
+    }
+
+    /// Check if the boxes overlap (touching boundaries included)
+    pub fn intersects(&self, other: &BoundingBox) -> bool {
+        (0..3).all(|i| self.min[i] <= other.max[i] && other.min[i] <= self.max[i])
+    }
+
     pub fn extend(&mut self, p: &Point3<f64>) {
         self.min = self.min.inf(p);
         self.max = self.max.sup(p);
     }
+
+    /// Axis aligned bounding box of the box transformed by the isometry (all 8 corners are transformed)
+    pub fn transformed(&self, iso: &Isometry3<f64>) -> BoundingBox {
+        let (min, max) = (self.min, self.max);
+        (0..8)
+            .map(|i| {
+                Point3::new(
+                    if i & 1 == 0 { min.x } else { max.x },
+                    if i & 2 == 0 { min.y } else { max.y },
+                    if i & 4 == 0 { min.z } else { max.z },
+                )
+            })
+            .map(|corner| iso.transform_point(&corner))
+            .collect()
+    }
+
+    /// Compute the bounding box of the points in parallel (per-chunk min/max reduced by rayon).
+    /// Use `from_iter` for streaming inputs.
+    pub fn from_points_parallel(points: &[Point]) -> BoundingBox {
+        points
+            .par_chunks(CHUNK_SIZE)
+            .map(|chunk| BoundingBox::from_iter(chunk.iter()))
+            .reduce(
+                || BoundingBox::from_iter(std::iter::empty::<Point3<f64>>()),
+                |a, b| BoundingBox::new(a.min.inf(&b.min), a.max.sup(&b.max)),
+            )
+    }
 }
 
+/// number of points per chunk of `BoundingBox::from_points_parallel`
+const CHUNK_SIZE: usize = 1 << 16;
+
 impl FromIterator<Point3<f64>> for BoundingBox {
     fn from_iter<I: IntoIterator<Item = Point3<f64>>>(iter: I) -> Self {
         let mut min: Vector3<f64> = zero();
@@ -111,3 +239,108 @@ impl<'a> FromIterator<&'a Point> for BoundingBox {
         b
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_and_denormalize() {
+        let b = BoundingBox::new(Point3::new(-1., 2., 10.), Point3::new(3., 2.5, 10.));
+        assert_eq!(b.normalize(&b.min), Vector3::new(0., 0., DEFAULT_FLAT_AXIS));
+        assert_eq!(b.normalize(&b.max), Vector3::new(1., 1., DEFAULT_FLAT_AXIS));
+        assert_eq!(
+            b.denormalize(&Vector3::new(0.5, 0.5, 0.9)),
+            Point3::new(1., 2.25, 10.)
+        );
+
+        for i in 0..100 {
+            let t = i as f64 / 99.;
+            let p = Point3::new(-1. + 4. * t, 2. + 0.5 * (t * 7.).fract(), 10.);
+            let v = b.normalize(&p);
+            assert!(v.iter().all(|c| (0. ..=1.).contains(c)));
+            assert!((b.denormalize(&v) - p).norm() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn cell_of_boundary_points() {
+        // (1.1 - 0.3) / 0.8 rounds down to 0.999.. without the edge correction
+        let b = BoundingBox::new(Point3::new(0.3, 0.3, 0.3), Point3::new(1.9, 1.9, 1.9));
+        assert_eq!(b.cell(&Point3::new(1.1, 0.3, 1.9), 2), (1, 0, 1));
+
+        for divisions in [2, 4, 8, 16] {
+            let unit = b.max_size() / divisions as f64;
+            for k in 0..divisions {
+                let edge = 0.3 + k as f64 * unit;
+                let below = edge - 1e-9;
+                let (x, y, z) = b.cell(&Point3::new(edge, below, edge), divisions);
+                assert_eq!((x, z), (k as i32, k as i32));
+                assert_eq!(y, (k as i32 - 1).max(0));
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_bounds_match_serial() {
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let points: Vec<Point> = (0..200_000)
+            .map(|_| {
+                let p = Point3::new(rng.gen(), rng.gen(), rng.gen()) * 100. - Vector3::repeat(50.);
+                Point::from(p)
+            })
+            .collect();
+
+        let serial = BoundingBox::from_iter(points.iter());
+        assert_eq!(BoundingBox::from_points_parallel(&points), serial);
+        assert_eq!(
+            BoundingBox::from_points_parallel(&points[..10]),
+            BoundingBox::from_iter(points[..10].iter())
+        );
+    }
+
+    #[test]
+    fn rotate_unit_cube() {
+        let b = BoundingBox::new(Point3::new(0., 0., 0.), Point3::new(1., 1., 1.));
+        let iso = Isometry3::rotation(Vector3::z() * std::f64::consts::FRAC_PI_4);
+        let r = b.transformed(&iso);
+
+        let h = std::f64::consts::FRAC_1_SQRT_2;
+        let expected = BoundingBox::new(Point3::new(-h, 0., 0.), Point3::new(h, 2. * h, 1.));
+        assert!((r.min - expected.min).norm() < 1e-12, "{:?}", r);
+        assert!((r.max - expected.max).norm() < 1e-12, "{:?}", r);
+
+        let t = b.transformed(&Isometry3::translation(1., 2., 3.));
+        assert_eq!(t.min, Point3::new(1., 2., 3.));
+        assert_eq!(t.max, Point3::new(2., 3., 4.));
+    }
+
+    #[test]
+    fn unit_cube_volume_and_area() {
+        let b = BoundingBox::new(Point3::new(0., 0., 0.), Point3::new(1., 1., 1.));
+        assert_eq!(b.volume(), 1.);
+        assert_eq!(b.surface_area(), 6.);
+    }
+
+    #[test]
+    fn flat_box_volume() {
+        let b = BoundingBox::new(Point3::new(0., 0., 2.), Point3::new(3., 2., 2.));
+        assert_eq!(b.volume(), 0.);
+        assert_eq!(b.surface_area(), 12.);
+
+        let empty = BoundingBox::from_iter(Vec::<Point3<f64>>::new());
+        assert_eq!(empty.volume(), 0.);
+        assert_eq!(empty.surface_area(), 0.);
+    }
+
+    #[test]
+    fn extent_of_flat_points_in_cube() {
+        let points = [Point3::new(1., 2., 3.), Point3::new(4., 2.5, 3.1)];
+        let cube = BoundingBox::from_iter(points.iter()).cube();
+        let extent = cube.extent_of(points.iter());
+        assert!((extent - Vector3::new(3., 0.5, 0.1)).abs().max() < 1e-12);
+        assert_eq!(cube.extent_of([].iter()), Vector3::zeros());
+    }
+}
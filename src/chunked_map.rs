@@ -0,0 +1,313 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use nalgebra::Point3;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use tempfile::TempDir;
+
+use crate::{
+    prelude::{BoundingBox, ColumnLayout, Point, PointCloudMap},
+    txt_points, LODKey,
+};
+
+/// deepest level chosen for the cells by `ChunkedPointCloudMap::from_txt_files`
+const MAX_CHUNKED_LOD: u32 = 10;
+
+/// Octree cells of a level partitioned out of core.
+/// Points are read in chunks and spilled in binary to a file per cell (`<x>-<y>-<z>.bin`)
+/// in a folder of the map, which is created empty and removed with the map,
+/// so that inputs larger than memory can be processed one cell at a time.
+pub struct ChunkedPointCloudMap {
+    lod: u32,
+    bounds: BoundingBox,
+    dir: TempDir,
+    counts: HashMap<LODKey, usize>,
+    /// all the points have a normal
+    normals: bool,
+    /// some point has a radius
+    radius: bool,
+}
+
+impl ChunkedPointCloudMap {
+    /// Partition the points of the text file into the cells of `lod`,
+    /// holding at most `chunk_size` points in memory.
+    /// The file is read twice: once for the bounds and once to spill the points to a new folder in `spill_dir`.
+    pub fn from_txt(
+        path: &Path,
+        lod: u32,
+        chunk_size: usize,
+        spill_dir: &Path,
+    ) -> anyhow::Result<Self> {
+        Self::partition(
+            &[path.to_path_buf()],
+            None,
+            Some(lod),
+            chunk_size,
+            spill_dir,
+        )
+    }
+
+    /// Partition the points of the text files as one input into the cells of the shallowest level
+    /// whose cells hold about `chunk_size` points for a scanned surface (over 4 cells of each parent cell),
+    /// parsed by the named columns if given (see `from_txt` and `LoadOptions::columns`)
+    pub fn from_txt_files(
+        paths: &[PathBuf],
+        columns: Option<&ColumnLayout>,
+        chunk_size: usize,
+        spill_dir: &Path,
+    ) -> anyhow::Result<Self> {
+        Self::partition(paths, columns, None, chunk_size, spill_dir)
+    }
+
+    fn partition(
+        paths: &[PathBuf],
+        columns: Option<&ColumnLayout>,
+        lod: Option<u32>,
+        chunk_size: usize,
+        spill_dir: &Path,
+    ) -> anyhow::Result<Self> {
+        // the first pass keeps only the bounds and the count, not the points
+        let mut count = 0;
+        let mut bounds = BoundingBox::from_iter(std::iter::empty::<Point3<f64>>());
+        for point in txt_points(paths, columns)? {
+            bounds.extend(&point?.position);
+            count += 1;
+        }
+        let chunk_size = chunk_size.max(1);
+        let lod = lod.unwrap_or_else(|| {
+            (0..MAX_CHUNKED_LOD)
+                .find(|lod| count / 4_usize.pow(*lod) <= chunk_size)
+                .unwrap_or(MAX_CHUNKED_LOD)
+        });
+        std::fs::create_dir_all(spill_dir)?;
+
+        let mut map = Self {
+            lod,
+            bounds,
+            dir: tempfile::tempdir_in(spill_dir)?,
+            counts: HashMap::new(),
+            normals: true,
+            radius: false,
+        };
+        let mut chunk = Vec::with_capacity(chunk_size);
+        for point in txt_points(paths, columns)? {
+            chunk.push(point?);
+            if chunk.len() >= chunk_size {
+                map.spill(&chunk)?;
+                chunk.clear();
+            }
+        }
+        map.spill(&chunk)?;
+        Ok(map)
+    }
+
+    pub fn lod(&self) -> u32 {
+        self.lod
+    }
+
+    pub fn bounds(&self) -> &BoundingBox {
+        &self.bounds
+    }
+
+    /// keys of the cells which have points (sorted)
+    pub fn keys(&self) -> Vec<LODKey> {
+        let mut keys: Vec<LODKey> = self.counts.keys().copied().collect();
+        keys.sort();
+        keys
+    }
+
+    /// number of points in the cell
+    pub fn count(&self, key: &LODKey) -> usize {
+        self.counts.get(key).copied().unwrap_or(0)
+    }
+
+    /// number of points in all the cells
+    pub fn point_count(&self) -> usize {
+        self.counts.values().sum()
+    }
+
+    /// all the points have a normal
+    pub fn has_normals(&self) -> bool {
+        self.normals && !self.counts.is_empty()
+    }
+
+    /// some point has a radius
+    pub fn has_radius(&self) -> bool {
+        self.radius
+    }
+
+    /// number of points in each cell of the coarser `lod` (the cells of this level summed by their parent)
+    pub fn counts_of(&self, lod: u32) -> HashMap<LODKey, usize> {
+        let shift = self.lod.saturating_sub(lod);
+        let mut counts: HashMap<LODKey, usize> = HashMap::new();
+        for ((x, y, z), count) in self.counts.iter() {
+            *counts
+                .entry((x >> shift, y >> shift, z >> shift))
+                .or_default() += count;
+        }
+        counts
+    }
+
+    /// load the points of the cell from the spilled file
+    pub fn load(&self, key: &LODKey) -> anyhow::Result<Vec<Point>> {
+        let count = self.count(key);
+        if count == 0 {
+            return Ok(vec![]);
+        }
+        let mut reader = BufReader::new(File::open(self.cell_path(key))?);
+        (0..count)
+            .map(|_| Ok(bincode::deserialize_from(&mut reader)?))
+            .collect()
+    }
+
+    /// the cell of `key` loaded as the only unit of a map of the level (with the bounds of all the cells),
+    /// so that it is divided into the same cells as the whole map
+    pub fn load_map(&self, key: &LODKey) -> anyhow::Result<PointCloudMap> {
+        Ok(PointCloudMap::cell(
+            self.bounds.clone(),
+            self.lod,
+            *key,
+            self.load(key)?,
+        ))
+    }
+
+    fn cell_path(&self, key: &LODKey) -> PathBuf {
+        let (x, y, z) = key;
+        self.dir.path().join(format!("{}-{}-{}.bin", x, y, z))
+    }
+
+    /// append the points of the chunk to the files of their cells
+    fn spill(&mut self, chunk: &[Point]) -> anyhow::Result<()> {
+        let divisions = 2_usize.pow(self.lod);
+        let keys: Vec<LODKey> = chunk
+            .par_iter()
+            .map(|p| self.bounds.cell(&p.position, divisions))
+            .collect();
+        let mut cells: HashMap<LODKey, Vec<&Point>> = HashMap::new();
+        for (key, point) in keys.into_iter().zip(chunk.iter()) {
+            cells.entry(key).or_default().push(point);
+        }
+        self.normals &= chunk.iter().all(|p| p.normal.is_some());
+        self.radius |= chunk.iter().any(|p| p.radius.is_some());
+
+        for (key, points) in cells {
+            let f = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.cell_path(&key))?;
+            let mut writer = BufWriter::new(f);
+            for point in points.iter() {
+                bincode::serialize_into(&mut writer, point)?;
+            }
+            writer.flush()?;
+            *self.counts.entry(key).or_default() += points.len();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn points_file() -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        for i in 0..500 {
+            let f = i as f64;
+            let line = format!(
+                "{} {} {} {} 0 0",
+                f.sin() * 3.,
+                (f * 0.7).cos(),
+                f * 0.01,
+                i % 256
+            );
+            writeln!(file, "{}", Point::try_parse(&line).unwrap().to_line(17)).unwrap();
+        }
+        file
+    }
+
+    #[test]
+    fn chunked_cells_match_in_memory() {
+        let file = points_file();
+        let points: Vec<Point> = std::fs::read_to_string(file.path())
+            .unwrap()
+            .lines()
+            .map(|line| Point::try_parse(line).unwrap())
+            .collect();
+
+        let dir = tempfile::tempdir().unwrap();
+        let chunked = ChunkedPointCloudMap::from_txt(file.path(), 2, 7, dir.path()).unwrap();
+
+        let bounds = BoundingBox::from_iter(points.iter());
+        let map = PointCloudMap::root(bounds.clone(), &points)
+            .divide(0)
+            .divide(0);
+        assert_eq!(chunked.bounds(), &bounds);
+        let mut keys: Vec<LODKey> = map.map().keys().copied().collect();
+        keys.sort();
+        assert_eq!(chunked.keys(), keys);
+
+        for key in keys.iter() {
+            let loaded = chunked.load(key).unwrap();
+            let expected = map.map()[key].points();
+            assert_eq!(loaded.len(), chunked.count(key));
+            assert_eq!(loaded.len(), expected.len());
+            for (a, b) in loaded.iter().zip(expected.iter()) {
+                assert_eq!(a.position, b.position);
+                assert_eq!(a.color, b.color);
+            }
+        }
+    }
+
+    #[test]
+    fn spill_in_binary_into_fresh_folder() {
+        let file = points_file();
+        let dir = tempfile::tempdir().unwrap();
+        let first = ChunkedPointCloudMap::from_txt(file.path(), 1, 3, dir.path()).unwrap();
+        let second = ChunkedPointCloudMap::from_txt(file.path(), 1, 3, dir.path()).unwrap();
+        assert_ne!(first.dir.path(), second.dir.path());
+        assert_eq!(first.point_count(), 500);
+        assert_eq!(second.point_count(), 500);
+        for key in first.keys() {
+            let (a, b) = (first.load(&key).unwrap(), second.load(&key).unwrap());
+            assert_eq!(a.len(), first.count(&key));
+            assert!(a
+                .iter()
+                .zip(b.iter())
+                .all(|(a, b)| a.position == b.position));
+            let bytes = std::fs::read(first.cell_path(&key)).unwrap();
+            assert_eq!(
+                bytes.len(),
+                a.iter()
+                    .map(|p| bincode::serialized_size(p).unwrap() as usize)
+                    .sum::<usize>()
+            );
+        }
+        drop((first, second));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn level_of_chunk_size_and_strict_rows() {
+        let file = points_file();
+        let dir = tempfile::tempdir().unwrap();
+        let paths = [file.path().to_path_buf()];
+        // 500 points over 4 cells per parent: 125 in level 1, 31 in level 2
+        let map = ChunkedPointCloudMap::from_txt_files(&paths, None, 40, dir.path()).unwrap();
+        assert_eq!(map.lod(), 2);
+        assert_eq!(map.counts_of(0)[&(0, 0, 0)], 500);
+        assert_eq!(map.counts_of(1).values().sum::<usize>(), 500);
+        let map = ChunkedPointCloudMap::from_txt_files(&paths, None, 500, dir.path()).unwrap();
+        assert_eq!(map.lod(), 0);
+
+        let mut bad = tempfile::NamedTempFile::new().unwrap();
+        writeln!(bad, "1 2 3\n4 five 6").unwrap();
+        assert!(ChunkedPointCloudMap::from_txt(bad.path(), 1, 10, dir.path()).is_err());
+    }
+}
@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 
 /// A color struct that holds the red, green, and blue values of a color.
-#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
 pub struct Color {
     pub red: u8,
     pub green: u8,
@@ -40,6 +40,76 @@ impl Color {
     pub fn b(&self) -> u8 {
         self.blue
     }
+
+    /// Color of the value (0.0 ~ 1.0) in a blue - cyan - green - yellow - red ramp, e.g. to color by time
+    pub fn ramp(value: f64) -> Self {
+        let v = if value.is_nan() {
+            0.
+        } else {
+            value.clamp(0., 1.)
+        };
+        let channel =
+            |center: f64| ((1.5 - (4. * v - center).abs()).clamp(0., 1.) * 255.).round() as u8;
+        Self::new(channel(3.), channel(2.), channel(1.))
+    }
+
+    /// Convert sRGB color to linear RGB in the range of 0.0 ~ 1.0
+    pub fn to_linear(&self) -> [f64; 3] {
+        [self.red, self.green, self.blue].map(|c| srgb_to_linear(c as f64 / 255.))
+    }
+
+    /// Create sRGB color from linear RGB in the range of 0.0 ~ 1.0
+    pub fn from_linear(rgb: [f64; 3]) -> Self {
+        let [red, green, blue] =
+            rgb.map(|c| (linear_to_srgb(c.clamp(0., 1.)) * 255.).round() as u8);
+        Self { red, green, blue }
+    }
+
+    /// Average of the colors in linear RGB (`None` if empty)
+    pub fn linear_average<'a>(colors: impl IntoIterator<Item = &'a Color>) -> Option<Self> {
+        let (sum, n) = colors.into_iter().fold(([0.; 3], 0), |(acc, n), c| {
+            let l = c.to_linear();
+            ([acc[0] + l[0], acc[1] + l[1], acc[2] + l[2]], n + 1)
+        });
+        (n > 0).then(|| Self::from_linear(sum.map(|v| v / n as f64)))
+    }
+
+    /// sum of the variances of the channels (in 0.0 ~ 1.0) of the colors, 0 for no color.
+    /// 0.75 at most (half black and half white)
+    pub fn variance<'a>(colors: impl IntoIterator<Item = &'a Color>) -> f64 {
+        let (sum, sq, n) = colors
+            .into_iter()
+            .fold(([0.; 3], [0.; 3], 0), |(sum, sq, n), c| {
+                let v = [c.r(), c.g(), c.b()].map(|v| v as f64 / 255.);
+                (
+                    std::array::from_fn(|i| sum[i] + v[i]),
+                    std::array::from_fn(|i| sq[i] + v[i] * v[i]),
+                    n + 1,
+                )
+            });
+        match n {
+            0 => 0.,
+            _ => (0..3)
+                .map(|i| (sq[i] / n as f64 - (sum[i] / n as f64).powi(2)).max(0.))
+                .sum(),
+        }
+    }
+}
+
+fn srgb_to_linear(c: f64) -> f64 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f64) -> f64 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1. / 2.4) - 0.055
+    }
 }
 
 #[cfg(feature = "bevy")]
@@ -0,0 +1,565 @@
+use std::{collections::HashMap, fs::read_dir, path::Path};
+
+use anyhow::ensure;
+use image::DynamicImage;
+use nalgebra::Vector3;
+
+use crate::{
+    prelude::{
+        builtin_pixel_layout, position_alpha_to_intensity, Atlas, Color, Coordinates, ImageKind,
+        Meta, PixelLayout, Point, TileBounds,
+    },
+    LODKey,
+};
+
+/// Load the atlas image of `kind` in `level` and its layout if the level is packed into atlases
+pub fn load_atlas(
+    dir: &Path,
+    level: u32,
+    kind: ImageKind,
+) -> anyhow::Result<Option<(DynamicImage, Atlas)>> {
+    let mut path = dir.to_path_buf();
+    path.push(level.to_string());
+    path.push(format!("atlas{}.json", kind.suffix()));
+    if !path.exists() {
+        return Ok(None);
+    }
+    let atlas: Atlas = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+    let image = image::open(path.with_extension("png"))?;
+    Ok(Some((image, atlas)))
+}
+
+/// Load a tile of `key` in `level` from the output directory and decode it into points.
+/// Positions are restored with the bounding box of the tile stored in the meta,
+/// and colors are read from the color image if exists.
+/// The position images of the detail level (`Meta::detail_level`) are quad encoded (`encode_8bit_quad`).
+/// The images are cut out of the level's atlas when the tile has no image files.
+/// The tiles of a custom pixel layout are loaded with `load_tile_with_layout`.
+pub fn load_tile(dir: &Path, meta: &Meta, key: &LODKey, level: u32) -> anyhow::Result<Vec<Point>> {
+    let layout = builtin_pixel_layout(&meta.pixel_layout).ok_or(anyhow::anyhow!(
+        "Pixel layout {} is not built in! Load the tile with load_tile_with_layout.",
+        meta.pixel_layout
+    ))?;
+    load_tile_with_layout(dir, meta, key, level, layout.as_ref())
+}
+
+/// Load a tile like `load_tile`, reading the pixels in the order of the layout the tiles were encoded with
+pub fn load_tile_with_layout(
+    dir: &Path,
+    meta: &Meta,
+    key: &LODKey,
+    level: u32,
+    layout: &dyn PixelLayout,
+) -> anyhow::Result<Vec<Point>> {
+    ensure!(
+        layout.name() == meta.pixel_layout,
+        "Tiles are encoded with the pixel layout {}, not {}",
+        meta.pixel_layout,
+        layout.name()
+    );
+    let (x, y, z) = key;
+    let c_key = format!("{}-{}-{}", x, y, z);
+    let bbox = meta.tile_bounds(level, key).ok_or(anyhow::anyhow!(
+        "Tile {} in level {} is not found in meta!",
+        c_key,
+        level
+    ))?;
+
+    // the positions are normalized over the scale of the tile, the size of the bounds unless it is recorded
+    let scale = meta.tile_scale(level, key).unwrap_or(bbox.size());
+
+    let tile_path = |kind: ImageKind| {
+        let mut path = dir.to_path_buf();
+        path.push(level.to_string());
+        path.push(format!("{}{}.png", c_key, kind.suffix()));
+        path
+    };
+
+    let open = |kind: ImageKind| -> anyhow::Result<Option<DynamicImage>> {
+        let path = tile_path(kind);
+        if path.exists() {
+            return Ok(Some(image::open(&path)?));
+        }
+        Ok(load_atlas(dir, level, kind)?.and_then(|(image, atlas)| atlas.crop(&image, key)))
+    };
+
+    let position = open(ImageKind::Position)?.ok_or(anyhow::anyhow!(
+        "Tile image {:?} is not existed!",
+        tile_path(ImageKind::Position).to_string_lossy()
+    ))?;
+
+    // the detail tiles hold the 4 bytes of each coordinate in the 4 quadrants of the image (`encode_8bit_quad`)
+    let quad = meta.detail_level == Some(level);
+    let side = match quad {
+        true => position.width() / 2,
+        false => position.width(),
+    };
+    // pixels in the order of the points, the ones with zero alpha are padding of the square image
+    let pixels = (0..(side * side) as usize).map(|i| layout.pixel(i, side));
+    let normalized: Vec<(Vector3<f64>, u8, (u32, u32))> = match &position {
+        img if quad => {
+            let img = img.to_rgba8();
+            pixels
+                .map(|(x, y)| {
+                    let quadrants = [(x, y), (x + side, y), (x, y + side), (x + side, y + side)]
+                        .map(|(x, y)| *img.get_pixel(x, y));
+                    (quadrants, (x, y))
+                })
+                .filter(|(q, _)| q[3][3] > 0)
+                .map(|(q, xy)| {
+                    let v = Vector3::from_fn(|c, _| {
+                        decode_8bit_4channels([q[0][c], q[1][c], q[2][c], q[3][c]])
+                    });
+                    (v, q[3][3], xy)
+                })
+                .collect()
+        }
+        DynamicImage::ImageRgba32F(img) => pixels
+            .map(|(x, y)| (img.get_pixel(x, y), (x, y)))
+            .filter(|(p, _)| p[3] > 0.)
+            .map(|(p, xy)| {
+                let alpha = (p[3] * u8::MAX as f32).round() as u8;
+                (Vector3::new(p[0], p[1], p[2]).cast::<f64>(), alpha, xy)
+            })
+            .collect(),
+        img => {
+            let img = img.to_rgba8();
+            pixels
+                .map(|(x, y)| (*img.get_pixel(x, y), (x, y)))
+                .filter(|(p, _)| p[3] > 0)
+                .map(|(p, xy)| {
+                    let v = Vector3::new(p[0], p[1], p[2]).cast::<f64>() / (u8::MAX as f64);
+                    (v, p[3], xy)
+                })
+                .collect()
+        }
+    };
+
+    let colors = open(ImageKind::Color)?.map(|image| image.to_rgba8());
+    let radii = open(ImageKind::Radius)?.map(|image| image.to_luma16());
+    let indices = open(ImageKind::Index)?.map(|image| image.to_rgba8());
+
+    let points = normalized
+        .iter()
+        .map(|(v, alpha, (x, y))| {
+            let mut point = Point::from(bbox.min() + scale.component_mul(v));
+            if let Some(c) = colors
+                .as_ref()
+                .and_then(|image| image.get_pixel_checked(*x, *y))
+            {
+                let intensity = c[3] as f64 / (u8::MAX as f64);
+                point.color = Some(Color::new(c[0], c[1], c[2]));
+                point.intensity = meta.intensity_in_alpha.then_some(intensity);
+            }
+            if meta.intensity_in_position_alpha {
+                point.intensity = Some(position_alpha_to_intensity(*alpha));
+            }
+            point.radius = radii
+                .as_ref()
+                .and_then(|image| image.get_pixel_checked(*x, *y))
+                .map(|r| r[0] as f64 / u16::MAX as f64 * bbox.max_size());
+            point.index = indices
+                .as_ref()
+                .and_then(|image| image.get_pixel_checked(*x, *y))
+                .and_then(|p| (u32::from_le_bytes(p.0) as usize).checked_sub(1));
+            point
+        })
+        .collect();
+
+    Ok(points)
+}
+
+/// Restore normalized f64 (0.0 ~ 1.0) from the 4 bytes (lowest first) of `encode_8bit_quad`
+fn decode_8bit_4channels(bytes: [u8; 4]) -> f64 {
+    u32::from_le_bytes(bytes) as f64 / u32::MAX as f64
+}
+
+/// Rebuild the meta data from the tiles in the output directory when `meta.json` is lost.
+/// The bounds of each tile are read from the sidecar `<level>/<x>-<y>-<z>.json`
+/// written by `FileSystemSink::bounds_sidecar`, and the bounds of the whole is the root tile's.
+pub fn reconstruct_meta(dir: &Path) -> anyhow::Result<Meta> {
+    let mut levels = vec![];
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let level = entry.file_name().to_string_lossy().parse::<u32>();
+        if let (Ok(level), true) = (level, entry.file_type()?.is_dir()) {
+            levels.push((level, entry.path()));
+        }
+    }
+    ensure!(!levels.is_empty(), "No tiles are found in {:?}!", dir);
+
+    let mut coordinates = Coordinates::new();
+    let mut point_counts = HashMap::new();
+    let mut geometric_error = HashMap::new();
+    let mut normals = false;
+    let mut radius = false;
+    let mut index = false;
+    for (level, path) in levels.iter() {
+        for entry in read_dir(path)? {
+            let path = entry?.path();
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let Some(key) = name.strip_suffix(".png") else {
+                continue;
+            };
+            if key.ends_with(ImageKind::Normal.suffix().as_str()) {
+                normals = true;
+            }
+            if key.ends_with(ImageKind::Radius.suffix().as_str()) {
+                radius = true;
+            }
+            if key.ends_with(ImageKind::Index.suffix().as_str()) {
+                index = true;
+            }
+            // position images are named `<x>-<y>-<z>.png`
+            let indices: Vec<_> = key.split('-').map(|v| v.parse::<i32>()).collect();
+            if indices.len() != 3 || indices.iter().any(|v| v.is_err()) {
+                continue;
+            }
+
+            let sidecar = path.with_extension("json");
+            ensure!(
+                sidecar.exists(),
+                "Bounds sidecar {:?} is not existed!",
+                sidecar.to_string_lossy()
+            );
+            let bounds: TileBounds = serde_json::from_str(&std::fs::read_to_string(sidecar)?)?;
+            coordinates
+                .entry(*level)
+                .or_default()
+                .insert(key.to_string(), bounds.bounding_box);
+            point_counts
+                .entry(*level)
+                .or_insert_with(HashMap::new)
+                .insert(key.to_string(), bounds.count);
+            if let Some(error) = bounds.geometric_error {
+                geometric_error.insert(*level, error);
+            }
+        }
+    }
+
+    let bounds = coordinates
+        .get(&0)
+        .and_then(|units| units.get("0-0-0"))
+        .cloned()
+        .ok_or(anyhow::anyhow!("Root tile is not found in {:?}!", dir))?;
+    let lod = levels.iter().map(|(level, _)| level + 1).max().unwrap_or(0);
+    let mut meta = Meta::new(lod, bounds, coordinates);
+    meta.point_counts = point_counts;
+    meta.geometric_error = geometric_error;
+    meta.normals = normals;
+    meta.radius = radius;
+    meta.index = index;
+    Ok(meta)
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Point3;
+
+    use crate::{
+        build_lod_with_meta,
+        prelude::{write_unit, EncoderOptions, FileSystemSink, TileSink},
+        LODOptions,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn load_tile_by_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let points: Vec<Point> = (0..4)
+            .flat_map(|z| (0..4).flat_map(move |y| (0..4).map(move |x| (x, y, z))))
+            .map(|(x, y, z)| {
+                let line = format!("{} {} {} {} 0 0", x, y, z, x * 60);
+                Point::try_parse(&line).unwrap()
+            })
+            .collect();
+
+        let sink = FileSystemSink::new(dir.path().to_path_buf());
+        let options = EncoderOptions::new();
+        build_lod_with_meta(
+            points.clone(),
+            |unit| {
+                let r = write_unit(&sink, &unit, &options);
+                async { r }
+            },
+            |meta| {
+                let r = sink.write_meta(&meta);
+                async { r }
+            },
+            &LODOptions {
+                point_count_threshold: 32,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let meta: Meta =
+            serde_json::from_str(&std::fs::read_to_string(dir.path().join("meta.json")).unwrap())
+                .unwrap();
+
+        // octant (1, 0, 1) of level 1 has the points with x, z in 2..4 and y in 0..2
+        let tile = load_tile(dir.path(), &meta, &(1, 0, 1), 1).unwrap();
+        assert_eq!(tile.len(), 8);
+        for p in tile.iter() {
+            let expected = points
+                .iter()
+                .find(|q| (q.position - p.position).norm() < 1e-2)
+                .unwrap();
+            assert_eq!(p.color.unwrap().r(), expected.color.unwrap().r());
+        }
+
+        let missing = load_tile(dir.path(), &meta, &(3, 3, 3), 1);
+        assert!(missing
+            .unwrap_err()
+            .to_string()
+            .contains("is not found in meta"));
+    }
+
+    /// row-major from the bottom right, the padding at the top left
+    struct Reversed;
+
+    impl PixelLayout for Reversed {
+        fn name(&self) -> &str {
+            "reversed"
+        }
+
+        fn pixel(&self, index: usize, side: u32) -> (u32, u32) {
+            let i = (side * side) as usize - 1 - index;
+            (i as u32 % side, i as u32 / side)
+        }
+    }
+
+    #[tokio::test]
+    async fn load_tile_with_custom_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        // 10 points in a 4 x 4 image
+        let points: Vec<Point> = (0..10)
+            .map(|i| {
+                let line = format!("{} {} 0 {} {} 0", i, i % 3, i * 20, 255 - i * 20);
+                Point::try_parse(&line).unwrap()
+            })
+            .collect();
+
+        let sink = FileSystemSink::new(dir.path().to_path_buf());
+        let options = EncoderOptions::new().pixel_layout(std::sync::Arc::new(Reversed));
+        build_lod_with_meta(
+            points.clone(),
+            |unit| {
+                let r = write_unit(&sink, &unit, &options);
+                async { r }
+            },
+            |meta| {
+                let r = sink.write_meta(&meta);
+                async { r }
+            },
+            &LODOptions {
+                encoder: Some(options.clone()),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let meta: Meta =
+            serde_json::from_str(&std::fs::read_to_string(dir.path().join("meta.json")).unwrap())
+                .unwrap();
+        assert_eq!(meta.pixel_layout, "reversed");
+        let image = image::open(dir.path().join("0").join("0-0-0.png"))
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(image.get_pixel(0, 0)[3], 0);
+        assert_eq!(image.get_pixel(3, 3)[3], 255);
+
+        let tile = load_tile_with_layout(dir.path(), &meta, &(0, 0, 0), 0, &Reversed).unwrap();
+        assert_eq!(tile.len(), points.len());
+        for p in tile.iter() {
+            let expected = points
+                .iter()
+                .find(|q| (q.position - p.position).norm() < 5e-2)
+                .unwrap();
+            assert_eq!(p.color, expected.color);
+        }
+
+        // the layout must match the one the tiles were encoded with
+        assert!(load_tile(dir.path(), &meta, &(0, 0, 0), 0)
+            .unwrap_err()
+            .to_string()
+            .contains("load_tile_with_layout"));
+        let row_major = crate::prelude::RowMajor;
+        assert!(load_tile_with_layout(dir.path(), &meta, &(0, 0, 0), 0, &row_major).is_err());
+    }
+
+    #[tokio::test]
+    async fn load_tile_from_atlas() {
+        let points: Vec<Point> = (0..4)
+            .flat_map(|z| (0..4).flat_map(move |y| (0..4).map(move |x| (x, y, z))))
+            .map(|(x, y, z)| {
+                let line = format!("{} {} {} {} {} 0", x, y, z, x * 60, y * 60);
+                Point::try_parse(&line).unwrap()
+            })
+            .collect();
+
+        let tiles = tempfile::tempdir().unwrap();
+        let atlases = tempfile::tempdir().unwrap();
+        for (dir, atlas) in [(&tiles, false), (&atlases, true)] {
+            let sink = FileSystemSink::new(dir.path().to_path_buf()).atlas(atlas);
+            let options = EncoderOptions::new();
+            build_lod_with_meta(
+                points.clone(),
+                |unit| {
+                    let r = write_unit(&sink, &unit, &options);
+                    async { r }
+                },
+                |meta| {
+                    let r = sink.write_meta(&meta);
+                    async { r }
+                },
+                &LODOptions {
+                    point_count_threshold: 32,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        }
+
+        let level = atlases.path().join("1");
+        assert!(level.join("atlas.png").exists());
+        assert!(level.join("atlas-color.json").exists());
+        assert!(!level.join("1-0-1.png").exists());
+
+        let meta: Meta = serde_json::from_str(
+            &std::fs::read_to_string(atlases.path().join("meta.json")).unwrap(),
+        )
+        .unwrap();
+        for (level, units) in meta.coordinates().iter() {
+            for c_key in units.keys() {
+                let key: Vec<i32> = c_key.split('-').map(|v| v.parse().unwrap()).collect();
+                let key = (key[0], key[1], key[2]);
+                let expected = load_tile(tiles.path(), &meta, &key, *level).unwrap();
+                let actual = load_tile(atlases.path(), &meta, &key, *level).unwrap();
+                assert_eq!(actual.len(), expected.len());
+                for (a, e) in actual.iter().zip(expected.iter()) {
+                    assert_eq!(a.position, e.position);
+                    assert_eq!(a.color, e.color);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn load_detail_tiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let points: Vec<Point> = (0..12)
+            .flat_map(|z| (0..12).flat_map(move |y| (0..12).map(move |x| (x, y, z))))
+            .map(|(x, y, z)| {
+                let line = format!("{} {} {} {} 0 0", x as f64 + 0.001 * y as f64, y, z, x * 20);
+                Point::try_parse(&line).unwrap()
+            })
+            .collect();
+
+        let sink = FileSystemSink::new(dir.path().to_path_buf());
+        let options = EncoderOptions::new();
+        build_lod_with_meta(
+            points.clone(),
+            |unit| {
+                let r = write_unit(&sink, &unit, &options);
+                async { r }
+            },
+            |meta| {
+                let r = sink.write_meta(&meta);
+                async { r }
+            },
+            &LODOptions {
+                max_texture_side: Some(16),
+                encoder: Some(options.clone()),
+                detail_tiles: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let meta: Meta =
+            serde_json::from_str(&std::fs::read_to_string(dir.path().join("meta.json")).unwrap())
+                .unwrap();
+        let detail_level = meta.detail_level.unwrap();
+        assert!(detail_level > 0);
+        let error = |level: u32| {
+            let mut count = 0;
+            let mut max_error = 0_f64;
+            for c_key in meta.coordinates()[&level].keys() {
+                let key: Vec<i32> = c_key.split('-').map(|v| v.parse().unwrap()).collect();
+                let tile = load_tile(dir.path(), &meta, &(key[0], key[1], key[2]), level).unwrap();
+                for p in tile.iter() {
+                    let nearest = points
+                        .iter()
+                        .min_by(|a, b| {
+                            let da = (a.position - p.position).norm();
+                            let db = (b.position - p.position).norm();
+                            da.total_cmp(&db)
+                        })
+                        .unwrap();
+                    max_error = max_error.max((nearest.position - p.position).norm());
+                    assert_eq!(p.color, nearest.color);
+                }
+                count += tile.len();
+            }
+            (count, max_error)
+        };
+
+        // the finest level keeps every point, at the precision of the quad encoding
+        let (count, detail_error) = error(detail_level);
+        assert_eq!(count, points.len());
+        assert!(detail_error < 1e-4, "{}", detail_error);
+        let (_, coarse_error) = error(detail_level - 1);
+        assert!(coarse_error > detail_error * 100., "{}", coarse_error);
+    }
+
+    #[tokio::test]
+    async fn reconstruct_meta_without_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let points: Vec<Point> = (0..6)
+            .flat_map(|z| (0..6).flat_map(move |y| (0..6).map(move |x| (x, y, z))))
+            .map(|(x, y, z)| Point::from(Point3::new(x as f64, y as f64 * 0.5, z as f64)))
+            .collect();
+
+        let sink = FileSystemSink::new(dir.path().to_path_buf()).bounds_sidecar(true);
+        let options = EncoderOptions::new();
+        build_lod_with_meta(
+            points,
+            |unit| {
+                let r = write_unit(&sink, &unit, &options);
+                async { r }
+            },
+            |meta| {
+                let r = sink.write_meta(&meta);
+                async { r }
+            },
+            &LODOptions {
+                point_count_threshold: 32,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let meta_path = dir.path().join("meta.json");
+        let meta: Meta =
+            serde_json::from_str(&std::fs::read_to_string(&meta_path).unwrap()).unwrap();
+        std::fs::remove_file(meta_path).unwrap();
+
+        let reconstructed = reconstruct_meta(dir.path()).unwrap();
+        assert_eq!(reconstructed.lod(), meta.lod());
+        assert_eq!(reconstructed.bounds(), meta.bounds());
+        assert_eq!(reconstructed.coordinates(), meta.coordinates());
+        assert_eq!(reconstructed.point_counts, meta.point_counts);
+        assert_eq!(reconstructed.geometric_error, meta.geometric_error);
+        assert_eq!(reconstructed.normals, meta.normals);
+    }
+}
@@ -0,0 +1,880 @@
+use anyhow::{bail, ensure};
+use nalgebra::{Point3, Vector3};
+
+use crate::prelude::{BoundingBox, Color, Point};
+
+/// version of the Draco bitstream
+const VERSION: [u8; 2] = [2, 2];
+const POINT_CLOUD: u8 = 0;
+const SEQUENTIAL_ENCODING: u8 = 0;
+
+/// attribute types
+const POSITION: u8 = 0;
+const COLOR: u8 = 2;
+
+/// data types of the attributes
+const DT_UINT8: u8 = 2;
+const DT_FLOAT32: u8 = 9;
+
+/// sequential attribute encoders
+const GENERIC: u8 = 0;
+const QUANTIZATION: u8 = 2;
+
+/// prediction of the integer values (`PREDICTION_DIFFERENCE` from the previous point)
+/// and the transform of the corrections into the range of the values
+const PREDICTION_NONE: u8 = -2_i8 as u8;
+const PREDICTION_DIFFERENCE: u8 = 0;
+const TRANSFORM_WRAP: u8 = 1;
+
+/// symbol coding schemes
+const TAGGED: u8 = 0;
+const RAW: u8 = 1;
+
+/// max bit length of the symbols coded with `RAW` (the probability table grows with it)
+const MAX_RAW_BIT_LENGTH: u32 = 18;
+/// bit length of the tags (the bit lengths of the values) coded with `TAGGED`
+const TAG_BIT_LENGTH: u32 = 5;
+
+/// Encode the points into a Draco point cloud (bitstream 2.2, sequential encoding).
+/// The positions are stored relative to `origin` (e.g. the min of the tile bounds in the meta),
+/// so that the f32 values of the Draco header keep the precision of the coordinates far from zero.
+/// They are quantized to `bits` (1 ~ 30) over the cube of the max size of their bounds,
+/// predicted from the previous point and entropy coded (rANS),
+/// and the colors are stored as RGB of u8 (white for the points without color) if any point has color.
+pub fn encode_draco(points: &[Point], origin: &Point3<f64>, bits: u8) -> anyhow::Result<Vec<u8>> {
+    ensure!(
+        (1..=30).contains(&bits),
+        "Draco quantization bits must be 1 ~ 30, got {}",
+        bits
+    );
+    ensure!(
+        points.len() <= i32::MAX as usize / 3,
+        "Draco can not hold {} points",
+        points.len()
+    );
+    let has_color = points.iter().any(|p| p.color.is_some());
+
+    let local: Vec<Vector3<f64>> = points.iter().map(|p| p.position - origin).collect();
+    let bounds = BoundingBox::from_iter(local.iter().map(|v| Point3::from(*v)));
+    let (min, range) = match points.is_empty() {
+        true => (Point3::origin(), 1_f32),
+        false => (
+            bounds.min().cast::<f32>(),
+            (bounds.max_size() as f32).max(f32::MIN_POSITIVE),
+        ),
+    };
+    let max_quantized = ((1_u32 << bits) - 1) as f64;
+
+    let mut bytes = b"DRACO".to_vec();
+    bytes.extend(VERSION);
+    bytes.extend([POINT_CLOUD, SEQUENTIAL_ENCODING]);
+    bytes.extend(0_u16.to_le_bytes()); // flags (no metadata)
+    bytes.extend((points.len() as i32).to_le_bytes());
+
+    // a decoder of all the attributes
+    bytes.push(1);
+    let attributes = 1 + has_color as u32;
+    push_varint(&mut bytes, attributes);
+    bytes.extend([POSITION, DT_FLOAT32, 3, 0]);
+    push_varint(&mut bytes, 0); // unique id
+    if has_color {
+        bytes.extend([COLOR, DT_UINT8, 3, 1]);
+        push_varint(&mut bytes, 1);
+    }
+    bytes.push(QUANTIZATION);
+    if has_color {
+        bytes.push(GENERIC);
+    }
+
+    // quantized positions, as the corrections of the difference from the previous point
+    let quantized: Vec<i32> = local
+        .iter()
+        .flat_map(|v| {
+            let v = (v - min.coords.cast::<f64>()) / range as f64;
+            v.iter()
+                .map(|v| (v * max_quantized).round().clamp(0., max_quantized) as i32)
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    let wrap = Wrap::new(&quantized);
+    let corrections: Vec<u32> = (0..quantized.len())
+        .map(|i| {
+            let predicted = match i < 3 {
+                true => 0,
+                false => quantized[i - 3],
+            };
+            signed_to_symbol(wrap.correction(quantized[i], predicted))
+        })
+        .collect();
+    bytes.extend([PREDICTION_DIFFERENCE, TRANSFORM_WRAP]);
+    bytes.push(1); // compressed
+    bytes.extend(encode_symbols(&corrections, 3));
+    bytes.extend(wrap.min.to_le_bytes());
+    bytes.extend(wrap.max.to_le_bytes());
+    if has_color {
+        for p in points {
+            let c = p.color.unwrap_or(Color::white());
+            bytes.extend([c.r(), c.g(), c.b()]);
+        }
+    }
+
+    // dequantization of the positions
+    min.iter().for_each(|v| bytes.extend(v.to_le_bytes()));
+    bytes.extend(range.to_le_bytes());
+    bytes.push(bits);
+    Ok(bytes)
+}
+
+/// Decode the Draco point cloud written by `encode_draco`, adding `origin` to the positions
+/// (other encodings, e.g. with a kd-tree or other attributes, are not supported)
+pub fn decode_draco(bytes: &[u8], origin: &Point3<f64>) -> anyhow::Result<Vec<Point>> {
+    let mut reader = Reader { bytes, pos: 0 };
+    ensure!(reader.take(5)? == b"DRACO", "Not a Draco file");
+    let header = reader.take(4)?;
+    ensure!(
+        header == [VERSION[0], VERSION[1], POINT_CLOUD, SEQUENTIAL_ENCODING],
+        "Only the sequential point clouds of the Draco bitstream {}.{} are supported",
+        VERSION[0],
+        VERSION[1]
+    );
+    ensure!(reader.u16()? == 0, "Draco metadata is not supported");
+    let count = reader.u32()? as i32;
+    ensure!(count >= 0, "Invalid number of points {}", count);
+    let count = count as usize;
+
+    ensure!(
+        reader.u8()? == 1,
+        "Draco attribute decoders are not supported"
+    );
+    let attributes = reader.varint()?;
+    let mut has_color = false;
+    for i in 0..attributes {
+        let descriptor = reader.take(4)?;
+        let unique_id = reader.varint()?;
+        match (i, descriptor) {
+            (0, [POSITION, DT_FLOAT32, 3, 0]) => {}
+            (1, [COLOR, DT_UINT8, 3, _]) => has_color = true,
+            _ => bail!("Draco attribute {:?} is not supported", descriptor),
+        }
+        ensure!(
+            unique_id == i,
+            "Draco attribute id {} is not supported",
+            unique_id
+        );
+    }
+    ensure!(
+        reader.u8()? == QUANTIZATION && (!has_color || reader.u8()? == GENERIC),
+        "Draco attribute encoders are not supported"
+    );
+
+    let prediction = reader.u8()?;
+    ensure!(
+        prediction == PREDICTION_NONE || prediction == PREDICTION_DIFFERENCE,
+        "Draco prediction scheme {} is not supported",
+        prediction as i8
+    );
+    if prediction == PREDICTION_DIFFERENCE {
+        ensure!(
+            reader.u8()? == TRANSFORM_WRAP,
+            "Draco prediction transform is not supported"
+        );
+    }
+    let values = count * 3;
+    let symbols = match reader.u8()? {
+        0 => {
+            ensure!(
+                reader.u8()? == 4,
+                "Draco values of other than 4 bytes are not supported"
+            );
+            (0..values)
+                .map(|_| reader.u32())
+                .collect::<anyhow::Result<Vec<_>>>()?
+        }
+        _ => decode_symbols(&mut reader, values, 3)?,
+    };
+    let mut quantized: Vec<i32> = symbols.into_iter().map(symbol_to_signed).collect();
+    if prediction == PREDICTION_DIFFERENCE {
+        let (min, max) = (reader.i32()?, reader.i32()?);
+        let wrap = Wrap::from_range(min, max)?;
+        for i in 0..quantized.len() {
+            let predicted = match i < 3 {
+                true => 0,
+                false => quantized[i - 3],
+            };
+            quantized[i] = wrap.original(quantized[i], predicted);
+        }
+    }
+    let colors = match has_color {
+        true => (0..count)
+            .map(|_| {
+                let c = reader.take(3)?;
+                Ok(Some(Color::new(c[0], c[1], c[2])))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?,
+        false => vec![None; count],
+    };
+
+    let min = Vector3::new(reader.f32()?, reader.f32()?, reader.f32()?).cast::<f64>();
+    let range = reader.f32()? as f64;
+    let bits = reader.u8()?;
+    ensure!(
+        (1..=30).contains(&bits),
+        "Invalid quantization bits {}",
+        bits
+    );
+    let step = range / ((1_u32 << bits) - 1) as f64;
+
+    let points = quantized
+        .chunks(3)
+        .zip(colors)
+        .map(|(q, color)| {
+            let q = Vector3::new(q[0], q[1], q[2]).cast::<f64>();
+            let mut point = Point::from(origin + min + q * step);
+            point.color = color;
+            point
+        })
+        .collect();
+    Ok(points)
+}
+
+/// wrap transform of the prediction: the corrections are wrapped into the range of the values
+struct Wrap {
+    min: i32,
+    max: i32,
+    max_dif: i32,
+    min_correction: i32,
+    max_correction: i32,
+}
+
+impl Wrap {
+    fn new(values: &[i32]) -> Self {
+        let min = values.iter().copied().min().unwrap_or(0);
+        let max = values.iter().copied().max().unwrap_or(0);
+        // the quantized values are 0 ~ 2^30 - 1, so the range is valid
+        Self::from_range(min, max).unwrap()
+    }
+
+    fn from_range(min: i32, max: i32) -> anyhow::Result<Self> {
+        let dif = max as i64 - min as i64;
+        ensure!(
+            (0..i32::MAX as i64).contains(&dif),
+            "Invalid range of the Draco values {} ~ {}",
+            min,
+            max
+        );
+        let max_dif = 1 + dif as i32;
+        let max_correction = max_dif / 2 - (max_dif % 2 == 0) as i32;
+        Ok(Self {
+            min,
+            max,
+            max_dif,
+            min_correction: -(max_dif / 2),
+            max_correction,
+        })
+    }
+
+    fn correction(&self, value: i32, predicted: i32) -> i32 {
+        let correction = value - predicted.clamp(self.min, self.max);
+        if correction < self.min_correction {
+            correction + self.max_dif
+        } else if correction > self.max_correction {
+            correction - self.max_dif
+        } else {
+            correction
+        }
+    }
+
+    fn original(&self, correction: i32, predicted: i32) -> i32 {
+        let value = predicted.clamp(self.min, self.max).wrapping_add(correction);
+        if value > self.max {
+            value - self.max_dif
+        } else if value < self.min {
+            value + self.max_dif
+        } else {
+            value
+        }
+    }
+}
+
+/// non-negative symbol of a signed value (`2 * v` for the positive, `-2 * v - 1` for the negative)
+fn signed_to_symbol(v: i32) -> u32 {
+    match v >= 0 {
+        true => (v as u32) << 1,
+        false => ((-(v + 1)) as u32) << 1 | 1,
+    }
+}
+
+fn symbol_to_signed(s: u32) -> i32 {
+    match s & 1 {
+        0 => (s >> 1) as i32,
+        _ => -((s >> 1) as i32) - 1,
+    }
+}
+
+/// number of the bits of the value (1 for zero)
+fn bit_length(v: u32) -> u32 {
+    (32 - v.leading_zeros()).max(1)
+}
+
+/// precision of the rANS probabilities for the symbols of the bit length
+fn rans_precision_bits(bit_length: u32) -> u32 {
+    (3 * bit_length / 2).clamp(12, 20)
+}
+
+/// Entropy code the symbols of the values of `components` each, with the smaller of the schemes:
+/// `RAW` codes each symbol with rANS, `TAGGED` codes the bit length of each value with rANS followed by the bits
+fn encode_symbols(symbols: &[u32], components: usize) -> Vec<u8> {
+    if symbols.is_empty() {
+        return vec![];
+    }
+    let tagged = encode_tagged_symbols(symbols, components);
+    match encode_raw_symbols(symbols) {
+        Some(raw) if raw.len() < tagged.len() => raw,
+        _ => tagged,
+    }
+}
+
+fn encode_raw_symbols(symbols: &[u32]) -> Option<Vec<u8>> {
+    let max = symbols.iter().copied().max().unwrap_or(0);
+    let bits = bit_length(max);
+    if bits > MAX_RAW_BIT_LENGTH {
+        return None;
+    }
+    let mut bytes = vec![RAW, bits as u8];
+    let mut encoder = RAnsEncoder::new(symbols, max as usize + 1, rans_precision_bits(bits));
+    encoder.write_table(&mut bytes);
+    for s in symbols.iter().rev() {
+        encoder.write(*s);
+    }
+    encoder.finish(&mut bytes);
+    Some(bytes)
+}
+
+fn encode_tagged_symbols(symbols: &[u32], components: usize) -> Vec<u8> {
+    let tags: Vec<u32> = symbols
+        .chunks(components)
+        .map(|value| bit_length(value.iter().copied().max().unwrap_or(0)))
+        .collect();
+    let max_tag = tags.iter().copied().max().unwrap_or(0);
+    let mut bytes = vec![TAGGED];
+    let mut encoder = RAnsEncoder::new(
+        &tags,
+        max_tag as usize + 1,
+        rans_precision_bits(TAG_BIT_LENGTH),
+    );
+    encoder.write_table(&mut bytes);
+    for tag in tags.iter().rev() {
+        encoder.write(*tag);
+    }
+    encoder.finish(&mut bytes);
+
+    // the bits of the values follow the tags, the least significant bit first
+    let mut bits = BitWriter::default();
+    for (value, tag) in symbols.chunks(components).zip(tags.iter()) {
+        value.iter().for_each(|v| bits.write(*v, *tag));
+    }
+    bytes.extend(bits.bytes);
+    bytes
+}
+
+fn decode_symbols(
+    reader: &mut Reader,
+    count: usize,
+    components: usize,
+) -> anyhow::Result<Vec<u32>> {
+    if count == 0 {
+        return Ok(vec![]);
+    }
+    match reader.u8()? {
+        TAGGED => {
+            let mut tags = RAnsDecoder::new(reader, rans_precision_bits(TAG_BIT_LENGTH))?;
+            let mut bits = BitReader {
+                bytes: &reader.bytes[reader.pos..],
+                offset: 0,
+            };
+            let mut symbols = Vec::with_capacity(count);
+            while symbols.len() < count {
+                let tag = tags.symbol()?;
+                ensure!(
+                    (1..=32).contains(&tag),
+                    "Invalid bit length {} of the Draco values",
+                    tag
+                );
+                for _ in 0..components {
+                    symbols.push(bits.read(tag)?);
+                }
+            }
+            reader.pos += bits.offset.div_ceil(8);
+            Ok(symbols)
+        }
+        RAW => {
+            let bits = reader.u8()? as u32;
+            ensure!(
+                (1..=MAX_RAW_BIT_LENGTH).contains(&bits),
+                "Invalid bit length {} of the Draco symbols",
+                bits
+            );
+            let mut decoder = RAnsDecoder::new(reader, rans_precision_bits(bits))?;
+            (0..count).map(|_| decoder.symbol()).collect()
+        }
+        scheme => bail!("Draco symbol coding {} is not supported", scheme),
+    }
+}
+
+/// rANS coder of the Draco symbols (base 256, the state renormalized between `4 * precision` and `1024 * precision`)
+struct RAnsEncoder {
+    precision_bits: u32,
+    probabilities: Vec<u32>,
+    cumulative: Vec<u32>,
+    state: u32,
+    bytes: Vec<u8>,
+}
+
+impl RAnsEncoder {
+    /// probabilities of the symbols (0 ~ `symbols - 1`) in proportion to their frequencies,
+    /// at least 1 for the symbols present, summed to the precision
+    fn new(values: &[u32], symbols: usize, precision_bits: u32) -> Self {
+        let precision = 1_u64 << precision_bits;
+        let mut frequencies = vec![0_u64; symbols];
+        values.iter().for_each(|v| frequencies[*v as usize] += 1);
+        let total = values.len().max(1) as u64;
+        let mut probabilities: Vec<u32> = frequencies
+            .iter()
+            .map(|f| match f {
+                0 => 0,
+                f => (f * precision / total).max(1) as u32,
+            })
+            .collect();
+        let mut order: Vec<usize> = (0..symbols).collect();
+        order.sort_by_key(|i| std::cmp::Reverse(probabilities[*i]));
+        let sum: u64 = probabilities.iter().map(|p| *p as u64).sum();
+        if sum < precision {
+            probabilities[order[0]] += (precision - sum) as u32;
+        } else {
+            // the symbols present are at most the precision, so the excess is taken from the likely ones
+            let mut excess = sum - precision;
+            for i in order {
+                let taken = excess.min(probabilities[i].saturating_sub(1) as u64);
+                probabilities[i] -= taken as u32;
+                excess -= taken;
+            }
+        }
+        let cumulative = probabilities
+            .iter()
+            .scan(0, |cumulative, p| {
+                let c = *cumulative;
+                *cumulative += p;
+                Some(c)
+            })
+            .collect();
+        Self {
+            precision_bits,
+            probabilities,
+            cumulative,
+            state: 4 << precision_bits,
+            bytes: vec![],
+        }
+    }
+
+    /// number of the symbols and their probabilities, the runs of zero probability in a byte
+    fn write_table(&self, bytes: &mut Vec<u8>) {
+        push_varint(bytes, self.probabilities.len() as u32);
+        let mut i = 0;
+        while i < self.probabilities.len() {
+            let p = self.probabilities[i];
+            if p == 0 {
+                let run = self.probabilities[i..]
+                    .iter()
+                    .take(64)
+                    .take_while(|p| **p == 0)
+                    .count();
+                bytes.push(((run as u8 - 1) << 2) | 3);
+                i += run;
+                continue;
+            }
+            let extra = match p {
+                p if p < 1 << 6 => 0,
+                p if p < 1 << 14 => 1,
+                _ => 2,
+            };
+            bytes.push(((p << 2) as u8) | extra as u8);
+            for b in 0..extra {
+                bytes.push((p >> (8 * (b + 1) - 2)) as u8);
+            }
+            i += 1;
+        }
+    }
+
+    /// write the symbols in the reverse order of the decoding
+    fn write(&mut self, symbol: u32) {
+        let p = self.probabilities[symbol as usize];
+        while self.state as u64 >= (1024_u64 * p as u64) {
+            self.bytes.push(self.state as u8);
+            self.state >>= 8;
+        }
+        self.state = ((self.state / p) << self.precision_bits)
+            + self.state % p
+            + self.cumulative[symbol as usize];
+    }
+
+    /// the size, the written bytes and the final state in 1 ~ 4 bytes tagged by the top 2 bits
+    fn finish(mut self, bytes: &mut Vec<u8>) {
+        let state = self.state - (4 << self.precision_bits);
+        match state {
+            s if s < 1 << 6 => self.bytes.push(s as u8),
+            s if s < 1 << 14 => self.bytes.extend(((1 << 14) | s as u16).to_le_bytes()),
+            s if s < 1 << 22 => self
+                .bytes
+                .extend(((2 << 22) | s).to_le_bytes()[..3].to_vec()),
+            s => self.bytes.extend(((3 << 30) | s).to_le_bytes()),
+        }
+        push_varint(bytes, self.bytes.len() as u32);
+        bytes.extend(self.bytes);
+    }
+}
+
+struct RAnsDecoder<'a> {
+    precision_bits: u32,
+    probabilities: Vec<u32>,
+    cumulative: Vec<u32>,
+    /// symbol of each slot of the precision
+    lookup: Vec<u32>,
+    state: u32,
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> RAnsDecoder<'a> {
+    /// read the table and the coded bytes written by `RAnsEncoder`
+    fn new(reader: &mut Reader<'a>, precision_bits: u32) -> anyhow::Result<Self> {
+        let precision = 1_u32 << precision_bits;
+        let symbols = reader.varint()? as usize;
+        ensure!(
+            symbols <= reader.bytes.len() * 64,
+            "Invalid number of Draco symbols {}",
+            symbols
+        );
+        let mut probabilities = vec![0_u32; symbols];
+        let mut i = 0;
+        while i < symbols {
+            let first = reader.u8()?;
+            match first & 3 {
+                3 => i += (first >> 2) as usize + 1,
+                extra => {
+                    let mut p = (first >> 2) as u32;
+                    for b in 0..extra as u32 {
+                        p |= (reader.u8()? as u32) << (8 * (b + 1) - 2);
+                    }
+                    probabilities[i] = p;
+                    i += 1;
+                }
+            }
+        }
+        ensure!(i == symbols, "Invalid Draco probability table");
+        let mut cumulative = Vec::with_capacity(symbols);
+        let mut lookup = Vec::with_capacity(precision as usize);
+        for (symbol, p) in probabilities.iter().enumerate() {
+            cumulative.push(lookup.len() as u32);
+            ensure!(
+                lookup.len() + *p as usize <= precision as usize,
+                "Invalid Draco probability table"
+            );
+            lookup.extend(std::iter::repeat_n(symbol as u32, *p as usize));
+        }
+        ensure!(
+            lookup.len() == precision as usize,
+            "Invalid Draco probability table"
+        );
+
+        let size = reader.varint()? as usize;
+        let bytes = reader.take(size)?;
+        ensure!(!bytes.is_empty(), "Draco rANS data is empty");
+        let last = bytes[size - 1];
+        let n = (last >> 6) as usize + 1;
+        ensure!(size >= n, "Draco rANS data is truncated");
+        let mut state = [0_u8; 4];
+        state[..n].copy_from_slice(&bytes[size - n..]);
+        let state = u32::from_le_bytes(state) & ((1 << (8 * n - 2)) - 1);
+        Ok(Self {
+            precision_bits,
+            probabilities,
+            cumulative,
+            lookup,
+            state: state + (4 << precision_bits),
+            bytes,
+            offset: size - n,
+        })
+    }
+
+    fn symbol(&mut self) -> anyhow::Result<u32> {
+        while self.state < 4 << self.precision_bits && self.offset > 0 {
+            self.offset -= 1;
+            self.state = (self.state << 8) | self.bytes[self.offset] as u32;
+        }
+        let quotient = self.state >> self.precision_bits;
+        let remainder = self.state & ((1 << self.precision_bits) - 1);
+        let symbol = self.lookup[remainder as usize];
+        let p = self.probabilities[symbol as usize];
+        self.state = quotient * p + remainder - self.cumulative[symbol as usize];
+        Ok(symbol)
+    }
+}
+
+/// bits of the values, the least significant bit first
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    offset: usize,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, bits: u32) {
+        for bit in 0..bits {
+            if self.offset.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            *self.bytes.last_mut().unwrap() |= (((value >> bit) & 1) as u8) << (self.offset % 8);
+            self.offset += 1;
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, bits: u32) -> anyhow::Result<u32> {
+        ensure!(
+            self.offset + bits as usize <= self.bytes.len() * 8,
+            "Draco data is truncated"
+        );
+        let mut value = 0;
+        for bit in 0..bits {
+            let b = (self.bytes[self.offset / 8] >> (self.offset % 8)) & 1;
+            value |= (b as u32) << bit;
+            self.offset += 1;
+        }
+        Ok(value)
+    }
+}
+
+/// LEB128 of the Draco varints
+fn push_varint(bytes: &mut Vec<u8>, mut v: u32) {
+    while v >= 0x80 {
+        bytes.push((v as u8 & 0x7f) | 0x80);
+        v >>= 7;
+    }
+    bytes.push(v as u8);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        ensure!(self.pos + n <= self.bytes.len(), "Draco data is truncated");
+        let taken = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn i32(&mut self) -> anyhow::Result<i32> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn f32(&mut self) -> anyhow::Result<f32> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn varint(&mut self) -> anyhow::Result<u32> {
+        let mut v = 0_u32;
+        for shift in (0..35).step_by(7) {
+            let b = self.u8()?;
+            v |= ((b & 0x7f) as u32) << shift;
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        bail!("Invalid Draco varint")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points() -> Vec<Point> {
+        (0..200)
+            .map(|i| {
+                let f = i as f64;
+                let line = format!(
+                    "{} {} {} {} {} {}",
+                    1000. + f.sin() * 20.,
+                    -500. + f.cos() * 5.,
+                    f * 0.05,
+                    i % 256,
+                    (i * 7) % 256,
+                    255 - i % 256
+                );
+                Point::try_parse(&line).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn draco_round_trip_within_quantization() {
+        let points = points();
+        let bounds = BoundingBox::from_iter(points.iter().map(|p| p.position));
+        for bits in [8, 11, 16, 30] {
+            let bytes = encode_draco(&points, bounds.min(), bits).unwrap();
+            assert_eq!(&bytes[..5], b"DRACO");
+            let decoded = decode_draco(&bytes, bounds.min()).unwrap();
+            assert_eq!(decoded.len(), points.len());
+
+            // half of the step, and the precision of f32 in the bounds
+            let step = bounds.max_size() / ((1_u32 << bits) - 1) as f64;
+            let tolerance = step * 0.5 + bounds.max_size() * 1e-6;
+            for (p, q) in points.iter().zip(decoded.iter()) {
+                assert!((p.position - q.position).abs().max() <= tolerance);
+                assert_eq!(p.color, q.color);
+            }
+        }
+    }
+
+    #[test]
+    fn draco_keeps_precision_far_from_origin() {
+        // georeferenced coordinates, where f32 has a step of about 0.06
+        let offset = Vector3::new(500_000., 5_000_000., 100.);
+        let points: Vec<Point> = points()
+            .iter()
+            .map(|p| Point::from(p.position * 0.01 + offset))
+            .collect();
+        let bounds = BoundingBox::from_iter(points.iter().map(|p| p.position));
+        let bytes = encode_draco(&points, bounds.min(), 16).unwrap();
+        let decoded = decode_draco(&bytes, bounds.min()).unwrap();
+        let step = bounds.max_size() / ((1_u32 << 16) - 1) as f64;
+        for (p, q) in points.iter().zip(decoded.iter()) {
+            assert!((p.position - q.position).abs().max() <= step, "{}", step);
+        }
+    }
+
+    #[test]
+    fn draco_positions_are_entropy_coded() {
+        // a scan line of nearby points predicts well from the previous point
+        let line: Vec<Point> = (0..10000)
+            .map(|i| {
+                let f = i as f64 * 0.01;
+                Point::from(Point3::new(f, f.sin(), 0.))
+            })
+            .collect();
+        let bytes = encode_draco(&line, &Point3::origin(), 16).unwrap();
+        assert!(bytes.len() < line.len() * 3, "{}", bytes.len());
+        let decoded = decode_draco(&bytes, &Point3::origin()).unwrap();
+        assert_eq!(decoded.len(), line.len());
+
+        // random points fall back to the bits of each value
+        let points = points();
+        let bytes = encode_draco(&points, &Point3::origin(), 12).unwrap();
+        assert!(
+            bytes.len() < points.len() * (3 * 2 + 3) + 100,
+            "{}",
+            bytes.len()
+        );
+    }
+
+    #[test]
+    fn symbols_round_trip() {
+        let symbols: Vec<Vec<u32>> = vec![
+            vec![0; 30],
+            (0..300).map(|i| i % 7).collect(),
+            (0..300).map(|i| (i * 7919) % 100_000).collect(),
+            (0..300)
+                .map(|i| (i as u32).wrapping_mul(2_654_435_761))
+                .collect(),
+            vec![5, 0, 0, 1 << 20, 3, 3],
+        ];
+        for symbols in symbols {
+            for (scheme, bytes) in [
+                ("tagged", Some(encode_tagged_symbols(&symbols, 3))),
+                ("raw", encode_raw_symbols(&symbols)),
+                ("best", Some(encode_symbols(&symbols, 3))),
+            ] {
+                let Some(mut bytes) = bytes else {
+                    continue;
+                };
+                bytes.push(42);
+                let mut reader = Reader {
+                    bytes: &bytes,
+                    pos: 0,
+                };
+                let decoded = decode_symbols(&mut reader, symbols.len(), 3).unwrap();
+                assert_eq!(decoded, symbols, "{}", scheme);
+                assert_eq!(reader.u8().unwrap(), 42, "{}", scheme);
+            }
+        }
+    }
+
+    #[test]
+    fn wrap_corrections() {
+        let values = [3, 9, 4, 4, 12, 3];
+        let wrap = Wrap::new(&values);
+        let mut predicted = 0;
+        for v in values {
+            let correction = wrap.correction(v, predicted);
+            assert!((wrap.min_correction..=wrap.max_correction).contains(&correction));
+            assert_eq!(wrap.original(correction, predicted), v);
+            predicted = v;
+        }
+        for v in [0, 1, -1, 1000, -1000, i32::MAX, i32::MIN + 1] {
+            assert_eq!(symbol_to_signed(signed_to_symbol(v)), v);
+        }
+    }
+
+    #[test]
+    fn draco_without_color() {
+        let origin = Point3::origin();
+        let points: Vec<Point> = points().iter().map(|p| Point::from(p.position)).collect();
+        let decoded = decode_draco(&encode_draco(&points, &origin, 12).unwrap(), &origin).unwrap();
+        assert!(decoded.iter().all(|p| p.color.is_none()));
+        assert!(
+            decode_draco(&encode_draco(&[], &origin, 12).unwrap(), &origin)
+                .unwrap()
+                .is_empty()
+        );
+
+        assert!(encode_draco(&points, &origin, 0).is_err());
+        assert!(encode_draco(&points, &origin, 31).is_err());
+        let bytes = encode_draco(&points, &origin, 12).unwrap();
+        assert!(decode_draco(&bytes[..bytes.len() - 1], &origin).is_err());
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        for v in [0, 1, 127, 128, 300, u32::MAX] {
+            let mut bytes = vec![];
+            push_varint(&mut bytes, v);
+            let mut reader = Reader {
+                bytes: &bytes,
+                pos: 0,
+            };
+            assert_eq!(reader.varint().unwrap(), v);
+            assert_eq!(reader.pos, bytes.len());
+        }
+    }
+}
@@ -1,13 +1,326 @@
-use std::iter::FromIterator;
+use std::{io::Cursor, iter::FromIterator, sync::Arc};
 
-use image::{Rgba, Rgba32FImage, RgbaImage};
+use anyhow::ensure;
+use image::{
+    codecs::png::{CompressionType, FilterType},
+    DynamicImage, GrayImage, ImageBuffer, ImageFormat, Luma, Rgba, Rgba32FImage, RgbaImage,
+};
+use nalgebra::{Point3, Vector3};
+use serde::{Deserialize, Serialize};
 
-use crate::{prelude::BoundingBox, prelude::Color, prelude::Point};
+use crate::{
+    prelude::BoundingBox, prelude::Color, prelude::DepthMode, prelude::PixelLayout, prelude::Point,
+    prelude::RowMajor,
+};
+
+/// Bit depth of the position image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BitDepth {
+    /// 8-bit per channel (`encode_8bit`)
+    #[default]
+    Eight,
+    /// 8-bit per channel split into 4 quadrants (`encode_8bit_quad`)
+    EightQuad,
+    /// 32-bit float per channel (`encode_32bit`)
+    ThirtyTwo,
+}
+
+/// Kind of the image emitted by the encoder
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ImageKind {
+    Position,
+    Color,
+    Intensity,
+    Normal,
+    Radius,
+    Index,
+    Depth,
+}
+
+impl ImageKind {
+    /// name of the image kind
+    pub fn name(&self) -> &'static str {
+        match self {
+            ImageKind::Position => "position",
+            ImageKind::Color => "color",
+            ImageKind::Intensity => "intensity",
+            ImageKind::Normal => "normal",
+            ImageKind::Radius => "radius",
+            ImageKind::Index => "index",
+            ImageKind::Depth => "depth",
+        }
+    }
+
+    /// suffix appended to the tile file name (e.g. `0-3-1-color.png`)
+    pub fn suffix(&self) -> String {
+        match self {
+            ImageKind::Position => String::new(),
+            _ => format!("-{}", self.name()),
+        }
+    }
+}
+
+/// An image emitted by the encoder
+pub struct EncodedImage {
+    pub kind: ImageKind,
+    pub image: DynamicImage,
+}
+
+/// 16-bit RGBA image of the integer coordinates of `Encoder::encode_quantized`
+pub type Rgba16Image = ImageBuffer<Rgba<u16>, Vec<u16>>;
+
+/// Origin and step of the integer coordinates of `Encoder::encode_quantized`,
+/// the world coordinate is `origin + q * step` for each axis
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Quantization {
+    pub bits: u8,
+    pub origin: Point3<f64>,
+    /// size of the bounding box divided by `2^bits - 1` (0 for a flat axis)
+    pub step: Vector3<f64>,
+}
+
+impl Quantization {
+    /// world coordinate of the integer coordinate
+    pub fn position(&self, q: [u16; 3]) -> Point3<f64> {
+        self.origin + Vector3::from(q.map(|v| v as f64)).component_mul(&self.step)
+    }
+}
+
+/// Options to select which channels the encoder emits and at what depth
+#[derive(Debug, Clone)]
+pub struct EncoderOptions {
+    bit_depth: BitDepth,
+    color: bool,
+    intensity: bool,
+    normals: bool,
+    radius: bool,
+    index: bool,
+    depth: Option<DepthMode>,
+    alpha_as_color: bool,
+    intensity_in_alpha: Option<bool>,
+    intensity_in_position_alpha: bool,
+    default_color: Color,
+    color_by_time: Option<(f64, f64)>,
+    gamma: Option<f64>,
+    pixel_layout: Arc<dyn PixelLayout>,
+    png_compression: CompressionType,
+    position_png_filter: FilterType,
+    color_png_filter: FilterType,
+}
+
+impl Default for EncoderOptions {
+    fn default() -> Self {
+        Self {
+            bit_depth: BitDepth::Eight,
+            color: true,
+            intensity: false,
+            normals: false,
+            radius: false,
+            index: false,
+            depth: None,
+            alpha_as_color: false,
+            intensity_in_alpha: None,
+            intensity_in_position_alpha: false,
+            default_color: Color::white(),
+            color_by_time: None,
+            gamma: None,
+            pixel_layout: Arc::new(RowMajor),
+            png_compression: CompressionType::default(),
+            position_png_filter: FilterType::default(),
+            color_png_filter: FilterType::default(),
+        }
+    }
+}
+
+impl EncoderOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// bit depth of the position image
+    pub fn bit_depth(mut self, bit_depth: BitDepth) -> Self {
+        self.bit_depth = bit_depth;
+        self
+    }
+
+    /// emit the color image
+    pub fn color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// emit the intensity image
+    pub fn intensity(mut self, intensity: bool) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    /// emit the normal image (only if any point has a normal)
+    pub fn normals(mut self, normals: bool) -> Self {
+        self.normals = normals;
+        self
+    }
+
+    pub fn has_normals(&self) -> bool {
+        self.normals
+    }
+
+    /// emit the radius image (only if any point has a radius)
+    pub fn radius(mut self, radius: bool) -> Self {
+        self.radius = radius;
+        self
+    }
+
+    pub fn has_radius(&self) -> bool {
+        self.radius
+    }
+
+    /// emit the image of the input indices (only if any point has an index)
+    pub fn index(mut self, index: bool) -> Self {
+        self.index = index;
+        self
+    }
+
+    pub fn has_index(&self) -> bool {
+        self.index
+    }
+
+    /// emit the depth image of the mode (only for the finest level in `process_lod`)
+    pub fn depth(mut self, depth: Option<DepthMode>) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    pub fn depth_mode(&self) -> Option<DepthMode> {
+        self.depth
+    }
+
+    /// side of the position image per `ceil(sqrt(n))` of n points
+    pub fn side_scale(&self) -> u32 {
+        match self.bit_depth {
+            BitDepth::EightQuad => 2,
+            _ => 1,
+        }
+    }
+
+    /// store color in the alpha channel of the position image (only for `BitDepth::EightQuad`)
+    pub fn alpha_as_color(mut self, alpha_as_color: bool) -> Self {
+        self.alpha_as_color = alpha_as_color;
+        self
+    }
+
+    /// store intensity in the alpha channel of the color image in `encode_unit` (255 if false),
+    /// by default for the 8-bit images but not for `BitDepth::ThirtyTwo`
+    pub fn intensity_in_alpha(mut self, intensity_in_alpha: bool) -> Self {
+        self.intensity_in_alpha = Some(intensity_in_alpha);
+        self
+    }
+
+    pub fn has_intensity_in_alpha(&self) -> bool {
+        self.intensity_in_alpha
+            .unwrap_or(self.bit_depth != BitDepth::ThirtyTwo)
+    }
+
+    /// store intensity in the alpha channel of the position image instead of the intensity image
+    pub fn intensity_in_position_alpha(mut self, intensity_in_position_alpha: bool) -> Self {
+        self.intensity_in_position_alpha = intensity_in_position_alpha;
+        self
+    }
+
+    pub fn has_intensity_in_position_alpha(&self) -> bool {
+        self.intensity_in_position_alpha
+    }
+
+    /// color used for the points without color in `encode_unit` (white by default)
+    pub fn default_color(mut self, color: Color) -> Self {
+        self.default_color = color;
+        self
+    }
+
+    pub fn get_default_color(&self) -> Color {
+        self.default_color
+    }
+
+    /// color the points by GPS time in the range (min, max) with `Color::ramp` in `encode_unit` (none by default)
+    pub fn color_by_time(mut self, range: Option<(f64, f64)>) -> Self {
+        self.color_by_time = range;
+        self
+    }
+
+    pub fn get_color_by_time(&self) -> Option<(f64, f64)> {
+        self.color_by_time
+    }
+
+    /// gamma applied to the channels of the color image in `encode_unit` (`c^gamma`, none by default)
+    pub fn gamma(mut self, gamma: Option<f64>) -> Self {
+        self.gamma = gamma;
+        self
+    }
+
+    pub fn get_gamma(&self) -> Option<f64> {
+        self.gamma
+    }
+
+    /// order of the points in the pixels of the images (`RowMajor` by default), recorded in meta.json
+    pub fn pixel_layout(mut self, layout: Arc<dyn PixelLayout>) -> Self {
+        self.pixel_layout = layout;
+        self
+    }
+
+    pub fn get_pixel_layout(&self) -> &Arc<dyn PixelLayout> {
+        &self.pixel_layout
+    }
+
+    /// compression level of the PNG images
+    pub fn png_compression(mut self, compression: CompressionType) -> Self {
+        self.png_compression = compression;
+        self
+    }
+
+    /// PNG filter of the position image (high-entropy low bytes)
+    pub fn position_png_filter(mut self, filter: FilterType) -> Self {
+        self.position_png_filter = filter;
+        self
+    }
+
+    /// PNG filter of the other images (color, intensity)
+    pub fn color_png_filter(mut self, filter: FilterType) -> Self {
+        self.color_png_filter = filter;
+        self
+    }
+
+    /// PNG compression level and filter for the image kind
+    pub fn png_quality(&self, kind: ImageKind) -> (CompressionType, FilterType) {
+        let filter = match kind {
+            ImageKind::Position => self.position_png_filter,
+            _ => self.color_png_filter,
+        };
+        (self.png_compression, filter)
+    }
+}
 
 /// Point cloud encoder to generate images from point cloud data
 pub struct Encoder {
     /// Normalized points in the range of 0.0 ~ 1.0 for x, y, z coordinates based on the bounding box
     normalized: Vec<Point>,
+    /// min of the bounding box
+    origin: Point3<f64>,
+    /// size of the bounding box
+    size: Vector3<f64>,
+    /// color used for the points without color
+    default_color: Color,
+    /// store intensity in the alpha channel of the color image (the default of the bit depth if not given)
+    intensity_in_alpha: Option<bool>,
+    /// store intensity in the alpha channel of the position image
+    intensity_in_position_alpha: bool,
+    /// range of GPS time mapped to the colors of `Color::ramp` instead of the colors of the points
+    color_by_time: Option<(f64, f64)>,
+    /// axes of zero size in the bounding box (all the points share the coordinate)
+    flat_axes: [bool; 3],
+    /// gamma applied to the channels of the color image
+    gamma: Option<f64>,
+    /// order of the points in the pixels of the images
+    pixel_layout: Arc<dyn PixelLayout>,
 }
 
 impl Encoder {
@@ -15,45 +328,333 @@ impl Encoder {
         let bbox = bbox.unwrap_or(BoundingBox::from_iter(points.iter().map(|p| p.position)));
         let min = bbox.min();
         let size = bbox.size();
+        let max_size = bbox.max_size();
         let normalized: Vec<_> = points
             .iter()
             .map(|pt| {
-                let p = pt.position - min;
-                let normalized = p.component_div(&size);
                 // x, y, z -> 0.0 ~ 1.0, 0.0 ~ 1.0, 0.0 ~ 1.0
+                // (a flat axis, e.g. of a planar tile, is at the center instead of NaN)
+                // radius -> ratio to the max size of the bounding box
                 Point {
-                    position: normalized.into(),
-                    color: pt.color,
-                    intensity: pt.intensity,
+                    position: bbox.normalize(&pt.position).into(),
+                    radius: pt
+                        .radius
+                        .map(|r| if max_size > 0. { r / max_size } else { 0. }),
+                    ..pt.clone()
                 }
             })
             .collect();
 
-        Self { normalized }
+        Self {
+            normalized,
+            origin: *min,
+            size,
+            default_color: Color::white(),
+            intensity_in_alpha: None,
+            intensity_in_position_alpha: false,
+            color_by_time: None,
+            flat_axes: std::array::from_fn(|i| size[i] <= 0.),
+            gamma: None,
+            pixel_layout: Arc::new(RowMajor),
+        }
+    }
+
+    /// normalize the positions over the extent of each axis from the min of the bounding box
+    /// (`BoundingBox::extent_of` the points, recorded in `Meta::tile_scales`) instead of the size of the box,
+    /// so a flat tile in a larger box (e.g. a cube) keeps the precision of its thin axis
+    pub fn position_scale(mut self, scale: Vector3<f64>) -> Self {
+        let size = self.size;
+        self.normalized.iter_mut().for_each(|p| {
+            p.position = Point3::from(Vector3::from_fn(|i, _| match scale[i] > 0. {
+                true => (p.position[i] * size[i] / scale[i]).min(1.),
+                false => p.position[i],
+            }));
+        });
+        self.size = scale;
+        self.flat_axes = std::array::from_fn(|i| scale[i] <= 0.);
+        self
+    }
+
+    /// normalized coordinate of the axes of zero size (0.5 by default)
+    pub fn flat_axis(mut self, value: f64) -> Self {
+        for (i, _) in self.flat_axes.iter().enumerate().filter(|(_, f)| **f) {
+            self.normalized
+                .iter_mut()
+                .for_each(|p| p.position[i] = value);
+        }
+        self
+    }
+
+    /// store intensity in the alpha channel of the color image (255 if false),
+    /// by default in `encode_8bit` but not in `encode_32bit`
+    pub fn intensity_in_alpha(mut self, intensity_in_alpha: bool) -> Self {
+        self.intensity_in_alpha = Some(intensity_in_alpha);
+        self
+    }
+
+    /// store intensity in the alpha channel of the position image (255 if false, false by default),
+    /// quantized to 1 ~ 255 so that the pixels of the points are not mistaken for the padding
+    pub fn intensity_in_position_alpha(mut self, intensity_in_position_alpha: bool) -> Self {
+        self.intensity_in_position_alpha = intensity_in_position_alpha;
+        self
+    }
+
+    /// side of the images, `ceil(sqrt(n))` of n points (1 without points, so that the image is not empty)
+    fn side(&self) -> u32 {
+        ((self.normalized.len() as f64).sqrt().ceil() as u32).max(1)
+    }
+
+    /// color used for the points without color (white by default)
+    pub fn default_color(mut self, color: Color) -> Self {
+        self.default_color = color;
+        self
+    }
+
+    /// color the points by GPS time in the range (min, max) with `Color::ramp`
+    /// (the points without GPS time keep their colors, none by default)
+    pub fn color_by_time(mut self, range: Option<(f64, f64)>) -> Self {
+        self.color_by_time = range;
+        self
+    }
+
+    /// gamma applied to the channels of the color image (`c^gamma` in 0.0 ~ 1.0, none by default)
+    pub fn gamma(mut self, gamma: Option<f64>) -> Self {
+        self.gamma = gamma;
+        self
+    }
+
+    /// order of the points in the pixels of the images (`RowMajor` by default)
+    pub fn pixel_layout(mut self, layout: Arc<dyn PixelLayout>) -> Self {
+        self.pixel_layout = layout;
+        self
+    }
+
+    /// Encode point cloud data to the images selected by the options.
+    pub fn encode(&self, options: &EncoderOptions) -> anyhow::Result<Vec<EncodedImage>> {
+        let mut images = vec![];
+        let (position, color) = match options.bit_depth {
+            BitDepth::Eight => {
+                let (position, color) = self.encode_8bit();
+                (DynamicImage::from(position), DynamicImage::from(color))
+            }
+            BitDepth::EightQuad => {
+                let position = self.encode_8bit_quad(options.alpha_as_color);
+                let (_, color) = self.encode_8bit();
+                (DynamicImage::from(position), DynamicImage::from(color))
+            }
+            BitDepth::ThirtyTwo => {
+                let (position, color) = self.encode_32bit();
+                (DynamicImage::from(position), DynamicImage::from(color))
+            }
+        };
+
+        images.push(EncodedImage {
+            kind: ImageKind::Position,
+            image: position,
+        });
+        if options.color {
+            images.push(EncodedImage {
+                kind: ImageKind::Color,
+                image: color,
+            });
+        }
+        if options.intensity && !self.intensity_in_position_alpha {
+            images.push(EncodedImage {
+                kind: ImageKind::Intensity,
+                image: DynamicImage::from(self.encode_intensity()),
+            });
+        }
+
+        if options.normals && self.normalized.iter().any(|p| p.normal.is_some()) {
+            images.push(EncodedImage {
+                kind: ImageKind::Normal,
+                image: DynamicImage::from(self.encode_normals()),
+            });
+        }
+
+        if options.radius && self.normalized.iter().any(|p| p.radius.is_some()) {
+            images.push(EncodedImage {
+                kind: ImageKind::Radius,
+                image: DynamicImage::from(self.encode_radius()),
+            });
+        }
+
+        if options.index && self.normalized.iter().any(|p| p.index.is_some()) {
+            images.push(EncodedImage {
+                kind: ImageKind::Index,
+                image: DynamicImage::from(self.encode_index()?),
+            });
+        }
+
+        if let Some(mode) = options.depth {
+            images.push(EncodedImage {
+                kind: ImageKind::Depth,
+                image: DynamicImage::from(self.encode_depth(mode)),
+            });
+        }
+
+        Ok(images)
+    }
+
+    /// Encode point cloud data to 8-bit images and return them as PNG bytes in memory.
+    /// The color image is `None` if no point has color.
+    pub fn encode_8bit_png_bytes(&self) -> anyhow::Result<(Vec<u8>, Option<Vec<u8>>)> {
+        let png_bytes = |image: &RgbaImage| -> anyhow::Result<Vec<u8>> {
+            let mut bytes = Cursor::new(vec![]);
+            image.write_to(&mut bytes, ImageFormat::Png)?;
+            Ok(bytes.into_inner())
+        };
+
+        let (position, color) = self.encode_8bit();
+        let position = png_bytes(&position)?;
+        let color = match self.normalized.iter().any(|p| p.color.is_some()) {
+            true => Some(png_bytes(&color)?),
+            false => None,
+        };
+        Ok((position, color))
+    }
+
+    /// Encode input indices of point cloud data to 8-bit image.
+    /// Each pixel holds `index + 1` as little endian u32 in RGBA (0 if the point has no index),
+    /// so the indices must be less than `u32::MAX`.
+    pub fn encode_index(&self) -> anyhow::Result<RgbaImage> {
+        let side = self.side();
+
+        let mut index = RgbaImage::new(side, side);
+        for (idx, p) in self.normalized.iter().enumerate() {
+            let (x, y) = self.pixel_layout.pixel(idx, side);
+            let i =
+                match p.index {
+                    Some(i) => u32::try_from(i).ok().and_then(|i| i.checked_add(1)).ok_or(
+                        anyhow::anyhow!("Point index {} does not fit in the index image", i),
+                    )?,
+                    None => 0,
+                };
+            index.put_pixel(x, y, Rgba(i.to_le_bytes()));
+        }
+
+        Ok(index)
+    }
+
+    /// Encode positions of point cloud data to 16-bit image of integer coordinates in `[0, 2^bits)`
+    /// relative to the min of the bounding box (e.g. for meshing tools working on a grid),
+    /// with the origin and the step to restore the world coordinates.
+    /// The alpha channel is 65535 for the points and 0 for the padding.
+    pub fn encode_quantized(&self, bits: u8) -> anyhow::Result<(Rgba16Image, Quantization)> {
+        ensure!(
+            (1..=16).contains(&bits),
+            "Quantization bits must be 1 ~ 16, got {}",
+            bits
+        );
+        let max = ((1_u32 << bits) - 1) as f64;
+        let side = self.side();
+
+        let mut image = ImageBuffer::new(side, side);
+        self.normalized.iter().enumerate().for_each(|(idx, p)| {
+            let (x, y) = self.pixel_layout.pixel(idx, side);
+            let q = p.position.map(|v| (v.clamp(0., 1.) * max).round() as u16);
+            image.put_pixel(x, y, Rgba([q.x, q.y, q.z, u16::MAX]));
+        });
+
+        let quantization = Quantization {
+            bits,
+            origin: self.origin,
+            step: self.size / max,
+        };
+        Ok((image, quantization))
+    }
+
+    /// Encode radius of point cloud data to 16-bit grayscale image.
+    /// The radius is mapped from 0.0 ~ max size of the bounding box to 0 ~ 65535.
+    pub fn encode_radius(&self) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+        let side = self.side();
+
+        let mut radius = ImageBuffer::new(side, side);
+        self.normalized.iter().enumerate().for_each(|(idx, p)| {
+            let (x, y) = self.pixel_layout.pixel(idx, side);
+            let r = p.radius.unwrap_or(0.).clamp(0., 1.) * u16::MAX as f64;
+            radius.put_pixel(x, y, Luma([r.round() as u16]));
+        });
+
+        radius
+    }
+
+    /// Encode depth of point cloud data to 16-bit grayscale image.
+    /// The depth of the mode is mapped from 0.0 ~ 1.0 to 0 ~ 65535.
+    pub fn encode_depth(&self, mode: DepthMode) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+        let side = self.side();
+        let half_diagonal = 3_f64.sqrt() * 0.5;
+
+        let mut depth = ImageBuffer::new(side, side);
+        self.normalized.iter().enumerate().for_each(|(idx, p)| {
+            let (x, y) = self.pixel_layout.pixel(idx, side);
+            let d = match mode {
+                DepthMode::X => p.position.x,
+                DepthMode::Y => p.position.y,
+                DepthMode::Z => p.position.z,
+                DepthMode::Center => {
+                    (p.position.coords - nalgebra::Vector3::repeat(0.5)).norm() / half_diagonal
+                }
+            };
+            let d = d.clamp(0., 1.) * u16::MAX as f64;
+            depth.put_pixel(x, y, Luma([d.round() as u16]));
+        });
+
+        depth
+    }
+
+    /// Encode normals of point cloud data to 8-bit image.
+    /// Each component of the unit normal is mapped from -1.0 ~ 1.0 to 0 ~ 255.
+    pub fn encode_normals(&self) -> RgbaImage {
+        let side = self.side();
+
+        let mut normals = RgbaImage::new(side, side);
+        self.normalized.iter().enumerate().for_each(|(idx, p)| {
+            let (x, y) = self.pixel_layout.pixel(idx, side);
+            let [nx, ny, nz] = match p.normal {
+                Some(n) => [n.x, n.y, n.z].map(|v| normalized_to_8bit((v + 1.) * 0.5)),
+                None => [0; 3],
+            };
+            normals.put_pixel(x, y, Rgba([nx, ny, nz, u8::MAX]));
+        });
+
+        normals
+    }
+
+    /// Encode intensity of point cloud data to 8-bit grayscale image.
+    pub fn encode_intensity(&self) -> GrayImage {
+        let side = self.side();
+
+        let mut intensity = GrayImage::new(side, side);
+        self.normalized.iter().enumerate().for_each(|(idx, p)| {
+            let (x, y) = self.pixel_layout.pixel(idx, side);
+            let i = normalized_to_8bit(p.intensity.unwrap_or(1.0));
+            intensity.put_pixel(x, y, Luma([i]));
+        });
+
+        intensity
     }
 
     /// Encode point cloud data to 8-bit image.
     /// The 1st image is for position and the 2nd image is for color.
     pub fn encode_8bit(&self) -> (RgbaImage, RgbaImage) {
-        let n = self.normalized.len();
-        let side = (n as f64).sqrt().ceil() as u32;
+        let side = self.side();
 
         let mut position = RgbaImage::new(side, side);
         let mut color = RgbaImage::new(side, side);
         self.normalized.iter().enumerate().for_each(|(idx, p)| {
-            let y = idx as u32 / side;
-            let x = idx as u32 % side;
+            let (x, y) = self.pixel_layout.pixel(idx, side);
             let pos = p.position;
 
             let ix = normalized_to_8bit(pos.x);
             let iy = normalized_to_8bit(pos.y);
             let iz = normalized_to_8bit(pos.z);
-            let c = p.color.unwrap_or(Color::white());
+            let c = self.color(p);
 
-            let intensity = normalized_to_8bit(p.intensity.unwrap_or(1.0));
+            let alpha = self.color_alpha(p, true);
 
-            position.put_pixel(x, y, Rgba([ix, iy, iz, u8::MAX]));
-            color.put_pixel(x, y, Rgba([c.r(), c.g(), c.b(), intensity]));
+            position.put_pixel(x, y, Rgba([ix, iy, iz, self.position_alpha(p)]));
+            color.put_pixel(x, y, Rgba([c.r(), c.g(), c.b(), alpha]));
         });
 
         (position, color)
@@ -64,14 +665,12 @@ impl Encoder {
     /// 1st quadrant: lowest 8-bit of x, y, z, and alpha channel has color.r() (if use_alpha_channel_as_color is true)
     /// 2nd quadrant: 2nd 8-bit of x, y, z, and alpha channel has color.g() (if use_alpha_channel_as_color is true)
     /// 3rd quadrant: 3rd 8-bit of x, y, z, and alpha channel has color.b() (if use_alpha_channel_as_color is true)
-    /// 4th quadrant: highest 8-bit of x, y, z, and alpha channel has 255
+    /// 4th quadrant: highest 8-bit of x, y, z, and alpha channel has 255 (or intensity with `intensity_in_position_alpha`)
     pub fn encode_8bit_quad(&self, use_alpha_channel_as_color: bool) -> RgbaImage {
-        let n = self.normalized.len();
-        let side = (n as f64).sqrt().ceil() as u32;
+        let side = self.side();
         let mut img8u = RgbaImage::new(side * 2, side * 2);
         self.normalized.iter().enumerate().for_each(|(idx, p)| {
-            let y = idx as u32 / side;
-            let x = idx as u32 % side;
+            let (x, y) = self.pixel_layout.pixel(idx, side);
             let pos = p.position;
 
             // convert f64 to f32, and split into 4 channels each with 8-bit
@@ -80,7 +679,7 @@ impl Encoder {
             let iz = encode_8bit_4channels(pos.z);
 
             if use_alpha_channel_as_color {
-                let color = p.color.unwrap_or(Color::white());
+                let color = self.color(p);
                 img8u.put_pixel(x, y, Rgba([ix.0, iy.0, iz.0, color.r()]));
                 img8u.put_pixel(x + side, y, Rgba([ix.1, iy.1, iz.1, color.g()]));
                 img8u.put_pixel(x, y + side, Rgba([ix.2, iy.2, iz.2, color.b()]));
@@ -89,7 +688,11 @@ impl Encoder {
                 img8u.put_pixel(x + side, y, Rgba([ix.1, iy.1, iz.1, u8::MAX]));
                 img8u.put_pixel(x, y + side, Rgba([ix.2, iy.2, iz.2, u8::MAX]));
             }
-            img8u.put_pixel(x + side, y + side, Rgba([ix.3, iy.3, iz.3, u8::MAX]));
+            img8u.put_pixel(
+                x + side,
+                y + side,
+                Rgba([ix.3, iy.3, iz.3, self.position_alpha(p)]),
+            );
         });
 
         img8u
@@ -98,25 +701,60 @@ impl Encoder {
     /// Encode point cloud data to 32-bit image.
     /// The 1st image is for position and the 2nd image is for color.
     pub fn encode_32bit(&self) -> (Rgba32FImage, RgbaImage) {
-        let n = self.normalized.len();
-        let side = (n as f64).sqrt().ceil() as u32;
+        let side = self.side();
 
         let mut position = Rgba32FImage::new(side, side);
         let mut color = RgbaImage::new(side, side);
 
         self.normalized.iter().enumerate().for_each(|(idx, p)| {
-            let y = idx as u32 / side;
-            let x = idx as u32 % side;
+            let (x, y) = self.pixel_layout.pixel(idx, side);
             let pos = p.position;
             let cast = pos.cast::<f32>();
-            position.put_pixel(x, y, Rgba([cast.x, cast.y, cast.z, 1.0]));
+            let alpha = self.position_alpha(p) as f32 / u8::MAX as f32;
+            position.put_pixel(x, y, Rgba([cast.x, cast.y, cast.z, alpha]));
 
-            let c = p.color.unwrap_or(Color::white());
-            color.put_pixel(x, y, Rgba([c.r(), c.g(), c.b(), u8::MAX]));
+            let c = self.color(p);
+            color.put_pixel(
+                x,
+                y,
+                Rgba([c.r(), c.g(), c.b(), self.color_alpha(p, false)]),
+            );
         });
 
         (position, color)
     }
+
+    /// color of the point (by GPS time, its own or the default color) with the gamma applied
+    fn color(&self, p: &Point) -> Color {
+        let by_time = self.color_by_time.zip(p.gps_time).map(|((min, max), t)| {
+            let span = max - min;
+            Color::ramp(if span > 0. { (t - min) / span } else { 0.5 })
+        });
+        let c = by_time.unwrap_or(p.color.unwrap_or(self.default_color));
+        match self.gamma {
+            Some(gamma) => {
+                let apply = |v: u8| ((v as f64 / 255.).powf(gamma) * 255.).round() as u8;
+                Color::new(apply(c.r()), apply(c.g()), apply(c.b()))
+            }
+            None => c,
+        }
+    }
+
+    /// alpha channel of the position image
+    fn position_alpha(&self, p: &Point) -> u8 {
+        match self.intensity_in_position_alpha {
+            true => intensity_to_position_alpha(p.intensity.unwrap_or(1.0)),
+            false => u8::MAX,
+        }
+    }
+
+    /// alpha channel of the color image, with intensity if `by_default` unless set
+    fn color_alpha(&self, p: &Point, by_default: bool) -> u8 {
+        match self.intensity_in_alpha.unwrap_or(by_default) {
+            true => normalized_to_8bit(p.intensity.unwrap_or(1.0)),
+            false => u8::MAX,
+        }
+    }
 }
 
 /// Convert f64 to 4 u8 channels
@@ -129,7 +767,347 @@ fn encode_8bit_4channels(v01: f64) -> (u8, u8, u8, u8) {
     (p0, p1, p2, p3)
 }
 
+/// Quantize intensity (0.0 ~ 1.0) to the alpha of the position image (1 ~ 255, 0 is the padding)
+pub fn intensity_to_position_alpha(intensity: f64) -> u8 {
+    1 + (intensity.clamp(0., 1.) * (u8::MAX - 1) as f64).round() as u8
+}
+
+/// Restore intensity (0.0 ~ 1.0) from the alpha of the position image
+pub fn position_alpha_to_intensity(alpha: u8) -> f64 {
+    alpha.saturating_sub(1) as f64 / (u8::MAX - 1) as f64
+}
+
 /// Convert normalized f64 (0.0 ~ 1.0) to u8 (0 ~ 255)
 fn normalized_to_8bit(v01: f64) -> u8 {
     (v01 * (u8::MAX as f64)).floor() as u8
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intensity_in_position_alpha() {
+        let points: Vec<Point> = (0..10)
+            .map(|i| {
+                let line = format!("{} {} {} {}", i, i * 2, i * 3, i as f64 / 9.);
+                Point::try_parse(&line).unwrap()
+            })
+            .collect();
+        let encoder = Encoder::new(&points, None).intensity_in_position_alpha(true);
+        let images = encoder
+            .encode(&EncoderOptions::new().color(false).intensity(true))
+            .unwrap();
+        let kinds: Vec<_> = images.iter().map(|i| i.kind).collect();
+        assert_eq!(kinds, vec![ImageKind::Position]);
+
+        let bbox = BoundingBox::from_iter(points.iter().map(|p| p.position));
+        let position = images[0].image.to_rgba8();
+        for (p, pixel) in points.iter().zip(position.pixels()) {
+            assert!(
+                (position_alpha_to_intensity(pixel[3]) - p.intensity.unwrap()).abs() < 0.5 / 254.
+            );
+            let v = Vector3::new(pixel[0], pixel[1], pixel[2]).cast::<f64>() / 255.;
+            let decoded = bbox.min().coords + bbox.size().component_mul(&v);
+            assert!((decoded - p.position.coords).abs().max() <= bbox.max_size() / 255.);
+        }
+        // the padding keeps zero alpha even for zero intensity
+        assert_eq!(points[0].intensity, Some(0.));
+        assert_eq!(position.get_pixel(0, 0)[3], 1);
+        assert_eq!(position.get_pixel(3, 3)[3], 0);
+    }
+
+    #[test]
+    fn quantized_positions_restore_world_coordinates() {
+        let points: Vec<Point> = (0..50)
+            .map(|i| {
+                let i = i as f64;
+                Point::from(Point3::new(1000. + i * 0.37, -20. + (i * 0.71).sin(), 5.))
+            })
+            .collect();
+        let encoder = Encoder::new(&points, None);
+        for bits in [4, 10, 16] {
+            let (image, quantization) = encoder.encode_quantized(bits).unwrap();
+            let bbox = BoundingBox::from_iter(points.iter().map(|p| p.position));
+            assert_eq!(quantization.origin, *bbox.min());
+            let max = (1 << bits) - 1;
+            for (p, pixel) in points.iter().zip(image.pixels()) {
+                assert!(pixel.0[..3].iter().all(|v| *v as u32 <= max));
+                assert_eq!(pixel[3], u16::MAX);
+                let world = quantization.position([pixel[0], pixel[1], pixel[2]]);
+                let error = (world - p.position).abs();
+                assert!((0..3).all(|i| error[i] <= quantization.step[i] / 2. + 1e-9));
+            }
+            // z is flat
+            assert_eq!(quantization.step.z, 0.);
+        }
+        assert!(encoder.encode_quantized(0).is_err());
+        assert!(encoder.encode_quantized(17).is_err());
+    }
+
+    #[test]
+    fn encode_position_color_intensity() {
+        let points: Vec<Point> = (0..10)
+            .map(|i| {
+                let line = format!("{} {} {} 255 0 0 {}", i, i * 2, i * 3, i as f64 / 10.);
+                Point::try_parse(&line).unwrap()
+            })
+            .collect();
+        let encoder = Encoder::new(&points, None);
+        let options = EncoderOptions::new().color(true).intensity(true);
+        let images = encoder.encode(&options).unwrap();
+
+        let kinds: Vec<_> = images.iter().map(|i| i.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![ImageKind::Position, ImageKind::Color, ImageKind::Intensity]
+        );
+        // ceil(sqrt(10)) = 4
+        assert!(images
+            .iter()
+            .all(|i| i.image.width() == 4 && i.image.height() == 4));
+    }
+
+    #[test]
+    fn encode_radius_relative_to_bounds() {
+        let points: Vec<Point> = (0..4)
+            .map(|i| {
+                let mut p = Point::from(nalgebra::Point3::new(i as f64 * 2., 0., 0.));
+                p.radius = Some(0.5 * (i + 1) as f64);
+                p
+            })
+            .collect();
+        let encoder = Encoder::new(&points, None);
+        let images = encoder
+            .encode(&EncoderOptions::new().color(false).radius(true))
+            .unwrap();
+        assert_eq!(images[1].kind, ImageKind::Radius);
+
+        // radius 1.5 of the 6.0 wide box
+        let radius = images[1].image.to_luma16();
+        assert_eq!(
+            radius.get_pixel(0, 1)[0],
+            (u16::MAX as f64 * 0.25).round() as u16
+        );
+    }
+
+    #[test]
+    fn depth_is_monotonic_along_axis() {
+        let points: Vec<Point> = (0..20)
+            .map(|i| {
+                let f = i as f64;
+                Point::from(nalgebra::Point3::new(
+                    (f * 7.).sin(),
+                    (f * 3.).cos(),
+                    f * f * 0.1,
+                ))
+            })
+            .collect();
+        let encoder = Encoder::new(&points, None);
+        let images = encoder
+            .encode(&EncoderOptions::new().color(false).depth(Some(DepthMode::Z)))
+            .unwrap();
+        assert_eq!(images[1].kind, ImageKind::Depth);
+
+        let depth = images[1].image.to_luma16();
+        let depths: Vec<u16> = (0..points.len() as u32)
+            .map(|i| depth.get_pixel(i % depth.width(), i / depth.width())[0])
+            .collect();
+        assert_eq!(depths.first(), Some(&0));
+        assert_eq!(depths.last(), Some(&u16::MAX));
+        // z increases with the index
+        assert!(depths.windows(2).all(|w| w[0] < w[1]), "{:?}", depths);
+
+        let center = encoder.encode_depth(DepthMode::Center);
+        assert!(center.pixels().take(points.len()).all(|p| p[0] > 0));
+    }
+
+    #[test]
+    fn encode_index_out_of_u32() {
+        let points: Vec<Point> = [0, u32::MAX as usize - 1, u32::MAX as usize]
+            .into_iter()
+            .map(|i| {
+                let mut p = Point::from(nalgebra::Point3::new(i as f64, 0., 0.));
+                p.index = Some(i);
+                p
+            })
+            .collect();
+
+        let index = Encoder::new(&points[..2], None).encode_index().unwrap();
+        assert_eq!(u32::from_le_bytes(index.get_pixel(0, 0).0), 1);
+        assert_eq!(u32::from_le_bytes(index.get_pixel(1, 0).0), u32::MAX);
+
+        assert!(Encoder::new(&points, None).encode_index().is_err());
+        assert!(Encoder::new(&points, None)
+            .encode(&EncoderOptions::new().index(true))
+            .is_err());
+    }
+
+    #[test]
+    fn encode_without_points() {
+        let images = Encoder::new(&[], None)
+            .encode(&EncoderOptions::new().intensity(true))
+            .unwrap();
+        assert_eq!(images.len(), 3);
+        assert!(images
+            .iter()
+            .all(|i| i.image.width() == 1 && i.image.height() == 1));
+    }
+
+    #[test]
+    fn encode_single_point_at_center() {
+        let mut point = Point::from(nalgebra::Point3::new(12.5, -3., 7.));
+        point.radius = Some(0.1);
+        let encoder = Encoder::new(&[point], None);
+
+        let (position, _) = encoder.encode_8bit();
+        assert_eq!((position.width(), position.height()), (1, 1));
+        let [x, y, z, _] = position.get_pixel(0, 0).0;
+        assert_eq!([x, y, z], [normalized_to_8bit(0.5); 3]);
+
+        let (position, _) = encoder.encode_32bit();
+        assert_eq!(position.get_pixel(0, 0).0[..3], [0.5_f32; 3]);
+        assert_eq!(encoder.encode_radius().get_pixel(0, 0)[0], 0);
+        let quad = encoder.encode_8bit_quad(false);
+        assert_eq!((quad.width(), quad.height()), (2, 2));
+    }
+
+    #[test]
+    fn planar_cloud_has_stable_z() {
+        let points: Vec<Point> = (0..16)
+            .map(|i| Point::from(nalgebra::Point3::new((i % 4) as f64, (i / 4) as f64, 3.)))
+            .collect();
+
+        let encoder = Encoder::new(&points, None);
+        let (position, _) = encoder.encode_32bit();
+        assert!(position.pixels().all(|p| p.0.iter().all(|v| v.is_finite())));
+        assert!(position.pixels().all(|p| p[2] == 0.5));
+
+        let (position, _) = Encoder::new(&points, None).flat_axis(0.).encode_8bit();
+        assert!(position.pixels().all(|p| p[2] == 0));
+        // the other axes are not affected
+        assert!(position.pixels().any(|p| p[0] == 255));
+    }
+
+    #[test]
+    fn gamma_darkens_midtones() {
+        let points: Vec<Point> = [0, 128, 255]
+            .into_iter()
+            .enumerate()
+            .map(|(i, c)| Point::try_parse(&format!("{} {} 0 {} {} {}", i, i, c, c, c)).unwrap())
+            .collect();
+
+        let (raw_position, raw) = Encoder::new(&points, None).encode_8bit();
+        let (position, color) = Encoder::new(&points, None).gamma(Some(2.2)).encode_8bit();
+        assert_eq!(position, raw_position);
+
+        let channel = |image: &RgbaImage, i: u32| image.get_pixel(i % 2, i / 2)[0];
+        assert_eq!(channel(&raw, 1), 128);
+        // (128 / 255)^2.2 * 255
+        assert_eq!(channel(&color, 1), 56);
+        // the ends are kept
+        assert_eq!((channel(&color, 0), channel(&color, 2)), (0, 255));
+    }
+
+    #[test]
+    fn colorless_points_use_default_color() {
+        let points: Vec<Point> = (0..4)
+            .map(|i| Point::from(nalgebra::Point3::new(i as f64, 0., 0.)))
+            .collect();
+        let gray = Color::new(128, 128, 128);
+
+        let (_, color) = Encoder::new(&points, None)
+            .default_color(gray)
+            .encode_8bit();
+        assert!(color.pixels().all(|c| c.0 == [128, 128, 128, 255]));
+
+        let (_, color) = Encoder::new(&points, None).encode_8bit();
+        assert!(color.pixels().all(|c| c.0 == [255, 255, 255, 255]));
+    }
+
+    #[test]
+    fn color_by_time_ramp() {
+        // 4 points captured at 10, 15 and 20 seconds, and one without time
+        let points: Vec<Point> = [Some(10.), Some(15.), Some(20.), None]
+            .into_iter()
+            .enumerate()
+            .map(|(i, t)| {
+                let mut p = Point::try_parse(&format!("{} 0 0 50 60 70", i)).unwrap();
+                p.gps_time = t;
+                p
+            })
+            .collect();
+
+        let (_, color) = Encoder::new(&points, None)
+            .color_by_time(Some((10., 20.)))
+            .encode_8bit();
+        let rgb = |i: u32| {
+            let c = color.get_pixel(i % 2, i / 2);
+            Color::new(c[0], c[1], c[2])
+        };
+        assert_eq!(rgb(0), Color::ramp(0.));
+        assert_eq!(rgb(1), Color::ramp(0.5));
+        assert_eq!(rgb(2), Color::ramp(1.));
+        assert_eq!(rgb(3), Color::new(50, 60, 70));
+        // blue to red through green
+        assert!(rgb(0).b() > rgb(0).r() && rgb(2).r() > rgb(2).b());
+        assert!(rgb(1).g() == 255);
+    }
+
+    #[test]
+    fn png_bytes_in_memory() {
+        let points: Vec<Point> = (0..10)
+            .map(|i| {
+                let line = format!("{} {} {} {} 0 0", i, i * 2, i * 3, i * 20);
+                Point::try_parse(&line).unwrap()
+            })
+            .collect();
+        let encoder = Encoder::new(&points, None);
+        let (position, color) = encoder.encode_8bit_png_bytes().unwrap();
+        let (expected_position, expected_color) = encoder.encode_8bit();
+
+        let position = image::load_from_memory(&position).unwrap().to_rgba8();
+        assert_eq!(position, expected_position);
+        let color = image::load_from_memory(&color.unwrap()).unwrap().to_rgba8();
+        assert_eq!(color, expected_color);
+
+        let colorless: Vec<Point> = points.iter().map(|p| Point::from(p.position)).collect();
+        let (_, color) = Encoder::new(&colorless, None)
+            .encode_8bit_png_bytes()
+            .unwrap();
+        assert!(color.is_none());
+    }
+
+    #[test]
+    fn intensity_in_color_alpha() {
+        let points: Vec<Point> = (0..9)
+            .map(|i| {
+                let line = format!("{} 0 0 255 0 0 {}", i, i as f64 / 10.);
+                Point::try_parse(&line).unwrap()
+            })
+            .collect();
+
+        let (_, color) = Encoder::new(&points, None).encode_8bit();
+        let (_, color32) = Encoder::new(&points, None)
+            .intensity_in_alpha(true)
+            .encode_32bit();
+        for image in [color, color32] {
+            let pixels: Vec<_> = image.pixels().collect();
+            assert!(pixels.iter().all(|c| c.0[..3] == [255, 0, 0]));
+            assert!(pixels.windows(2).all(|w| w[0][3] < w[1][3]));
+        }
+
+        // the 32-bit color image keeps the opaque alpha unless intensity is requested
+        let (_, color32) = Encoder::new(&points, None).encode_32bit();
+        assert!(color32.pixels().all(|c| c.0 == [255, 0, 0, 255]));
+        let options = EncoderOptions::new().bit_depth(BitDepth::ThirtyTwo);
+        assert!(!options.has_intensity_in_alpha());
+        assert!(options.intensity_in_alpha(true).has_intensity_in_alpha());
+        assert!(EncoderOptions::new().has_intensity_in_alpha());
+
+        let (_, color) = Encoder::new(&points, None)
+            .intensity_in_alpha(false)
+            .encode_8bit();
+        assert!(color.pixels().all(|c| c.0 == [255, 0, 0, 255]));
+    }
+}
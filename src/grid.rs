@@ -1,3 +1,8 @@
+use std::collections::HashMap;
+
+use anyhow::ensure;
+use nalgebra::Vector3;
+
 #[derive(Debug)]
 pub struct Grid<'a, P> {
     representative: Option<P>,
@@ -14,10 +19,6 @@ impl<'a, P> Default for Grid<'a, P> {
 }
 
 impl<'a, P> Grid<'a, P> {
-    pub fn new() -> Self {
-        Self::default()
-    }
-
     pub fn set(&mut self, representative: P) {
         self.representative = Some(representative);
     }
@@ -42,3 +43,189 @@ impl<'a, P> Grid<'a, P> {
         &mut self.candidates
     }
 }
+
+/// Check the sampling parameters before building the grid of `cell_size` over `size` for `points` points:
+/// the radius must be positive, the cell indices must fit in `isize`,
+/// which otherwise means the radius is far too small for the extent (e.g. in the wrong unit),
+/// and the cells allocated by `SparseGrid` (the occupied ones, at most one per point) must be at most `max_cells`.
+pub fn validate_grid(
+    size: &Vector3<f64>,
+    radius: f64,
+    cell_size: f64,
+    points: usize,
+    max_cells: u64,
+) -> anyhow::Result<()> {
+    ensure!(
+        radius.is_finite() && radius > 0.,
+        "Sampling radius must be positive: {}",
+        radius
+    );
+    let counts = size.map(|s| (s / cell_size).ceil().max(1.));
+    let max_count = counts.max();
+    if !max_count.is_finite() || max_count > isize::MAX as f64 {
+        let suggested = radius * max_count / isize::MAX as f64;
+        anyhow::bail!(
+            "Sampling radius {} is too small for the extent [{}, {}, {}]: {:.3e} grid cells on an axis overflow the cell index. Use a radius of {} or larger.",
+            radius,
+            size.x,
+            size.y,
+            size.z,
+            max_count,
+            suggested
+        );
+    }
+    let cells = counts.iter().product::<f64>().min(points as f64);
+    ensure!(
+        cells <= max_cells as f64,
+        "{} grid cells for {} points exceed the limit {}",
+        cells,
+        points,
+        max_cells
+    );
+    Ok(())
+}
+
+/// index of a grid cell (x, y, z)
+pub type GridIndex = (usize, usize, usize);
+
+/// storage of the grid cells of the samplers
+pub trait Cells<'a, P> {
+    fn get(&self, index: &GridIndex) -> Option<&Grid<'a, P>>;
+
+    /// add the point to the candidates of the cell
+    fn insert(&mut self, index: GridIndex, point: &'a P);
+
+    /// set the representative of the cell
+    fn set(&mut self, index: GridIndex, representative: P);
+
+    /// indices of the occupied cells
+    fn indices(&self) -> Vec<GridIndex>;
+
+    /// representatives of the cells in (z, y, x) order
+    fn representatives(&self) -> Vec<&P>;
+
+    /// number of the allocated cells
+    #[cfg(test)]
+    fn cell_count(&self) -> usize;
+}
+
+/// Grid cells allocated only where the points are, so that a sparse cloud with a small radius
+/// does not allocate a huge mostly empty grid
+#[derive(Debug)]
+pub struct SparseGrid<'a, P> {
+    cells: HashMap<GridIndex, Grid<'a, P>>,
+}
+
+impl<'a, P> Default for SparseGrid<'a, P> {
+    fn default() -> Self {
+        Self {
+            cells: HashMap::new(),
+        }
+    }
+}
+
+impl<'a, P> SparseGrid<'a, P> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<'a, P> Cells<'a, P> for SparseGrid<'a, P> {
+    fn get(&self, index: &GridIndex) -> Option<&Grid<'a, P>> {
+        self.cells.get(index)
+    }
+
+    fn insert(&mut self, index: GridIndex, point: &'a P) {
+        self.cells.entry(index).or_default().insert(point);
+    }
+
+    fn set(&mut self, index: GridIndex, representative: P) {
+        self.cells.entry(index).or_default().set(representative);
+    }
+
+    fn indices(&self) -> Vec<GridIndex> {
+        self.cells.keys().copied().collect()
+    }
+
+    fn representatives(&self) -> Vec<&P> {
+        let mut cells: Vec<_> = self.cells.iter().collect();
+        cells.sort_by_key(|((x, y, z), _)| (*z, *y, *x));
+        cells
+            .into_iter()
+            .filter_map(|(_, g)| g.representative())
+            .collect()
+    }
+
+    #[cfg(test)]
+    fn cell_count(&self) -> usize {
+        self.cells.len()
+    }
+}
+
+/// Every cell of the bounding box allocated up front, as the samplers did before `SparseGrid`.
+/// Kept as the reference of the tests.
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct DenseGrid<'a, P> {
+    size: GridIndex,
+    cells: Vec<Grid<'a, P>>,
+}
+
+#[cfg(test)]
+impl<'a, P> DenseGrid<'a, P> {
+    pub fn new(size: GridIndex) -> Self {
+        let (x, y, z) = size;
+        Self {
+            size,
+            cells: (0..x * y * z).map(|_| Grid::default()).collect(),
+        }
+    }
+
+    fn offset(&self, (x, y, z): GridIndex) -> usize {
+        (z * self.size.1 + y) * self.size.0 + x
+    }
+
+    fn index(&self, offset: usize) -> GridIndex {
+        let (sx, sy, _) = self.size;
+        (offset % sx, (offset / sx) % sy, offset / (sx * sy))
+    }
+}
+
+#[cfg(test)]
+impl<'a, P> Cells<'a, P> for DenseGrid<'a, P> {
+    fn get(&self, index: &GridIndex) -> Option<&Grid<'a, P>> {
+        let g = &self.cells[self.offset(*index)];
+        (g.visited() || !g.candidates().is_empty()).then_some(g)
+    }
+
+    fn insert(&mut self, index: GridIndex, point: &'a P) {
+        let i = self.offset(index);
+        self.cells[i].insert(point);
+    }
+
+    fn set(&mut self, index: GridIndex, representative: P) {
+        let i = self.offset(index);
+        self.cells[i].set(representative);
+    }
+
+    fn indices(&self) -> Vec<GridIndex> {
+        self.cells
+            .iter()
+            .enumerate()
+            .filter(|(_, g)| !g.candidates().is_empty())
+            .map(|(i, _)| self.index(i))
+            .collect()
+    }
+
+    fn representatives(&self) -> Vec<&P> {
+        // the cells are laid out in (z, y, x) order
+        self.cells
+            .iter()
+            .filter_map(|g| g.representative())
+            .collect()
+    }
+
+    fn cell_count(&self) -> usize {
+        self.cells.len()
+    }
+}
@@ -0,0 +1,207 @@
+use nalgebra::Point3;
+
+use crate::prelude::Point;
+
+/// KD-tree over the positions of points for neighbor queries.
+/// The tree is stored implicitly: each range of `indices` has its splitting point at the middle.
+#[derive(Debug, Clone)]
+pub struct KdTree {
+    positions: Vec<Point3<f64>>,
+    indices: Vec<usize>,
+}
+
+impl KdTree {
+    pub fn new(points: &[Point]) -> Self {
+        Self::from_positions(points.iter().map(|p| p.position).collect())
+    }
+
+    pub fn from_positions(positions: Vec<Point3<f64>>) -> Self {
+        let mut indices: Vec<usize> = (0..positions.len()).collect();
+        build(&positions, &mut indices, 0);
+        Self { positions, indices }
+    }
+
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    /// Find the indices of the points within `radius` from `p` (boundary included)
+    pub fn radius_search(&self, p: &Point3<f64>, radius: f64) -> Vec<usize> {
+        let mut found = vec![];
+        self.radius_search_range(&self.indices, 0, p, radius * radius, &mut found);
+        found
+    }
+
+    /// Find the index of the nearest point and its distance
+    pub fn nearest(&self, p: &Point3<f64>) -> Option<(usize, f64)> {
+        let mut best = None;
+        self.nearest_range(&self.indices, 0, p, &mut best);
+        best.map(|(i, d2): (usize, f64)| (i, d2.sqrt()))
+    }
+
+    /// Find the k nearest points as (index, distance) sorted by the distance
+    pub fn k_nearest(&self, p: &Point3<f64>, k: usize) -> Vec<(usize, f64)> {
+        let mut best = Vec::with_capacity(k + 1);
+        if k > 0 {
+            self.k_nearest_range(&self.indices, 0, p, k, &mut best);
+        }
+        best.into_iter().map(|(i, d2)| (i, d2.sqrt())).collect()
+    }
+
+    fn radius_search_range(
+        &self,
+        range: &[usize],
+        depth: usize,
+        p: &Point3<f64>,
+        radius_squared: f64,
+        found: &mut Vec<usize>,
+    ) {
+        if range.is_empty() {
+            return;
+        }
+        let axis = depth % 3;
+        let mid = range.len() / 2;
+        let index = range[mid];
+        let q = &self.positions[index];
+        if (q - p).norm_squared() <= radius_squared {
+            found.push(index);
+        }
+        let d = p[axis] - q[axis];
+        let (near, far) = if d < 0. {
+            (&range[..mid], &range[mid + 1..])
+        } else {
+            (&range[mid + 1..], &range[..mid])
+        };
+        self.radius_search_range(near, depth + 1, p, radius_squared, found);
+        if d * d <= radius_squared {
+            self.radius_search_range(far, depth + 1, p, radius_squared, found);
+        }
+    }
+
+    fn nearest_range(
+        &self,
+        range: &[usize],
+        depth: usize,
+        p: &Point3<f64>,
+        best: &mut Option<(usize, f64)>,
+    ) {
+        if range.is_empty() {
+            return;
+        }
+        let axis = depth % 3;
+        let mid = range.len() / 2;
+        let index = range[mid];
+        let q = &self.positions[index];
+        let d2 = (q - p).norm_squared();
+        if best.map(|(_, b)| d2 < b).unwrap_or(true) {
+            *best = Some((index, d2));
+        }
+        let d = p[axis] - q[axis];
+        let (near, far) = if d < 0. {
+            (&range[..mid], &range[mid + 1..])
+        } else {
+            (&range[mid + 1..], &range[..mid])
+        };
+        self.nearest_range(near, depth + 1, p, best);
+        if best.map(|(_, b)| d * d < b).unwrap_or(true) {
+            self.nearest_range(far, depth + 1, p, best);
+        }
+    }
+
+    fn k_nearest_range(
+        &self,
+        range: &[usize],
+        depth: usize,
+        p: &Point3<f64>,
+        k: usize,
+        best: &mut Vec<(usize, f64)>,
+    ) {
+        if range.is_empty() {
+            return;
+        }
+        let axis = depth % 3;
+        let mid = range.len() / 2;
+        let index = range[mid];
+        let q = &self.positions[index];
+        let d2 = (q - p).norm_squared();
+        if best.len() < k || d2 < best[best.len() - 1].1 {
+            let at = best.partition_point(|(_, b)| *b <= d2);
+            best.insert(at, (index, d2));
+            best.truncate(k);
+        }
+        let d = p[axis] - q[axis];
+        let (near, far) = if d < 0. {
+            (&range[..mid], &range[mid + 1..])
+        } else {
+            (&range[mid + 1..], &range[..mid])
+        };
+        self.k_nearest_range(near, depth + 1, p, k, best);
+        if best.len() < k || d * d < best[best.len() - 1].1 {
+            self.k_nearest_range(far, depth + 1, p, k, best);
+        }
+    }
+}
+
+/// sort the range so that the middle element splits it along the axis of the depth
+fn build(positions: &[Point3<f64>], range: &mut [usize], depth: usize) {
+    if range.len() <= 1 {
+        return;
+    }
+    let axis = depth % 3;
+    let mid = range.len() / 2;
+    range.select_nth_unstable_by(mid, |a, b| {
+        positions[*a][axis].total_cmp(&positions[*b][axis])
+    });
+    let (left, right) = range.split_at_mut(mid);
+    build(positions, left, depth + 1);
+    build(positions, &mut right[1..], depth + 1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queries_match_brute_force() {
+        let positions: Vec<Point3<f64>> = (0..500)
+            .map(|i| {
+                let f = i as f64;
+                Point3::new(
+                    (f * 0.37).sin() * 5.,
+                    (f * 0.11).cos() * 5.,
+                    (f * 0.07) % 3.,
+                )
+            })
+            .collect();
+        let tree = KdTree::from_positions(positions.clone());
+
+        let p = Point3::new(0.5, -0.2, 1.0);
+        let mut found = tree.radius_search(&p, 1.5);
+        found.sort();
+        let expected: Vec<usize> = (0..positions.len())
+            .filter(|i| (positions[*i] - p).norm() <= 1.5)
+            .collect();
+        assert_eq!(found, expected);
+
+        let (nearest, distance) = tree.nearest(&p).unwrap();
+        let expected = positions
+            .iter()
+            .map(|q| (q - p).norm())
+            .fold(f64::MAX, f64::min);
+        assert_eq!(distance, expected);
+        assert_eq!((positions[nearest] - p).norm(), expected);
+
+        let knn = tree.k_nearest(&p, 7);
+        let mut expected: Vec<f64> = positions.iter().map(|q| (q - p).norm()).collect();
+        expected.sort_by(f64::total_cmp);
+        assert_eq!(knn.len(), 7);
+        for ((i, d), e) in knn.iter().zip(expected.iter()) {
+            assert_eq!(d, e);
+            assert_eq!((positions[*i] - p).norm(), *d);
+        }
+    }
+}
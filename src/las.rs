@@ -0,0 +1,329 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Write},
+    path::Path,
+};
+
+use anyhow::ensure;
+use nalgebra::{Point3, Vector3};
+
+use crate::prelude::{BoundingBox, Color, Meta, Point};
+
+/// size of the LAS 1.2 header, the points follow it without variable length records
+const HEADER_SIZE: u16 = 227;
+/// size of the LAS 1.4 header, which has the 64-bit number of points at 247
+const HEADER_SIZE_1_4: u16 = 375;
+
+/// point data record format 3 (position, intensity, returns, classification, GPS time and RGB)
+const POINT_FORMAT: u8 = 3;
+const POINT_RECORD_LENGTH: u16 = 34;
+
+/// Write the points to a LAS 1.2 file (point data record format 3).
+/// The positions are stored as integers of `(position - offset) / scale`,
+/// intensity (0.0 ~ 1.0) and color are scaled to 16-bit.
+pub fn write_las(
+    path: &Path,
+    points: &[Point],
+    offset: Vector3<f64>,
+    scale: Vector3<f64>,
+) -> anyhow::Result<()> {
+    ensure!(
+        scale.iter().all(|s| s.is_finite() && *s > 0.),
+        "LAS scale must be positive"
+    );
+    ensure!(
+        points.len() <= u32::MAX as usize,
+        "LAS 1.2 can not hold {} points",
+        points.len()
+    );
+
+    let quantize = |p: &Point3<f64>| -> anyhow::Result<[i32; 3]> {
+        let q = (p.coords - offset).component_div(&scale);
+        ensure!(
+            q.iter()
+                .all(|v| v.round() >= i32::MIN as f64 && v.round() <= i32::MAX as f64),
+            "Point {} does not fit in LAS with the offset and scale",
+            p
+        );
+        Ok([q.x.round() as i32, q.y.round() as i32, q.z.round() as i32])
+    };
+
+    let bounds = BoundingBox::from_iter(points.iter().map(|p| p.position));
+    let (min, max) = match points.is_empty() {
+        true => (Point3::origin(), Point3::origin()),
+        false => (*bounds.min(), *bounds.max()),
+    };
+    let mut by_return = [0u32; 5];
+    points.iter().for_each(|p| {
+        let r = p.return_number.unwrap_or(1).clamp(1, 5);
+        by_return[r as usize - 1] += 1;
+    });
+
+    let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+    header.extend(b"LASF");
+    header.extend(0u16.to_le_bytes()); // file source id
+    header.extend(0u16.to_le_bytes()); // global encoding
+    header.extend([0u8; 16]); // project id
+    header.extend([1u8, 2]); // version
+    header.extend(padded::<32>(b"pcd-lod")); // system identifier
+    header.extend(padded::<32>(
+        format!("pcd-lod {}", env!("CARGO_PKG_VERSION")).as_bytes(),
+    ));
+    header.extend(0u16.to_le_bytes()); // creation day of year
+    header.extend(0u16.to_le_bytes()); // creation year
+    header.extend(HEADER_SIZE.to_le_bytes());
+    header.extend((HEADER_SIZE as u32).to_le_bytes()); // offset to the points
+    header.extend(0u32.to_le_bytes()); // number of variable length records
+    header.push(POINT_FORMAT);
+    header.extend(POINT_RECORD_LENGTH.to_le_bytes());
+    header.extend((points.len() as u32).to_le_bytes());
+    by_return
+        .iter()
+        .for_each(|n| header.extend(n.to_le_bytes()));
+    scale.iter().for_each(|v| header.extend(v.to_le_bytes()));
+    offset.iter().for_each(|v| header.extend(v.to_le_bytes()));
+    (0..3).for_each(|i| {
+        header.extend(max[i].to_le_bytes());
+        header.extend(min[i].to_le_bytes());
+    });
+    debug_assert_eq!(header.len(), HEADER_SIZE as usize);
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(&header)?;
+    for p in points {
+        let mut record = Vec::with_capacity(POINT_RECORD_LENGTH as usize);
+        quantize(&p.position)?
+            .iter()
+            .for_each(|v| record.extend(v.to_le_bytes()));
+        let intensity = (p.intensity.unwrap_or(0.).clamp(0., 1.) * u16::MAX as f64).round();
+        record.extend((intensity as u16).to_le_bytes());
+        let returns = (p.return_number.unwrap_or(1) & 0b111)
+            | ((p.number_of_returns.unwrap_or(1) & 0b111) << 3);
+        record.push(returns);
+        record.push(p.classification.unwrap_or(0));
+        record.push(0); // scan angle rank
+        record.push(0); // user data
+        record.extend(0u16.to_le_bytes()); // point source id
+        record.extend(p.gps_time.unwrap_or(0.).to_le_bytes());
+        let c = p.color.unwrap_or(Color::new(0, 0, 0));
+        [c.r(), c.g(), c.b()]
+            .iter()
+            .for_each(|v| record.extend((*v as u16 * 257).to_le_bytes()));
+        writer.write_all(&record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write the points to a LAS file in the georeferenced coordinates,
+/// undoing the global shift recorded in the meta (the offset is the georeferenced origin of the shifted points)
+pub fn write_las_with_meta(
+    path: &Path,
+    points: &[Point],
+    meta: &Meta,
+    scale: Vector3<f64>,
+) -> anyhow::Result<()> {
+    let offset = meta.georeferenced(&Point3::origin()).coords;
+    let points: Vec<Point> = points
+        .iter()
+        .map(|p| Point {
+            position: meta.georeferenced(&p.position),
+            ..p.clone()
+        })
+        .collect();
+    write_las(path, &points, offset, scale)
+}
+
+/// Read the points of a LAS 1.0 ~ 1.4 file with point data record format 0 ~ 3
+pub fn read_las(path: &Path) -> anyhow::Result<Vec<Point>> {
+    let mut bytes = vec![];
+    File::open(path)?.read_to_end(&mut bytes)?;
+    ensure!(
+        bytes.len() >= HEADER_SIZE as usize && &bytes[..4] == b"LASF",
+        "{:?} is not a LAS file",
+        path
+    );
+
+    let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+    let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+    let f64_at = |i: usize| f64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+
+    let data_offset = u32_at(96) as usize;
+    let format = bytes[104] & 0x3f;
+    let record_length = u16_at(105) as usize;
+    let mut count = u32_at(107) as usize;
+    // LAS 1.4 may leave the legacy number of points 0 and keep the number of points in 64-bit
+    if bytes[25] >= 4 && count == 0 {
+        ensure!(
+            u16_at(94) >= HEADER_SIZE_1_4 && bytes.len() >= HEADER_SIZE_1_4 as usize,
+            "{:?} is truncated",
+            path
+        );
+        count = usize::try_from(u64_at(247))?;
+    }
+    let scale = Vector3::new(f64_at(131), f64_at(139), f64_at(147));
+    let offset = Vector3::new(f64_at(155), f64_at(163), f64_at(171));
+    ensure!(
+        format <= 3,
+        "LAS point data record format {} is not supported",
+        format
+    );
+    let has_time = format == 1 || format == 3;
+    let has_color = format >= 2;
+    let min_length = 20 + has_time as usize * 8 + has_color as usize * 6;
+    ensure!(
+        record_length >= min_length,
+        "LAS point record length {} is too short for the format {}",
+        record_length,
+        format
+    );
+    ensure!(
+        data_offset + count * record_length <= bytes.len(),
+        "{:?} is truncated",
+        path
+    );
+
+    let points = (0..count)
+        .map(|n| {
+            let i = data_offset + n * record_length;
+            let q = Vector3::new(
+                u32_at(i) as i32 as f64,
+                u32_at(i + 4) as i32 as f64,
+                u32_at(i + 8) as i32 as f64,
+            );
+            let mut point = Point::from(Point3::from(q.component_mul(&scale) + offset));
+            point.intensity = Some(u16_at(i + 12) as f64 / u16::MAX as f64);
+            point.return_number = Some(bytes[i + 14] & 0b111);
+            point.number_of_returns = Some((bytes[i + 14] >> 3) & 0b111);
+            point.classification = Some(bytes[i + 15]);
+            let mut j = i + 20;
+            if has_time {
+                point.gps_time = Some(f64_at(j));
+                j += 8;
+            }
+            if has_color {
+                let [r, g, b] = [0, 2, 4].map(|k| (u16_at(j + k) / 257) as u8);
+                point.color = Some(Color::new(r, g, b));
+            }
+            point
+        })
+        .collect();
+    Ok(points)
+}
+
+/// zero padded string field of the header
+fn padded<const N: usize>(s: &[u8]) -> [u8; N] {
+    let mut buf = [0u8; N];
+    let n = s.len().min(N);
+    buf[..n].copy_from_slice(&s[..n]);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points() -> Vec<Point> {
+        (0..50)
+            .map(|i| {
+                let line = format!(
+                    "{} {} {} {} {} {} {}",
+                    1000. + i as f64 * 0.123,
+                    -2000. + i as f64 * 0.456,
+                    i as f64 * 0.0789,
+                    i * 5,
+                    255 - i * 5,
+                    i,
+                    i as f64 / 50.
+                );
+                Point::try_parse(&line).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn write_and_read_las() {
+        let points = points();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let scale = Vector3::repeat(0.001);
+        write_las(file.path(), &points, Vector3::new(1000., -2000., 0.), scale).unwrap();
+
+        let read = read_las(file.path()).unwrap();
+        assert_eq!(read.len(), points.len());
+        for (p, q) in points.iter().zip(read.iter()) {
+            assert!((p.position - q.position).abs().max() <= scale.x * 0.5 + 1e-9);
+            assert_eq!(p.color, q.color);
+            assert!((p.intensity.unwrap() - q.intensity.unwrap()).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn read_las_1_4() {
+        let points = points();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        write_las(
+            file.path(),
+            &points,
+            Vector3::new(1000., -2000., 0.),
+            Vector3::repeat(0.001),
+        )
+        .unwrap();
+
+        // the LAS 1.2 file turned to 1.4 with the legacy number of points 0
+        let bytes = std::fs::read(file.path()).unwrap();
+        let (header, records) = bytes.split_at(HEADER_SIZE as usize);
+        let mut header = header.to_vec();
+        header[25] = 4;
+        header[94..96].copy_from_slice(&HEADER_SIZE_1_4.to_le_bytes());
+        header[96..100].copy_from_slice(&(HEADER_SIZE_1_4 as u32).to_le_bytes());
+        header[107..111].copy_from_slice(&0u32.to_le_bytes());
+        header.resize(HEADER_SIZE_1_4 as usize, 0);
+        header[247..255].copy_from_slice(&(points.len() as u64).to_le_bytes());
+        std::fs::write(file.path(), [header, records.to_vec()].concat()).unwrap();
+
+        let read = read_las(file.path()).unwrap();
+        assert_eq!(read.len(), points.len());
+        for (p, q) in points.iter().zip(read.iter()) {
+            assert!((p.position - q.position).abs().max() <= 0.0005 + 1e-9);
+            assert_eq!(p.color, q.color);
+        }
+    }
+
+    #[test]
+    fn write_las_in_georeferenced_coordinates() {
+        let shift = Vector3::new(-1000., 2000., 0.);
+        let mut meta = Meta::new(
+            1,
+            BoundingBox::new(Point3::origin(), Point3::origin()),
+            Default::default(),
+        );
+        meta.global_shift = Some((shift, 1.));
+        let shifted: Vec<Point> = points()
+            .into_iter()
+            .map(|p| Point {
+                position: p.position + shift,
+                ..p
+            })
+            .collect();
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        write_las_with_meta(file.path(), &shifted, &meta, Vector3::repeat(0.001)).unwrap();
+        let read = read_las(file.path()).unwrap();
+        for (p, q) in points().iter().zip(read.iter()) {
+            assert!((p.position - q.position).abs().max() <= 0.0005 + 1e-9);
+        }
+    }
+
+    #[test]
+    fn reject_points_out_of_range() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let result = write_las(
+            file.path(),
+            &points(),
+            Vector3::zeros(),
+            Vector3::repeat(1e-9),
+        );
+        assert!(result.is_err());
+    }
+}
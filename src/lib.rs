@@ -1,46 +1,99 @@
 use std::{
+    collections::{HashMap, HashSet},
     ffi::OsStr,
-    fs::{canonicalize, File},
+    fs::{canonicalize, create_dir_all, File},
     future::Future,
-    io::{BufRead, BufReader},
+    io::{BufRead, BufReader, BufWriter, Write},
     path::{Path, PathBuf},
+    pin::Pin,
     process::Command,
+    sync::{Arc, Mutex, RwLock},
+    task::Poll,
+    time::Instant,
 };
 
 use anyhow::ensure;
 
+use nalgebra::{Matrix4, Point3};
 use point::Point;
 use prelude::{
-    BoundingBox, Coordinates, ParallelPoissonDiskSampling, PointCloudMap, PoissonDiskSampling,
+    assign_dominant_colors, is_native_pcd, is_native_ply, needs_division, BoundingBox,
+    ChunkedPointCloudMap, ColumnLayout, Coordinates, GlobalShift, LODReport, Meta,
+    ParallelPoissonDiskSampler, PcdReader, PlyReader, PointCloud, PointCloudMap,
+    PoissonDiskSampling, Reservoir, RetentionStats, Sampler,
 };
+#[cfg(feature = "image")]
+use prelude::{encode_unit, BitDepth, EncoderOptions, ImageKind};
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 
+#[cfg(feature = "image")]
+mod atlas;
 mod bounding_box;
+mod chunked_map;
 mod color;
+#[cfg(feature = "image")]
+mod decoder;
+#[cfg(feature = "draco")]
+mod draco;
+#[cfg(feature = "image")]
 mod encoder;
 mod grid;
 mod has_position;
+mod kd_tree;
+#[cfg(feature = "las")]
+mod las;
+mod lod;
 mod meta;
 pub mod misc;
 mod parallel_poisson_disk_sampling;
+mod pcd;
+mod pixel_layout;
+mod ply;
 mod point;
+mod point_cloud;
 mod point_cloud_map;
 mod point_cloud_unit;
 mod poisson_disk_sampling;
+mod report;
+mod sampler;
+mod tile_index;
+#[cfg(feature = "image")]
+mod tile_sink;
 
 /// key represents level of detail for hash map
-type LODKey = (i32, i32, i32);
+pub type LODKey = (i32, i32, i32);
 
 pub mod prelude {
+    #[cfg(feature = "image")]
+    pub use crate::atlas::*;
     pub use crate::bounding_box::*;
+    pub use crate::chunked_map::*;
     pub use crate::color::*;
+    #[cfg(feature = "image")]
+    pub use crate::decoder::*;
+    #[cfg(feature = "draco")]
+    pub use crate::draco::*;
+    #[cfg(feature = "image")]
     pub use crate::encoder::*;
+    pub use crate::kd_tree::*;
+    #[cfg(feature = "las")]
+    pub use crate::las::*;
+    pub use crate::lod::*;
     pub use crate::meta::*;
     pub use crate::parallel_poisson_disk_sampling::*;
+    pub use crate::pcd::*;
+    pub use crate::pixel_layout::*;
+    pub use crate::ply::*;
     pub use crate::point::*;
+    pub use crate::point_cloud::*;
     pub use crate::point_cloud_map::*;
     pub use crate::point_cloud_unit::*;
     pub use crate::poisson_disk_sampling::*;
+    pub use crate::report::*;
+    pub use crate::sampler::*;
+    pub use crate::tile_index::*;
+    #[cfg(feature = "image")]
+    pub use crate::tile_sink::*;
 }
 
 /// get Command instance for CloudCompare
@@ -73,13 +126,15 @@ pub fn detect_cloudcompare_exists(path: Option<&String>) -> anyhow::Result<Strin
     Ok(msg.to_string())
 }
 
-/// convert pcd file to txt file with CloudCompare
-fn convert_pcd_file_to_txt<S0: AsRef<OsStr>, S1: AsRef<OsStr>>(
+/// build Command to convert pcd file to txt file with CloudCompare
+/// the global shift is detected automatically unless an explicit shift is given
+fn convert_command<S0: AsRef<OsStr>, S1: AsRef<OsStr>>(
     cmd: Option<&String>,
     input_file_path: S0,
     out_txt_path: S1,
     drop_global_shift: bool,
-) -> anyhow::Result<()> {
+    global_shift: Option<&GlobalShift>,
+) -> Command {
     let mut cmd = command(cmd);
     cmd.arg("-SILENT")
         .arg("-AUTO_SAVE")
@@ -87,9 +142,15 @@ fn convert_pcd_file_to_txt<S0: AsRef<OsStr>, S1: AsRef<OsStr>>(
         .arg("-O")
         // CAUTION!: global shift fixes accuracy errors
         // [ccGlobalShiftManager] Entity has very big coordinates: original accuracy may be lost! (you should apply a Global Shift or Scale)
-        .arg("-GLOBAL_SHIFT")
-        .arg("AUTO")
-        .arg(input_file_path)
+        .arg("-GLOBAL_SHIFT");
+    match global_shift {
+        Some((shift, _)) => cmd
+            .arg(shift.x.to_string())
+            .arg(shift.y.to_string())
+            .arg(shift.z.to_string()),
+        None => cmd.arg("AUTO"),
+    };
+    cmd.arg(input_file_path)
         .arg("-C_EXPORT_FMT")
         .arg("ASC")
         .arg("-SEP") // separator
@@ -101,55 +162,307 @@ fn convert_pcd_file_to_txt<S0: AsRef<OsStr>, S1: AsRef<OsStr>>(
 
     cmd.arg("-MERGE_CLOUDS");
     cmd.arg("-SAVE_CLOUDS").arg("FILE").arg(out_txt_path);
+    cmd
+}
+
+/// convert pcd file to txt file with CloudCompare
+/// returns stdout and stderr of CloudCompare
+fn convert_pcd_file_to_txt<S0: AsRef<OsStr>, S1: AsRef<OsStr>>(
+    cmd: Option<&String>,
+    input_file_path: S0,
+    out_txt_path: S1,
+    drop_global_shift: bool,
+    global_shift: Option<&GlobalShift>,
+) -> anyhow::Result<(String, String)> {
+    let mut cmd = convert_command(
+        cmd,
+        input_file_path,
+        out_txt_path,
+        drop_global_shift,
+        global_shift,
+    );
 
     let output = cmd.output()?;
-    let msg = std::str::from_utf8(&output.stdout)?;
-    println!("{}", msg);
-    Ok(())
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    println!("{}", stdout);
+    Ok((stdout, stderr))
 }
 
-/// read points from txt file
-fn read_points_from_txt(path: &std::path::Path) -> anyhow::Result<Vec<Point>> {
-    let f = File::open(path);
-    match f {
-        Ok(f) => {
-            let reader = BufReader::new(f);
-            let points = reader
-                .lines()
-                .map_while(Result::ok)
-                .filter_map(|line| Point::try_parse(&line).ok())
-                .collect();
-            Ok(points)
+/// Failure of the conversion with CloudCompare, with its output to tell why
+/// (returned through `anyhow::Error`, get it with `downcast_ref`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConversionError {
+    /// no seed file (`seed.txt` or one with a suffix, see `seed_files`) is written
+    /// (e.g. unsupported format, crash, permissions)
+    NoOutput { stdout: String, stderr: String },
+    /// the seed files are written but empty (`path` is the first one)
+    EmptyOutput {
+        path: PathBuf,
+        stdout: String,
+        stderr: String,
+    },
+}
+
+impl std::fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (reason, stdout, stderr) = match self {
+            ConversionError::NoOutput { stdout, stderr } => {
+                ("CloudCompare produced no file".to_string(), stdout, stderr)
+            }
+            ConversionError::EmptyOutput {
+                path,
+                stdout,
+                stderr,
+            } => (
+                format!("CloudCompare produced an empty file {:?}", path),
+                stdout,
+                stderr,
+            ),
+        };
+        write!(
+            f,
+            "Generating seed file is failed! {}\nstdout:\n{}\nstderr:\n{}",
+            reason,
+            stdout.trim_end(),
+            stderr.trim_end()
+        )
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+/// limit of the points read from the input for quick previews
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PointLimit {
+    /// the first N points of the input
+    First(usize),
+    /// N points drawn uniformly from the whole input by reservoir sampling (deterministic)
+    Reservoir(usize),
+}
+
+/// read points from txt file (whitespace, comma, semicolon or tab separated)
+/// fails as soon as the number of points exceeds `max_points` (if given)
+/// and keeps only the points selected by `limit` (if given).
+/// The first line is skipped if it is the names of the columns, and any other line that is not a point is an error.
+/// The lines are parsed by the named columns if given (see `ColumnLayout`)
+#[cfg(test)]
+fn read_points_from_txt(
+    path: &std::path::Path,
+    columns: Option<&ColumnLayout>,
+    max_points: Option<usize>,
+    limit: Option<PointLimit>,
+) -> anyhow::Result<Vec<Point>> {
+    let paths = [path.to_path_buf()];
+    let points = txt_points(&paths, columns)?;
+    collect_points(points, max_points, limit)
+}
+
+/// stream the points of the txt files one after another (the header line skipped, any other bad line an error),
+/// parsed by the named columns if given (see `ColumnLayout`)
+fn txt_points<'a>(
+    paths: &'a [PathBuf],
+    columns: Option<&'a ColumnLayout>,
+) -> anyhow::Result<impl Iterator<Item = anyhow::Result<Point>> + 'a> {
+    let readers = paths
+        .iter()
+        .map(|path| File::open(path).map(BufReader::new))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| anyhow::anyhow!("failed to open file"))?;
+    let points = readers
+        .into_iter()
+        .zip(paths)
+        .flat_map(move |(reader, path)| {
+            let mut first = true;
+            reader.lines().enumerate().filter_map(move |(i, line)| {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(e) => return Some(Err(e.into())),
+                };
+                if line.trim().is_empty() {
+                    return None;
+                }
+                let header = std::mem::replace(&mut first, false)
+                    && line.chars().any(|c| c.is_ascii_alphabetic());
+                let point = match columns {
+                    Some(columns) => Point::try_parse_delimited_with_layout(&line, columns),
+                    None => Point::try_parse_delimited(&line),
+                };
+                match point {
+                    Ok(point) => Some(Ok(point)),
+                    // the names of the columns (e.g. `x,y,z` of a CSV)
+                    Err(_) if header => None,
+                    Err(e) => Some(Err(anyhow::anyhow!(
+                        "Invalid point at line {} of {:?}: {} ({:?})",
+                        i + 1,
+                        path,
+                        e,
+                        line
+                    ))),
+                }
+            })
+        });
+    Ok(points)
+}
+
+/// collect the points up to `max_points` and select them by `limit`
+fn collect_points(
+    points: impl Iterator<Item = anyhow::Result<Point>>,
+    max_points: Option<usize>,
+    limit: Option<PointLimit>,
+) -> anyhow::Result<Vec<Point>> {
+    let first = match limit {
+        Some(PointLimit::Reservoir(n)) => {
+            let mut reservoir = Reservoir::new(n, 0);
+            fill_reservoir(points, &mut reservoir, n, max_points)?;
+            return Ok(reservoir.into_vec());
         }
-        _ => Err(anyhow::anyhow!("failed to open file")),
+        Some(PointLimit::First(n)) => n,
+        None => usize::MAX,
+    };
+    let mut collected = vec![];
+    for point in points.take(first) {
+        let point = point?;
+        ensure_under_max(collected.len(), max_points)?;
+        collected.push(point);
     }
+    Ok(collected)
 }
 
-/// unit result of level of detail
-pub struct LODUnit {
-    pub lod: u32,
-    pub bounding_box: BoundingBox,
-    pub points: Vec<Point>,
-    pub x: i32,
-    pub y: i32,
-    pub z: i32,
+/// push the points to the reservoir of `n` points, failing as soon as it would keep more than `max_points`
+fn fill_reservoir(
+    points: impl Iterator<Item = anyhow::Result<Point>>,
+    reservoir: &mut Reservoir<Point>,
+    n: usize,
+    max_points: Option<usize>,
+) -> anyhow::Result<()> {
+    for point in points {
+        let point = point?;
+        if reservoir.seen() < n {
+            ensure_under_max(reservoir.seen(), max_points)?;
+        }
+        reservoir.push(point);
+    }
+    Ok(())
+}
+
+/// fail if another point after `count` points exceeds `max_points`
+fn ensure_under_max(count: usize, max_points: Option<usize>) -> anyhow::Result<()> {
+    if let Some(max_points) = max_points {
+        ensure!(
+            count < max_points,
+            "Input has more than {} points! Downsample the input beforehand or raise the limit of max points.",
+            max_points
+        );
+    }
+    Ok(())
+}
+
+/// fractional digits of the coordinates written to text (millimeters for survey data in meters)
+pub const DEFAULT_TEXT_DECIMALS: usize = 3;
+
+/// write points to txt file (`x y z [r g b] [intensity]` per line)
+/// with `decimals` fractional digits for each coordinate
+pub fn write_points_to_txt(path: &Path, points: &[Point], decimals: usize) -> anyhow::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for point in points {
+        writeln!(writer, "{}", point.to_line(decimals))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// write the cells of `partition` to `dir` as text files (`x-y-z.txt`, see `write_points_to_txt`),
+/// returning the paths of the files
+pub fn write_partition(
+    dir: &Path,
+    cells: &HashMap<LODKey, Vec<Point>>,
+    decimals: usize,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut keys: Vec<&LODKey> = cells.keys().collect();
+    keys.sort();
+    keys.into_iter()
+        .map(|key| {
+            let (x, y, z) = key;
+            let path = dir.join(format!("{}-{}-{}.txt", x, y, z));
+            write_points_to_txt(&path, &cells[key], decimals)?;
+            Ok(path)
+        })
+        .collect()
+}
+
+/// check if the file can be read without CloudCompare
+/// (whitespace or comma separated text, ascii or binary PCD, or PLY)
+pub fn is_native_format(path: &Path) -> bool {
+    match extension(path).as_deref() {
+        _ if is_text(path) => true,
+        Some("pcd") => is_native_pcd(path),
+        Some("ply") => is_native_ply(path),
+        _ => false,
+    }
+}
+
+/// text file of points by the extension
+fn is_text(path: &Path) -> bool {
+    matches!(
+        extension(path).as_deref(),
+        Some("txt" | "xyz" | "asc" | "csv")
+    )
+}
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase())
 }
 
-/// process level of detail
-pub async fn process_lod<F0, F1, Fut0, Fut1>(
+/// options of reading the input files (see `load_points`)
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    /// apply global shift with CloudCompare
+    pub use_global_shift: bool,
+    /// explicit global shift (x, y, z) and scale used instead of the automatic one when global shift is applied,
+    /// the points are given as (global + shift) * scale
+    pub global_shift: Option<GlobalShift>,
+    /// fail as soon as the input has more points than this limit
+    pub max_points: Option<usize>,
+    /// read only a part of the input (preview mode)
+    pub limit: Option<PointLimit>,
+    /// read text, PCD and PLY files without CloudCompare (see `is_native_format`) unless global shift is applied,
+    /// every file is converted with CloudCompare otherwise
+    pub native: bool,
+    /// named columns of the text files read natively (`x y z [r g b] [intensity]` if not given)
+    pub columns: Option<ColumnLayout>,
+}
+
+/// load points from the input file, converted with CloudCompare
+/// (text, PCD and PLY files are read natively with `LoadOptions::native` unless global shift is applied)
+pub fn load_points<P: AsRef<Path>>(
     exec_path: Option<&String>,
-    input_file_path: &String,
-    callback_per_unit: F0,
-    callback_per_lod: F1,
-    use_global_shift: bool,
-) -> anyhow::Result<()>
-where
-    F0: Fn(LODUnit) -> Fut0,
-    F1: Fn(u32, BoundingBox, Coordinates) -> Fut1,
-    Fut0: Future<Output = anyhow::Result<()>>,
-    Fut1: Future<Output = anyhow::Result<()>>,
-{
-    let i_path = PathBuf::from(&input_file_path);
+    input_file_path: P,
+    options: &LoadOptions,
+) -> anyhow::Result<Vec<Point>> {
+    let (max_points, limit) = (options.max_points, options.limit);
+    read_file_points(exec_path, input_file_path.as_ref(), options, |points| {
+        collect_points(points, max_points, limit)
+    })
+}
+
+/// stream the points of the input file to `read`, read natively or converted with CloudCompare (see `load_points`),
+/// `max_points` and `limit` are left to `read`
+fn read_file_points<R>(
+    exec_path: Option<&String>,
+    i_path: &Path,
+    options: &LoadOptions,
+    read: impl FnOnce(&mut dyn Iterator<Item = anyhow::Result<Point>>) -> anyhow::Result<R>,
+) -> anyhow::Result<R> {
+    let LoadOptions {
+        use_global_shift,
+        global_shift,
+        native,
+        columns,
+        ..
+    } = options.clone();
 
     ensure!(
         i_path.exists(),
@@ -157,164 +470,2947 @@ where
         i_path.to_string_lossy()
     );
 
-    let full_input_file_path = canonicalize(&i_path)?;
+    let read_natively = native && !use_global_shift && is_native_format(i_path);
+    ensure!(
+        columns.is_none() || read_natively && is_text(i_path),
+        "Columns are given for the text files read natively, not for {:?}",
+        i_path.to_string_lossy()
+    );
+    if read_natively {
+        return match extension(i_path).as_deref() {
+            Some("pcd") => read(&mut PcdReader::open(i_path)?),
+            Some("ply") => read(&mut PlyReader::open(i_path)?),
+            _ => {
+                let paths = [i_path.to_path_buf()];
+                let mut points = txt_points(&paths, columns.as_ref())?;
+                read(&mut points)
+            }
+        };
+    }
 
-    let mut o_path = full_input_file_path.clone();
+    // Create initial pcd with txt format in a folder of this conversion,
+    // so that no file of the user is read as its output or removed, and nothing is left when it fails
+    let seed_dir = tempfile::tempdir()?;
+    let paths = convert_to_txt(
+        exec_path,
+        i_path,
+        seed_dir.path(),
+        use_global_shift,
+        global_shift.as_ref(),
+    )?;
 
-    // Create initial pcd with txt format
-    o_path.set_file_name("seed.txt");
+    // CloudCompare only takes the shift from the command line, so the scale is applied here
+    let scale = match (use_global_shift, global_shift) {
+        (true, Some((_, scale))) => Some(scale),
+        _ => None,
+    };
+    let mut points = txt_points(&paths, None)?.map(|point| {
+        let mut point = point?;
+        if let Some(scale) = scale {
+            point.position = Point3::from(point.position.coords * scale);
+        }
+        Ok(point)
+    });
+    read(&mut points)
+}
 
-    let seed_file_path = String::from(o_path.to_str().unwrap());
+/// convert the input file to text files in the empty folder `seed_dir` with CloudCompare,
+/// returning the paths of the output files (see `seed_files`)
+fn convert_to_txt(
+    exec_path: Option<&String>,
+    i_path: &Path,
+    seed_dir: &Path,
+    use_global_shift: bool,
+    global_shift: Option<&GlobalShift>,
+) -> anyhow::Result<Vec<PathBuf>> {
+    // paths are kept as `Path` (not `str`), so that non UTF-8 paths are passed to CloudCompare as is
+    let full_input_file_path = canonicalize(i_path)?;
+    let seed_file_path = seed_dir.join("seed.txt");
 
     println!("Converting pcd to txt...");
 
-    convert_pcd_file_to_txt(
+    let (stdout, stderr) = convert_pcd_file_to_txt(
         exec_path,
         &full_input_file_path,
         &seed_file_path,
         use_global_shift,
+        global_shift,
     )?;
 
     println!("Converting pcd to txt is done!");
 
-    // When multiple point clouds are merged and written out with CloudCompare, the suffix of the file name is _0.
-    // Therefore, if _0 is attached, use it.
-    o_path.set_file_name("seed.txt_0");
-    let seed_file_path_0 = String::from(o_path.to_str().unwrap());
+    // the clouds merged or split by CloudCompare are written with a suffix, so all of them are read
+    let paths = seed_files(seed_dir)?;
+    let Some(first) = paths.first().cloned() else {
+        return Err(ConversionError::NoOutput { stdout, stderr }.into());
+    };
+
+    let mut size = 0;
+    for path in paths.iter() {
+        size += std::fs::metadata(path)?.len();
+    }
+    if size == 0 {
+        return Err(ConversionError::EmptyOutput {
+            path: first,
+            stdout,
+            stderr,
+        }
+        .into());
+    }
+    Ok(paths)
+}
+
+/// Output files of CloudCompare for `seed.txt` in the folder of the conversion, sorted by the suffix:
+/// `seed.txt` itself, and the merged or split clouds with a suffix depending on the version
+/// (`seed.txt_0`, `seed.txt_1`, ..., `seed_0.txt`, `seed_1.txt`, ...)
+fn seed_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let suffix = |name: &str| -> Option<Option<u64>> {
+        if name == "seed.txt" {
+            return Some(None);
+        }
+        let number = name
+            .strip_prefix("seed.txt_")
+            .or_else(|| name.strip_prefix("seed_")?.strip_suffix(".txt"))?;
+        number.parse().ok().map(Some)
+    };
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|name| name.to_str());
+        if let Some(suffix) = name.and_then(suffix) {
+            if path.is_file() {
+                files.push((suffix, path));
+            }
+        }
+    }
+    files.sort();
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
 
+/// load and concatenate the points of the input files (each read natively or with CloudCompare, see `load_points`).
+/// `max_points` and `limit` apply to the points of all the files,
+/// and global shift needs an explicit shift with multiple files so that they share it
+pub fn load_points_from_files<P: AsRef<Path>>(
+    exec_path: Option<&String>,
+    input_file_paths: &[P],
+    options: &LoadOptions,
+) -> anyhow::Result<Vec<Point>> {
+    if let [input_file_path] = input_file_paths {
+        return load_points(exec_path, input_file_path, options);
+    }
+    let (max_points, limit) = (options.max_points, options.limit);
+    ensure!(!input_file_paths.is_empty(), "No input file is given!");
     ensure!(
-        PathBuf::from(&seed_file_path).exists() || PathBuf::from(&seed_file_path_0).exists(),
-        "Generating seed file is failed!"
+        !options.use_global_shift || options.global_shift.is_some(),
+        "Multiple input files need an explicit global shift, since the automatic one may differ between the files"
     );
 
-    let path = if PathBuf::from(&seed_file_path).exists() {
-        seed_file_path
-    } else {
-        seed_file_path_0
-    };
+    // reservoir sampling needs all the points to be uniform over the files,
+    // so the points of every file are streamed to one reservoir keeping only `n` points
+    if let Some(PointLimit::Reservoir(n)) = limit {
+        let mut reservoir = Reservoir::new(n, 0);
+        for input_file_path in input_file_paths {
+            read_file_points(exec_path, input_file_path.as_ref(), options, |points| {
+                fill_reservoir(points, &mut reservoir, n, max_points)
+            })?;
+        }
+        return Ok(reservoir.into_vec());
+    }
+    // the first points of all the files are among the first points of each file
+    let mut points = vec![];
+    for input_file_path in input_file_paths {
+        points.extend(load_points(exec_path, input_file_path, options)?);
+    }
+    collect_points(points.into_iter().map(Ok), max_points, limit)
+}
 
-    let points = read_points_from_txt(Path::new(&path))?;
-    let bounds = BoundingBox::from_iter(points.iter().map(|p| p.position));
-    let point_count_threshold = 2_u32.pow(14) as usize; // 16384
-                                                        // let point_count_threshold = 2_u32.pow(10) as usize;
-    let side = (point_count_threshold as f64).sqrt();
+/// unit result of level of detail
+pub struct LODUnit {
+    pub lod: u32,
+    pub bounding_box: BoundingBox,
+    pub points: Vec<Point>,
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+    /// sampling radius of the level of the unit (see `Meta::geometric_error`)
+    pub geometric_error: f64,
+    /// PNG encoded images of the unit (empty unless `LODOptions::encoder` is set)
+    #[cfg(feature = "image")]
+    pub images: Vec<(ImageKind, Vec<u8>)>,
+}
 
-    let mut coordinates = Coordinates::new();
+/// default of `LODOptions::min_retention`, a tile of 16384 points collapsing to 16 samples or less
+pub const DEFAULT_MIN_RETENTION: f64 = 0.001;
 
-    println!("Start processing...");
+/// level of detail completed by `lod_levels`
+pub struct LODLevel {
+    pub level: u32,
+    /// meta up to this level
+    pub meta: Meta,
+    pub units: Vec<LODUnit>,
+}
 
-    // create root map
-    let sampler = PoissonDiskSampling::<f64, Point>::new();
-    let size = bounds.size();
-    let max_size = size.x.max(size.y).max(size.z);
-    let calculate_sampling_radius = |lod: u32| {
-        let unit_size = max_size / (lod as f64);
-        unit_size / side
-    };
-    let mut parent_map = {
-        let map = PointCloudMap::root(bounds.clone(), &points);
-        let points = map.map().get(&(0, 0, 0));
-        if let Some(unit) = points {
-            let c_key = format!("{}-{}-{}", 0, 0, 0);
-            coordinates
-                .entry(map.lod())
-                .or_default()
-                .entry(c_key)
-                .or_insert(map.bounds().clone());
-            let under_threshold = unit.points.len() < point_count_threshold;
-            let pts = if under_threshold {
-                unit.points.clone()
-            } else {
-                // sampler.sample(unit.points())
-                let radius = calculate_sampling_radius(1);
-                let mut sampler =
-                    ParallelPoissonDiskSampling::new(unit.points().iter().collect(), radius);
-                let _ = sampler.sample();
-                sampler.samples().into_iter().cloned().collect()
-            };
-            callback_per_unit(LODUnit {
-                lod: 0,
-                bounding_box: map.bounds().clone(),
-                points: pts,
-                x: 0,
-                y: 0,
-                z: 0,
-            })
-            .await?;
+/// options for processing level of detail
+#[derive(Debug, Clone)]
+pub struct LODOptions {
+    /// a unit of the octree is divided while it has this number of points or more
+    pub point_count_threshold: usize,
+    /// affine transform (e.g. an alignment from registration) applied to the input before cropping
+    pub transform: Option<Matrix4<f64>>,
+    /// crop the input to a sphere (center, radius) before building the octree
+    pub crop_sphere: Option<(Point3<f64>, f64)>,
+    /// fail fast if the input has more points than this limit
+    pub max_points: Option<usize>,
+    /// read only a part of the input (preview mode)
+    pub limit: Option<PointLimit>,
+    /// read text, PCD and PLY files without CloudCompare (see `LoadOptions::native`)
+    pub native_input: bool,
+    /// named columns of the text files read natively (see `LoadOptions::columns`)
+    pub columns: Option<ColumnLayout>,
+    /// record each unit's bounding box as a cube (max extent on every axis).
+    /// The positions of the tile are still normalized over the extent of each axis (`Meta::tile_scales`),
+    /// so a flat tile keeps the precision of its thin axis.
+    pub cubic_tile_bounds: bool,
+    /// explicit global shift (x, y, z) and scale used instead of AUTO when global shift is applied
+    pub global_shift: Option<GlobalShift>,
+    /// encode the images of the units in parallel before `callback_per_unit` is called
+    #[cfg(feature = "image")]
+    pub encoder: Option<EncoderOptions>,
+    /// cap the side of the tile images, lowering the point count threshold to fit
+    pub max_texture_side: Option<u32>,
+    /// reorder the points of each unit so that any prefix of the tile image is a uniform subsample
+    pub progressive_order: bool,
+    /// include the points of the adjacent cells within this ratio of the cell size
+    /// into each unit's sampling input, so that the borders of the tiles blend
+    pub halo: Option<f64>,
+    /// record the index of each point in the input (`Point::index`),
+    /// so that the points retained at each level can be traced back to the input
+    pub carry_index: bool,
+    /// set the color of each sample to the dominant color of the points it represents
+    pub dominant_color: bool,
+    /// encode the position images of the finest level with `BitDepth::EightQuad` for higher precision
+    /// where the points are not sampled (requires `encoder`)
+    pub detail_tiles: bool,
+    /// warn about the sampled tiles keeping less than this fraction of the points they could hold
+    /// (see `RetentionStats`), which usually means a misconfigured radius
+    pub min_retention: f64,
+    /// seed the sampling and emit the tiles sorted by key, so that the same input gives byte-identical output
+    pub deterministic: bool,
+    /// sample the next level on the blocking threads of the tokio runtime while the units of a level are passed
+    /// to `callback_per_unit`, whose futures are awaited concurrently (false by default: sampled on the current thread
+    /// and awaited one by one in the order of the units, so that no tokio runtime is needed)
+    pub pipeline: bool,
+    /// fold the units with fewer points into the nearest unit of the same level
+    /// (see `PointCloudMap::merge_small_units`), so that nearly empty tiles are not written
+    pub min_points_per_tile: Option<usize>,
+    /// store in each level only the points not stored in the coarser levels,
+    /// so that a viewer accumulates the tiles of the ancestors (not with `halo`)
+    pub delta_levels: bool,
+    /// divide each axis of the octree independently (see `PointCloudMap::anisotropic`),
+    /// e.g. for a long corridor whose short axes would waste the cubic cells
+    pub anisotropic: bool,
+    /// divide only the units with the point count threshold or with the color variance over this
+    /// (see `PointCloudMap::divide_detail`), for a pyramid deeper in the regions of color detail
+    /// whose other tiles are the leaves not repeated in the finer levels
+    pub color_variance: Option<f64>,
+    /// algorithm sampling the points of the tiles over the threshold
+    /// (`ParallelPoissonDiskSampler` seeded with `deterministic` if `None`, not with `delta_levels`)
+    pub sampler: Option<Arc<dyn Sampler>>,
+    /// build out of core, holding about this number of input points in memory at once (see `build_lod_chunked`).
+    /// `process_lod` reads text files natively and converts the other files to text with CloudCompare
+    pub chunk_size: Option<usize>,
+}
+
+impl Default for LODOptions {
+    fn default() -> Self {
+        Self {
+            point_count_threshold: 2_u32.pow(14) as usize, // 16384
+            transform: None,
+            crop_sphere: None,
+            max_points: None,
+            limit: None,
+            native_input: false,
+            columns: None,
+            cubic_tile_bounds: false,
+            global_shift: None,
+            #[cfg(feature = "image")]
+            encoder: None,
+            max_texture_side: None,
+            progressive_order: false,
+            halo: None,
+            carry_index: false,
+            dominant_color: false,
+            detail_tiles: false,
+            min_retention: DEFAULT_MIN_RETENTION,
+            deterministic: false,
+            pipeline: false,
+            min_points_per_tile: None,
+            delta_levels: false,
+            anisotropic: false,
+            color_variance: None,
+            sampler: None,
+            chunk_size: None,
         }
-        callback_per_lod(map.lod() + 1, bounds.clone(), coordinates.clone()).await?;
-        map
-    };
+    }
+}
+
+impl LODOptions {
+    /// point count threshold lowered so that the tile images fit in `max_texture_side`
+    pub fn effective_point_count_threshold(&self) -> usize {
+        match self.max_texture_side {
+            Some(max_side) => {
+                // detail tiles of the finest level have the side of the quad encoding
+                #[cfg(feature = "image")]
+                let scale = self
+                    .encoder
+                    .as_ref()
+                    .map(|encoder| encoder.side_scale())
+                    .unwrap_or(1)
+                    .max(if self.detail_tiles { 2 } else { 1 });
+                #[cfg(not(feature = "image"))]
+                let scale = 1;
+                let side = (max_side / scale).max(1) as usize;
+                self.point_count_threshold.min(side * side)
+            }
+            None => self.point_count_threshold,
+        }
+    }
+}
+
+/// sampling with the options of the pipeline
+fn sample_unit(
+    sampler: &dyn Sampler,
+    points: &[Point],
+    radius: f64,
+    capacity: Option<usize>,
+    options: &LODOptions,
+) -> anyhow::Result<Vec<Point>> {
+    let mut samples = sample_points(sampler, points, radius, capacity)?;
+    if options.dominant_color {
+        assign_dominant_colors(&mut samples, points);
+    }
+    Ok(samples)
+}
+
+/// sampling growing the radius until the samples fit in `capacity` if given
+fn sample_points(
+    sampler: &dyn Sampler,
+    points: &[Point],
+    radius: f64,
+    capacity: Option<usize>,
+) -> anyhow::Result<Vec<Point>> {
+    // poisson disk sampling keeps a single point once the radius is over the extent of the points
+    let extent = BoundingBox::from_iter(points.iter()).size().norm();
+    let mut radius = radius;
+    loop {
+        let samples = sampler.sample(points, radius)?;
+        match capacity {
+            Some(capacity) if samples.len() > capacity => {
+                ensure!(
+                    radius <= extent,
+                    "Sampler keeps {} points over the capacity {} of the tile at radius {}",
+                    samples.len(),
+                    capacity,
+                    radius
+                );
+                let ratio = samples.len() as f64 / capacity as f64;
+                radius *= ratio.cbrt().max(1.05);
+            }
+            _ => return Ok(samples),
+        }
+    }
+}
 
+/// poisson disk sampling keeping the `required` points (first in the samples),
+/// growing the radius until the other samples fit in `capacity` if given
+fn sample_points_with_required(
+    points: &[Point],
+    radius: f64,
+    capacity: Option<usize>,
+    required: &[usize],
+) -> anyhow::Result<Vec<Point>> {
+    let sampler = PoissonDiskSampling::<f64, Point>::new();
+    let mut radius = radius;
     loop {
-        let next = parent_map.divide(point_count_threshold);
-        let lod = 2_u32.pow(next.lod());
-        let sampling_radius = calculate_sampling_radius(lod);
+        let samples = sampler.sample_with_required(points, radius, required)?;
+        let added = samples.len() - required.len();
+        match capacity {
+            Some(capacity) if added > capacity => {
+                let ratio = added as f64 / capacity as f64;
+                radius *= ratio.cbrt().max(1.05);
+            }
+            _ => return Ok(samples),
+        }
+    }
+}
 
-        let samples = next
-            .map()
-            .par_iter()
-            .map(|(k, u)| {
-                let pts = if u.points.len() < point_count_threshold {
-                    u.points.clone()
-                } else {
-                    // sampler.sample(u.points(), sampling_radius)
-                    let mut sampler = ParallelPoissonDiskSampling::new(
-                        u.points().iter().collect(),
-                        sampling_radius,
-                    );
-                    let _ = sampler.sample();
-                    sampler.samples().into_iter().cloned().collect()
+/// settings to sample the units of each level, owned so that a level can be sampled in the background
+struct LevelSampler {
+    options: LODOptions,
+    sampler: Arc<dyn Sampler>,
+    /// indices of the points stored in the sampled levels (with `delta_levels`)
+    stored: RwLock<HashSet<usize>>,
+    point_count_threshold: usize,
+    capacity: Option<usize>,
+    /// side of the tile images
+    side: f64,
+    #[cfg(feature = "image")]
+    coarse_encoder: Option<EncoderOptions>,
+    #[cfg(feature = "image")]
+    finest_encoder: Option<EncoderOptions>,
+}
+
+/// number of the samples and the input size of a sampled unit
+type Retention = (usize, usize);
+
+/// units of a level sampled (and encoded) in parallel, with the retention of the sampled ones
+struct SampledLevel {
+    map: PointCloudMap,
+    units: Vec<(LODUnit, Option<Retention>)>,
+    /// some unit has enough points to be divided further
+    has_over_threshold: bool,
+}
+
+impl LevelSampler {
+    fn new(options: &LODOptions) -> Self {
+        let point_count_threshold = options.effective_point_count_threshold();
+        // the finest level has the detail tiles and the depth images
+        #[cfg(feature = "image")]
+        let (coarse_encoder, finest_encoder) = match &options.encoder {
+            Some(encoder) => {
+                let finest = match options.detail_tiles {
+                    true => encoder.clone().bit_depth(BitDepth::EightQuad),
+                    false => encoder.clone(),
                 };
-                (k, pts)
-            })
-            .collect::<Vec<_>>();
+                (Some(encoder.clone().depth(None)), Some(finest))
+            }
+            None => (None, None),
+        };
+        // the same seed for every tile, so that the output only depends on the input
+        let seed = options.deterministic.then_some(0);
+        Self {
+            options: options.clone(),
+            sampler: options.sampler.clone().unwrap_or_else(|| {
+                let sampler = ParallelPoissonDiskSampler::new();
+                Arc::new(match seed {
+                    Some(seed) => sampler.seed(seed),
+                    None => sampler,
+                })
+            }),
+            stored: RwLock::new(HashSet::new()),
+            point_count_threshold,
+            capacity: options.max_texture_side.map(|_| point_count_threshold),
+            side: (point_count_threshold as f64).sqrt(),
+            #[cfg(feature = "image")]
+            coarse_encoder,
+            #[cfg(feature = "image")]
+            finest_encoder,
+        }
+    }
 
-        for (k, pts) in samples.into_iter() {
-            let (x, y, z) = k;
-            let c_key = format!("{}-{}-{}", x, y, z);
-            let bbox = BoundingBox::from_iter(pts.iter());
-            coordinates
-                .entry(next.lod())
-                .or_default()
-                .entry(c_key)
-                .or_insert(bbox.clone());
-            callback_per_unit(LODUnit {
-                lod: next.lod(),
-                bounding_box: bbox,
-                points: pts,
-                x: *x,
-                y: *y,
-                z: *z,
-            })
-            .await?;
+    /// meta of the level 0 with the settings of the options,
+    /// `normals` if all the points have a normal and `radius` if some point has a radius
+    #[allow(unused_variables)]
+    fn meta(&self, bounds: BoundingBox, normals: bool, radius: bool) -> Meta {
+        let options = &self.options;
+        let mut meta = Meta::new(0, bounds, Coordinates::new());
+        #[cfg(feature = "image")]
+        {
+            meta.normals = options
+                .encoder
+                .as_ref()
+                .map(|encoder| encoder.has_normals())
+                .unwrap_or(false)
+                && normals;
+            meta.radius = options
+                .encoder
+                .as_ref()
+                .map(|encoder| encoder.has_radius())
+                .unwrap_or(false)
+                && radius;
+            meta.index = options
+                .encoder
+                .as_ref()
+                .map(|encoder| encoder.has_index())
+                .unwrap_or(false)
+                && options.carry_index;
+            meta.intensity_in_alpha = options
+                .encoder
+                .as_ref()
+                .map(|encoder| encoder.has_intensity_in_alpha())
+                .unwrap_or(true);
+            meta.intensity_in_position_alpha = options
+                .encoder
+                .as_ref()
+                .map(|encoder| encoder.has_intensity_in_position_alpha())
+                .unwrap_or(false);
+            meta.gamma = options
+                .encoder
+                .as_ref()
+                .and_then(|encoder| encoder.get_gamma());
+            if let Some(encoder) = &options.encoder {
+                meta.pixel_layout = encoder.get_pixel_layout().name().to_string();
+            }
+            meta.color_by_time = options
+                .encoder
+                .as_ref()
+                .and_then(|encoder| encoder.get_color_by_time());
         }
-        callback_per_lod(next.lod() + 1, bounds.clone(), coordinates.clone()).await?;
+        meta.point_count_threshold = self.point_count_threshold;
+        meta.progressive_order = options.progressive_order;
+        meta.halo = options.halo.unwrap_or(0.);
+        meta.delta_levels = options.delta_levels;
+        meta.color_variance = options.color_variance;
+        meta
+    }
 
-        // Break loop if all points are under threshold
-        let has_over_threshold = next
-            .map()
+    /// record the finest level, which has the detail tiles and the depth images
+    #[cfg(feature = "image")]
+    fn mark_finest(&self, meta: &mut Meta, level: u32) {
+        if self.options.detail_tiles && self.finest_encoder.is_some() {
+            meta.detail_level = Some(level);
+        }
+        if let Some(depth) = self.finest_encoder.as_ref().and_then(|e| e.depth_mode()) {
+            meta.depth = Some(depth);
+            meta.depth_level = Some(level);
+        }
+    }
+
+    /// record the sampling radius (and the cell divisions with `anisotropic`) of the level of the map
+    fn record_level(&self, meta: &mut Meta, map: &PointCloudMap) {
+        meta.geometric_error
+            .insert(map.lod(), self.sampling_radius(map));
+        if self.options.anisotropic {
+            meta.cell_divisions.insert(map.lod(), map.divisions());
+        }
+    }
+
+    /// record the units and their retention,
+    /// the ratio of the samples to the points the tile could hold, warning about the tiles under `min_retention`
+    fn record_units(&self, meta: &mut Meta, units: &[(LODUnit, Option<Retention>)]) {
+        for (unit, retention) in units.iter() {
+            if let Some((kept, input)) = *retention {
+                let ratio = kept as f64 / input.min(self.point_count_threshold) as f64;
+                let low = ratio < self.options.min_retention;
+                if low {
+                    println!(
+                        "Warning: sampling kept {} of {} points in tile {:?} of level {}, check the sampling radius",
+                        kept,
+                        input,
+                        (unit.x, unit.y, unit.z),
+                        unit.lod
+                    );
+                }
+                meta.retention
+                    .get_or_insert_with(RetentionStats::default)
+                    .insert(ratio, low);
+            }
+            meta.insert_unit(unit);
+        }
+    }
+
+    /// sampling radius of the level of the map (the longest side of the cells over the side of the tile image)
+    fn sampling_radius(&self, map: &PointCloudMap) -> f64 {
+        let unit_size = map.cell_size().max();
+        unit_size / self.side
+    }
+
+    fn order(&self, points: Vec<Point>) -> Vec<Point> {
+        if self.options.progressive_order {
+            PointCloud::new(points).progressive_order().into_points()
+        } else {
+            points
+        }
+    }
+
+    fn tile_bounds(&self, bbox: BoundingBox) -> BoundingBox {
+        if self.options.cubic_tile_bounds {
+            bbox.cube()
+        } else {
+            bbox
+        }
+    }
+
+    /// points of a unit to store: the samples of the input (all the points under the threshold),
+    /// only the ones not stored in the coarser levels with `delta_levels`.
+    /// The number of the samples and the input size are returned if sampled
+    fn unit_points(
+        &self,
+        input: &[Point],
+        radius: f64,
+    ) -> anyhow::Result<(Vec<Point>, Option<Retention>)> {
+        let sampled = input.len() >= self.point_count_threshold;
+        if !self.options.delta_levels {
+            return match sampled {
+                true => {
+                    let pts = sample_unit(
+                        self.sampler.as_ref(),
+                        input,
+                        radius,
+                        self.capacity,
+                        &self.options,
+                    )?;
+                    let kept = pts.len();
+                    Ok((pts, Some((kept, input.len()))))
+                }
+                false => Ok((input.to_vec(), None)),
+            };
+        }
+
+        let stored = self.stored.read().unwrap();
+        let is_stored = |p: &Point| p.index.is_some_and(|i| stored.contains(&i));
+        if !sampled {
+            return Ok((
+                input.iter().filter(|p| !is_stored(p)).cloned().collect(),
+                None,
+            ));
+        }
+        let required: Vec<usize> = input
             .iter()
-            .any(|u| u.1.points.len() >= point_count_threshold);
-        if !has_over_threshold {
-            break;
+            .enumerate()
+            .filter(|(_, p)| is_stored(p))
+            .map(|(i, _)| i)
+            .collect();
+        let mut samples = sample_points_with_required(input, radius, self.capacity, &required)?;
+        let kept = samples.len();
+        let mut added = samples.split_off(required.len());
+        if self.options.dominant_color {
+            assign_dominant_colors(&mut added, input);
         }
+        Ok((added, Some((kept, input.len()))))
+    }
 
-        println!("Processing level:{} is done!", next.lod());
+    /// record the points of the units as stored for the finer levels (with `delta_levels`),
+    /// dropping the indices unless `carry_index`
+    fn store(&self, points: &mut [Point]) -> Vec<usize> {
+        if !self.options.delta_levels {
+            return vec![];
+        }
+        let indices = points.iter().filter_map(|p| p.index).collect();
+        if !self.options.carry_index {
+            points.iter_mut().for_each(|p| p.index = None);
+        }
+        indices
+    }
 
-        parent_map = next;
+    #[cfg(feature = "image")]
+    fn encoder(&self, finest: bool) -> Option<&EncoderOptions> {
+        match finest {
+            true => self.finest_encoder.as_ref(),
+            false => self.coarse_encoder.as_ref(),
+        }
     }
 
-    std::fs::remove_file(&path)?;
+    /// sample and encode the root unit of the map (the tile of the whole bounds),
+    /// with the encoder of the finest level if `single_level`
+    #[allow(unused_variables)]
+    fn sample_root(
+        &self,
+        map: &PointCloudMap,
+        single_level: bool,
+    ) -> anyhow::Result<Option<(LODUnit, Option<Retention>)>> {
+        let Some(unit) = map.map().get(&(0, 0, 0)) else {
+            return Ok(None);
+        };
+        let bbox = self.tile_bounds(map.bounds().clone());
+        let radius = self.sampling_radius(map);
+        let (mut pts, retention) = self.unit_points(unit.points(), radius)?;
+        let stored = self.store(&mut pts);
+        self.stored.write().unwrap().extend(stored);
+        let pts = self.order(pts);
+        #[allow(unused_mut)]
+        let mut unit = LODUnit {
+            lod: 0,
+            bounding_box: bbox,
+            points: pts,
+            x: 0,
+            y: 0,
+            z: 0,
+            geometric_error: radius,
+            #[cfg(feature = "image")]
+            images: vec![],
+        };
+        #[cfg(feature = "image")]
+        if let Some(encoder) = self.encoder(single_level) {
+            unit.images = encode_unit(&unit, encoder)?;
+        }
+        Ok(Some((unit, retention)))
+    }
 
-    Ok(())
+    /// divide the parent level and sample the units of the next level
+    fn sample_children(&self, parent: PointCloudMap) -> anyhow::Result<SampledLevel> {
+        let threshold = self.point_count_threshold;
+        let next = match self.options.color_variance {
+            Some(color_variance) => parent.divide_detail(threshold, color_variance),
+            None => parent.divide(threshold),
+        };
+        self.sample(next)
+    }
+
+    /// sample and encode the units of the level in parallel
+    fn sample(&self, mut next: PointCloudMap) -> anyhow::Result<SampledLevel> {
+        if let Some(min_points) = self.options.min_points_per_tile {
+            next.merge_small_units(min_points);
+        }
+        let point_count_threshold = self.point_count_threshold;
+        let color_variance = self.options.color_variance;
+        let has_over_threshold = next
+            .map()
+            .values()
+            .any(|u| needs_division(&u.points, point_count_threshold, color_variance));
+        self.sample_level(next, has_over_threshold)
+    }
+
+    /// sample and encode the units of the level in parallel,
+    /// with the encoder of the finest level unless `has_over_threshold`
+    fn sample_level(
+        &self,
+        next: PointCloudMap,
+        has_over_threshold: bool,
+    ) -> anyhow::Result<SampledLevel> {
+        let sampling_radius = self.sampling_radius(&next);
+        let units = next
+            .map()
+            .par_iter()
+            .map(|(k, u)| {
+                let halo;
+                let input = match self.options.halo {
+                    Some(ratio) => {
+                        let unit = next.cell_size().max();
+                        halo = [u.points.clone(), next.halo_points(k, unit * ratio)].concat();
+                        &halo
+                    }
+                    None => &u.points,
+                };
+                let (mut pts, retention) = self.unit_points(input, sampling_radius)?;
+                let stored = self.store(&mut pts);
+                let pts = self.order(pts);
+                let (x, y, z) = *k;
+                #[allow(unused_mut)]
+                let mut unit = LODUnit {
+                    lod: next.lod(),
+                    bounding_box: self.tile_bounds(BoundingBox::from_iter(pts.iter())),
+                    points: pts,
+                    x,
+                    y,
+                    z,
+                    geometric_error: sampling_radius,
+                    #[cfg(feature = "image")]
+                    images: vec![],
+                };
+                // encoding is CPU heavy, so it runs here in parallel and only the callback is serialized
+                #[cfg(feature = "image")]
+                if let Some(encoder) = self.encoder(!has_over_threshold) {
+                    unit.images = encode_unit(&unit, encoder)?;
+                }
+                Ok(((unit, retention), stored))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let (units, stored): (Vec<_>, Vec<_>) = units.into_iter().unzip();
+        self.stored
+            .write()
+            .unwrap()
+            .extend(stored.into_iter().flatten());
+
+        Ok(SampledLevel {
+            map: next,
+            units,
+            has_over_threshold,
+        })
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn detect_app_exists() {
-        let r = super::detect_cloudcompare_exists(None);
-        assert!(r.is_ok());
+/// level to be sampled next: in the background with `pipeline`, or from its parent level on the current thread
+enum NextLevel {
+    Sampling(tokio::task::JoinHandle<anyhow::Result<SampledLevel>>),
+    Parent(PointCloudMap),
+}
+
+/// await the futures concurrently on the current task, failing with the first error
+async fn try_join_all<Fut>(futures: Vec<Fut>) -> anyhow::Result<()>
+where
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    let mut futures: Vec<Option<Pin<Box<Fut>>>> =
+        futures.into_iter().map(|f| Some(Box::pin(f))).collect();
+    std::future::poll_fn(|cx| {
+        let mut pending = false;
+        for slot in futures.iter_mut() {
+            if let Some(future) = slot {
+                match future.as_mut().poll(cx) {
+                    Poll::Ready(Ok(())) => *slot = None,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => pending = true,
+                }
+            }
+        }
+        match pending {
+            true => Poll::Pending,
+            false => Poll::Ready(Ok(())),
+        }
+    })
+    .await
+}
+
+/// process level of detail of the points of all the input files as one scene
+pub async fn process_lod<P, F0, F1, Fut0, Fut1>(
+    exec_path: Option<&String>,
+    input_file_paths: &[P],
+    callback_per_unit: F0,
+    callback_per_lod: F1,
+    use_global_shift: bool,
+    options: &LODOptions,
+) -> anyhow::Result<LODReport>
+where
+    P: AsRef<Path>,
+    F0: Fn(LODUnit) -> Fut0,
+    F1: Fn(u32, BoundingBox, Coordinates) -> Fut1,
+    Fut0: Future<Output = anyhow::Result<()>>,
+    Fut1: Future<Output = anyhow::Result<()>>,
+{
+    process_lod_with_meta(
+        exec_path,
+        input_file_paths,
+        callback_per_unit,
+        |meta| {
+            callback_per_lod(
+                meta.lod(),
+                meta.bounds().clone(),
+                meta.coordinates().clone(),
+            )
+        },
+        use_global_shift,
+        options,
+    )
+    .await
+}
+
+/// process level of detail like `process_lod`, passing the whole `Meta` of each level to `callback_per_lod`
+pub async fn process_lod_with_meta<P, F0, F1, Fut0, Fut1>(
+    exec_path: Option<&String>,
+    input_file_paths: &[P],
+    callback_per_unit: F0,
+    callback_per_lod: F1,
+    use_global_shift: bool,
+    options: &LODOptions,
+) -> anyhow::Result<LODReport>
+where
+    P: AsRef<Path>,
+    F0: Fn(LODUnit) -> Fut0,
+    F1: Fn(Meta) -> Fut1,
+    Fut0: Future<Output = anyhow::Result<()>>,
+    Fut1: Future<Output = anyhow::Result<()>>,
+{
+    let started = Instant::now();
+    // the metas tell the explicit shift the points are given in, like the report
+    let global_shift = options.global_shift.filter(|_| use_global_shift);
+    let callback_per_lod = |mut meta: Meta| {
+        meta.global_shift = global_shift;
+        callback_per_lod(meta)
+    };
+    let load_options = LoadOptions {
+        use_global_shift,
+        global_shift: options.global_shift,
+        max_points: options.max_points,
+        limit: options.limit,
+        native: options.native_input,
+        columns: options.columns.clone(),
+    };
+    let mut report = match options.chunk_size {
+        Some(chunk_size) => {
+            // the converted files and the spilled cells are removed with the folder
+            let dir = tempfile::tempdir()?;
+            let paths = text_files(exec_path, input_file_paths, &load_options, dir.path())?;
+            let map = ChunkedPointCloudMap::from_txt_files(
+                &paths,
+                load_options.columns.as_ref(),
+                chunk_size,
+                dir.path(),
+            )?;
+            if let Some(max_points) = options.max_points {
+                ensure!(
+                    map.point_count() <= max_points,
+                    "Input has more than {} points! Downsample the input beforehand or raise the limit of max points.",
+                    max_points
+                );
+            }
+            let load = started.elapsed().as_secs_f64();
+            let mut report =
+                build_lod_chunked(&map, callback_per_unit, callback_per_lod, options).await?;
+            report.timings.load = load;
+            report
+        }
+        None => {
+            let points = load_points_from_files(exec_path, input_file_paths, &load_options)?;
+            let load = started.elapsed().as_secs_f64();
+            let mut report =
+                build_lod_with_meta(points, callback_per_unit, callback_per_lod, options).await?;
+            report.timings.load = load;
+            report
+        }
+    };
+    report.input_files = input_file_paths
+        .iter()
+        .map(|path| path.as_ref().to_string_lossy().to_string())
+        .collect();
+    report.global_shift = global_shift;
+    Ok(report)
+}
+
+/// text files of the input files to be read as one input: the text files themselves if read natively
+/// (see `LoadOptions::native`), the others converted with CloudCompare into a folder in `dir`
+fn text_files<P: AsRef<Path>>(
+    exec_path: Option<&String>,
+    input_file_paths: &[P],
+    options: &LoadOptions,
+    dir: &Path,
+) -> anyhow::Result<Vec<PathBuf>> {
+    ensure!(!input_file_paths.is_empty(), "No input file is given!");
+    ensure!(
+        options.limit.is_none(),
+        "Chunked input can not be limited to a part of the points"
+    );
+    ensure!(
+        !options.use_global_shift || options.global_shift.is_some() || input_file_paths.len() == 1,
+        "Multiple input files need an explicit global shift, since the automatic one may differ between the files"
+    );
+    ensure!(
+        !options.use_global_shift || options.global_shift.is_none_or(|(_, scale)| scale == 1.),
+        "Chunked input can not be scaled after the global shift"
+    );
+    let mut paths = vec![];
+    for (i, input_file_path) in input_file_paths.iter().enumerate() {
+        let path = input_file_path.as_ref();
+        ensure!(
+            path.exists(),
+            "Input file {:?} is not existed!",
+            path.to_string_lossy()
+        );
+        if options.native && !options.use_global_shift && is_text(path) {
+            paths.push(path.to_path_buf());
+            continue;
+        }
+        ensure!(
+            options.columns.is_none(),
+            "Columns are given for the text files read natively, not for {:?}",
+            path.to_string_lossy()
+        );
+        let seed_dir = dir.join(i.to_string());
+        create_dir_all(&seed_dir)?;
+        paths.extend(convert_to_txt(
+            exec_path,
+            path,
+            &seed_dir,
+            options.use_global_shift,
+            options.global_shift.as_ref(),
+        )?);
+    }
+    Ok(paths)
+}
+
+/// record the units of a level and pass them to `callback_per_unit` one by one (sorted by key with `deterministic`)
+async fn emit_units<F0, Fut0>(
+    level_sampler: &LevelSampler,
+    meta: &mut Meta,
+    mut units: Vec<(LODUnit, Option<Retention>)>,
+    callback_per_unit: &F0,
+) -> anyhow::Result<()>
+where
+    F0: Fn(LODUnit) -> Fut0,
+    Fut0: Future<Output = anyhow::Result<()>>,
+{
+    if level_sampler.options.deterministic {
+        units.sort_by_key(|(unit, _)| (unit.x, unit.y, unit.z));
+    }
+    level_sampler.record_units(meta, &units);
+    for (unit, _) in units {
+        callback_per_unit(unit).await?;
+    }
+    Ok(())
+}
+
+/// Build level of detail from the cells of the chunked map, loading one cell at a time.
+/// The levels from the level of the cells are the same as in `build_lod_with_meta` (up to the order of the units),
+/// each cell being loaded again for every level, while the coarser levels are sampled
+/// from the union of the samples of the cells, which approximates the samples of the whole input.
+/// Transform, crop, halo, delta levels, anisotropic, color variance, min points per tile and carry index
+/// need the whole input and are not supported.
+pub async fn build_lod_chunked<F0, F1, Fut0, Fut1>(
+    map: &ChunkedPointCloudMap,
+    callback_per_unit: F0,
+    callback_per_lod: F1,
+    options: &LODOptions,
+) -> anyhow::Result<LODReport>
+where
+    F0: Fn(LODUnit) -> Fut0,
+    F1: Fn(Meta) -> Fut1,
+    Fut0: Future<Output = anyhow::Result<()>>,
+    Fut1: Future<Output = anyhow::Result<()>>,
+{
+    let started = Instant::now();
+    let mut report = LODReport::new(options);
+    report.point_count = map.point_count();
+    ensure!(
+        options.transform.is_none() && options.crop_sphere.is_none(),
+        "Chunked build can not transform or crop the input"
+    );
+    ensure!(
+        options.halo.is_none()
+            && !options.delta_levels
+            && !options.anisotropic
+            && options.color_variance.is_none()
+            && options.min_points_per_tile.is_none()
+            && !options.carry_index,
+        "Chunked build samples each cell on its own, so it can not be combined with halo, delta levels, anisotropic, color variance, min points per tile or carry index"
+    );
+
+    let level_sampler = LevelSampler::new(options);
+    let threshold = level_sampler.point_count_threshold;
+    let bounds = map.bounds().clone();
+    let mut meta = level_sampler.meta(bounds.clone(), map.has_normals(), map.has_radius());
+
+    // the finest level is the first one with no unit to divide, known from the counts down to the level of the cells
+    let over_threshold = |lod: u32| map.counts_of(lod).values().any(|count| *count >= threshold);
+    let finest = match (0..=map.lod()).find(|lod| !over_threshold(*lod)) {
+        Some(lod) => lod,
+        None => {
+            let mut finest = map.lod();
+            for key in map.keys() {
+                let mut cell = map.load_map(&key)?;
+                while cell.map().values().any(|u| u.points.len() >= threshold) {
+                    cell = cell.divide(threshold);
+                }
+                finest = finest.max(cell.lod());
+            }
+            finest
+        }
+    };
+
+    println!("Start processing...");
+
+    // the samples of the cells, which are the input of the coarser levels
+    let mut cell_units = vec![];
+    let mut reduced = vec![];
+    for key in map.keys() {
+        let cell = map.load_map(&key)?;
+        level_sampler.record_level(&mut meta, &cell);
+        let units = match map.lod() {
+            // the root tile has the whole bounds
+            0 => level_sampler
+                .sample_root(&cell, finest == 0)?
+                .into_iter()
+                .collect(),
+            _ => level_sampler.sample_level(cell, map.lod() != finest)?.units,
+        };
+        reduced.extend(
+            units
+                .iter()
+                .flat_map(|(unit, _)| unit.points.iter().cloned()),
+        );
+        cell_units.extend(units);
+    }
+
+    let mut coarse = PointCloudMap::root(bounds.clone(), &reduced);
+    for lod in 0..=finest {
+        if lod < map.lod() {
+            let units = match lod {
+                0 => {
+                    level_sampler.record_level(&mut meta, &coarse);
+                    level_sampler
+                        .sample_root(&coarse, finest == 0)?
+                        .into_iter()
+                        .collect()
+                }
+                _ => {
+                    let sampled =
+                        level_sampler.sample_level(coarse.divide(threshold), lod != finest)?;
+                    coarse = sampled.map;
+                    level_sampler.record_level(&mut meta, &coarse);
+                    sampled.units
+                }
+            };
+            emit_units(&level_sampler, &mut meta, units, &callback_per_unit).await?;
+        } else if lod == map.lod() {
+            let units = std::mem::take(&mut cell_units);
+            emit_units(&level_sampler, &mut meta, units, &callback_per_unit).await?;
+        } else {
+            for key in map.keys() {
+                let mut cell = map.load_map(&key)?;
+                for _ in map.lod()..lod {
+                    cell = cell.divide(threshold);
+                }
+                let sampled = level_sampler.sample_level(cell, lod != finest)?;
+                level_sampler.record_level(&mut meta, &sampled.map);
+                emit_units(&level_sampler, &mut meta, sampled.units, &callback_per_unit).await?;
+            }
+        }
+
+        #[cfg(feature = "image")]
+        if lod == finest {
+            level_sampler.mark_finest(&mut meta, lod);
+        }
+        meta.lod = lod + 1;
+        callback_per_lod(meta.clone()).await?;
+        println!("Processing level:{} is done!", lod);
+    }
+
+    report.timings.build = started.elapsed().as_secs_f64();
+    Ok(report.levels_of(&meta))
+}
+
+/// Build level of detail from the points on a blocking thread of the tokio runtime (the sampling is CPU bound),
+/// receiving each level as soon as it is completed
+/// (e.g. to upload the coarse levels while the finer ones are still being built).
+/// The build waits while a level is not received yet, and stops when the receiver is dropped.
+pub fn lod_levels(
+    points: Vec<Point>,
+    options: LODOptions,
+) -> tokio::sync::mpsc::Receiver<anyhow::Result<LODLevel>> {
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    let runtime = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || {
+        runtime.block_on(async move {
+            let units = Mutex::new(vec![]);
+            let result = build_lod_with_meta(
+                points,
+                |unit| {
+                    units.lock().unwrap().push(unit);
+                    async { Ok(()) }
+                },
+                |meta| {
+                    let level = LODLevel {
+                        level: meta.lod() - 1,
+                        meta,
+                        units: std::mem::take(&mut *units.lock().unwrap()),
+                    };
+                    let tx = tx.clone();
+                    async move {
+                        tx.send(Ok(level))
+                            .await
+                            .map_err(|_| anyhow::anyhow!("Receiver of the levels is dropped"))
+                    }
+                },
+                &options,
+            )
+            .await;
+            if let Err(e) = result {
+                let _ = tx.send(Err(e)).await;
+            }
+        })
+    });
+    rx
+}
+
+/// build level of detail from the points in memory
+pub async fn build_lod<F0, F1, Fut0, Fut1>(
+    points: Vec<Point>,
+    callback_per_unit: F0,
+    callback_per_lod: F1,
+    options: &LODOptions,
+) -> anyhow::Result<LODReport>
+where
+    F0: Fn(LODUnit) -> Fut0,
+    F1: Fn(u32, BoundingBox, Coordinates) -> Fut1,
+    Fut0: Future<Output = anyhow::Result<()>>,
+    Fut1: Future<Output = anyhow::Result<()>>,
+{
+    build_lod_with_meta(
+        points,
+        callback_per_unit,
+        |meta| {
+            callback_per_lod(
+                meta.lod(),
+                meta.bounds().clone(),
+                meta.coordinates().clone(),
+            )
+        },
+        options,
+    )
+    .await
+}
+
+/// build level of detail like `build_lod`, passing the whole `Meta` of each level to `callback_per_lod`
+pub async fn build_lod_with_meta<F0, F1, Fut0, Fut1>(
+    points: Vec<Point>,
+    callback_per_unit: F0,
+    callback_per_lod: F1,
+    options: &LODOptions,
+) -> anyhow::Result<LODReport>
+where
+    F0: Fn(LODUnit) -> Fut0,
+    F1: Fn(Meta) -> Fut1,
+    Fut0: Future<Output = anyhow::Result<()>>,
+    Fut1: Future<Output = anyhow::Result<()>>,
+{
+    let started = Instant::now();
+    let mut report = LODReport::new(options);
+    report.point_count = points.len();
+    ensure!(
+        !(options.delta_levels && options.halo.is_some()),
+        "Delta levels can not be combined with halo"
+    );
+    ensure!(
+        !(options.delta_levels && options.sampler.is_some()),
+        "Delta levels need the built-in sampler to keep the points stored in the coarser levels"
+    );
+    let mut points = points;
+    // delta levels tell the stored points by the index
+    if options.carry_index || options.delta_levels {
+        points
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, p)| p.index = Some(i));
+    }
+
+    if let Some(matrix) = &options.transform {
+        let mut cloud = PointCloud::new(points);
+        cloud.transform(matrix);
+        points = cloud.into_points();
+    }
+
+    let points = match options.crop_sphere {
+        Some((center, radius)) => PointCloud::new(points)
+            .crop_sphere(center, radius)
+            .into_points(),
+        None => points,
+    };
+
+    let bounds = BoundingBox::from_points_parallel(&points);
+    let level_sampler = Arc::new(LevelSampler::new(options));
+    let point_count_threshold = level_sampler.point_count_threshold;
+    let mut meta = level_sampler.meta(
+        bounds.clone(),
+        points.iter().all(|p| p.normal.is_some()),
+        points.iter().any(|p| p.radius.is_some()),
+    );
+
+    println!("Start processing...");
+
+    // a cloud under the threshold fits in the root tile as is, so no level is divided
+    let single_level = !needs_division(&points, point_count_threshold, options.color_variance);
+    let parent_map = {
+        // create root map
+        let map = PointCloudMap::root(bounds.clone(), &points).anisotropic(options.anisotropic);
+        level_sampler.record_level(&mut meta, &map);
+        if let Some(root) = level_sampler.sample_root(&map, single_level)? {
+            #[cfg(feature = "image")]
+            if single_level {
+                level_sampler.mark_finest(&mut meta, 0);
+            }
+            level_sampler.record_units(&mut meta, std::slice::from_ref(&root));
+            callback_per_unit(root.0).await?;
+        }
+        meta.lod = map.lod() + 1;
+        callback_per_lod(meta.clone()).await?;
+        map
+    };
+
+    if single_level {
+        report.timings.build = started.elapsed().as_secs_f64();
+        return Ok(report.levels_of(&meta));
+    }
+
+    // sampling is CPU bound, so with `pipeline` it runs on the blocking threads while the previous level is written
+    let sample_next = |parent: PointCloudMap| {
+        let level_sampler = level_sampler.clone();
+        tokio::task::spawn_blocking(move || level_sampler.sample_children(parent))
+    };
+    let mut pending = match options.pipeline {
+        true => NextLevel::Sampling(sample_next(parent_map)),
+        false => NextLevel::Parent(parent_map),
+    };
+    loop {
+        let SampledLevel {
+            map: next,
+            mut units,
+            has_over_threshold,
+        } = match pending {
+            NextLevel::Sampling(handle) => handle.await??,
+            NextLevel::Parent(parent) => level_sampler.sample_children(parent)?,
+        };
+        level_sampler.record_level(&mut meta, &next);
+
+        // the last level has no unit to divide further
+        #[cfg(feature = "image")]
+        if !has_over_threshold {
+            level_sampler.mark_finest(&mut meta, next.lod());
+        }
+
+        if options.deterministic {
+            units.sort_by_key(|(unit, _)| (unit.x, unit.y, unit.z));
+        }
+        level_sampler.record_units(&mut meta, &units);
+
+        let lod = next.lod();
+        let mut parent = Some(next);
+        let next_pending = match (options.pipeline, has_over_threshold) {
+            (true, true) => parent.take().map(sample_next),
+            _ => None,
+        };
+        let callbacks = units.into_iter().map(|(unit, _)| callback_per_unit(unit));
+        match options.pipeline {
+            true => try_join_all(callbacks.collect()).await?,
+            false => {
+                for callback in callbacks {
+                    callback.await?;
+                }
+            }
+        }
+        meta.lod = lod + 1;
+        callback_per_lod(meta.clone()).await?;
+
+        // Break loop if all points are under threshold
+        if !has_over_threshold {
+            break;
+        }
+
+        println!("Processing level:{} is done!", lod);
+
+        pending = match (next_pending, parent) {
+            (Some(next_pending), _) => NextLevel::Sampling(next_pending),
+            (None, Some(parent)) => NextLevel::Parent(parent),
+            (None, None) => unreachable!(),
+        };
+    }
+
+    report.timings.build = started.elapsed().as_secs_f64();
+    Ok(report.levels_of(&meta))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, sync::Mutex};
+
+    use crate::prelude::{estimate_lod_levels, partition, Color, DepthMode};
+
+    use super::*;
+
+    #[test]
+    fn detect_app_exists() {
+        let r = super::detect_cloudcompare_exists(None);
+        assert!(r.is_ok());
+    }
+
+    /// near-uniform grid of n^3 points with a small jitter
+    fn uniform_points(n: usize) -> Vec<Point> {
+        (0..n)
+            .flat_map(|z| (0..n).flat_map(move |y| (0..n).map(move |x| (x, y, z))))
+            .map(|(x, y, z)| {
+                let jitter = ((x * 7 + y * 13 + z * 17) % 10) as f64 * 1e-3;
+                let line = format!("{} {} {}", x as f64 + jitter, y as f64, z as f64);
+                Point::try_parse(&line).unwrap()
+            })
+            .collect()
+    }
+
+    /// build the levels of the points, returning the units and the meta of each level in the order received
+    async fn build_levels(points: Vec<Point>, options: &LODOptions) -> (Vec<LODUnit>, Vec<Meta>) {
+        let units = Mutex::new(vec![]);
+        let metas = Mutex::new(vec![]);
+        build_lod_with_meta(
+            points,
+            |unit| {
+                units.lock().unwrap().push(unit);
+                async { Ok(()) }
+            },
+            |meta| {
+                metas.lock().unwrap().push(meta);
+                async { Ok(()) }
+            },
+            options,
+        )
+        .await
+        .unwrap();
+        (units.into_inner().unwrap(), metas.into_inner().unwrap())
+    }
+
+    #[tokio::test]
+    async fn small_cloud_has_single_level() {
+        let points = uniform_points(4);
+        let options = LODOptions {
+            point_count_threshold: 100,
+            ..Default::default()
+        };
+
+        let (units, metas) = build_levels(points.clone(), &options).await;
+
+        let units: Vec<_> = units.iter().map(|u| (u.lod, u.points.len())).collect();
+        assert_eq!(units, vec![(0, points.len())]);
+        let levels: Vec<_> = metas.iter().map(|m| m.lod()).collect();
+        assert_eq!(levels, vec![1]);
+        let bounds = BoundingBox::from_iter(points.iter());
+        assert_eq!(estimate_lod_levels(&bounds, points.len(), 100), 1);
+    }
+
+    #[tokio::test]
+    async fn estimated_lod_levels_match_actual_run() {
+        let points = uniform_points(20);
+        let options = LODOptions {
+            point_count_threshold: 100,
+            ..Default::default()
+        };
+        let bounds = BoundingBox::from_iter(points.iter());
+        let estimated = estimate_lod_levels(&bounds, points.len(), options.point_count_threshold);
+
+        let (_, metas) = build_levels(points, &options).await;
+
+        let actual = metas.last().unwrap().lod();
+        assert!(
+            estimated.abs_diff(actual) <= 1,
+            "estimated {} vs actual {}",
+            estimated,
+            actual
+        );
+    }
+
+    #[cfg(feature = "image")]
+    /// decode 8-bit position image back to world coordinates
+    fn decode_8bit(image: &image::RgbaImage, bbox: &BoundingBox, n: usize) -> Vec<Point3<f64>> {
+        image
+            .pixels()
+            .take(n)
+            .map(|pix| {
+                let v = nalgebra::Vector3::new(pix[0], pix[1], pix[2]).cast::<f64>() / 255.;
+                Point3::from(bbox.size().component_mul(&v) + bbox.min().coords)
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "image")]
+    /// max decoding error per axis of the points of a flat tile built with the options and loaded with `load_tile`
+    async fn flat_tile_error(options: LODOptions) -> (nalgebra::Vector3<f64>, Meta) {
+        use crate::prelude::{load_tile, write_unit, FileSystemSink, TileSink};
+
+        // 10 x 10 x 0.1 facade-like tile
+        let points: Vec<Point> = (0..500)
+            .map(|i| {
+                let (x, y, z) = (
+                    (i % 23) as f64 * 0.43,
+                    (i % 19) as f64 * 0.52,
+                    (i % 7) as f64 * 0.0157,
+                );
+                Point::from(Point3::new(x, y, z))
+            })
+            .collect();
+        let dir = tempfile::tempdir().unwrap();
+        let sink = FileSystemSink::new(dir.path().to_path_buf());
+        let encoder = EncoderOptions::new();
+        let meta = Mutex::new(None);
+        build_lod_with_meta(
+            points.clone(),
+            |unit| {
+                let r = write_unit(&sink, &unit, &encoder);
+                async { r }
+            },
+            |m| {
+                let r = sink.write_meta(&m);
+                *meta.lock().unwrap() = Some(m);
+                async { r }
+            },
+            &options,
+        )
+        .await
+        .unwrap();
+
+        let meta = meta.into_inner().unwrap().unwrap();
+        let decoded = load_tile(dir.path(), &meta, &(0, 0, 0), 0).unwrap();
+        assert_eq!(decoded.len(), points.len());
+        let error = points
+            .iter()
+            .zip(decoded.iter())
+            .fold(nalgebra::Vector3::zeros(), |e, (p, q)| {
+                e.sup(&(p.position - q.position).abs())
+            });
+        (error, meta)
+    }
+
+    #[cfg(feature = "image")]
+    #[tokio::test]
+    async fn cubic_tile_bounds_precision() {
+        let (per_axis, _) = flat_tile_error(LODOptions::default()).await;
+        let (cubic, meta) = flat_tile_error(LODOptions {
+            cubic_tile_bounds: true,
+            ..Default::default()
+        })
+        .await;
+
+        // the cube is recorded as the bounds, with the extent of the points as the scale
+        let bounds = meta.tile_bounds(0, &(0, 0, 0)).unwrap();
+        assert!(
+            (bounds.size() - nalgebra::Vector3::repeat(9.46))
+                .abs()
+                .max()
+                < 1e-9
+        );
+        let scale = meta.tile_scale(0, &(0, 0, 0)).unwrap();
+        assert!(
+            (scale - nalgebra::Vector3::new(9.46, 9.36, 0.0942))
+                .abs()
+                .max()
+                < 1e-9
+        );
+
+        // the thin axis keeps the precision of its own extent instead of the one of the cube
+        let (thin, cube) = (0.0942 / 255., 9.46 / 255.);
+        assert!(per_axis.z <= thin);
+        assert!(cubic.z <= per_axis.z + 1e-12, "{} {}", cubic.z, per_axis.z);
+        assert!(cubic.z < cube / 10.);
+        assert!(cubic.x <= cube && cubic.y <= cube);
+    }
+
+    #[test]
+    fn explicit_global_shift_arguments() {
+        let shift = (nalgebra::Vector3::new(-500000., -4000000., 10.5), 1.);
+        let cmd = convert_command(None, "input.las", "seed.txt", true, Some(&shift));
+        let args: Vec<_> = cmd.get_args().map(|a| a.to_string_lossy()).collect();
+        let i = args.iter().position(|a| a == "-GLOBAL_SHIFT").unwrap();
+        assert_eq!(
+            args[i + 1..i + 5],
+            ["-500000", "-4000000", "10.5", "input.las"]
+        );
+
+        let cmd = convert_command(None, "input.las", "seed.txt", true, None);
+        let args: Vec<_> = cmd.get_args().map(|a| a.to_string_lossy()).collect();
+        let i = args.iter().position(|a| a == "-GLOBAL_SHIFT").unwrap();
+        assert_eq!(args[i + 1..i + 3], ["AUTO", "input.las"]);
+    }
+
+    #[cfg(feature = "image")]
+    #[tokio::test]
+    async fn parallel_encoding_matches_serial() {
+        // encoded in parallel by the build, or one by one in the callback without `LODOptions::encoder`
+        let encoder = EncoderOptions::new().intensity(true);
+        let run = |parallel: bool| {
+            let encoder = encoder.clone();
+            async move {
+                let options = LODOptions {
+                    point_count_threshold: 100,
+                    encoder: parallel.then(|| encoder.clone()),
+                    deterministic: true,
+                    ..Default::default()
+                };
+                let images = Mutex::new(HashMap::new());
+                build_lod_with_meta(
+                    uniform_points(12),
+                    |unit| {
+                        let tile = match parallel {
+                            true => unit.images.clone(),
+                            false => {
+                                assert!(unit.images.is_empty());
+                                encode_unit(&unit, &encoder).unwrap()
+                            }
+                        };
+                        images
+                            .lock()
+                            .unwrap()
+                            .insert((unit.lod, unit.x, unit.y, unit.z), tile);
+                        async { Ok(()) }
+                    },
+                    |_| async { Ok(()) },
+                    &options,
+                )
+                .await
+                .unwrap();
+                images.into_inner().unwrap()
+            }
+        };
+
+        let parallel = run(true).await;
+        let serial = run(false).await;
+        assert!(parallel.len() > 8);
+        assert_eq!(parallel.len(), serial.len());
+        for (key, images) in parallel.iter() {
+            assert!(!images.is_empty());
+            assert!(serial[key] == *images, "tile {:?} differs", key);
+        }
+    }
+
+    #[cfg(feature = "image")]
+    #[tokio::test]
+    async fn normal_tiles() {
+        // points on a unit sphere with outward normals
+        let points: Vec<Point> = (0..2000)
+            .map(|i| {
+                let (t, p) = (i as f64 * 0.1, i as f64 * 0.37);
+                let n = nalgebra::Vector3::new(t.sin() * p.cos(), t.sin() * p.sin(), t.cos());
+                let mut point = Point::from(Point3::from(n));
+                point.normal = Some(n);
+                point
+            })
+            .collect();
+        let options = LODOptions {
+            point_count_threshold: 500,
+            encoder: Some(EncoderOptions::new().normals(true)),
+            ..Default::default()
+        };
+        let (units, metas) = build_levels(points, &options).await;
+
+        assert!(metas.iter().all(|m| m.normals));
+        for unit in units.iter() {
+            let (_, bytes) = unit
+                .images
+                .iter()
+                .find(|(kind, _)| *kind == ImageKind::Normal)
+                .unwrap();
+            let image = image::load_from_memory(bytes).unwrap().to_rgba8();
+            for pix in image.pixels().take(unit.points.len()) {
+                let n = nalgebra::Vector3::new(pix[0], pix[1], pix[2]).cast::<f64>() / 255. * 2.
+                    - nalgebra::Vector3::repeat(1.);
+                assert!((n.norm() - 1.).abs() < 0.02, "{}", n.norm());
+            }
+        }
+
+        // a point without normal leaves tiles without normal image
+        let mut points = units
+            .iter()
+            .flat_map(|unit| unit.points.clone())
+            .collect::<Vec<_>>();
+        points[0].normal = None;
+        let (_, metas) = build_levels(points, &options).await;
+        assert!(metas.iter().all(|m| !m.normals));
+    }
+
+    #[tokio::test]
+    async fn lod_callback_with_level_bounds_and_coordinates() {
+        let options = LODOptions {
+            point_count_threshold: 500,
+            ..Default::default()
+        };
+        let levels = Mutex::new(vec![]);
+        build_lod(
+            uniform_points(20),
+            |_| async { Ok(()) },
+            |lod, bounds, coordinates| {
+                levels.lock().unwrap().push((lod, bounds, coordinates));
+                async { Ok(()) }
+            },
+            &options,
+        )
+        .await
+        .unwrap();
+        let (_, metas) = build_levels(uniform_points(20), &options).await;
+
+        let levels = levels.lock().unwrap();
+        assert!(levels.len() > 1 && levels.len() == metas.len());
+        for ((lod, bounds, coordinates), meta) in levels.iter().zip(metas.iter()) {
+            assert_eq!(*lod, meta.lod());
+            assert_eq!(bounds, meta.bounds());
+            assert_eq!(coordinates, meta.coordinates());
+        }
+    }
+
+    #[test]
+    fn max_points_limit() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        for i in 0..11 {
+            writeln!(file, "{} 0 0", i).unwrap();
+        }
+
+        let points = read_points_from_txt(file.path(), None, Some(11), None).unwrap();
+        assert_eq!(points.len(), 11);
+
+        let err = read_points_from_txt(file.path(), None, Some(10), None).unwrap_err();
+        assert!(err.to_string().contains("more than 10 points"));
+    }
+
+    #[test]
+    fn csv_header_is_skipped_and_bad_rows_fail() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), "x,y,z\n0,0,0\n\n1,2,3\n").unwrap();
+        let points = read_points_from_txt(file.path(), None, None, None).unwrap();
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[1].position, Point3::new(1., 2., 3.));
+
+        std::fs::write(file.path(), "0,0,0\n1,2,oops\n3,4,5\n").unwrap();
+        let err = read_points_from_txt(file.path(), None, None, None).unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+        std::fs::write(file.path(), "0,0,0\nx,y,z\n").unwrap();
+        assert!(read_points_from_txt(file.path(), None, None, None).is_err());
+    }
+
+    #[test]
+    fn columns_of_native_text() {
+        let file = tempfile::Builder::new().suffix(".csv").tempfile().unwrap();
+        std::fs::write(
+            file.path(),
+            "x,y,z,nx,ny,nz,r,g,b\n1,2,3,0,0,1,10,20,30\n4,5,6,1,0,0,40,50,60\n",
+        )
+        .unwrap();
+        let options = LoadOptions {
+            native: true,
+            columns: Some(ColumnLayout::from_header("x y z nx ny nz r g b").unwrap()),
+            ..Default::default()
+        };
+        let points = load_points(None, file.path(), &options).unwrap();
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[1].position, Point3::new(4., 5., 6.));
+        assert_eq!(points[1].normal, Some(nalgebra::Vector3::new(1., 0., 0.)));
+        assert_eq!(points[1].color, Some(Color::new(40, 50, 60)));
+
+        // the columns of the text converted by CloudCompare are not the ones of the input
+        let converted = LoadOptions {
+            native: false,
+            ..options.clone()
+        };
+        assert!(load_points(None, file.path(), &converted).is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let paths = [file.path().to_path_buf()];
+        let map =
+            ChunkedPointCloudMap::from_txt_files(&paths, options.columns.as_ref(), 1, dir.path())
+                .unwrap();
+        assert_eq!(map.point_count(), 2);
+        assert!(map.has_normals());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn text_goes_through_cloudcompare_unless_native() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.xyz");
+        std::fs::write(&input, "9 9 9\n").unwrap();
+        let exec = fake_cloudcompare(dir.path(), "printf '1 2 3\\n' > \"$1\"");
+
+        let converted = load_points(Some(&exec), &input, &LoadOptions::default()).unwrap();
+        assert_eq!(converted[0].position, Point3::new(1., 2., 3.));
+        let native = LoadOptions {
+            native: true,
+            ..Default::default()
+        };
+        let read = load_points(Some(&exec), &input, &native).unwrap();
+        assert_eq!(read[0].position, Point3::new(9., 9., 9.));
+    }
+
+    #[tokio::test]
+    async fn receive_levels_in_order() {
+        // sampled on the blocking thread of the build, or on the other blocking threads with pipeline
+        for pipeline in [false, true] {
+            let options = LODOptions {
+                point_count_threshold: 100,
+                pipeline,
+                ..Default::default()
+            };
+            let mut levels = lod_levels(uniform_points(20), options);
+            let mut received = vec![];
+            while let Some(level) = levels.recv().await {
+                received.push(level.unwrap());
+            }
+
+            assert!(received.len() > 2);
+            for (i, level) in received.iter().enumerate() {
+                assert_eq!(level.level, i as u32);
+                assert_eq!(level.meta.lod(), level.level + 1);
+                assert_eq!(
+                    level.units.len(),
+                    level.meta.coordinates()[&level.level].len()
+                );
+                assert!(level.units.iter().all(|u| u.lod == level.level));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn geometric_error_decreases_with_level() {
+        let options = LODOptions {
+            point_count_threshold: 100,
+            ..Default::default()
+        };
+        let (_, metas) = build_levels(uniform_points(20), &options).await;
+
+        let meta = metas.last().cloned().unwrap();
+        assert!(meta.lod() > 2);
+        let errors: Vec<_> = (0..meta.lod())
+            .map(|level| meta.geometric_error(level).unwrap())
+            .collect();
+        assert!(errors.windows(2).all(|w| w[1] < w[0]), "{:?}", errors);
+    }
+
+    #[tokio::test]
+    async fn huge_radius_has_low_retention() {
+        let run = |outlier: bool| async move {
+            // a far outlier spreads the coarse cells, so their radius is huge for the rest of the points
+            let mut points = uniform_points(20);
+            if outlier {
+                points.push(Point::try_parse("1000 1000 1000").unwrap());
+            }
+            let options = LODOptions {
+                point_count_threshold: 100,
+                min_retention: 0.05,
+                ..Default::default()
+            };
+            let (_, metas) = build_levels(points, &options).await;
+            let meta = metas.last().cloned().unwrap();
+            meta.retention.unwrap()
+        };
+
+        let retention = run(false).await;
+        assert!(retention.tiles > 0);
+        assert_eq!(retention.low_tiles, 0, "{:?}", retention);
+
+        let retention = run(true).await;
+        assert!(retention.low_tiles > 0);
+        assert!(retention.min < 0.05);
+        assert!(retention.min <= retention.average);
+    }
+
+    #[tokio::test]
+    async fn point_size_decreases_with_level() {
+        let options = LODOptions {
+            point_count_threshold: 100,
+            ..Default::default()
+        };
+        let (_, metas) = build_levels(uniform_points(20), &options).await;
+
+        let meta = metas.last().cloned().unwrap();
+        assert!(meta.lod() > 2);
+        let sizes: Vec<_> = (0..meta.lod())
+            .map(|level| meta.point_size(level))
+            .collect();
+        assert!(sizes.iter().all(|s| *s > 0.));
+        assert!(sizes.windows(2).all(|w| w[1] < w[0]), "{:?}", sizes);
+        assert_eq!(meta.point_size(meta.lod()), 0.);
+    }
+
+    #[cfg(feature = "image")]
+    #[tokio::test]
+    async fn max_texture_side_caps_tile_images() {
+        let options = LODOptions {
+            max_texture_side: Some(8),
+            encoder: Some(EncoderOptions::new()),
+            ..Default::default()
+        };
+        let (units, metas) = build_levels(uniform_points(12), &options).await;
+
+        assert!(metas.iter().all(|m| m.point_count_threshold == 64));
+        assert!(units.len() > 8);
+        for unit in units.iter() {
+            for (_, bytes) in unit.images.iter() {
+                let image = image::load_from_memory(bytes).unwrap();
+                assert!(image.width() <= 8 && image.height() <= 8);
+            }
+        }
+    }
+
+    #[cfg(feature = "image")]
+    #[tokio::test]
+    async fn deterministic_output() {
+        let options = LODOptions {
+            point_count_threshold: 100,
+            encoder: Some(EncoderOptions::new()),
+            deterministic: true,
+            ..Default::default()
+        };
+        let run = || async {
+            let tiles = Mutex::new(vec![]);
+            let metas = Mutex::new(vec![]);
+            build_lod_with_meta(
+                uniform_points(20),
+                |unit| {
+                    tiles
+                        .lock()
+                        .unwrap()
+                        .push(((unit.lod, unit.x, unit.y, unit.z), unit.images));
+                    async { Ok(()) }
+                },
+                |meta| {
+                    metas.lock().unwrap().push(meta);
+                    async { Ok(()) }
+                },
+                &options,
+            )
+            .await
+            .unwrap();
+            let meta = metas.lock().unwrap().pop().unwrap();
+            (
+                tiles.into_inner().unwrap(),
+                serde_json::to_string(&serde_json::to_value(meta).unwrap()).unwrap(),
+            )
+        };
+
+        let (tiles, meta) = run().await;
+        assert!(tiles.len() > 8);
+        for _ in 0..2 {
+            let (other_tiles, other_meta) = run().await;
+            assert_eq!(other_meta, meta);
+            assert_eq!(other_tiles.len(), tiles.len());
+            for ((k0, images0), (k1, images1)) in tiles.iter().zip(other_tiles.iter()) {
+                assert_eq!(k0, k1);
+                assert!(images0 == images1, "tile {:?} differs", k0);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn merge_small_tiles() {
+        // a dense cube with a few isolated points around it
+        let mut points = uniform_points(12);
+        points.extend(
+            [
+                (30., 0., 0.),
+                (0., 30., 0.),
+                (25., 25., 25.),
+                (30., 30., 1.),
+            ]
+            .map(|(x, y, z)| Point::from(Point3::new(x, y, z))),
+        );
+        let run = |min_points_per_tile: Option<usize>| {
+            let points = points.clone();
+            async move {
+                let options = LODOptions {
+                    point_count_threshold: 100,
+                    min_points_per_tile,
+                    ..Default::default()
+                };
+                let (units, _) = build_levels(points, &options).await;
+                units
+                    .iter()
+                    .map(|u| (u.lod, u.points.len()))
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        let units = run(None).await;
+        assert!(units.iter().any(|(_, n)| *n < 10));
+
+        let units = run(Some(10)).await;
+        assert!(units.iter().all(|(_, n)| *n >= 10), "{:?}", units);
+        // no point is dropped from the finest level
+        let finest = units.iter().map(|(lod, _)| *lod).max().unwrap();
+        let count: usize = units
+            .iter()
+            .filter(|(lod, _)| *lod == finest)
+            .map(|(_, n)| n)
+            .sum();
+        assert_eq!(count, points.len());
+    }
+
+    #[cfg(feature = "image")]
+    #[tokio::test]
+    async fn pipeline_matches_serial_output() {
+        let run = |pipeline: bool| async move {
+            let options = LODOptions {
+                point_count_threshold: 100,
+                encoder: Some(EncoderOptions::new()),
+                deterministic: true,
+                pipeline,
+                ..Default::default()
+            };
+            let tiles = Mutex::new(vec![]);
+            let metas = Mutex::new(vec![]);
+            build_lod_with_meta(
+                uniform_points(20),
+                |unit| {
+                    let tiles = &tiles;
+                    async move {
+                        // let the other callbacks of the level run in between
+                        tokio::task::yield_now().await;
+                        tiles
+                            .lock()
+                            .unwrap()
+                            .push(((unit.lod, unit.x, unit.y, unit.z), unit.images));
+                        Ok(())
+                    }
+                },
+                |meta| {
+                    // every unit of the level is done before its meta
+                    let level = meta.lod() - 1;
+                    let tiles = tiles.lock().unwrap();
+                    assert!(tiles.iter().all(|((lod, ..), _)| *lod <= level));
+                    assert!(tiles.iter().any(|((lod, ..), _)| *lod == level));
+                    metas
+                        .lock()
+                        .unwrap()
+                        .push(serde_json::to_string(&serde_json::to_value(meta).unwrap()).unwrap());
+                    async { Ok(()) }
+                },
+                &options,
+            )
+            .await
+            .unwrap();
+            let mut tiles = tiles.into_inner().unwrap();
+            tiles.sort_by_key(|(key, _)| *key);
+            (tiles, metas.into_inner().unwrap())
+        };
+
+        let (serial_tiles, serial_metas) = run(false).await;
+        let (tiles, metas) = run(true).await;
+        assert!(metas.len() > 2);
+        assert_eq!(metas, serial_metas);
+        assert_eq!(tiles.len(), serial_tiles.len());
+        for ((k0, images0), (k1, images1)) in tiles.iter().zip(serial_tiles.iter()) {
+            assert_eq!(k0, k1);
+            assert!(images0 == images1, "tile {:?} differs", k0);
+        }
+    }
+
+    #[test]
+    fn serial_build_without_runtime() {
+        let options = LODOptions {
+            point_count_threshold: 100,
+            ..Default::default()
+        };
+        assert!(!options.pipeline);
+        let future = build_lod_with_meta(
+            uniform_points(10),
+            |_| async { Ok(()) },
+            |_| async { Ok(()) },
+            &options,
+        );
+        // polled by hand, no tokio runtime is running
+        let mut future = std::pin::pin!(future);
+        let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+        let report = loop {
+            if let Poll::Ready(report) = future.as_mut().poll(&mut cx) {
+                break report.unwrap();
+            }
+        };
+        assert!(report.levels > 1);
+    }
+
+    #[tokio::test]
+    async fn delta_levels_store_each_point_once() {
+        let run = |delta_levels: bool, carry_index: bool| async move {
+            let options = LODOptions {
+                point_count_threshold: 100,
+                carry_index,
+                delta_levels,
+                ..Default::default()
+            };
+            let (units, metas) = build_levels(uniform_points(15), &options).await;
+            let meta = metas.last().cloned().unwrap();
+            (units, meta)
+        };
+
+        let (units, meta) = run(false, true).await;
+        assert!(!meta.delta_levels);
+        let finest = meta.lod() - 1;
+        let mut full: Vec<usize> = units
+            .iter()
+            .filter(|u| u.lod == finest)
+            .flat_map(|u| u.points.iter().map(|p| p.index.unwrap()))
+            .collect();
+        full.sort();
+
+        let (units, meta) = run(true, true).await;
+        assert!(meta.delta_levels);
+        assert_eq!(meta.lod() - 1, finest);
+        assert!(units.iter().any(|u| u.lod < finest && !u.points.is_empty()));
+        let mut union: Vec<usize> = units
+            .iter()
+            .flat_map(|u| u.points.iter().map(|p| p.index.unwrap()))
+            .collect();
+        union.sort();
+        assert_eq!(union, full);
+
+        // the indices telling the stored points apart are dropped unless asked
+        let (units, _) = run(true, false).await;
+        assert!(units
+            .iter()
+            .all(|u| u.points.iter().all(|p| p.index.is_none())));
+    }
+
+    #[tokio::test]
+    async fn report_levels_and_point_count() {
+        let points = uniform_points(20);
+        let count = points.len();
+        let metas = Mutex::new(vec![]);
+        let units = Mutex::new(vec![]);
+        let report = build_lod_with_meta(
+            points,
+            |unit| {
+                units.lock().unwrap().push(unit.lod);
+                async { Ok(()) }
+            },
+            |meta| {
+                metas.lock().unwrap().push(meta);
+                async { Ok(()) }
+            },
+            &LODOptions {
+                point_count_threshold: 100,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let meta = metas.into_inner().unwrap().pop().unwrap();
+        assert_eq!(report.point_count, count);
+        assert_eq!(report.levels, meta.lod());
+        assert!(report.levels > 1);
+        let units = units.into_inner().unwrap();
+        assert_eq!(report.tiles_per_level.len(), report.levels as usize);
+        for (level, tiles) in report.tiles_per_level.iter().enumerate() {
+            assert_eq!(*tiles, units.iter().filter(|l| **l == level as u32).count());
+        }
+        assert_eq!(report.parameters.point_count_threshold, 100);
+        assert!(report.input_files.is_empty());
+    }
+
+    #[cfg(feature = "image")]
+    #[tokio::test]
+    async fn depth_images_on_finest_level() {
+        let options = LODOptions {
+            point_count_threshold: 100,
+            encoder: Some(EncoderOptions::new().depth(Some(DepthMode::Z))),
+            ..Default::default()
+        };
+        let (units, metas) = build_levels(uniform_points(12), &options).await;
+
+        let meta = metas.last().cloned().unwrap();
+        assert!(meta.lod > 1);
+        assert_eq!(meta.depth, Some(DepthMode::Z));
+        assert_eq!(serde_json::to_value(meta.depth).unwrap(), "z");
+        assert_eq!(meta.depth_level, Some(meta.lod - 1));
+        for unit in units.iter() {
+            let has_depth = unit
+                .images
+                .iter()
+                .any(|(kind, _)| *kind == ImageKind::Depth);
+            assert_eq!(has_depth, unit.lod == meta.lod - 1);
+        }
+    }
+
+    #[cfg(feature = "image")]
+    #[tokio::test]
+    async fn detail_tiles_on_finest_level() {
+        let options = LODOptions {
+            max_texture_side: Some(16),
+            encoder: Some(EncoderOptions::new()),
+            detail_tiles: true,
+            ..Default::default()
+        };
+        let (units, metas) = build_levels(uniform_points(12), &options).await;
+
+        let meta = metas.last().cloned().unwrap();
+        assert!(meta.lod > 1);
+        assert_eq!(meta.detail_level, Some(meta.lod - 1));
+        for unit in units.iter() {
+            let side = (unit.points.len() as f64).sqrt().ceil() as u32;
+            let (_, bytes) = unit
+                .images
+                .iter()
+                .find(|(kind, _)| *kind == ImageKind::Position)
+                .unwrap();
+            let image = image::load_from_memory(bytes).unwrap();
+            let expected = if unit.lod == meta.lod - 1 {
+                side * 2
+            } else {
+                side
+            };
+            assert_eq!((image.width(), image.height()), (expected, expected));
+        }
+    }
+
+    #[cfg(feature = "image")]
+    #[tokio::test]
+    async fn progressive_order_prefix_covers_tile() {
+        let options = LODOptions {
+            progressive_order: true,
+            encoder: Some(EncoderOptions::new()),
+            ..Default::default()
+        };
+        let (units, metas) = build_levels(uniform_points(12), &options).await;
+        assert!(metas.iter().all(|m| m.progressive_order));
+
+        let unit = &units[0];
+        assert_eq!(unit.lod, 0);
+        let (_, bytes) = &unit.images[0];
+        let image = image::load_from_memory(bytes).unwrap().to_rgba8();
+        let prefix = unit.points.len() / 4;
+        let decoded = decode_8bit(&image, &unit.bounding_box, prefix);
+
+        // count the decoded points in each octant of the tile
+        let center = unit.bounding_box.center();
+        let mut octants = [0; 8];
+        for p in decoded.iter() {
+            let i = (p.x > center.x) as usize
+                | ((p.y > center.y) as usize) << 1
+                | ((p.z > center.z) as usize) << 2;
+            octants[i] += 1;
+        }
+        let expected = prefix as f64 / 8.;
+        for count in octants {
+            assert!(
+                (count as f64 - expected).abs() < expected * 0.3,
+                "{:?}",
+                octants
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn points_per_level_increase() {
+        let points = uniform_points(20);
+        let count = points.len() as u64;
+        let options = LODOptions {
+            point_count_threshold: 200,
+            ..Default::default()
+        };
+        let (_, metas) = build_levels(points, &options).await;
+
+        let meta = metas.last().cloned().unwrap();
+        let counts = meta.points_per_level();
+        assert_eq!(counts.len(), meta.lod() as usize);
+        assert!(counts[0] < count);
+        assert!(counts.windows(2).all(|w| w[0] <= w[1]), "{:?}", counts);
+        // the finest level keeps all the points
+        assert_eq!(counts.last(), Some(&count));
+    }
+
+    #[tokio::test]
+    async fn halo_includes_adjacent_points() {
+        let points = uniform_points(13);
+        let bounds = BoundingBox::from_iter(points.iter());
+        let options = LODOptions {
+            point_count_threshold: 2000,
+            halo: Some(0.1),
+            ..Default::default()
+        };
+        let (units, metas) = build_levels(points, &options).await;
+        assert!(metas.iter().all(|m| m.halo == 0.1));
+
+        let unit = units
+            .iter()
+            .find(|u| u.lod == 1 && (u.x, u.y, u.z) == (0, 0, 0))
+            .unwrap();
+        let half = bounds.min().x + bounds.max_size() / 2.;
+        let width = bounds.max_size() / 2. * 0.1;
+        let outside: Vec<_> = unit
+            .points
+            .iter()
+            .filter(|p| p.position.x >= half)
+            .collect();
+        assert!(!outside.is_empty());
+        assert!(outside.iter().all(|p| p.position.x <= half + width));
+    }
+
+    #[cfg(feature = "image")]
+    #[tokio::test]
+    async fn carry_original_index() {
+        let input = uniform_points(12);
+        let options = LODOptions {
+            point_count_threshold: 500,
+            carry_index: true,
+            encoder: Some(EncoderOptions::new().index(true)),
+            ..Default::default()
+        };
+        let (units, metas) = build_levels(input.clone(), &options).await;
+        assert!(metas.iter().all(|m| m.index));
+
+        for unit in units.iter() {
+            for p in unit.points.iter() {
+                let i = p.index.unwrap();
+                assert_eq!(input[i].position, p.position);
+            }
+            let (_, bytes) = unit
+                .images
+                .iter()
+                .find(|(kind, _)| *kind == ImageKind::Index)
+                .unwrap();
+            let image = image::load_from_memory(bytes).unwrap().to_rgba8();
+            for (p, pix) in unit.points.iter().zip(image.pixels()) {
+                assert_eq!(u32::from_le_bytes(pix.0) as usize, p.index.unwrap() + 1);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn transform_before_building() {
+        let input = uniform_points(6);
+        // rotate 90 degrees around z, scale by 2 and translate
+        let matrix = Matrix4::new(
+            0., -2., 0., 10., //
+            2., 0., 0., 20., //
+            0., 0., 2., 30., //
+            0., 0., 0., 1.,
+        );
+        let options = LODOptions {
+            transform: Some(matrix),
+            point_count_threshold: 100,
+            ..Default::default()
+        };
+        let (units, metas) = build_levels(input.clone(), &options).await;
+
+        let expected = BoundingBox::from_iter(
+            input
+                .iter()
+                .map(|p| matrix.transform_point(&p.position))
+                .collect::<Vec<_>>()
+                .iter(),
+        );
+        assert_eq!(metas.last().unwrap().bounds(), &expected);
+        assert_eq!(units[0].lod, 0);
+        assert_eq!(units[0].bounding_box, expected);
+        // x spans the scaled y extent of the input and is shifted by the translation
+        let source = BoundingBox::from_iter(input.iter());
+        assert_eq!(expected.min().x, 10. - 2. * source.max().y);
+        assert_eq!(expected.max().z, 30. + 2. * source.max().z);
+    }
+
+    #[tokio::test]
+    async fn average_color_per_tile() {
+        let input: Vec<Point> = uniform_points(12)
+            .into_iter()
+            .map(|p| {
+                let q = p.position;
+                let color = Color::new(
+                    (q.x * 20.) as u8,
+                    (q.y * 20.) as u8,
+                    255 - (q.z * 20.) as u8,
+                );
+                p.with_color(color)
+            })
+            .collect();
+        let options = LODOptions {
+            point_count_threshold: 500,
+            ..Default::default()
+        };
+        let (units, metas) = build_levels(input, &options).await;
+
+        let meta = metas.last().cloned().unwrap();
+        assert!(units.len() > 1);
+        for unit in units.iter() {
+            let sum = unit.points.iter().fold([0.; 3], |acc, p| {
+                let l = p.color.unwrap().to_linear();
+                [acc[0] + l[0], acc[1] + l[1], acc[2] + l[2]]
+            });
+            let mean = Color::from_linear(sum.map(|v| v / unit.points.len() as f64));
+            let key = (unit.x, unit.y, unit.z);
+            assert_eq!(meta.tile_average_color(unit.lod, &key), Some(mean));
+        }
+        assert_eq!(meta.tile_average_color(1, &(9, 9, 9)), None);
+    }
+
+    #[test]
+    fn partition_cube_into_octants() {
+        let points: Vec<Point> = (0..512)
+            .map(|i| {
+                Point::from(Point3::new(
+                    (i % 8) as f64,
+                    (i / 8 % 8) as f64,
+                    (i / 64) as f64,
+                ))
+            })
+            .collect();
+        let cells = partition(&points, 2);
+        let dir = tempfile::tempdir().unwrap();
+        let paths = write_partition(dir.path(), &cells, DEFAULT_TEXT_DECIMALS).unwrap();
+        assert_eq!(paths.len(), 8);
+
+        for path in paths {
+            let name = path.file_stem().unwrap().to_string_lossy().to_string();
+            let key: Vec<f64> = name.split('-').map(|v| v.parse().unwrap()).collect();
+            let read = read_points_from_txt(&path, None, None, None).unwrap();
+            assert_eq!(read.len(), 64);
+            // the octant of the 0 ~ 7 cube is split at 3.5
+            for p in read {
+                let octant = p.position.map(|v| (v > 3.5) as u8 as f64);
+                assert_eq!(
+                    octant.coords.as_slice(),
+                    key.as_slice(),
+                    "{} in {}",
+                    p.position,
+                    name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn write_points_with_decimals() {
+        let points = vec![
+            Point::try_parse("-12345.6789 0.00049 1 255 128 0").unwrap(),
+            Point::try_parse("1.5 2.25 3.125 0.5").unwrap(),
+        ];
+        let file = tempfile::NamedTempFile::new().unwrap();
+        write_points_to_txt(file.path(), &points, 3).unwrap();
+
+        let text = std::fs::read_to_string(file.path()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines,
+            vec!["-12345.679 0.000 1.000 255 128 0", "1.500 2.250 3.125 0.5"]
+        );
+        let read = read_points_from_txt(file.path(), None, None, None).unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[1].intensity, Some(0.5));
+    }
+
+    #[test]
+    fn limit_points_for_preview() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        for i in 0..1000 {
+            writeln!(file, "{} 0 0", i).unwrap();
+        }
+
+        let first =
+            read_points_from_txt(file.path(), None, None, Some(PointLimit::First(10))).unwrap();
+        let xs: Vec<f64> = first.iter().map(|p| p.position.x).collect();
+        assert_eq!(xs, (0..10).map(|i| i as f64).collect::<Vec<_>>());
+
+        // the limit applies before max points
+        let reservoir = read_points_from_txt(
+            file.path(),
+            None,
+            Some(100),
+            Some(PointLimit::Reservoir(100)),
+        )
+        .unwrap();
+        assert_eq!(reservoir.len(), 100);
+        for quarter in 0..4 {
+            let range = (quarter * 250) as f64..((quarter + 1) * 250) as f64;
+            let count = reservoir
+                .iter()
+                .filter(|p| range.contains(&p.position.x))
+                .count();
+            assert!(count > 10, "{} points in quarter {}", count, quarter);
+        }
+    }
+
+    #[tokio::test]
+    async fn multiple_input_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<String> = [(0., "a.xyz"), (100., "b.xyz")]
+            .iter()
+            .map(|(offset, name)| {
+                let path = dir.path().join(name);
+                let mut file = File::create(&path).unwrap();
+                for i in 0..500 {
+                    writeln!(
+                        file,
+                        "{} {} {}",
+                        offset + (i % 10) as f64,
+                        (i / 10) % 10,
+                        i / 100
+                    )
+                    .unwrap();
+                }
+                path.to_string_lossy().to_string()
+            })
+            .collect();
+
+        let metas = Mutex::new(vec![]);
+        process_lod_with_meta(
+            None,
+            &paths,
+            |_| async { Ok(()) },
+            |meta| {
+                metas.lock().unwrap().push(meta);
+                async { Ok(()) }
+            },
+            false,
+            &LODOptions {
+                point_count_threshold: 100,
+                native_input: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let meta = metas.lock().unwrap().pop().unwrap();
+        let bounds = meta.bounds();
+        assert_eq!(bounds.min(), &Point3::new(0., 0., 0.));
+        assert_eq!(bounds.max(), &Point3::new(109., 9., 4.));
+        assert_eq!(meta.points_per_level().last(), Some(&1000));
+
+        let options = LoadOptions {
+            native: true,
+            ..Default::default()
+        };
+        let points = load_points_from_files(
+            None,
+            &paths,
+            &LoadOptions {
+                max_points: Some(1000),
+                limit: Some(PointLimit::Reservoir(10)),
+                ..options.clone()
+            },
+        )
+        .unwrap();
+        assert_eq!(points.len(), 10);
+        // drawn from both files, not only from the first one
+        assert!(points.iter().any(|p| p.position.x < 100.));
+        assert!(points.iter().any(|p| p.position.x >= 100.));
+        // the limit of max points applies to the points kept by the reservoir
+        let kept_over = LoadOptions {
+            max_points: Some(9),
+            limit: Some(PointLimit::Reservoir(10)),
+            ..options.clone()
+        };
+        assert!(load_points_from_files(None, &paths, &kept_over).is_err());
+        let over = LoadOptions {
+            max_points: Some(999),
+            ..options.clone()
+        };
+        assert!(load_points_from_files(None, &paths, &over).is_err());
+        let shifted = LoadOptions {
+            use_global_shift: true,
+            ..options
+        };
+        assert!(load_points_from_files(None, &paths, &shifted).is_err());
+    }
+
+    #[tokio::test]
+    async fn crop_sphere_before_processing() {
+        let points = uniform_points(10);
+        let center = Point3::new(4.5, 4.5, 4.5);
+        let options = LODOptions {
+            crop_sphere: Some((center, 3.)),
+            ..Default::default()
+        };
+
+        let (units, _) = build_levels(points, &options).await;
+
+        let processed = &units[0].points;
+        assert_eq!(units[0].lod, 0);
+        assert!(!processed.is_empty());
+        assert!(processed.iter().all(|p| (p.position - center).norm() <= 3.));
+    }
+
+    /// fake CloudCompare that runs the shell script with the last argument (the output path) as `$1`
+    #[cfg(unix)]
+    fn fake_cloudcompare(dir: &Path, script: &str) -> String {
+        use std::os::unix::fs::PermissionsExt;
+        let path = dir.join("CloudCompare");
+        std::fs::write(
+            &path,
+            format!(
+                "#!/bin/sh\nfor last; do :; done\nset -- \"$last\"\n{}\n",
+                script
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn conversion_errors_tell_why() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.e57");
+        std::fs::write(&input, b"").unwrap();
+        let input = input.to_string_lossy().to_string();
+        let load = |script: &str| {
+            let exec = fake_cloudcompare(dir.path(), script);
+            load_points(Some(&exec), &input, &LoadOptions::default())
+                .unwrap_err()
+                .downcast::<ConversionError>()
+                .unwrap()
+        };
+
+        let error = load("echo 'unsupported format'; echo 'cannot open' >&2");
+        assert_eq!(
+            error,
+            ConversionError::NoOutput {
+                stdout: "unsupported format\n".to_string(),
+                stderr: "cannot open\n".to_string(),
+            }
+        );
+        assert!(error.to_string().contains("cannot open"));
+
+        let error = load(": > \"$1\"; echo 'saved'");
+        match &error {
+            ConversionError::EmptyOutput { path, stdout, .. } => {
+                assert!(path.ends_with("seed.txt"));
+                assert!(!path.exists());
+                assert_eq!(stdout, "saved\n");
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn seed_files_of_user_are_not_touched() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.e57");
+        std::fs::write(&input, b"").unwrap();
+        std::fs::write(dir.path().join("seed.txt"), "9 9 9\n").unwrap();
+        std::fs::write(dir.path().join("seed.txt_0"), "9 9 9\n8 8 8\n").unwrap();
+        let untouched = || {
+            assert_eq!(
+                std::fs::read_to_string(dir.path().join("seed.txt")).unwrap(),
+                "9 9 9\n"
+            );
+            assert!(dir.path().join("seed.txt_0").exists());
+        };
+
+        // the conversion writes `seed.txt_0` in its own folder, the files next to the input are not read
+        let exec = fake_cloudcompare(dir.path(), "printf '1 2 3\\n4 5 6\\n' > \"${1}_0\"");
+        let points = load_points(Some(&exec), &input, &LoadOptions::default()).unwrap();
+        let positions: Vec<_> = points.iter().map(|p| p.position).collect();
+        assert_eq!(
+            positions,
+            vec![Point3::new(1., 2., 3.), Point3::new(4., 5., 6.)]
+        );
+        untouched();
+
+        // the conversion fails, the files next to the input are not read as its output
+        let exec = fake_cloudcompare(dir.path(), "echo 'crashed'");
+        let error = load_points(Some(&exec), &input, &LoadOptions::default()).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ConversionError>(),
+            Some(ConversionError::NoOutput { .. })
+        ));
+        untouched();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn seed_files_with_any_suffix_are_read() {
+        let dir = tempfile::tempdir().unwrap();
+        // an input of the name of the output, converted with CloudCompare for the global shift
+        let input = dir.path().join("seed.txt");
+        std::fs::write(&input, "9 9 9\n").unwrap();
+        for name in ["seed_0.txt", "seed.txt_1"] {
+            std::fs::write(dir.path().join(name), "8 8 8\n").unwrap();
+        }
+        let mut files: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        files.sort();
+
+        // the clouds written with the suffixes of several versions, read in the order of the suffixes
+        let exec = fake_cloudcompare(
+            dir.path(),
+            "d=$(dirname \"$1\"); \
+             printf '1 0 0\\n' > \"$d/seed_0.txt\"; \
+             printf '2 0 0\\n' > \"${1}_1\"; \
+             printf '0 0 0\\n' > \"${1}_0\"; \
+             printf '3 0 0\\n4 0 0\\n' > \"$d/seed_10.txt\"",
+        );
+        let shifted = LoadOptions {
+            use_global_shift: true,
+            native: true,
+            ..Default::default()
+        };
+        let points = load_points(Some(&exec), &input, &shifted).unwrap();
+        let xs: Vec<f64> = points.iter().map(|p| p.position.x).collect();
+        assert_eq!(xs, vec![0., 1., 2., 3., 4.]);
+
+        // the limit applies to the points of all the files
+        let limited = LoadOptions {
+            limit: Some(PointLimit::First(3)),
+            ..shifted.clone()
+        };
+        let points = load_points(Some(&exec), &input, &limited).unwrap();
+        assert_eq!(points.len(), 3);
+        let over = LoadOptions {
+            max_points: Some(4),
+            ..shifted
+        };
+        assert!(load_points(Some(&exec), &input, &over).is_err());
+
+        // the input and the files next to it are left as they are
+        let mut after: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        after.retain(|name| name != "CloudCompare");
+        after.sort();
+        assert_eq!(after, files);
+        assert_eq!(std::fs::read_to_string(&input).unwrap(), "9 9 9\n");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn metas_tell_explicit_global_shift() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.e57");
+        std::fs::write(&input, b"").unwrap();
+        let exec = fake_cloudcompare(dir.path(), "printf '1 2 3\\n4 5 6\\n' > \"$1\"");
+        let shift = (nalgebra::Vector3::new(-100., -200., 0.), 1.);
+        for use_global_shift in [true, false] {
+            let metas = Mutex::new(vec![]);
+            let report = process_lod_with_meta(
+                Some(&exec),
+                &[&input],
+                |_| async { Ok(()) },
+                |meta| {
+                    metas.lock().unwrap().push(meta);
+                    async { Ok(()) }
+                },
+                use_global_shift,
+                &LODOptions {
+                    global_shift: Some(shift),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            let expected = use_global_shift.then_some(shift);
+            assert_eq!(report.global_shift, expected);
+            let metas = metas.into_inner().unwrap();
+            assert!(!metas.is_empty());
+            assert!(metas
+                .iter()
+                .all(|meta| meta.global_shift() == expected.as_ref()));
+        }
+    }
+
+    #[tokio::test]
+    async fn input_path_with_spaces_and_non_ascii() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().join("point clouds");
+        std::fs::create_dir(&dir).unwrap();
+        let input = dir.join("点群 scan 1.txt");
+        std::fs::write(&input, "0 0 0\n1 2 3\n4 5 6\n").unwrap();
+
+        let count = Mutex::new(0);
+        let report = process_lod_with_meta(
+            None,
+            &[&input],
+            |unit| {
+                *count.lock().unwrap() += unit.points.len();
+                async { Ok(()) }
+            },
+            |_| async { Ok(()) },
+            false,
+            &LODOptions {
+                native_input: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(*count.lock().unwrap(), 3);
+        assert_eq!(
+            report.input_files,
+            vec![input.to_string_lossy().to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn process_with_custom_sampler() {
+        /// keeps every point
+        struct Identity;
+        impl Sampler for Identity {
+            fn sample(&self, points: &[Point], _radius: f64) -> anyhow::Result<Vec<Point>> {
+                Ok(points.to_vec())
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("grid.xyz");
+        let lines: Vec<String> = (0..1000)
+            .map(|i| format!("{} {} {}", i % 10, (i / 10) % 10, i / 100))
+            .collect();
+        std::fs::write(&input, lines.join("\n")).unwrap();
+
+        let metas = Mutex::new(vec![]);
+        let options = LODOptions {
+            point_count_threshold: 100,
+            native_input: true,
+            sampler: Some(Arc::new(Identity)),
+            ..Default::default()
+        };
+        process_lod_with_meta(
+            None,
+            &[&input],
+            |_| async { Ok(()) },
+            |meta| {
+                metas.lock().unwrap().push(meta);
+                async { Ok(()) }
+            },
+            false,
+            &options,
+        )
+        .await
+        .unwrap();
+        let meta = metas.into_inner().unwrap().pop().unwrap();
+        assert!(meta.lod() > 1);
+        assert!(meta.points_per_level().iter().all(|count| *count == 1000));
+
+        // the sampler can not shrink the tiles to the texture
+        let limited = LODOptions {
+            max_texture_side: Some(8),
+            ..options.clone()
+        };
+        let process = |options: LODOptions| {
+            let input = input.clone();
+            async move {
+                process_lod_with_meta(
+                    None,
+                    &[&input],
+                    |_| async { Ok(()) },
+                    |_| async { Ok(()) },
+                    false,
+                    &options,
+                )
+                .await
+            }
+        };
+        assert!(process(limited).await.is_err());
+        let delta = LODOptions {
+            delta_levels: true,
+            ..options
+        };
+        assert!(process(delta).await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cloudcompare_gets_paths_with_spaces_as_is() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().join("scans of 2024");
+        std::fs::create_dir(&dir).unwrap();
+        let exec = fake_cloudcompare(&dir, "printf '0 0 0\\n1 2 3\\n' > \"$1\"");
+        let load = |input: &Path| {
+            std::fs::write(input, b"").unwrap();
+            load_points(Some(&exec), input, &LoadOptions::default()).unwrap()
+        };
+
+        assert_eq!(load(&dir.join("site é 1.e57")).len(), 2);
+        // file names on Linux are any bytes
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let name = OsStr::from_bytes(b"site \xff.e57");
+            assert_eq!(load(&dir.join(name)).len(), 2);
+        }
+        assert!(!dir.join("seed.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn anisotropic_records_cell_divisions() {
+        let points: Vec<Point> = (0..20000)
+            .map(|i| {
+                let t = i as f64 / 19999.;
+                Point::from(Point3::new(t * 100., (t * 37.).fract(), (t * 53.).fract()))
+            })
+            .collect();
+        let options = LODOptions {
+            point_count_threshold: 1000,
+            anisotropic: true,
+            ..Default::default()
+        };
+        let (units, metas) = build_levels(points, &options).await;
+        let meta = metas.last().cloned().unwrap();
+        assert!(meta.lod() > 1);
+        assert_eq!(meta.cell_divisions.len() as u32, meta.lod());
+        for level in 0..meta.lod() {
+            assert_eq!(meta.divisions(level), [2_usize.pow(level), 1, 1]);
+        }
+        assert!(units.iter().all(|u| u.y == 0 && u.z == 0));
+    }
+
+    #[tokio::test]
+    async fn level_bounds_within_root_bounds() {
+        let points: Vec<Point> = (0..20000)
+            .map(|i| {
+                let t = i as f64 / 19999.;
+                Point::from(Point3::new(
+                    (t * 17.).fract() * 10.,
+                    (t * 31.).fract() * 4.,
+                    t * 2.,
+                ))
+            })
+            .collect();
+        let options = LODOptions {
+            point_count_threshold: 1000,
+            ..Default::default()
+        };
+        let (_, metas) = build_levels(points, &options).await;
+        let mut meta = metas.last().cloned().unwrap();
+        assert!(meta.lod() > 1);
+        let root = meta.bounds().clone();
+        let levels: Vec<_> = (0..meta.lod())
+            .map(|level| meta.level_bounds(level).unwrap())
+            .collect();
+        for bounds in levels.iter() {
+            assert!(root.contains(bounds.min()) && root.contains(bounds.max()));
+        }
+        assert!(meta.level_bounds(meta.lod()).is_none());
+
+        // computed from the tiles for the meta written without level bounds
+        meta.level_bounds.clear();
+        for (level, bounds) in levels.iter().enumerate() {
+            assert_eq!(meta.level_bounds(level as u32).as_ref(), Some(bounds));
+        }
+    }
+
+    #[tokio::test]
+    async fn chunked_build_matches_in_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("points.txt");
+        write_points_to_txt(&path, &uniform_points(20), 6).unwrap();
+        let points = read_points_from_txt(&path, None, None, None).unwrap();
+        let options = LODOptions {
+            point_count_threshold: 100,
+            native_input: true,
+            deterministic: true,
+            chunk_size: Some(1000),
+            ..Default::default()
+        };
+
+        type Tiles = HashMap<(u32, i32, i32, i32), Vec<[u64; 3]>>;
+        let tiles = Mutex::new(Tiles::new());
+        let metas = Mutex::new(vec![]);
+        let on_unit = |unit: LODUnit| {
+            let mut positions: Vec<[u64; 3]> = unit
+                .points
+                .iter()
+                .map(|p| [p.position.x, p.position.y, p.position.z].map(f64::to_bits))
+                .collect();
+            positions.sort();
+            tiles
+                .lock()
+                .unwrap()
+                .insert((unit.lod, unit.x, unit.y, unit.z), positions);
+            async { Ok(()) }
+        };
+        let on_lod = |meta: Meta| {
+            metas.lock().unwrap().push(meta);
+            async { Ok(()) }
+        };
+        let report = process_lod_with_meta(None, &[&path], on_unit, on_lod, false, &options)
+            .await
+            .unwrap();
+        assert_eq!(report.point_count, points.len());
+        let chunked = std::mem::take(&mut *tiles.lock().unwrap());
+        let chunked_metas = std::mem::take(&mut *metas.lock().unwrap());
+
+        build_lod_with_meta(points, on_unit, on_lod, &options)
+            .await
+            .unwrap();
+        let in_memory = tiles.into_inner().unwrap();
+        let in_memory_metas = metas.into_inner().unwrap();
+
+        // 8000 points in the cells of level 2 of about 1000 points, sampled the same from there
+        let levels: Vec<u32> = chunked_metas.iter().map(|meta| meta.lod()).collect();
+        assert_eq!(
+            levels,
+            in_memory_metas.iter().map(|m| m.lod()).collect::<Vec<_>>()
+        );
+        assert!(levels.len() > 3);
+        let mut keys: Vec<_> = chunked.keys().copied().collect();
+        keys.sort();
+        let mut expected: Vec<_> = in_memory.keys().copied().collect();
+        expected.sort();
+        assert_eq!(keys, expected);
+        for key in keys.iter().filter(|(lod, ..)| *lod >= 2) {
+            assert_eq!(chunked[key], in_memory[key], "tile {:?} differs", key);
+        }
+        let (chunked_meta, meta) = (
+            chunked_metas.last().unwrap(),
+            in_memory_metas.last().unwrap(),
+        );
+        assert_eq!(chunked_meta.bounds(), meta.bounds());
+        assert_eq!(chunked_meta.geometric_error, meta.geometric_error);
+
+        let error = build_lod_chunked(
+            &ChunkedPointCloudMap::from_txt(&path, 1, 1000, dir.path()).unwrap(),
+            |_| async { Ok(()) },
+            |_| async { Ok(()) },
+            &LODOptions {
+                delta_levels: true,
+                ..options.clone()
+            },
+        )
+        .await;
+        assert!(error.is_err());
     }
 }
@@ -0,0 +1,18 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{prelude::BoundingBox, LODKey};
+
+/// Tile of a level flattened from `Coordinates` (see `Meta::to_lods`)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LOD {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+    pub bounds: BoundingBox,
+}
+
+impl LOD {
+    pub fn key(&self) -> LODKey {
+        (self.x, self.y, self.z)
+    }
+}
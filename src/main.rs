@@ -1,100 +1,569 @@
 use anyhow::ensure;
-use clap::Parser;
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand, ValueEnum};
+use image::codecs::png::{CompressionType, FilterType};
+use nalgebra::{Matrix4, Vector3};
 
-use image::DynamicImage;
 use pcd_lod::{
-    detect_cloudcompare_exists,
-    prelude::{Encoder, Meta},
-    process_lod, LODUnit,
+    detect_cloudcompare_exists, is_native_format, load_points,
+    prelude::{
+        partition, write_unit, Color, ColumnLayout, DepthMode, EncoderOptions, FileSystemSink,
+        Meta, PointCloud, TileSink,
+    },
+    process_lod_with_meta, write_partition, LODOptions, LODUnit, LoadOptions, PointLimit,
+    DEFAULT_MIN_RETENTION, DEFAULT_TEXT_DECIMALS,
 };
 
 use std::{
-    convert::From,
-    fs::{canonicalize, create_dir, File},
-    io::Write,
+    fs::{canonicalize, create_dir_all, read_dir, remove_dir_all, remove_file},
+    path::{Path, PathBuf},
 };
 
 /// Command line arguments
 #[derive(Parser)]
-#[clap(author, version, about)]
+#[clap(author, version, about, args_conflicts_with_subcommands = true)]
 struct Args {
-    /// point cloud file name of the point cloud to be input (.txt, .csv, .las, .xyz, .e57 supported)
-    #[clap(short = 'i', long)]
-    input_file: String,
+    #[clap(subcommand)]
+    command: Option<Commands>,
+
+    /// arguments to build LOD, required unless a subcommand is given
+    #[clap(flatten)]
+    build: Option<BuildArgs>,
+}
+
+/// Arguments to build LOD of the input files
+#[derive(clap::Args)]
+struct BuildArgs {
+    /// point cloud file names of the point clouds to be input as one scene (.txt, .csv, .las, .xyz, .e57 supported)
+    #[clap(short = 'i', long, required = true, num_args = 1..)]
+    input_file: Vec<PathBuf>,
 
     /// folder name to be output
     #[clap(short = 'o', long)]
-    output_directory: String,
+    output_directory: PathBuf,
+
+    /// overwrite the previous output (level folders, `meta.json`, `index.json` and `report.json`) in the output folder
+    #[clap(long)]
+    force: bool,
 
     /// apply global shift or not (0: no, 1: yes)
     #[clap(long, default_value_t = 0)]
     global_shift: u8,
 
+    /// (Optional) explicit global shift used instead of the automatic one when global shift is applied
+    #[clap(long, num_args = 3, value_names = ["X", "Y", "Z"], allow_negative_numbers = true)]
+    shift: Option<Vec<f64>>,
+
+    /// scale used with the explicit global shift
+    #[clap(long, default_value_t = 1.0)]
+    scale: f64,
+
     /// (Optional) execute path to CloudCompare
     #[clap(long)]
     cloud_compare_path: Option<String>,
+
+    /// read text, PCD and PLY files without CloudCompare (unless global shift is applied)
+    #[clap(long)]
+    native: bool,
+
+    /// (Optional) names of the columns of the text files read with --native (x y z r g b i nx ny nz class gps_time, others ignored),
+    /// `x y z [r g b] [intensity]` by default
+    #[clap(long, num_args = 3.., value_name = "NAME", requires = "native")]
+    columns: Option<Vec<String>>,
+
+    /// (Optional) 4x4 affine matrix (row-major) applied to the points right after loading, e.g. an alignment from registration
+    #[clap(long, num_args = 16, value_name = "M", allow_negative_numbers = true)]
+    transform: Option<Vec<f64>>,
+
+    /// (Optional) maximum number of points to be processed, fails if the input has more
+    #[clap(long)]
+    max_points: Option<usize>,
+
+    /// (Optional) read only the first N points for a quick preview
+    #[clap(long)]
+    limit: Option<usize>,
+
+    /// draw the `--limit` points uniformly from the whole input instead of the first ones
+    #[clap(long, requires = "limit")]
+    reservoir: bool,
+
+    /// record the bounding box of each tile as a cube (the positions keep the precision of each axis with `tile_scales`)
+    #[clap(long)]
+    cubic_tile_bounds: bool,
+
+    /// write normal images (`x-y-z-normal.png`) if the input points have normals
+    #[clap(long)]
+    normals: bool,
+
+    /// store intensity in the alpha channel of the position images (recorded in meta.json)
+    #[clap(long)]
+    intensity_in_position_alpha: bool,
+
+    /// write the bounds of each tile (`x-y-z.json`) to recover the meta data from the tiles
+    #[clap(long)]
+    bounds_sidecar: bool,
+
+    /// (Optional) write the points of each tile as a Draco point cloud (`x-y-z.drc`) with the positions quantized to BITS (1 ~ 30)
+    #[cfg(feature = "draco")]
+    #[clap(long, value_name = "BITS")]
+    draco: Option<u8>,
+
+    /// pack the tiles of each level into a single atlas image (`atlas.png`) with the sub-rectangles in `atlas.json`
+    #[clap(long)]
+    atlas: bool,
+
+    /// order the points in each tile so that any prefix of the image is a uniform subsample
+    #[clap(long)]
+    progressive_order: bool,
+
+    /// (Optional) color of the points without color (white by default)
+    #[clap(long, num_args = 3, value_names = ["R", "G", "B"])]
+    default_color: Option<Vec<u8>>,
+
+    /// (Optional) color the points by GPS time from MIN (blue) to MAX (red) instead of their colors (recorded in meta.json)
+    #[clap(long, num_args = 2, value_names = ["MIN", "MAX"])]
+    color_by_time: Option<Vec<f64>>,
+
+    /// (Optional) gamma applied to the channels of the color images (recorded in meta.json to invert it)
+    #[clap(long)]
+    gamma: Option<f64>,
+
+    /// (Optional) include the points of the adjacent cells within this ratio of the cell size into each tile
+    #[clap(long)]
+    halo: Option<f64>,
+
+    /// (Optional) fold the tiles with fewer points into the nearest tile of the same level
+    #[clap(long)]
+    min_points_per_tile: Option<usize>,
+
+    /// store in each level only the points not stored in the coarser levels (not with --halo)
+    #[clap(long)]
+    delta_levels: bool,
+
+    /// divide each axis of the octree independently so that a long and thin cloud is divided along its long axes only
+    #[clap(long)]
+    anisotropic: bool,
+
+    /// (Optional) divide the tiles under the point count threshold while the color variance of their points is over this (0 ~ 0.75),
+    /// so that the regions of color detail get deeper levels than the flat ones
+    #[clap(long)]
+    color_variance: Option<f64>,
+
+    /// write the index of each point in the input (`x-y-z-index.png`) to trace the points back
+    #[clap(long)]
+    carry_index: bool,
+
+    /// set the color of each sample to the dominant color of the points it represents
+    #[clap(long)]
+    dominant_color: bool,
+
+    /// quad encode the position images of the finest level for twice the precision
+    #[clap(long)]
+    detail_tiles: bool,
+
+    /// warn about the sampled tiles keeping less than this fraction of the points they could hold
+    #[clap(long, default_value_t = DEFAULT_MIN_RETENTION)]
+    min_retention: f64,
+
+    /// (Optional) write depth images (`x-y-z-depth.png`) of the finest level along the axis or from the tile center
+    #[clap(long, value_enum)]
+    depth: Option<Depth>,
+
+    /// seed the sampling and emit the tiles sorted, so that the same input gives byte-identical output
+    #[clap(long)]
+    deterministic: bool,
+
+    /// (Optional) build out of core, holding about this number of input points in memory at once
+    /// (text files are read natively with --native, the others converted to text with CloudCompare)
+    #[clap(long, value_name = "POINTS")]
+    chunk_size: Option<usize>,
+
+    /// (Optional) maximum side of the tile images, lowers the point count threshold to fit
+    #[clap(long)]
+    max_texture_side: Option<u32>,
+
+    /// compression level of the PNG images
+    #[clap(long, value_enum, default_value_t = PngCompression::Fast)]
+    png_compression: PngCompression,
+
+    /// PNG filter of the position images
+    #[clap(long, value_enum, default_value_t = PngFilter::Adaptive)]
+    position_png_filter: PngFilter,
+
+    /// PNG filter of the color images
+    #[clap(long, value_enum, default_value_t = PngFilter::Adaptive)]
+    color_png_filter: PngFilter,
+}
+
+/// depth of the points in the depth images
+#[derive(Clone, Copy, ValueEnum)]
+enum Depth {
+    X,
+    Y,
+    Z,
+    Center,
+}
+
+impl From<Depth> for DepthMode {
+    fn from(value: Depth) -> Self {
+        match value {
+            Depth::X => DepthMode::X,
+            Depth::Y => DepthMode::Y,
+            Depth::Z => DepthMode::Z,
+            Depth::Center => DepthMode::Center,
+        }
+    }
+}
+
+/// PNG compression level
+#[derive(Clone, Copy, ValueEnum)]
+enum PngCompression {
+    Fast,
+    Default,
+    Best,
+}
+
+impl From<PngCompression> for CompressionType {
+    fn from(value: PngCompression) -> Self {
+        match value {
+            PngCompression::Fast => CompressionType::Fast,
+            PngCompression::Default => CompressionType::Default,
+            PngCompression::Best => CompressionType::Best,
+        }
+    }
+}
+
+/// PNG filter
+#[derive(Clone, Copy, ValueEnum)]
+enum PngFilter {
+    None,
+    Sub,
+    Up,
+    Avg,
+    Paeth,
+    Adaptive,
+}
+
+impl From<PngFilter> for FilterType {
+    fn from(value: PngFilter) -> Self {
+        match value {
+            PngFilter::None => FilterType::NoFilter,
+            PngFilter::Sub => FilterType::Sub,
+            PngFilter::Up => FilterType::Up,
+            PngFilter::Avg => FilterType::Avg,
+            PngFilter::Paeth => FilterType::Paeth,
+            PngFilter::Adaptive => FilterType::Adaptive,
+        }
+    }
+}
+
+/// Subcommands
+#[derive(Subcommand)]
+enum Commands {
+    /// print statistics of the input point cloud without building LOD
+    Stats {
+        /// point cloud file name of the point cloud to be input (.txt, .csv, .las, .xyz, .e57 supported)
+        #[clap(short = 'i', long)]
+        input_file: PathBuf,
+
+        /// apply global shift or not (0: no, 1: yes)
+        #[clap(long, default_value_t = 0)]
+        global_shift: u8,
+
+        /// (Optional) execute path to CloudCompare
+        #[clap(long)]
+        cloud_compare_path: Option<String>,
+
+        /// read text, PCD and PLY files without CloudCompare (unless global shift is applied)
+        #[clap(long)]
+        native: bool,
+    },
+    /// bin the input points into a grid and write one text file (`x-y-z.txt`) per occupied cell
+    Partition {
+        /// point cloud file name of the point cloud to be input (.txt, .csv, .las, .xyz, .e57 supported)
+        #[clap(short = 'i', long)]
+        input_file: PathBuf,
+
+        /// folder name to be output
+        #[clap(short = 'o', long)]
+        output_directory: PathBuf,
+
+        /// number of cells along each axis of the cubic bounds of the input
+        #[clap(long, default_value_t = 2)]
+        grid_size: usize,
+
+        /// apply global shift or not (0: no, 1: yes)
+        #[clap(long, default_value_t = 0)]
+        global_shift: u8,
+
+        /// (Optional) execute path to CloudCompare
+        #[clap(long)]
+        cloud_compare_path: Option<String>,
+
+        /// read text, PCD and PLY files without CloudCompare (unless global shift is applied)
+        #[clap(long)]
+        native: bool,
+    },
+}
+
+/// ensure CloudCompare is available if the input is not read natively
+fn ensure_cloudcompare(
+    exec_path: Option<&String>,
+    input_file: &Path,
+    options: &LoadOptions,
+) -> anyhow::Result<()> {
+    if !options.native || options.use_global_shift || !is_native_format(input_file) {
+        ensure!(
+            detect_cloudcompare_exists(exec_path).is_ok(),
+            "CloudCompare is not installed!"
+        );
+    }
+    Ok(())
+}
+
+/// Refuse to write into a non-empty output directory unless `force`,
+/// in which case the level directories, `meta.json`, `index.json` and `report.json` of the previous output are removed
+/// so that no stale tile is left
+fn prepare_output_directory(path: &Path, force: bool) -> anyhow::Result<()> {
+    let entries = read_dir(path)?.collect::<Result<Vec<_>, _>>()?;
+    if entries.is_empty() {
+        return Ok(());
+    }
+    ensure!(
+        force,
+        "Output directory {:?} is not empty! Pass --force to overwrite the previous output.",
+        path
+    );
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if entry.path().is_dir() && name.parse::<u32>().is_ok() {
+            remove_dir_all(entry.path())?;
+        } else if ["meta.json", "index.json", "report.json"]
+            .iter()
+            .any(|file| name == *file || name == format!("{}.tmp", file))
+        {
+            remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Handler for `stats` subcommand
+fn stats(
+    input_file: &Path,
+    options: &LoadOptions,
+    exec_path: Option<&String>,
+) -> anyhow::Result<()> {
+    ensure_cloudcompare(exec_path, input_file, options)?;
+
+    let points = load_points(exec_path, input_file, options)?;
+    let stats = PointCloud::new(points).statistics();
+    let (min, max) = (stats.bounds.min(), stats.bounds.max());
+
+    println!("points: {}", stats.count);
+    println!("bounds min: {} {} {}", min.x, min.y, min.z);
+    println!("bounds max: {} {} {}", max.x, max.y, max.z);
+    println!("has color: {}", stats.has_color);
+    match stats.intensity_range {
+        Some((lo, hi)) => println!("intensity: {} ~ {}", lo, hi),
+        None => println!("intensity: none"),
+    }
+    match stats.time_range {
+        Some((lo, hi)) => println!("gps time: {} ~ {}", lo, hi),
+        None => println!("gps time: none"),
+    }
+    match stats.density {
+        Some(density) => println!("density: {} points per unit volume", density),
+        None => println!("density: none"),
+    }
+
+    Ok(())
+}
+
+/// Handler for `partition` subcommand
+fn partition_file(
+    input_file: &Path,
+    output_directory: &Path,
+    grid_size: usize,
+    options: &LoadOptions,
+    exec_path: Option<&String>,
+) -> anyhow::Result<()> {
+    ensure!(grid_size > 0, "Grid size must be positive");
+    ensure_cloudcompare(exec_path, input_file, options)?;
+
+    let points = load_points(exec_path, input_file, options)?;
+    let cells = partition(&points, grid_size);
+    create_dir_all(output_directory)?;
+    let paths = write_partition(output_directory, &cells, DEFAULT_TEXT_DECIMALS)?;
+    println!("{} points in {} cells", points.len(), paths.len());
+
+    Ok(())
 }
 
 /// Main handler for CLI
 async fn handler() -> anyhow::Result<()> {
     let args: Args = Args::parse();
-    let input_file = &args.input_file;
-    let output_directory = &args.output_directory;
+
+    let args = match args.command {
+        Some(Commands::Stats {
+            input_file,
+            global_shift,
+            cloud_compare_path,
+            native,
+        }) => {
+            let options = LoadOptions {
+                use_global_shift: global_shift == 1,
+                native,
+                ..Default::default()
+            };
+            return stats(&input_file, &options, cloud_compare_path.as_ref());
+        }
+        Some(Commands::Partition {
+            input_file,
+            output_directory,
+            grid_size,
+            global_shift,
+            cloud_compare_path,
+            native,
+        }) => {
+            let options = LoadOptions {
+                use_global_shift: global_shift == 1,
+                native,
+                ..Default::default()
+            };
+            return partition_file(
+                &input_file,
+                &output_directory,
+                grid_size,
+                &options,
+                cloud_compare_path.as_ref(),
+            );
+        }
+        None => match args.build {
+            Some(build) => build,
+            None => Args::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "the input files and the output directory are required",
+                )
+                .exit(),
+        },
+    };
+    let (input_files, output_directory) = (&args.input_file, &args.output_directory);
     let use_global_shift = args.global_shift == 1;
     let exec_path = args.cloud_compare_path.as_ref();
+    let global_shift = args
+        .shift
+        .as_ref()
+        .map(|shift| (Vector3::new(shift[0], shift[1], shift[2]), args.scale));
 
-    ensure!(
-        detect_cloudcompare_exists(exec_path).is_ok(),
-        "CloudCompare is not installed!"
-    );
+    let columns = args
+        .columns
+        .as_ref()
+        .map(|names| ColumnLayout::from_header(&names.join(" ")))
+        .transpose()?;
+    let load_options = LoadOptions {
+        use_global_shift,
+        native: args.native,
+        ..Default::default()
+    };
+    for input_file in input_files {
+        ensure_cloudcompare(exec_path, input_file, &load_options)?;
+    }
+
+    if let Some(gamma) = args.gamma {
+        ensure!(
+            gamma.is_finite() && gamma > 0.,
+            "Gamma must be a positive number"
+        );
+    }
+
+    if let Some(range) = &args.color_by_time {
+        ensure!(
+            range.iter().all(|t| t.is_finite()) && range[0] <= range[1],
+            "Time range of --color-by-time must be MIN <= MAX"
+        );
+    }
+
+    if let Some(variance) = args.color_variance {
+        ensure!(
+            variance.is_finite() && variance >= 0.,
+            "Color variance must be a non-negative number"
+        );
+    }
 
     let output_path = canonicalize(output_directory)?;
     ensure!(output_path.is_dir(), "Output path must be directory");
-    let output_path = &output_path;
-
-    let per_unit = |unit: LODUnit| async move {
-        let LODUnit {
-            lod,
-            bounding_box: bbox,
-            points: pts,
-            x,
-            y,
-            z,
-        } = unit;
-        let encoder = Encoder::new(&pts, Some(bbox));
-        // let img = encoder.encode_8bit_quad();
-        // let img = DynamicImage::from(img);
-        // let _ = img.save_with_format(&out_file_path, image::ImageFormat::WebP);
-        // let _ = img.save_with_format(out_file_path, image::ImageFormat::Png);
-
-        let mut path = output_path.clone();
-        path.push(lod.to_string());
-        let _ = create_dir(&path);
-
-        let mut position_image_path = path.clone();
-        position_image_path.push(format!("{}-{}-{}.png", x, y, z));
-        let mut color_image_path = path.clone();
-        color_image_path.push(format!("{}-{}-{}-color.png", x, y, z));
-        let (position, color) = encoder.encode_8bit();
-        let _ = DynamicImage::from(position)
-            .save_with_format(&position_image_path, image::ImageFormat::Png);
-        let _ =
-            DynamicImage::from(color).save_with_format(&color_image_path, image::ImageFormat::Png);
-
-        Ok(())
-    };
-    let per_lod = |lod, bounds, coordinates| async move {
-        let meta = Meta::new(lod, bounds, coordinates);
-        let json = serde_json::to_string(&meta).unwrap();
+    prepare_output_directory(&output_path, args.force)?;
+    let default_color = args
+        .default_color
+        .as_ref()
+        .map(|c| Color::new(c[0], c[1], c[2]))
+        .unwrap_or(Color::white());
+    let encoder_options = &EncoderOptions::new()
+        .normals(args.normals)
+        .intensity_in_position_alpha(args.intensity_in_position_alpha)
+        .index(args.carry_index)
+        .depth(args.depth.map(DepthMode::from))
+        .default_color(default_color)
+        .color_by_time(args.color_by_time.as_ref().map(|t| (t[0], t[1])))
+        .gamma(args.gamma)
+        .png_compression(args.png_compression.into())
+        .position_png_filter(args.position_png_filter.into())
+        .color_png_filter(args.color_png_filter.into());
 
-        let mut meta_file_path = output_path.clone();
-        meta_file_path.push("meta.json");
-        let mut f = File::create(meta_file_path).unwrap();
-        f.write_all(json.as_bytes()).unwrap();
-
-        Ok(())
+    let sink = FileSystemSink::new(output_path)
+        .bounds_sidecar(args.bounds_sidecar)
+        .atlas(args.atlas);
+    #[cfg(feature = "draco")]
+    let sink = {
+        if let Some(bits) = args.draco {
+            ensure!(
+                (1..=30).contains(&bits),
+                "Draco quantization bits must be 1 ~ 30"
+            );
+        }
+        sink.draco(args.draco)
     };
-    process_lod(exec_path, input_file, per_unit, per_lod, use_global_shift).await?;
+    let sink = &sink;
+    let per_unit = |unit: LODUnit| async move { write_unit(sink, &unit, encoder_options) };
+    let per_lod = |meta: Meta| async move { sink.write_meta(&meta) };
+    let report = process_lod_with_meta(
+        exec_path,
+        input_files,
+        per_unit,
+        per_lod,
+        use_global_shift,
+        &LODOptions {
+            max_points: args.max_points,
+            native_input: args.native,
+            columns,
+            limit: args.limit.map(|n| match args.reservoir {
+                true => PointLimit::Reservoir(n),
+                false => PointLimit::First(n),
+            }),
+            transform: args.transform.as_deref().map(Matrix4::from_row_slice),
+            cubic_tile_bounds: args.cubic_tile_bounds,
+            max_texture_side: args.max_texture_side,
+            progressive_order: args.progressive_order,
+            halo: args.halo,
+            min_points_per_tile: args.min_points_per_tile,
+            delta_levels: args.delta_levels,
+            anisotropic: args.anisotropic,
+            color_variance: args.color_variance,
+            carry_index: args.carry_index,
+            dominant_color: args.dominant_color,
+            detail_tiles: args.detail_tiles,
+            min_retention: args.min_retention,
+            deterministic: args.deterministic,
+            pipeline: true,
+            chunk_size: args.chunk_size,
+            global_shift,
+            encoder: Some(encoder_options.clone()),
+            ..Default::default()
+        },
+    )
+    .await?;
+    sink.write_report(&report)?;
 
     Ok(())
 }
@@ -1,12 +1,19 @@
 use std::collections::HashMap;
 
+use nalgebra::{Point3, Vector3};
 use serde::{Deserialize, Serialize};
 
-use crate::prelude::BoundingBox;
+use crate::{
+    prelude::{BoundingBox, Color, LOD, ROW_MAJOR},
+    LODKey, LODUnit,
+};
 
 /// bounding boxes for each unit in octree of LOD
 pub type Coordinates = HashMap<u32, HashMap<String, BoundingBox>>;
 
+/// global shift (x, y, z) and scale applied to the input coordinates
+pub type GlobalShift = (Vector3<f64>, f64);
+
 /// Meta representation of the processed lod data
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Meta {
@@ -14,6 +21,136 @@ pub struct Meta {
     pub lod: u32,
     pub bounds: BoundingBox,
     pub coordinates: Coordinates,
+    /// union of the bounding boxes of the tiles in each level (the extent of the data stored in the level)
+    #[serde(default)]
+    pub level_bounds: HashMap<u32, BoundingBox>,
+    /// explicit global shift applied to the input, if any
+    #[serde(default)]
+    pub global_shift: Option<GlobalShift>,
+    /// normal images (`x-y-z-normal.png`) are written for each unit (all the points have a normal)
+    #[serde(default)]
+    pub normals: bool,
+    /// radius images (`x-y-z-radius.png`) are written for each unit
+    #[serde(default)]
+    pub radius: bool,
+    /// index images (`x-y-z-index.png`) of the input points are written for each unit
+    #[serde(default)]
+    pub index: bool,
+    /// the alpha channel of the color images holds intensity (255 otherwise)
+    #[serde(default = "default_intensity_in_alpha")]
+    pub intensity_in_alpha: bool,
+    /// the alpha channel of the position images holds intensity (1 ~ 255, 0 is the padding)
+    #[serde(default)]
+    pub intensity_in_position_alpha: bool,
+    /// geometric error (sampling radius) for each level
+    #[serde(default)]
+    pub geometric_error: HashMap<u32, f64>,
+    /// point count threshold actually used to divide the octree
+    #[serde(default)]
+    pub point_count_threshold: usize,
+    /// number of points in each unit
+    #[serde(default)]
+    pub point_counts: HashMap<u32, HashMap<String, usize>>,
+    /// average color (in linear RGB) of the points in each unit, for previews without decoding the tiles
+    #[serde(default)]
+    pub average_colors: HashMap<u32, HashMap<String, Color>>,
+    /// points in each tile are in progressive order (any prefix is a uniform subsample)
+    #[serde(default)]
+    pub progressive_order: bool,
+    /// width of the halo around each unit as a ratio of the cell size (0 if disabled)
+    #[serde(default)]
+    pub halo: f64,
+    /// each level stores only the points not stored in the coarser levels,
+    /// so the points of a tile are the ones of its tile and all the ancestor tiles
+    #[serde(default)]
+    pub delta_levels: bool,
+    /// number of the cells along each axis of each level of the anisotropic octree,
+    /// whose cells are the size of the bounds over the divisions
+    /// (empty for the octree of `2^level` cubic cells of the max size on every axis)
+    #[serde(default)]
+    pub cell_divisions: HashMap<u32, [usize; 3]>,
+    /// the tiles under the point count threshold are divided only if the color variance of their points is over this,
+    /// so a tile without the tiles of its cell in the next level is a leaf (`None` if every level covers the whole cloud)
+    #[serde(default)]
+    pub color_variance: Option<f64>,
+    /// extent of each axis the positions of each tile are normalized over from the min of its bounds,
+    /// for the tiles whose bounds are larger than their points (e.g. with cubic tile bounds)
+    #[serde(default)]
+    pub tile_scales: HashMap<u32, HashMap<String, Vector3<f64>>>,
+    /// level whose position images are quad encoded (`encode_8bit_quad`) as detail tiles,
+    /// the other levels use the bit depth of the encoder
+    #[serde(default)]
+    pub detail_level: Option<u32>,
+    /// retention of the sampled tiles (`None` if no tile was sampled)
+    #[serde(default)]
+    pub retention: Option<RetentionStats>,
+    /// depth images (`x-y-z-depth.png`) are written for the tiles of `depth_level`
+    #[serde(default)]
+    pub depth: Option<DepthMode>,
+    #[serde(default)]
+    pub depth_level: Option<u32>,
+    /// gamma applied to the color images (`c^gamma`), invert with `c^(1 / gamma)`
+    #[serde(default)]
+    pub gamma: Option<f64>,
+    /// quantization bits of the positions in the Draco point clouds (`x-y-z.drc`) written for each unit, if any
+    #[serde(default)]
+    pub draco_bits: Option<u8>,
+    /// name of the `PixelLayout` of the points in the pixels of the tile images
+    #[serde(default = "default_pixel_layout")]
+    pub pixel_layout: String,
+    /// range of GPS time (min, max) mapped to the colors of `Color::ramp` in the color images, if colored by time
+    #[serde(default)]
+    pub color_by_time: Option<(f64, f64)>,
+}
+
+/// Depth of each point in the depth image (e.g. for eye-dome lighting), normalized to 0.0 ~ 1.0 in the tile
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DepthMode {
+    /// x coordinate from the min to the max of the tile
+    X,
+    /// y coordinate from the min to the max of the tile
+    Y,
+    /// z coordinate from the min to the max of the tile
+    Z,
+    /// distance from the center of the tile over the half diagonal, in the normalized coordinates of the tile
+    Center,
+}
+
+/// Ratio of the samples to the points each sampled tile could hold
+/// (its input points, capped by the point count threshold)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RetentionStats {
+    pub min: f64,
+    pub average: f64,
+    /// number of the sampled tiles
+    pub tiles: usize,
+    /// number of the tiles under `LODOptions::min_retention`
+    pub low_tiles: usize,
+}
+
+impl RetentionStats {
+    pub fn insert(&mut self, ratio: f64, low: bool) {
+        self.min = match self.tiles {
+            0 => ratio,
+            _ => self.min.min(ratio),
+        };
+        self.tiles += 1;
+        self.average += (ratio - self.average) / self.tiles as f64;
+        if low {
+            self.low_tiles += 1;
+        }
+    }
+}
+
+/// the meta written before the option existed has intensity in the alpha channel
+fn default_intensity_in_alpha() -> bool {
+    true
+}
+
+/// the meta written before the option existed has the points in the row-major order
+fn default_pixel_layout() -> String {
+    ROW_MAJOR.to_string()
 }
 
 impl Meta {
@@ -23,6 +160,31 @@ impl Meta {
             lod,
             bounds,
             coordinates,
+            level_bounds: HashMap::new(),
+            global_shift: None,
+            normals: false,
+            radius: false,
+            index: false,
+            intensity_in_alpha: true,
+            intensity_in_position_alpha: false,
+            geometric_error: HashMap::new(),
+            point_count_threshold: 0,
+            point_counts: HashMap::new(),
+            average_colors: HashMap::new(),
+            progressive_order: false,
+            delta_levels: false,
+            cell_divisions: HashMap::new(),
+            color_variance: None,
+            halo: 0.,
+            tile_scales: HashMap::new(),
+            detail_level: None,
+            retention: None,
+            depth: None,
+            depth_level: None,
+            gamma: None,
+            draco_bits: None,
+            pixel_layout: default_pixel_layout(),
+            color_by_time: None,
         }
     }
 
@@ -41,4 +203,395 @@ impl Meta {
     pub fn coordinates(&self) -> &Coordinates {
         &self.coordinates
     }
+
+    pub fn global_shift(&self) -> Option<&GlobalShift> {
+        self.global_shift.as_ref()
+    }
+
+    /// position in the georeferenced coordinates of the input, undoing the global shift
+    /// (`(p + shift) * scale` is applied when loading)
+    pub fn georeferenced(&self, position: &Point3<f64>) -> Point3<f64> {
+        match &self.global_shift {
+            Some((shift, scale)) => Point3::from(position.coords / *scale - shift),
+            None => *position,
+        }
+    }
+
+    pub fn geometric_error(&self, level: u32) -> Option<f64> {
+        self.geometric_error.get(&level).copied()
+    }
+
+    /// recommended splat size (in the units of the input) of the points in `level`,
+    /// the geometric error of the level as the samples are about the sampling radius apart (0 if unknown)
+    pub fn point_size(&self, level: u32) -> f64 {
+        self.geometric_error(level).unwrap_or(0.)
+    }
+
+    /// union of the bounding boxes of the tiles in `level` (`None` if the level has no tile).
+    /// Computed from the tiles for the meta written without level bounds
+    pub fn level_bounds(&self, level: u32) -> Option<BoundingBox> {
+        self.level_bounds.get(&level).cloned().or_else(|| {
+            let counts = self.point_counts.get(&level);
+            self.coordinates
+                .get(&level)?
+                .iter()
+                .filter(|(key, _)| counts.and_then(|c| c.get(*key)) != Some(&0))
+                .map(|(_, bbox)| bbox.clone())
+                .reduce(|mut union, bbox| {
+                    union.extend(bbox.min());
+                    union.extend(bbox.max());
+                    union
+                })
+        })
+    }
+
+    /// extent of each axis the positions of the tile of `key` in `level` are normalized over
+    /// (the size of its bounds if not recorded in `tile_scales`)
+    pub fn tile_scale(&self, level: u32, key: &LODKey) -> Option<Vector3<f64>> {
+        self.tile_scales
+            .get(&level)
+            .and_then(|units| units.get(&coordinate_key(key)))
+            .copied()
+            .or_else(|| self.tile_bounds(level, key).map(|bounds| bounds.size()))
+    }
+
+    /// the tile of `key` exists in `level`
+    pub fn has_tile(&self, level: u32, key: &LODKey) -> bool {
+        self.tile_bounds(level, key).is_some()
+    }
+
+    /// bounding box of the tile of `key` in `level`
+    pub fn tile_bounds(&self, level: u32, key: &LODKey) -> Option<BoundingBox> {
+        self.coordinates
+            .get(&level)
+            .and_then(|units| units.get(&coordinate_key(key)))
+            .cloned()
+    }
+
+    /// average color of the points in the tile of `key` in `level` (`None` if the points have no color)
+    pub fn tile_average_color(&self, level: u32, key: &LODKey) -> Option<Color> {
+        self.average_colors
+            .get(&level)
+            .and_then(|colors| colors.get(&coordinate_key(key)))
+            .copied()
+    }
+
+    /// record the bounding box, the number of points and the average color of the unit
+    pub fn insert_unit(&mut self, unit: &LODUnit) {
+        let key = coordinate_key(&(unit.x, unit.y, unit.z));
+        self.coordinates
+            .entry(unit.lod)
+            .or_default()
+            .entry(key.clone())
+            .or_insert(unit.bounding_box.clone());
+        let scale = unit
+            .bounding_box
+            .extent_of(unit.points.iter().map(|p| &p.position));
+        if scale != unit.bounding_box.size() {
+            self.tile_scales
+                .entry(unit.lod)
+                .or_default()
+                .insert(key.clone(), scale);
+        }
+        // the bounds of an empty unit are inverted (min > max) and would spoil the union
+        if !unit.points.is_empty() {
+            self.level_bounds
+                .entry(unit.lod)
+                .and_modify(|union| {
+                    union.extend(unit.bounding_box.min());
+                    union.extend(unit.bounding_box.max());
+                })
+                .or_insert(unit.bounding_box.clone());
+        }
+        if let Some(color) =
+            Color::linear_average(unit.points.iter().filter_map(|p| p.color.as_ref()))
+        {
+            self.average_colors
+                .entry(unit.lod)
+                .or_default()
+                .insert(key.clone(), color);
+        }
+        self.point_counts
+            .entry(unit.lod)
+            .or_default()
+            .insert(key, unit.points.len());
+    }
+
+    /// total number of points in each level (index is the level)
+    pub fn points_per_level(&self) -> Vec<u64> {
+        (0..self.lod)
+            .map(|level| {
+                self.point_counts
+                    .get(&level)
+                    .map(|counts| counts.values().map(|c| *c as u64).sum())
+                    .unwrap_or(0)
+            })
+            .collect()
+    }
+
+    /// tiles of all the levels as (level, tile) sorted by level and key
+    pub fn to_lods(&self) -> Vec<(u32, LOD)> {
+        let mut lods: Vec<(u32, LOD)> = self
+            .coordinates
+            .iter()
+            .flat_map(|(level, units)| {
+                units.iter().filter_map(move |(key, bounds)| {
+                    let (x, y, z) = parse_coordinate_key(key)?;
+                    Some((
+                        *level,
+                        LOD {
+                            x,
+                            y,
+                            z,
+                            bounds: bounds.clone(),
+                        },
+                    ))
+                })
+            })
+            .collect();
+        lods.sort_by_key(|(level, lod)| (*level, lod.key()));
+        lods
+    }
+
+    /// per-unit bounding boxes as CSV
+    /// (`level,x,y,z,min_x,min_y,min_z,max_x,max_y,max_z,count`, count is empty if unknown)
+    pub fn to_csv(&self) -> String {
+        let mut rows = vec![];
+        for (level, units) in self.coordinates.iter() {
+            for (key, bbox) in units.iter() {
+                let xyz: Vec<i32> = key.split('-').filter_map(|v| v.parse().ok()).collect();
+                let count = self
+                    .point_counts
+                    .get(level)
+                    .and_then(|counts| counts.get(key))
+                    .map(|count| count.to_string())
+                    .unwrap_or_default();
+                rows.push((*level, xyz, bbox, count));
+            }
+        }
+        rows.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+
+        let mut csv = String::from("level,x,y,z,min_x,min_y,min_z,max_x,max_y,max_z,count\n");
+        for (level, xyz, bbox, count) in rows {
+            let (min, max) = (bbox.min(), bbox.max());
+            csv.push_str(&format!(
+                "{},{},{},{},{},{},{},{},{},{},{}\n",
+                level,
+                xyz.first().unwrap_or(&0),
+                xyz.get(1).unwrap_or(&0),
+                xyz.get(2).unwrap_or(&0),
+                min.x,
+                min.y,
+                min.z,
+                max.x,
+                max.y,
+                max.z,
+                count
+            ));
+        }
+        csv
+    }
+
+    /// number of the cells along each axis of the level
+    pub fn divisions(&self, level: u32) -> [usize; 3] {
+        self.cell_divisions
+            .get(&level)
+            .copied()
+            .unwrap_or([2_usize.pow(level); 3])
+    }
+
+    /// size of the cells along each axis of the level
+    pub fn cell_size(&self, level: u32) -> Vector3<f64> {
+        let divisions = self.divisions(level);
+        match self.cell_divisions.contains_key(&level) {
+            true => {
+                let size = self.bounds.size();
+                Vector3::from_fn(|i, _| size[i] / divisions[i] as f64)
+            }
+            false => Vector3::repeat(self.bounds.max_size() / divisions[0] as f64),
+        }
+    }
+
+    /// key of the octree cell containing the point at the level (`None` if outside of bounds)
+    pub fn locate(&self, p: &Point3<f64>, level: u32) -> Option<LODKey> {
+        if !self.bounds.contains(p) {
+            return None;
+        }
+        Some(
+            self.bounds
+                .grid_cell(p, self.divisions(level), self.cell_size(level)),
+        )
+    }
+}
+
+/// key of the tile in `Coordinates` (`x-y-z`)
+fn coordinate_key(key: &LODKey) -> String {
+    let (x, y, z) = key;
+    format!("{}-{}-{}", x, y, z)
+}
+
+/// key of the tile from the key in `Coordinates` (`None` if malformed)
+pub(crate) fn parse_coordinate_key(key: &str) -> Option<LODKey> {
+    let mut xyz = key.splitn(3, '-').map(|v| v.parse().ok());
+    Some((xyz.next()??, xyz.next()??, xyz.next()??))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::point::Point;
+
+    use super::*;
+
+    #[test]
+    fn locate_point_in_octant() {
+        let bounds = BoundingBox::new(Point3::new(0., 0., 0.), Point3::new(8., 8., 8.));
+        let meta = Meta::new(3, bounds, Coordinates::new());
+
+        let p = Point3::new(6., 1., 5.);
+        assert_eq!(meta.locate(&p, 0), Some((0, 0, 0)));
+        assert_eq!(meta.locate(&p, 1), Some((1, 0, 1)));
+        assert_eq!(meta.locate(&p, 2), Some((3, 0, 2)));
+        // the max corner belongs to the last cell
+        assert_eq!(meta.locate(&Point3::new(8., 8., 8.), 2), Some((3, 3, 3)));
+        assert_eq!(meta.locate(&Point3::new(9., 1., 1.), 1), None);
+    }
+
+    #[test]
+    fn query_tiles() {
+        let bounds = BoundingBox::new(Point3::new(0., 0., 0.), Point3::new(2., 2., 2.));
+        let mut meta = Meta::new(2, bounds.clone(), Coordinates::new());
+        let bbox = BoundingBox::new(Point3::new(1., 0., 1.), Point3::new(2., 1., 2.));
+        meta.insert_unit(&LODUnit {
+            lod: 1,
+            bounding_box: bbox.clone(),
+            points: vec![Point::from(Point3::new(1.5, 0.5, 1.5))],
+            x: 1,
+            y: 0,
+            z: 1,
+            geometric_error: 1.,
+            #[cfg(feature = "image")]
+            images: vec![],
+        });
+
+        assert!(meta.has_tile(1, &(1, 0, 1)));
+        assert_eq!(meta.tile_bounds(1, &(1, 0, 1)), Some(bbox));
+        assert!(!meta.has_tile(1, &(0, 0, 0)));
+        assert!(!meta.has_tile(0, &(1, 0, 1)));
+        assert_eq!(meta.tile_bounds(2, &(1, 0, 1)), None);
+    }
+
+    #[test]
+    fn level_bounds_skip_empty_units() {
+        let bounds = BoundingBox::new(Point3::new(0., 0., 0.), Point3::new(2., 2., 2.));
+        let mut meta = Meta::new(2, bounds, Coordinates::new());
+        let bbox = BoundingBox::new(Point3::new(1., 0., 1.), Point3::new(2., 1., 2.));
+        let unit = |(x, y, z), bounding_box, points| LODUnit {
+            lod: 1,
+            bounding_box,
+            points,
+            x,
+            y,
+            z,
+            geometric_error: 1.,
+            #[cfg(feature = "image")]
+            images: vec![],
+        };
+        meta.insert_unit(&unit(
+            (1, 0, 1),
+            bbox.clone(),
+            vec![Point::from(Point3::new(1.5, 0.5, 1.5))],
+        ));
+        meta.insert_unit(&unit(
+            (0, 0, 0),
+            BoundingBox::from_iter(std::iter::empty::<Point3<f64>>()),
+            vec![],
+        ));
+        assert_eq!(meta.level_bounds(1), Some(bbox.clone()));
+
+        // computed from the tiles for the meta written without level bounds
+        meta.level_bounds.clear();
+        assert_eq!(meta.level_bounds(1), Some(bbox));
+    }
+
+    #[test]
+    fn flatten_coordinates() {
+        let bounds = BoundingBox::new(Point3::new(0., 0., 0.), Point3::new(4., 4., 4.));
+        let mut meta = Meta::new(3, bounds, Coordinates::new());
+        let keys = [
+            (0, (0, 0, 0)),
+            (1, (1, 0, 1)),
+            (1, (0, 1, 0)),
+            (2, (3, 2, 0)),
+            (2, (0, 0, 3)),
+        ];
+        for (lod, (x, y, z)) in keys {
+            let min = Point3::new(x as f64, y as f64, z as f64);
+            meta.insert_unit(&LODUnit {
+                lod,
+                bounding_box: BoundingBox::new(min, min + Vector3::repeat(1.)),
+                points: vec![Point::from(min)],
+                x,
+                y,
+                z,
+                geometric_error: 1.,
+                #[cfg(feature = "image")]
+                images: vec![],
+            });
+        }
+
+        let lods = meta.to_lods();
+        let tiles: usize = meta.coordinates().values().map(|units| units.len()).sum();
+        assert_eq!(lods.len(), tiles);
+        for (level, lod) in lods.iter() {
+            assert!(keys.contains(&(*level, lod.key())));
+            assert_eq!(
+                meta.tile_bounds(*level, &lod.key()).as_ref(),
+                Some(&lod.bounds)
+            );
+        }
+        assert_eq!(lods[0].1.key(), (0, 0, 0));
+        assert_eq!(
+            parse_coordinate_key(&coordinate_key(&(12, 0, 7))),
+            Some((12, 0, 7))
+        );
+    }
+
+    #[test]
+    fn coordinates_to_csv() {
+        let bounds = BoundingBox::new(Point3::new(0., 0., 0.), Point3::new(2., 2., 2.));
+        let mut meta = Meta::new(2, bounds.clone(), Coordinates::new());
+        let unit = |lod, (x, y, z), min: Point3<f64>, n| LODUnit {
+            lod,
+            bounding_box: BoundingBox::new(min, min + Vector3::repeat(0.5)),
+            points: vec![Point::from(min); n],
+            x,
+            y,
+            z,
+            geometric_error: 1.,
+            #[cfg(feature = "image")]
+            images: vec![],
+        };
+        meta.insert_unit(&unit(0, (0, 0, 0), Point3::new(0., 0., 0.), 3));
+        meta.insert_unit(&unit(1, (1, 0, 1), Point3::new(1.25, 0.5, 1.), 2));
+        meta.insert_unit(&unit(1, (0, 1, 0), Point3::new(0., 1.5, 0.75), 1));
+
+        let csv = meta.to_csv();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(
+            lines[0],
+            "level,x,y,z,min_x,min_y,min_z,max_x,max_y,max_z,count"
+        );
+        assert_eq!(lines.len(), 4);
+        for line in lines.iter().skip(1) {
+            let cols: Vec<&str> = line.split(',').collect();
+            let level: u32 = cols[0].parse().unwrap();
+            let key = format!("{}-{}-{}", cols[1], cols[2], cols[3]);
+            let v: Vec<f64> = cols[4..10].iter().map(|v| v.parse().unwrap()).collect();
+            let bbox = &meta.coordinates[&level][&key];
+            assert_eq!(bbox.min(), &Point3::new(v[0], v[1], v[2]));
+            assert_eq!(bbox.max(), &Point3::new(v[3], v[4], v[5]));
+            let count: usize = cols[10].parse().unwrap();
+            assert_eq!(count, meta.point_counts[&level][&key]);
+        }
+    }
 }
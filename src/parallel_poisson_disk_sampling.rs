@@ -1,26 +1,42 @@
 use itertools::Itertools;
 use nalgebra::{OPoint, Point3, RealField, Vector3, U3};
 use num_traits::ToPrimitive;
-use rand::seq::SliceRandom;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
 use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
 
-use crate::{grid::Grid, has_position::HasPosition, misc::min_max, point::Point};
+use crate::{
+    grid::{validate_grid, Cells, GridIndex, SparseGrid},
+    has_position::HasPosition,
+    misc::min_max,
+    point::Point,
+    poisson_disk_sampling::DEFAULT_MAX_GRID_CELLS,
+};
 
 #[derive(Debug)]
 pub struct ParallelPoissonDiskSampling<'a> {
     radius: f64,
     half_radius: f64,
-    grid: Vec<Vec<Vec<Grid<'a, Point>>>>,
-    grid_count: Vector3<usize>,
+    grid: SparseGrid<'a, Point>,
     grid_min: Vector3<f64>,
     grid_max: Vector3<f64>,
     partitions: Vec<Vector3<usize>>,
     partitions_count: usize,
     grid_cell_size: f64,
+    /// take the first valid candidate of each cell instead of any
+    deterministic: bool,
 }
 
 impl<'a> ParallelPoissonDiskSampling<'a> {
-    pub fn new(inputs: Vec<&'a Point>, radius: f64) -> Self {
+    pub fn new(inputs: Vec<&'a Point>, radius: f64) -> anyhow::Result<Self> {
+        Self::with_max_grid_cells(inputs, radius, DEFAULT_MAX_GRID_CELLS)
+    }
+
+    /// fail when the grid would allocate more cells than `max_grid_cells` (the occupied cells, at most one per input)
+    pub fn with_max_grid_cells(
+        inputs: Vec<&'a Point>,
+        radius: f64,
+        max_grid_cells: u64,
+    ) -> anyhow::Result<Self> {
         let (grid_min, grid_max) = min_max(inputs.iter().map(|pt| pt.position()));
         let size = grid_max - grid_min;
 
@@ -29,26 +45,14 @@ impl<'a> ParallelPoissonDiskSampling<'a> {
         // "Understanding the Cell Size" section
         let grid_cell_size = radius / (3.0_f64).sqrt();
         let half_radius = radius / 2.;
+        validate_grid(&size, radius, grid_cell_size, inputs.len(), max_grid_cells)?;
 
         let grid_count = size.map(|v| (v / grid_cell_size).ceil().max(1.) as usize);
 
-        // println!("grid_size: {:?}", u_grid_size);
-        let mut grid: Vec<Vec<Vec<Grid<'_, Point>>>> = vec![];
-        for _ in 0..grid_count.z {
-            let mut gz = vec![];
-            for _ in 0..grid_count.y {
-                let mut gy = vec![];
-                for _ in 0..grid_count.x {
-                    gy.push(Grid::new());
-                }
-                gz.push(gy);
-            }
-            grid.push(gz);
-        }
-
+        let mut grid = SparseGrid::new();
         inputs.iter().for_each(|pt| {
             let i = index(pt.position(), &grid_min, grid_cell_size);
-            grid[i.z][i.y][i.x].insert(pt);
+            grid.insert(i, *pt);
         });
 
         // 3 x 3 x 3 partitions
@@ -79,26 +83,30 @@ impl<'a> ParallelPoissonDiskSampling<'a> {
 
         let partitions_count = partitions.len();
 
-        Self {
+        Ok(Self {
             radius,
             half_radius,
             grid,
-            grid_count,
             grid_min,
             grid_max,
             grid_cell_size,
             partitions,
             partitions_count,
-        }
+            deterministic: false,
+        })
+    }
+
+    /// reproducible sampling: the partitions are shuffled with the seed,
+    /// and the first valid candidate of each cell is taken
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.partitions.sort_by_key(|v| (v.z, v.y, v.x));
+        self.partitions.shuffle(&mut StdRng::seed_from_u64(seed));
+        self.deterministic = true;
+        self
     }
 
     pub fn samples(&self) -> Vec<&Point> {
-        self.grid
-            .iter()
-            .flatten()
-            .flatten()
-            .filter_map(|g| g.representative())
-            .collect()
+        self.grid.representatives()
     }
 
     pub fn is_completed(&self) -> bool {
@@ -117,24 +125,15 @@ impl<'a> ParallelPoissonDiskSampling<'a> {
     }
 
     pub fn step(&mut self) -> anyhow::Result<()> {
-        let divs = self.grid_count.map(|i| (i as f64 / 3_f64).ceil() as usize);
         let address = self.partitions.pop().ok_or(anyhow::anyhow!("no address"))?;
         // println!("address: {:?}", address);
 
-        let count = self.grid_count;
-        let items = (0..=divs.z)
-            .flat_map(|z| {
-                (0..=divs.y).flat_map(move |y| {
-                    (0..=divs.x).filter_map(move |x| {
-                        let item = Vector3::new(x, y, z) * 3 + address;
-                        if item.x >= count.x || item.y >= count.y || item.z >= count.z {
-                            None
-                        } else {
-                            Some(item)
-                        }
-                    })
-                })
-            })
+        // occupied cells of the partition
+        let items = self
+            .grid
+            .indices()
+            .into_iter()
+            .filter(|(x, y, z)| (x % 3, y % 3, z % 3) == (address.x, address.y, address.z))
             .collect_vec();
 
         if self.partitions.len() + 1 == self.partitions_count {
@@ -142,20 +141,20 @@ impl<'a> ParallelPoissonDiskSampling<'a> {
             let seeds = items
                 .into_par_iter()
                 .filter_map(|addr| {
-                    let g = &self.grid[addr.z][addr.y][addr.x];
+                    let g = self.grid.get(&addr)?;
                     g.candidates().first().cloned()
                 })
                 .collect::<Vec<_>>();
 
             for pt in seeds {
                 let i = index(pt.position(), &self.grid_min, self.grid_cell_size);
-                self.grid[i.z][i.y][i.x].set(pt.clone());
+                self.grid.set(i, pt.clone());
             }
         } else {
             let next = items
                 .into_par_iter()
                 .filter_map(|i| {
-                    let g = &self.grid[i.z][i.y][i.x];
+                    let g = self.grid.get(&i)?;
 
                     /*
                     let neighbors = &self.neighbors(i);
@@ -170,44 +169,34 @@ impl<'a> ParallelPoissonDiskSampling<'a> {
                         .cloned()
                     */
 
-                    g.candidates()
-                        .par_iter()
-                        .find_any(|p| self.is_valid(p))
-                        .cloned()
+                    let candidates = g.candidates().par_iter();
+                    match self.deterministic {
+                        true => candidates.find_first(|p| self.is_valid(p)),
+                        false => candidates.find_any(|p| self.is_valid(p)),
+                    }
+                    .cloned()
                 })
                 .collect::<Vec<_>>();
             // println!("#next: {}", next.len());
             for pt in next {
                 let i = index(pt.position(), &self.grid_min, self.grid_cell_size);
-                self.grid[i.z][i.y][i.x].set(pt.clone());
+                self.grid.set(i, pt.clone());
             }
         }
 
         Ok(())
     }
 
-    fn neighbors(&self, i: Vector3<usize>) -> Vec<Point3<f64>> {
+    fn neighbors(&self, i: GridIndex) -> Vec<Point3<f64>> {
         (-1..=1)
             .flat_map(|dz| {
-                let z = i.z as isize + dz;
                 (-1..=1).flat_map(move |dy| {
-                    let y = i.y as isize + dy;
                     (-1..=1).filter_map(move |dx| {
                         if dz == 0 && dy == 0 && dx == 0 {
                             None
                         } else {
-                            let x = i.x as isize + dx;
-                            if x >= 0
-                                && x < self.grid_count.x as isize
-                                && y >= 0
-                                && y < self.grid_count.y as isize
-                                && z >= 0
-                                && z < self.grid_count.z as isize
-                            {
-                                self.grid[z as usize][y as usize][x as usize].representative()
-                            } else {
-                                None
-                            }
+                            let j = offset(i, (dx, dy, dz))?;
+                            self.grid.get(&j)?.representative()
                         }
                     })
                 })
@@ -220,26 +209,18 @@ impl<'a> ParallelPoissonDiskSampling<'a> {
         let i = index(p.position(), &self.grid_min, self.grid_cell_size);
 
         for dz in -1..=1 {
-            let z = i.z as isize + dz;
-            if 0 <= z && z < self.grid_count.z as isize {
-                for dy in -1..=1 {
-                    let y = i.y as isize + dy;
-                    if 0 <= y && y < self.grid_count.y as isize {
-                        for dx in -1..=1 {
-                            if dz == 0 && dy == 0 && dx == 0 {
-                                continue;
-                            }
-                            let x = i.x as isize + dx;
-                            if 0 <= x && x < self.grid_count.x as isize {
-                                if let Some(q) =
-                                    self.grid[z as usize][y as usize][x as usize].representative()
-                                {
-                                    let dist = (p.position() - q.position()).norm();
-                                    if dist <= self.radius {
-                                        return false;
-                                    }
-                                }
-                            }
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    if dz == 0 && dy == 0 && dx == 0 {
+                        continue;
+                    }
+                    if let Some(q) = offset(i, (dx, dy, dz))
+                        .and_then(|j| self.grid.get(&j))
+                        .and_then(|g| g.representative())
+                    {
+                        let dist = (p.position() - q.position()).norm();
+                        if dist <= self.radius {
+                            return false;
                         }
                     }
                 }
@@ -250,7 +231,90 @@ impl<'a> ParallelPoissonDiskSampling<'a> {
     }
 }
 
-fn index(point: &OPoint<f64, U3>, grid_min: &Vector3<f64>, cell_size: f64) -> Vector3<usize> {
+fn index(point: &OPoint<f64, U3>, grid_min: &Vector3<f64>, cell_size: f64) -> GridIndex {
     let n = point.coords - grid_min;
-    n.map(|x| (x / cell_size).floor().to_usize().unwrap())
+    let i = n.map(|x| (x / cell_size).floor().to_usize().unwrap());
+    (i.x, i.y, i.z)
+}
+
+/// index of the adjacent cell (None if out of the grid)
+fn offset(i: GridIndex, d: (isize, isize, isize)) -> Option<GridIndex> {
+    Some((
+        i.0.checked_add_signed(d.0)?,
+        i.1.checked_add_signed(d.1)?,
+        i.2.checked_add_signed(d.2)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    // the clusters are far apart, so a dense grid over them would not fit in memory
+    use crate::poisson_disk_sampling::tests::sparse_clusters;
+
+    use super::*;
+
+    #[test]
+    fn sparse_cloud_keeps_separated_points() {
+        let points = sparse_clusters(0.1);
+        let mut sampler = ParallelPoissonDiskSampling::new(points.iter().collect(), 0.05).unwrap();
+        sampler.sample().unwrap();
+
+        // the samples are in (z, y, x) order of the cells like the input
+        let samples = sampler.samples();
+        assert_eq!(samples.len(), points.len());
+        for (p, q) in samples.iter().zip(points.iter()) {
+            assert_eq!(p.position, q.position);
+        }
+    }
+
+    #[test]
+    fn sparse_cloud_is_covered() {
+        let points = sparse_clusters(0.1);
+        let radius = 0.25;
+        let mut sampler =
+            ParallelPoissonDiskSampling::new(points.iter().collect(), radius).unwrap();
+        sampler.sample().unwrap();
+
+        let samples = sampler.samples();
+        assert!(samples.len() < points.len());
+        for p in points.iter() {
+            assert!(samples.iter().any(|q| p.distance(q) <= radius));
+        }
+    }
+
+    #[test]
+    fn seeded_sampling_is_reproducible() {
+        let points = sparse_clusters(0.1);
+        let sample = || {
+            let mut sampler = ParallelPoissonDiskSampling::new(points.iter().collect(), 0.25)
+                .unwrap()
+                .seed(7);
+            sampler.sample().unwrap();
+            sampler
+                .samples()
+                .iter()
+                .map(|p| p.position)
+                .collect::<Vec<_>>()
+        };
+        let first = sample();
+        for _ in 0..4 {
+            assert_eq!(sample(), first);
+        }
+    }
+
+    #[test]
+    fn tiny_radius_is_rejected() {
+        let points = sparse_clusters(0.1);
+        let err = ParallelPoissonDiskSampling::new(points.iter().collect(), 1e-20)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("too small"), "{}", err);
+        assert!(ParallelPoissonDiskSampling::new(points.iter().collect(), -1.).is_err());
+        assert!(ParallelPoissonDiskSampling::with_max_grid_cells(
+            points[..125].iter().collect(),
+            0.1,
+            10
+        )
+        .is_err());
+    }
 }
@@ -0,0 +1,458 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use anyhow::{bail, ensure};
+
+use crate::prelude::{Color, Point};
+
+/// encoding of the points after the header of a PCD file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PcdData {
+    Ascii,
+    /// little-endian records
+    Binary,
+    /// not read natively (converted with CloudCompare)
+    BinaryCompressed,
+}
+
+/// field of the points (`FIELDS`, `SIZE`, `TYPE` and `COUNT` of the header)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PcdField {
+    pub name: String,
+    pub size: usize,
+    /// `F` (float), `U` (unsigned) or `I` (signed)
+    pub kind: char,
+    pub count: usize,
+}
+
+/// header of a PCD file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PcdHeader {
+    pub fields: Vec<PcdField>,
+    pub points: usize,
+    pub data: PcdData,
+}
+
+impl PcdHeader {
+    /// read the header lines up to and including `DATA`
+    pub fn read(reader: &mut impl BufRead) -> anyhow::Result<Self> {
+        let mut names: Vec<String> = vec![];
+        let mut sizes: Vec<usize> = vec![];
+        let mut kinds: Vec<char> = vec![];
+        let mut counts: Vec<usize> = vec![];
+        let (mut width, mut height, mut points) = (None, 1, None);
+        let mut line = String::new();
+        let data = loop {
+            line.clear();
+            ensure!(reader.read_line(&mut line)? > 0, "PCD header has no DATA");
+            let mut tokens = line.split_whitespace();
+            let Some(key) = tokens.next() else {
+                continue;
+            };
+            let values: Vec<&str> = tokens.collect();
+            match key.to_uppercase().as_str() {
+                "FIELDS" => names = values.iter().map(|v| v.to_lowercase()).collect(),
+                "SIZE" => sizes = parse_values(&values)?,
+                "TYPE" => kinds = values.iter().filter_map(|v| v.chars().next()).collect(),
+                "COUNT" => counts = parse_values(&values)?,
+                "WIDTH" => width = parse_values(&values)?.first().copied(),
+                "HEIGHT" => height = parse_values(&values)?.first().copied().unwrap_or(1),
+                "POINTS" => points = parse_values(&values)?.first().copied(),
+                "DATA" => {
+                    break match values.first().map(|v| v.to_lowercase()).as_deref() {
+                        Some("ascii") => PcdData::Ascii,
+                        Some("binary") => PcdData::Binary,
+                        Some("binary_compressed") => PcdData::BinaryCompressed,
+                        other => bail!("Unknown PCD data {:?}", other),
+                    };
+                }
+                // comments, VERSION and VIEWPOINT
+                _ => {}
+            }
+        };
+
+        ensure!(!names.is_empty(), "PCD header has no FIELDS");
+        if counts.is_empty() {
+            counts = vec![1; names.len()];
+        }
+        ensure!(
+            sizes.len() == names.len() && kinds.len() == names.len() && counts.len() == names.len(),
+            "SIZE, TYPE and COUNT of the PCD header do not match FIELDS"
+        );
+        let fields: Vec<PcdField> = names
+            .into_iter()
+            .zip(sizes)
+            .zip(kinds)
+            .zip(counts)
+            .map(|(((name, size), kind), count)| PcdField {
+                name,
+                size,
+                kind: kind.to_ascii_uppercase(),
+                count,
+            })
+            .collect();
+        for field in fields.iter() {
+            ensure!(
+                matches!(
+                    (field.kind, field.size),
+                    ('F', 4 | 8) | ('U' | 'I', 1 | 2 | 4 | 8)
+                ),
+                "Unsupported PCD field {} of type {} and size {}",
+                field.name,
+                field.kind,
+                field.size
+            );
+        }
+
+        let points = match (points, width) {
+            (Some(points), _) => points,
+            (None, Some(width)) => width * height,
+            (None, None) => bail!("PCD header has no POINTS"),
+        };
+        Ok(Self {
+            fields,
+            points,
+            data,
+        })
+    }
+
+    /// read the header of the file
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        Self::read(&mut BufReader::new(File::open(path)?))
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.fields.iter().position(|f| f.name == name)
+    }
+}
+
+/// iterator of the points of an ascii or binary PCD file (x y z, packed rgb or rgba, intensity).
+/// Integer intensity is normalized to 0.0 ~ 1.0 by the range of its type (e.g. 65535 for `U` of size 2),
+/// float intensity is kept as is.
+/// The points with a NaN position (invalid points of organized clouds) are skipped.
+pub struct PcdReader<R> {
+    header: PcdHeader,
+    reader: R,
+    xyz: [usize; 3],
+    rgb: Option<usize>,
+    intensity: Option<usize>,
+    /// byte offset of each field in a binary record
+    offsets: Vec<usize>,
+    /// token index of each field in an ascii line
+    columns: Vec<usize>,
+    record: Vec<u8>,
+    line: String,
+    remaining: usize,
+}
+
+impl PcdReader<BufReader<File>> {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: BufRead> PcdReader<R> {
+    pub fn new(mut reader: R) -> anyhow::Result<Self> {
+        let header = PcdHeader::read(&mut reader)?;
+        ensure!(
+            header.data != PcdData::BinaryCompressed,
+            "binary_compressed PCD is not supported natively"
+        );
+        let (Some(x), Some(y), Some(z)) = (
+            header.position("x"),
+            header.position("y"),
+            header.position("z"),
+        ) else {
+            bail!("PCD has no x, y or z field");
+        };
+        let rgb = header
+            .position("rgb")
+            .or(header.position("rgba"))
+            .filter(|i| header.fields[*i].size == 4);
+        let intensity = header.position("intensity");
+
+        let mut offsets = vec![];
+        let mut columns = vec![];
+        let (mut offset, mut column) = (0, 0);
+        for field in header.fields.iter() {
+            offsets.push(offset);
+            columns.push(column);
+            offset += field.size * field.count;
+            column += field.count;
+        }
+
+        let remaining = header.points;
+        Ok(Self {
+            header,
+            reader,
+            xyz: [x, y, z],
+            rgb,
+            intensity,
+            offsets,
+            columns,
+            record: vec![0; offset],
+            line: String::new(),
+            remaining,
+        })
+    }
+
+    pub fn header(&self) -> &PcdHeader {
+        &self.header
+    }
+
+    /// read the next record into a point
+    fn read_point(&mut self) -> anyhow::Result<Point> {
+        let record = match self.header.data {
+            PcdData::Ascii => {
+                self.line.clear();
+                ensure!(
+                    self.reader.read_line(&mut self.line)? > 0,
+                    "PCD has fewer points than the header"
+                );
+                Record::Ascii(self.line.split_whitespace().collect())
+            }
+            _ => {
+                self.reader.read_exact(&mut self.record)?;
+                Record::Binary(&self.record)
+            }
+        };
+
+        let [x, y, z] = self.xyz;
+        let value = |i: usize| -> anyhow::Result<f64> {
+            match &record {
+                Record::Ascii(tokens) => Ok(self.token(tokens, i)?.parse()?),
+                Record::Binary(bytes) => {
+                    let offset = self.offsets[i];
+                    let field = &self.header.fields[i];
+                    Ok(binary_value(
+                        field.kind,
+                        &bytes[offset..offset + field.size],
+                    ))
+                }
+            }
+        };
+        let mut point = Point::new(value(x)?, value(y)?, value(z)?);
+        if let Some(intensity) = self.intensity {
+            let field = &self.header.fields[intensity];
+            point.intensity = Some(value(intensity)? / max_value(field.kind, field.size));
+        }
+        if let Some(rgb) = self.rgb {
+            // packed as 0x00RRGGBB (0xAARRGGBB for rgba) into the bits of the field
+            let bits = match &record {
+                Record::Ascii(tokens) => match self.header.fields[rgb].kind {
+                    'F' => self.token(tokens, rgb)?.parse::<f32>()?.to_bits(),
+                    _ => self.token(tokens, rgb)?.parse::<u32>()?,
+                },
+                Record::Binary(bytes) => {
+                    let offset = self.offsets[rgb];
+                    u32::from_le_bytes(bytes[offset..offset + 4].try_into()?)
+                }
+            };
+            point.color = Some(Color::new(
+                (bits >> 16) as u8,
+                (bits >> 8) as u8,
+                bits as u8,
+            ));
+        }
+        Ok(point)
+    }
+
+    /// first value of the field in an ascii line
+    fn token<'a>(&self, tokens: &[&'a str], i: usize) -> anyhow::Result<&'a str> {
+        tokens
+            .get(self.columns[i])
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("PCD line has fewer values than the fields"))
+    }
+}
+
+/// a point record of the data
+enum Record<'a> {
+    Ascii(Vec<&'a str>),
+    Binary(&'a [u8]),
+}
+
+impl<R: BufRead> Iterator for PcdReader<R> {
+    type Item = anyhow::Result<Point>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.remaining > 0 {
+            self.remaining -= 1;
+            match self.read_point() {
+                Ok(point) if point.position.iter().any(|v| v.is_nan()) => continue,
+                Ok(point) => return Some(Ok(point)),
+                Err(e) => {
+                    self.remaining = 0;
+                    return Some(Err(e));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// check if the PCD file can be read by `PcdReader`
+pub fn is_native_pcd(path: &Path) -> bool {
+    PcdHeader::open(path)
+        .map(|header| header.data != PcdData::BinaryCompressed)
+        .unwrap_or(false)
+}
+
+fn parse_values(values: &[&str]) -> anyhow::Result<Vec<usize>> {
+    Ok(values.iter().map(|v| v.parse()).collect::<Result<_, _>>()?)
+}
+
+/// little-endian value of the field (type and size are validated with the header)
+fn binary_value(kind: char, bytes: &[u8]) -> f64 {
+    let mut buf = [0u8; 8];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    match (kind, bytes.len()) {
+        ('F', 4) => f32::from_le_bytes(buf[..4].try_into().unwrap()) as f64,
+        ('F', _) => f64::from_le_bytes(buf),
+        ('U', _) => u64::from_le_bytes(buf) as f64,
+        (_, n) => {
+            // sign extend
+            let shift = 64 - 8 * n as u32;
+            ((i64::from_le_bytes(buf) << shift) >> shift) as f64
+        }
+    }
+}
+
+/// max value of the integer type of the field (1 for float)
+fn max_value(kind: char, size: usize) -> f64 {
+    let bits = 8 * size as i32;
+    match kind {
+        'U' => 2_f64.powi(bits) - 1.,
+        'I' => 2_f64.powi(bits - 1) - 1.,
+        _ => 1.,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use nalgebra::Point3;
+
+    use super::*;
+
+    const HEADER: &str = "# .PCD v0.7 - Point Cloud Data file format
+VERSION 0.7
+FIELDS x y z rgb intensity
+SIZE 4 4 8 4 2
+TYPE F F F F U
+COUNT 1 1 1 1 1
+WIDTH 3
+HEIGHT 1
+VIEWPOINT 0 0 0 1 0 0 0
+POINTS 3
+";
+
+    fn packed_rgb(r: u8, g: u8, b: u8) -> f32 {
+        f32::from_bits(((r as u32) << 16) | ((g as u32) << 8) | b as u32)
+    }
+
+    fn expected() -> Vec<(Point3<f64>, Color, f64)> {
+        vec![
+            (Point3::new(1.5, 2.5, 3.25), Color::new(255, 0, 10), 100.),
+            (Point3::new(-1., 0., 1e6), Color::new(1, 2, 3), 0.),
+            (Point3::new(0.25, -0.5, 0.), Color::new(0, 255, 0), 65535.),
+        ]
+    }
+
+    fn assert_points(points: &[Point]) {
+        let expected = expected();
+        assert_eq!(points.len(), expected.len());
+        for (p, (position, color, intensity)) in points.iter().zip(expected) {
+            assert_eq!(p.position, position);
+            assert_eq!(p.color, Some(color));
+            // u16 intensity over its range
+            assert_eq!(p.intensity, Some(intensity / 65535.));
+        }
+    }
+
+    fn pcd_file(data: &[u8]) -> tempfile::NamedTempFile {
+        let mut file = tempfile::Builder::new().suffix(".pcd").tempfile().unwrap();
+        file.write_all(data).unwrap();
+        file.flush().unwrap();
+        file
+    }
+
+    #[test]
+    fn read_ascii_pcd() {
+        let mut data = format!("{}DATA ascii\n", HEADER);
+        for (p, c, i) in expected() {
+            let rgb = packed_rgb(c.r(), c.g(), c.b());
+            data.push_str(&format!("{} {} {} {:e} {}\n", p.x, p.y, p.z, rgb, i));
+        }
+        let file = pcd_file(data.as_bytes());
+
+        let reader = PcdReader::open(file.path()).unwrap();
+        assert_eq!(reader.header().data, PcdData::Ascii);
+        assert_eq!(reader.header().points, 3);
+        let points: Vec<Point> = reader.collect::<anyhow::Result<_>>().unwrap();
+        assert_points(&points);
+    }
+
+    #[test]
+    fn read_binary_pcd() {
+        // with an invalid point of an organized cloud at the end
+        let header = HEADER.replacen("POINTS 3", "POINTS 4", 1);
+        let mut data = format!("{}DATA binary\n", header).into_bytes();
+        for (p, c, i) in expected() {
+            data.extend((p.x as f32).to_le_bytes());
+            data.extend((p.y as f32).to_le_bytes());
+            data.extend(p.z.to_le_bytes());
+            data.extend(packed_rgb(c.r(), c.g(), c.b()).to_le_bytes());
+            data.extend((i as u16).to_le_bytes());
+        }
+        data.extend(f32::NAN.to_le_bytes());
+        data.extend([0; 4 + 8 + 4 + 2]);
+        let file = pcd_file(&data);
+
+        assert!(is_native_pcd(file.path()));
+        let points = crate::load_points(
+            None,
+            file.path(),
+            &crate::LoadOptions {
+                native: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_points(&points);
+    }
+
+    #[test]
+    fn intensity_over_range_of_type() {
+        assert_eq!(max_value('U', 1), 255.);
+        assert_eq!(max_value('U', 2), 65535.);
+        assert_eq!(max_value('I', 2), 32767.);
+        assert_eq!(max_value('F', 4), 1.);
+
+        let header = HEADER
+            .replace("SIZE 4 4 8 4 2", "SIZE 4 4 8 4 4")
+            .replace("TYPE F F F F U", "TYPE F F F F F");
+        let data = format!(
+            "{}DATA ascii\n1 2 3 0 0.25\n1 2 3 0 0.5\n1 2 3 0 1\n",
+            header
+        );
+        let file = pcd_file(data.as_bytes());
+        let points: Vec<Point> = PcdReader::open(file.path())
+            .unwrap()
+            .collect::<anyhow::Result<_>>()
+            .unwrap();
+        let intensities: Vec<_> = points.iter().map(|p| p.intensity.unwrap()).collect();
+        assert_eq!(intensities, vec![0.25, 0.5, 1.]);
+    }
+
+    #[test]
+    fn compressed_pcd_is_not_native() {
+        let file = pcd_file(format!("{}DATA binary_compressed\n", HEADER).as_bytes());
+        assert!(!is_native_pcd(file.path()));
+        assert!(PcdReader::open(file.path()).is_err());
+    }
+}
@@ -0,0 +1,60 @@
+use std::{fmt::Debug, sync::Arc};
+
+/// name of the default layout (`RowMajor`)
+pub const ROW_MAJOR: &str = "row-major";
+
+/// Order of the points in the pixels of the tile images (e.g. a space filling curve for the locality of the texels).
+/// The encoder puts the point at `index` on `pixel(index, side)`, and the decoder reads the pixels in the same order.
+pub trait PixelLayout: Send + Sync {
+    /// identifier recorded in meta.json to decode the tiles with the same layout
+    fn name(&self) -> &str;
+
+    /// pixel (x, y) of the point at `index` in the `side x side` image,
+    /// distinct for each index under `side * side`
+    fn pixel(&self, index: usize, side: u32) -> (u32, u32);
+}
+
+impl Debug for dyn PixelLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PixelLayout({})", self.name())
+    }
+}
+
+/// Points filling the rows from the top left, the padding at the end (default)
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RowMajor;
+
+impl PixelLayout for RowMajor {
+    fn name(&self) -> &str {
+        ROW_MAJOR
+    }
+
+    fn pixel(&self, index: usize, side: u32) -> (u32, u32) {
+        (index as u32 % side, index as u32 / side)
+    }
+}
+
+/// Built-in layout of the name recorded in meta.json
+pub fn builtin_pixel_layout(name: &str) -> Option<Arc<dyn PixelLayout>> {
+    match name {
+        ROW_MAJOR => Some(Arc::new(RowMajor)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn row_major_covers_image() {
+        let side = 5;
+        let pixels: HashSet<_> = (0..25).map(|i| RowMajor.pixel(i, side)).collect();
+        assert_eq!(pixels.len(), 25);
+        assert_eq!(RowMajor.pixel(7, side), (2, 1));
+        assert_eq!(builtin_pixel_layout(ROW_MAJOR).unwrap().name(), ROW_MAJOR);
+        assert!(builtin_pixel_layout("hilbert").is_none());
+    }
+}
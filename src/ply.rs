@@ -0,0 +1,496 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{bail, ensure};
+use nalgebra::Vector3;
+
+use crate::prelude::{Color, Point};
+
+/// encoding of the elements after the header of a PLY file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlyFormat {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+/// scalar type of a property
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlyType {
+    Char,
+    UChar,
+    Short,
+    UShort,
+    Int,
+    UInt,
+    Float,
+    Double,
+}
+
+impl PlyType {
+    fn parse(name: &str) -> anyhow::Result<Self> {
+        Ok(match name {
+            "char" | "int8" => PlyType::Char,
+            "uchar" | "uint8" => PlyType::UChar,
+            "short" | "int16" => PlyType::Short,
+            "ushort" | "uint16" => PlyType::UShort,
+            "int" | "int32" => PlyType::Int,
+            "uint" | "uint32" => PlyType::UInt,
+            "float" | "float32" => PlyType::Float,
+            "double" | "float64" => PlyType::Double,
+            other => bail!("Unknown PLY type {}", other),
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            PlyType::Char | PlyType::UChar => 1,
+            PlyType::Short | PlyType::UShort => 2,
+            PlyType::Int | PlyType::UInt | PlyType::Float => 4,
+            PlyType::Double => 8,
+        }
+    }
+
+    /// value of the bytes of the type
+    fn value(&self, bytes: &[u8], big_endian: bool) -> f64 {
+        macro_rules! read {
+            ($t:ty) => {{
+                let b = bytes.try_into().unwrap();
+                match big_endian {
+                    true => <$t>::from_be_bytes(b) as f64,
+                    false => <$t>::from_le_bytes(b) as f64,
+                }
+            }};
+        }
+        match self {
+            PlyType::Char => read!(i8),
+            PlyType::UChar => read!(u8),
+            PlyType::Short => read!(i16),
+            PlyType::UShort => read!(u16),
+            PlyType::Int => read!(i32),
+            PlyType::UInt => read!(u32),
+            PlyType::Float => read!(f32),
+            PlyType::Double => read!(f64),
+        }
+    }
+
+    /// value normalized by the range of the integer type (e.g. 65535 for ushort), floats as is
+    fn normalized(&self, v: f64) -> f64 {
+        match self {
+            PlyType::Char => v / i8::MAX as f64,
+            PlyType::UChar => v / u8::MAX as f64,
+            PlyType::Short => v / i16::MAX as f64,
+            PlyType::UShort => v / u16::MAX as f64,
+            PlyType::Int => v / i32::MAX as f64,
+            PlyType::UInt => v / u32::MAX as f64,
+            PlyType::Float | PlyType::Double => v,
+        }
+    }
+
+    /// color channel of the value (8-bit as is, 16-bit scaled down, floats in 0.0 ~ 1.0)
+    fn color(&self, v: f64) -> u8 {
+        match self {
+            PlyType::Float | PlyType::Double => (v.clamp(0., 1.) * 255.).round() as u8,
+            PlyType::Short | PlyType::UShort => (v.clamp(0., 65535.) / 257.).round() as u8,
+            _ => v.clamp(0., 255.) as u8,
+        }
+    }
+}
+
+/// scalar property of the vertices
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlyProperty {
+    pub name: String,
+    pub kind: PlyType,
+}
+
+/// header of a PLY file, with the properties of the vertex element
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlyHeader {
+    pub format: PlyFormat,
+    pub vertices: usize,
+    pub properties: Vec<PlyProperty>,
+}
+
+impl PlyHeader {
+    /// read the header lines up to and including `end_header`.
+    /// The vertex element must be the first one (the other elements, e.g. faces, are not read)
+    pub fn read(reader: &mut impl BufRead) -> anyhow::Result<Self> {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        ensure!(line.trim() == "ply", "PLY file must start with ply");
+
+        let (mut format, mut vertices) = (None, None);
+        let mut properties = vec![];
+        // name of the element whose properties are being read
+        let mut element: Option<String> = None;
+        loop {
+            line.clear();
+            ensure!(
+                reader.read_line(&mut line)? > 0,
+                "PLY header has no end_header"
+            );
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            match tokens.as_slice() {
+                ["end_header"] => break,
+                ["format", f, ..] => {
+                    format = Some(match *f {
+                        "ascii" => PlyFormat::Ascii,
+                        "binary_little_endian" => PlyFormat::BinaryLittleEndian,
+                        "binary_big_endian" => PlyFormat::BinaryBigEndian,
+                        other => bail!("Unknown PLY format {}", other),
+                    })
+                }
+                ["element", name, count] => {
+                    if *name == "vertex" {
+                        ensure!(element.is_none(), "PLY vertex element must be the first");
+                        vertices = Some(count.parse()?);
+                    }
+                    element = Some(name.to_string());
+                }
+                ["property", "list", ..] => {
+                    ensure!(
+                        element.as_deref() != Some("vertex"),
+                        "PLY vertex can not have a list property"
+                    );
+                }
+                ["property", kind, name] if element.as_deref() == Some("vertex") => {
+                    properties.push(PlyProperty {
+                        name: name.to_string(),
+                        kind: PlyType::parse(kind)?,
+                    });
+                }
+                // comments, obj_info and the properties of the other elements
+                _ => {}
+            }
+        }
+
+        let (Some(format), Some(vertices)) = (format, vertices) else {
+            bail!("PLY header has no format or vertex element");
+        };
+        Ok(Self {
+            format,
+            vertices,
+            properties,
+        })
+    }
+
+    /// read the header of the file
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        Self::read(&mut BufReader::new(File::open(path)?))
+    }
+
+    fn position(&self, names: &[&str]) -> Option<usize> {
+        self.properties
+            .iter()
+            .position(|p| names.contains(&p.name.as_str()))
+    }
+}
+
+/// iterator of the vertices of a PLY file (x y z, red green blue, intensity, nx ny nz).
+/// Integer intensity is normalized to 0.0 ~ 1.0 by the range of its type, float intensity is kept as is
+pub struct PlyReader<R> {
+    header: PlyHeader,
+    reader: R,
+    xyz: [usize; 3],
+    rgb: Option<[usize; 3]>,
+    intensity: Option<usize>,
+    normal: Option<[usize; 3]>,
+    /// byte offset of each property in a binary record
+    offsets: Vec<usize>,
+    record: Vec<u8>,
+    line: String,
+    remaining: usize,
+}
+
+impl PlyReader<BufReader<File>> {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: BufRead> PlyReader<R> {
+    pub fn new(mut reader: R) -> anyhow::Result<Self> {
+        let header = PlyHeader::read(&mut reader)?;
+        let triple = |names: [&[&str]; 3]| match names.map(|n| header.position(n)) {
+            [Some(a), Some(b), Some(c)] => Some([a, b, c]),
+            _ => None,
+        };
+        let Some(xyz) = triple([&["x"], &["y"], &["z"]]) else {
+            bail!("PLY has no x, y or z property");
+        };
+        let rgb = triple([
+            &["red", "diffuse_red"],
+            &["green", "diffuse_green"],
+            &["blue", "diffuse_blue"],
+        ]);
+        let intensity = header.position(&["intensity", "scalar_intensity"]);
+        let normal = triple([&["nx"], &["ny"], &["nz"]]);
+
+        let mut offsets = vec![];
+        let mut offset = 0;
+        for property in header.properties.iter() {
+            offsets.push(offset);
+            offset += property.kind.size();
+        }
+
+        let remaining = header.vertices;
+        Ok(Self {
+            header,
+            reader,
+            xyz,
+            rgb,
+            intensity,
+            normal,
+            offsets,
+            record: vec![0; offset],
+            line: String::new(),
+            remaining,
+        })
+    }
+
+    pub fn header(&self) -> &PlyHeader {
+        &self.header
+    }
+
+    /// read the next vertex into a point
+    fn read_point(&mut self) -> anyhow::Result<Point> {
+        let values: Vec<f64> = match self.header.format {
+            PlyFormat::Ascii => {
+                self.line.clear();
+                ensure!(
+                    self.reader.read_line(&mut self.line)? > 0,
+                    "PLY has fewer vertices than the header"
+                );
+                let values = self
+                    .line
+                    .split_whitespace()
+                    .map(|v| v.parse())
+                    .collect::<Result<Vec<f64>, _>>()?;
+                ensure!(
+                    values.len() >= self.header.properties.len(),
+                    "PLY line has fewer values than the properties"
+                );
+                values
+            }
+            format => {
+                self.reader.read_exact(&mut self.record)?;
+                let big_endian = format == PlyFormat::BinaryBigEndian;
+                self.header
+                    .properties
+                    .iter()
+                    .zip(self.offsets.iter())
+                    .map(|(p, offset)| {
+                        p.kind
+                            .value(&self.record[*offset..*offset + p.kind.size()], big_endian)
+                    })
+                    .collect()
+            }
+        };
+
+        let [x, y, z] = self.xyz;
+        let mut point = Point::new(values[x], values[y], values[z]);
+        if let Some([r, g, b]) = self.rgb {
+            let channel = |i: usize| self.header.properties[i].kind.color(values[i]);
+            point.color = Some(Color::new(channel(r), channel(g), channel(b)));
+        }
+        if let Some(intensity) = self.intensity {
+            let kind = self.header.properties[intensity].kind;
+            point.intensity = Some(kind.normalized(values[intensity]));
+        }
+        if let Some([nx, ny, nz]) = self.normal {
+            point.normal = Vector3::new(values[nx], values[ny], values[nz]).try_normalize(0.);
+        }
+        Ok(point)
+    }
+}
+
+impl<R: BufRead> Iterator for PlyReader<R> {
+    type Item = anyhow::Result<Point>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let point = self.read_point();
+        if point.is_err() {
+            self.remaining = 0;
+        }
+        Some(point)
+    }
+}
+
+/// check if the PLY file can be read by `PlyReader`
+pub fn is_native_ply(path: &Path) -> bool {
+    PlyHeader::open(path).is_ok()
+}
+
+/// read the vertices of an ascii or binary PLY file
+pub fn read_ply(path: &Path) -> anyhow::Result<Vec<Point>> {
+    PlyReader::open(path)?.collect()
+}
+
+/// Write the points to a binary little-endian PLY file.
+/// Color (`red green blue`), intensity and normals (`nx ny nz`) are written if any point has them
+/// (0 for the points without them).
+pub fn write_ply(path: &Path, points: &[Point]) -> anyhow::Result<()> {
+    let has_color = points.iter().any(|p| p.color.is_some());
+    let has_intensity = points.iter().any(|p| p.intensity.is_some());
+    let has_normal = points.iter().any(|p| p.normal.is_some());
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "ply")?;
+    writeln!(writer, "format binary_little_endian 1.0")?;
+    writeln!(writer, "comment pcd-lod {}", env!("CARGO_PKG_VERSION"))?;
+    writeln!(writer, "element vertex {}", points.len())?;
+    for axis in ["x", "y", "z"] {
+        writeln!(writer, "property double {}", axis)?;
+    }
+    if has_color {
+        for channel in ["red", "green", "blue"] {
+            writeln!(writer, "property uchar {}", channel)?;
+        }
+    }
+    if has_intensity {
+        writeln!(writer, "property float intensity")?;
+    }
+    if has_normal {
+        for axis in ["nx", "ny", "nz"] {
+            writeln!(writer, "property float {}", axis)?;
+        }
+    }
+    writeln!(writer, "end_header")?;
+
+    for p in points {
+        for v in p.position.iter() {
+            writer.write_all(&v.to_le_bytes())?;
+        }
+        if has_color {
+            let c = p.color.unwrap_or(Color::new(0, 0, 0));
+            writer.write_all(&[c.r(), c.g(), c.b()])?;
+        }
+        if has_intensity {
+            writer.write_all(&(p.intensity.unwrap_or(0.) as f32).to_le_bytes())?;
+        }
+        if has_normal {
+            for v in p.normal.unwrap_or_default().iter() {
+                writer.write_all(&(*v as f32).to_le_bytes())?;
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn points() -> Vec<Point> {
+        (0..20)
+            .map(|i| {
+                let t = i as f64 * 0.3;
+                let mut p = Point::new(t.cos() * 10., t.sin() * 10., i as f64 * 0.5);
+                p.color = Some(Color::new(i * 10, 255 - i * 10, 7));
+                p.intensity = Some(i as f64 / 20.);
+                p.normal = Some(Vector3::new(t.cos(), t.sin(), 0.));
+                p
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip_ply_with_normals() {
+        let points = points();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        write_ply(file.path(), &points).unwrap();
+
+        let header = PlyHeader::open(file.path()).unwrap();
+        assert_eq!(header.format, PlyFormat::BinaryLittleEndian);
+        assert_eq!(header.vertices, points.len());
+        let read = read_ply(file.path()).unwrap();
+        assert_eq!(read.len(), points.len());
+        for (p, q) in points.iter().zip(read.iter()) {
+            assert_eq!(p.position, q.position);
+            assert_eq!(p.color, q.color);
+            assert!((p.intensity.unwrap() - q.intensity.unwrap()).abs() < 1e-6);
+            let (n, m) = (p.normal.unwrap(), q.normal.unwrap());
+            assert!((n - m).norm() < 1e-6, "{} vs {}", n, m);
+        }
+    }
+
+    #[test]
+    fn read_ascii_ply_with_faces() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            file,
+            "ply
+format ascii 1.0
+comment exported elsewhere
+element vertex 3
+property float x
+property float y
+property float z
+property float nx
+property float ny
+property float nz
+property uchar red
+property uchar green
+property uchar blue
+element face 1
+property list uchar int vertex_indices
+end_header
+0 0 0 0 0 2 255 0 0
+1 0 0 0 0 1 0 255 0
+0 1 0 0 0 1 0 0 255
+3 0 1 2
+"
+        )
+        .unwrap();
+
+        assert!(is_native_ply(file.path()));
+        let read = read_ply(file.path()).unwrap();
+        assert_eq!(read.len(), 3);
+        assert_eq!(read[1].position, nalgebra::Point3::new(1., 0., 0.));
+        assert_eq!(read[2].color, Some(Color::new(0, 0, 255)));
+        // normals are normalized
+        assert_eq!(read[0].normal, Some(Vector3::z()));
+        assert!(read[0].intensity.is_none());
+    }
+
+    #[test]
+    fn read_big_endian_ply() {
+        let mut data = b"ply
+format binary_big_endian 1.0
+element vertex 2
+property double x
+property double y
+property double z
+property ushort intensity
+end_header
+"
+        .to_vec();
+        for (i, v) in [1.5_f64, 2., 3., -1., -2., -3.].iter().enumerate() {
+            data.extend(v.to_be_bytes());
+            if i % 3 == 2 {
+                data.extend((1000_u16 * (i as u16 / 3 + 1)).to_be_bytes());
+            }
+        }
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&data).unwrap();
+
+        let read = read_ply(file.path()).unwrap();
+        assert_eq!(read[0].position, nalgebra::Point3::new(1.5, 2., 3.));
+        assert_eq!(read[1].position, nalgebra::Point3::new(-1., -2., -3.));
+        // ushort intensity over its range
+        assert_eq!(read[0].intensity, Some(1000. / 65535.));
+        assert_eq!(read[1].intensity, Some(2000. / 65535.));
+        assert!(read[0].normal.is_none());
+    }
+}
@@ -1,4 +1,7 @@
-use nalgebra::Point3;
+use std::str::FromStr;
+
+use anyhow::{bail, ensure};
+use nalgebra::{Point3, Vector3};
 use serde::{Deserialize, Serialize};
 
 use crate::prelude::Color;
@@ -9,9 +12,132 @@ pub struct Point {
     pub position: Point3<f64>,
     pub color: Option<Color>,
     pub intensity: Option<f64>,
+    /// LAS classification code (e.g. 2: ground, 5: high vegetation, 6: building)
+    #[serde(default)]
+    pub classification: Option<u8>,
+    /// GPS time of the acquisition
+    #[serde(default)]
+    pub gps_time: Option<f64>,
+    /// return number of the pulse (1-based)
+    #[serde(default)]
+    pub return_number: Option<u8>,
+    /// total number of returns of the pulse
+    #[serde(default)]
+    pub number_of_returns: Option<u8>,
+    /// unit normal vector
+    #[serde(default)]
+    pub normal: Option<Vector3<f64>>,
+    /// radius of the point for splatting (e.g. estimated from the local density)
+    #[serde(default)]
+    pub radius: Option<f64>,
+    /// index of the point in the input (set with `LODOptions::carry_index`)
+    #[serde(default)]
+    pub index: Option<usize>,
+}
+
+/// Parse the field ignoring a trailing non-numeric suffix (e.g. a unit as in `1.5m`)
+fn parse_lenient<T: FromStr>(field: &str) -> Option<T> {
+    field.parse().ok().or_else(|| {
+        field
+            .trim_end_matches(|c: char| !c.is_ascii_digit())
+            .parse()
+            .ok()
+    })
+}
+
+/// the line with the delimiters of `Point::try_parse_delimited` replaced by whitespace
+fn whitespace_delimited(line: &str) -> String {
+    let line = line.replace('"', "");
+    if line.contains(';') || line.contains('\t') {
+        line.replace(',', ".").replace([';', '\t'], " ")
+    } else {
+        line.replace(',', " ")
+    }
+}
+
+/// Column of a text point cloud
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Column {
+    X,
+    Y,
+    Z,
+    R,
+    G,
+    B,
+    Intensity,
+    Nx,
+    Ny,
+    Nz,
+    Classification,
+    GpsTime,
+    /// column not read into the point
+    Ignore,
+}
+
+impl Column {
+    const COUNT: usize = Column::Ignore as usize;
+
+    /// column of the header name (case insensitive), `Ignore` if unknown
+    pub fn from_name(name: &str) -> Self {
+        match name.to_lowercase().as_str() {
+            "x" => Column::X,
+            "y" => Column::Y,
+            "z" => Column::Z,
+            "r" | "red" => Column::R,
+            "g" | "green" => Column::G,
+            "b" | "blue" => Column::B,
+            "i" | "intensity" => Column::Intensity,
+            "nx" => Column::Nx,
+            "ny" => Column::Ny,
+            "nz" => Column::Nz,
+            "class" | "classification" => Column::Classification,
+            "gps_time" | "time" => Column::GpsTime,
+            _ => Column::Ignore,
+        }
+    }
+}
+
+/// Named columns of the lines of a text point cloud (see `Point::try_parse_with_layout`).
+/// The columns past the end of the layout are ignored.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ColumnLayout {
+    columns: Vec<Column>,
+}
+
+impl ColumnLayout {
+    pub fn new(columns: Vec<Column>) -> anyhow::Result<Self> {
+        for axis in [Column::X, Column::Y, Column::Z] {
+            ensure!(columns.contains(&axis), "Layout has no {:?} column", axis);
+        }
+        Ok(Self { columns })
+    }
+
+    /// layout from the column names of the header (e.g. `x y z r g b nx ny nz class gps_time`)
+    pub fn from_header(header: &str) -> anyhow::Result<Self> {
+        Self::new(header.split_whitespace().map(Column::from_name).collect())
+    }
+
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
 }
 
 impl Point {
+    /// point at the position without any attributes
+    pub fn new(x: f64, y: f64, z: f64) -> Self {
+        Point3::new(x, y, z).into()
+    }
+
+    pub fn with_color(mut self, color: Color) -> Self {
+        self.color = Some(color);
+        self
+    }
+
+    pub fn with_intensity(mut self, intensity: f64) -> Self {
+        self.intensity = Some(intensity);
+        self
+    }
+
     pub fn try_parse(line: &str) -> anyhow::Result<Self> {
         let mut split = line.split_whitespace();
         let x = split.next();
@@ -20,31 +146,28 @@ impl Point {
         let r = split.next();
         let g = split.next();
         let b = split.next();
-        let intensity = split.next();
+        // the 7th column is intensity only in `x y z r g b intensity`, with more columns
+        // the layout is unknown (e.g. normals after the color), so see `try_parse_with_layout`
+        let intensity = split.next().filter(|_| split.next().is_none());
         match (x, y, z, r, g, b, intensity) {
             (Some(x), Some(y), Some(z), r, g, b, intensity) => {
                 let x = x.parse()?;
                 let y = y.parse()?;
                 let z = z.parse()?;
 
+                // the trailing fields are optional, so a malformed one is dropped instead of the point
+                let color = |r, g, b| {
+                    Some(Color::new(
+                        parse_lenient(r)?,
+                        parse_lenient(g)?,
+                        parse_lenient(b)?,
+                    ))
+                };
                 let (color, intensity) = match (r, g, b, intensity) {
-                    (Some(r), Some(g), Some(b), Some(intensity)) => {
-                        let r = r.parse()?;
-                        let g = g.parse()?;
-                        let b = b.parse()?;
-                        let intensity = intensity.parse()?;
-                        (Some(Color::new(r, g, b)), Some(intensity))
-                    }
-                    (Some(r), Some(g), Some(b), None) => {
-                        let r = r.parse()?;
-                        let g = g.parse()?;
-                        let b = b.parse()?;
-                        (Some(Color::new(r, g, b)), None)
-                    }
-                    (Some(intensity), _, _, _) => {
-                        let intensity = intensity.parse()?;
-                        (None, Some(intensity))
+                    (Some(r), Some(g), Some(b), intensity) => {
+                        (color(r, g, b), intensity.and_then(parse_lenient))
                     }
+                    (Some(intensity), _, _, _) => (None, parse_lenient(intensity)),
                     _ => (None, None),
                 };
 
@@ -52,12 +175,114 @@ impl Point {
                     position: Point3::new(x, y, z),
                     color,
                     intensity,
+                    classification: None,
+                    gps_time: None,
+                    return_number: None,
+                    number_of_returns: None,
+                    normal: None,
+                    radius: None,
+                    index: None,
                 })
             }
             _ => Err(anyhow::anyhow!("Invalid point format")),
         }
     }
 
+    /// Parse the line by the named columns of the layout, ignoring the other columns
+    pub fn try_parse_with_layout(line: &str, layout: &ColumnLayout) -> anyhow::Result<Self> {
+        let mut values = [None; Column::COUNT];
+        for (column, field) in layout.columns.iter().zip(line.split_whitespace()) {
+            if *column != Column::Ignore {
+                values[*column as usize] = Some(field.parse::<f64>()?);
+            }
+        }
+        let value = |column: Column| values[column as usize];
+        let (Some(x), Some(y), Some(z)) = (value(Column::X), value(Column::Y), value(Column::Z))
+        else {
+            bail!("Missing x, y or z column");
+        };
+        let mut point = Point::new(x, y, z);
+        if let (Some(r), Some(g), Some(b)) = (value(Column::R), value(Column::G), value(Column::B))
+        {
+            point.color = Some(Color::new(r as u8, g as u8, b as u8));
+        }
+        if let (Some(nx), Some(ny), Some(nz)) =
+            (value(Column::Nx), value(Column::Ny), value(Column::Nz))
+        {
+            point.normal = Some(Vector3::new(nx, ny, nz));
+        }
+        point.intensity = value(Column::Intensity);
+        point.classification = value(Column::Classification).map(|c| c as u8);
+        point.gps_time = value(Column::GpsTime);
+        Ok(point)
+    }
+
+    /// Parse a line of delimited text.
+    /// With `;` or tab delimiters (e.g. European CSV), `,` is taken as the decimal point (`1,5;2,3;0,0`),
+    /// otherwise `,` is a delimiter as well as whitespace. Quotes around the fields are ignored.
+    pub fn try_parse_delimited(line: &str) -> anyhow::Result<Self> {
+        Point::try_parse(&whitespace_delimited(line))
+    }
+
+    /// Parse a line of delimited text (see `try_parse_delimited`) by the named columns of the layout
+    pub fn try_parse_delimited_with_layout(
+        line: &str,
+        layout: &ColumnLayout,
+    ) -> anyhow::Result<Self> {
+        Point::try_parse_with_layout(&whitespace_delimited(line), layout)
+    }
+
+    /// Parse the lines (see `try_parse_delimited` for the delimiters) into points.
+    /// Blank lines are skipped, and the first `max_errors` failures are returned
+    /// as (line number starting from 1, offending line and reason).
+    pub fn try_parse_batch<'a>(
+        lines: impl IntoIterator<Item = &'a str>,
+        max_errors: usize,
+    ) -> Result<Vec<Point>, Vec<(usize, String)>> {
+        let mut points = vec![];
+        let mut errors = vec![];
+        for (i, line) in lines.into_iter().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match Point::try_parse_delimited(line) {
+                Ok(point) => points.push(point),
+                Err(e) => {
+                    errors.push((i + 1, format!("{:?}: {}", line, e)));
+                    if errors.len() >= max_errors {
+                        break;
+                    }
+                }
+            }
+        }
+        match errors.is_empty() {
+            true => Ok(points),
+            false => Err(errors),
+        }
+    }
+
+    /// Format the point as a text line (`x y z [r g b] [intensity]`) readable by `try_parse`,
+    /// with `decimals` fractional digits for each coordinate.
+    pub fn to_line(&self, decimals: usize) -> String {
+        let p = &self.position;
+        let mut line = format!(
+            "{:.*} {:.*} {:.*}",
+            decimals, p.x, decimals, p.y, decimals, p.z
+        );
+        if let Some(c) = &self.color {
+            line.push_str(&format!(" {} {} {}", c.r(), c.g(), c.b()));
+        }
+        if let Some(intensity) = self.intensity {
+            line.push_str(&format!(" {}", intensity));
+        }
+        line
+    }
+
+    /// position as `[x, y, z]`
+    pub fn as_position_array(&self) -> [f64; 3] {
+        self.position.coords.into()
+    }
+
     pub fn distance(&self, other: &Self) -> f64 {
         let d = self.distance_squared(other);
         d.sqrt()
@@ -67,4 +292,213 @@ impl Point {
         let d = self.position - other.position;
         d.magnitude_squared()
     }
+
+    /// Interpolate between the points by `t` (0: self, 1: other).
+    /// Color is interpolated in linear RGB space, and the other attributes
+    /// are `None` unless both points have them.
+    pub fn lerp(&self, other: &Point, t: f64) -> Point {
+        let lerp = |a: f64, b: f64| a + (b - a) * t;
+        let both = |a: Option<f64>, b: Option<f64>| a.zip(b).map(|(a, b)| lerp(a, b));
+        let color = self.color.zip(other.color).map(|(a, b)| {
+            let (a, b) = (a.to_linear(), b.to_linear());
+            Color::from_linear([0, 1, 2].map(|i| lerp(a[i], b[i])))
+        });
+        let normal = self
+            .normal
+            .zip(other.normal)
+            .and_then(|(a, b)| a.lerp(&b, t).try_normalize(f64::EPSILON));
+        let same = |a: Option<u8>, b: Option<u8>| a.filter(|a| Some(*a) == b);
+
+        Point {
+            position: self.position + (other.position - self.position) * t,
+            color,
+            intensity: both(self.intensity, other.intensity),
+            classification: same(self.classification, other.classification),
+            gps_time: both(self.gps_time, other.gps_time),
+            return_number: same(self.return_number, other.return_number),
+            number_of_returns: same(self.number_of_returns, other.number_of_returns),
+            normal,
+            radius: both(self.radius, other.radius),
+            index: None,
+        }
+    }
+}
+
+impl From<Point3<f64>> for Point {
+    fn from(position: Point3<f64>) -> Self {
+        Point {
+            position,
+            color: None,
+            intensity: None,
+            classification: None,
+            gps_time: None,
+            return_number: None,
+            number_of_returns: None,
+            normal: None,
+            radius: None,
+            index: None,
+        }
+    }
+}
+
+impl From<[f64; 3]> for Point {
+    fn from(position: [f64; 3]) -> Self {
+        Point::from(Point3::from(position))
+    }
+}
+
+impl From<(Point3<f64>, Color)> for Point {
+    fn from((position, color): (Point3<f64>, Color)) -> Self {
+        Point {
+            color: Some(color),
+            ..Point::from(position)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_position_array() {
+        let position = [1.5, -2.25, 1e6 + 0.125];
+        let p = Point::from(position);
+        assert_eq!(p.as_position_array(), position);
+        assert_eq!(p.position, Point3::new(1.5, -2.25, 1e6 + 0.125));
+        assert!(p.color.is_none() && p.intensity.is_none());
+
+        let colored = Point::from((Point3::from(position), Color::new(1, 2, 3)));
+        assert_eq!(colored.as_position_array(), position);
+        assert_eq!(colored.color, Some(Color::new(1, 2, 3)));
+        assert_eq!(
+            Point::from(colored.as_position_array()).position,
+            colored.position
+        );
+    }
+
+    #[test]
+    fn parse_scientific_notation() {
+        let p = Point::try_parse("+1.234e3 1.5E-2 -2e0 +255 +0 12").unwrap();
+        assert_eq!(p.position, Point3::new(1234., 0.015, -2.));
+        assert_eq!(p.color, Some(Color::new(255, 0, 12)));
+    }
+
+    #[test]
+    fn stray_characters_in_trailing_fields() {
+        let p = Point::try_parse("1 2 3 255 128u 0 0.5m").unwrap();
+        assert_eq!(p.position, Point3::new(1., 2., 3.));
+        assert_eq!(p.color, Some(Color::new(255, 128, 0)));
+        assert_eq!(p.intensity, Some(0.5));
+
+        // the position is kept even if the trailing fields are not numbers
+        let p = Point::try_parse("1 2 3 red green blue").unwrap();
+        assert_eq!(p.position, Point3::new(1., 2., 3.));
+        assert_eq!(p.color, None);
+        let p = Point::try_parse("1 2 3 n/a").unwrap();
+        assert_eq!(p.intensity, None);
+
+        assert!(Point::try_parse("1 2m 3").is_err());
+    }
+
+    #[test]
+    fn trailing_columns_are_ignored() {
+        let line = "1.5 2.5 3.5 10 20 30 0 0 1 2 12345.5 7";
+        let p = Point::try_parse(line).unwrap();
+        assert_eq!(p.position, Point3::new(1.5, 2.5, 3.5));
+        assert_eq!(p.color, Some(Color::new(10, 20, 30)));
+        assert_eq!(p.intensity, None);
+
+        let layout = ColumnLayout::from_header("x y z r g b nx ny nz class gps_time").unwrap();
+        let p = Point::try_parse_with_layout(line, &layout).unwrap();
+        assert_eq!(p.position, Point3::new(1.5, 2.5, 3.5));
+        assert_eq!(p.color, Some(Color::new(10, 20, 30)));
+        assert_eq!(p.normal, Some(Vector3::new(0., 0., 1.)));
+        assert_eq!(p.classification, Some(2));
+        assert_eq!(p.gps_time, Some(12345.5));
+        assert_eq!(p.intensity, None);
+
+        assert!(ColumnLayout::from_header("x y r g b").is_err());
+
+        let layout = ColumnLayout::from_header("i x y z").unwrap();
+        let p = Point::try_parse_delimited_with_layout("0,5;1;2;3", &layout).unwrap();
+        assert_eq!(p.position, Point3::new(1., 2., 3.));
+        assert_eq!(p.intensity, Some(0.5));
+    }
+
+    #[test]
+    fn build_point() {
+        let p = Point::new(1., 2., 3.)
+            .with_color(Color::new(10, 20, 30))
+            .with_intensity(0.5);
+        assert_eq!(p.position, Point3::new(1., 2., 3.));
+        assert_eq!(p.color, Some(Color::new(10, 20, 30)));
+        assert_eq!(p.intensity, Some(0.5));
+        assert_eq!(p.classification, None);
+        assert_eq!(p.normal, None);
+        assert_eq!(p.index, None);
+    }
+
+    #[test]
+    fn batch_errors_with_line_numbers() {
+        let text = "0 0 0\n1,2,3,255,0,0\n\n4 five 6\n7 8 9 0.5\nx\n";
+        let errors = Point::try_parse_batch(text.lines(), 10).unwrap_err();
+        let lines: Vec<usize> = errors.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![4, 6]);
+        assert!(errors[0].1.contains("4 five 6"));
+        assert!(errors[0].1.contains("invalid float literal"));
+
+        let errors = Point::try_parse_batch(text.lines(), 1).unwrap_err();
+        assert_eq!(errors.len(), 1);
+
+        let points = Point::try_parse_batch("0 0 0\n1 1 1 0.5".lines(), 10).unwrap();
+        assert_eq!(points.len(), 2);
+    }
+
+    #[test]
+    fn format_line_with_decimals() {
+        let p = Point::try_parse("123456.78951 -0.0004 12 10 20 30 0.25").unwrap();
+        assert_eq!(p.to_line(3), "123456.790 -0.000 12.000 10 20 30 0.25");
+        let p = Point::from(Point3::new(1.23456, 2., 3.));
+        assert_eq!(p.to_line(1), "1.2 2.0 3.0");
+        assert_eq!(p.to_line(0), "1 2 3");
+    }
+
+    #[test]
+    fn parse_locale_formatted_numbers() {
+        let p = Point::try_parse_delimited("1,5;2,3;0,0").unwrap();
+        assert_eq!(p.position, Point3::new(1.5, 2.3, 0.));
+        let p = Point::try_parse_delimited("\"1,5\"\t\"-2,25\"\t3\t10\t20\t30").unwrap();
+        assert_eq!(p.position, Point3::new(1.5, -2.25, 3.));
+        assert_eq!(p.color, Some(Color::new(10, 20, 30)));
+
+        // commas are delimiters without `;` or tab
+        let p = Point::try_parse_delimited("1.5,2.3,0.0,0.5").unwrap();
+        assert_eq!(p.position, Point3::new(1.5, 2.3, 0.));
+        assert_eq!(p.intensity, Some(0.5));
+        let p = Point::try_parse_delimited("\"1.5\", \"2\", \"3\"").unwrap();
+        assert_eq!(p.position, Point3::new(1.5, 2., 3.));
+    }
+
+    #[test]
+    fn lerp_midpoint() {
+        let a = Point::try_parse("0 0 0 255 0 0 0.2").unwrap();
+        let b = Point::try_parse("2 4 -6 0 0 255 0.6").unwrap();
+
+        let m = a.lerp(&b, 0.5);
+        assert_eq!(m.position, Point3::new(1., 2., -3.));
+        assert!((m.intensity.unwrap() - 0.4).abs() < 1e-9);
+        // half of the linear intensity is brighter than half of the sRGB value
+        let c = m.color.unwrap();
+        assert_eq!((c.r(), c.g(), c.b()), (188, 0, 188));
+        assert_eq!(c, Color::from_linear([0.5, 0., 0.5]));
+
+        assert_eq!(a.lerp(&b, 0.).position, a.position);
+        assert_eq!(a.lerp(&b, 1.).position, b.position);
+
+        // attributes missing in either point are dropped
+        let c = Point::from(Point3::new(1., 1., 1.));
+        let m = a.lerp(&c, 0.5);
+        assert!(m.color.is_none() && m.intensity.is_none());
+    }
 }
@@ -0,0 +1,1008 @@
+use std::collections::HashMap;
+
+use anyhow::ensure;
+
+use nalgebra::{Matrix3, Matrix4, Point3, Vector3, Vector4};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rayon::iter::{
+    IndexedParallelIterator, IntoParallelRefIterator, IntoParallelRefMutIterator, ParallelIterator,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::prelude::{BoundingBox, Color, KdTree, Point};
+
+/// Statistics of a point cloud
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PointCloudStatistics {
+    pub count: usize,
+    pub bounds: BoundingBox,
+    pub has_color: bool,
+    /// (min, max) of intensity if any point has intensity
+    pub intensity_range: Option<(f64, f64)>,
+    /// (min, max) of GPS time if any point has GPS time
+    pub time_range: Option<(f64, f64)>,
+    /// number of points per unit volume of the bounding box (None if the box has no volume)
+    pub density: Option<f64>,
+}
+
+/// Statistics of the nearest-neighbor distances from one point cloud to another
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct CloudDistanceStats {
+    pub mean: f64,
+    pub median: f64,
+    pub max: f64,
+    pub rms: f64,
+}
+
+/// Geometric features of the neighborhood of a point (Weinmann et al.) from the eigenvalues
+/// `l1 >= l2 >= l3` of the covariance of the neighbors, e.g. for the classification of the points.
+/// All the features are 0 with fewer than 3 neighbors or without extent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PointFeatures {
+    /// number of the points in the neighborhood (the point itself included)
+    pub neighbors: usize,
+    /// `(l1 - l2) / l1`, high along edges and wires
+    pub linearity: f64,
+    /// `(l2 - l3) / l1`, high on surfaces
+    pub planarity: f64,
+    /// `l3 / l1`, high in volumetric scatter (e.g. vegetation)
+    pub sphericity: f64,
+    /// change of curvature `l3 / (l1 + l2 + l3)`, 0 on a plane and 1/3 at most
+    pub curvature: f64,
+}
+
+impl PointFeatures {
+    /// features of the eigenvalues of the covariance in any order
+    fn from_eigenvalues(neighbors: usize, eigenvalues: &Vector3<f64>) -> Self {
+        let mut l = [eigenvalues.x, eigenvalues.y, eigenvalues.z].map(|v| v.max(0.));
+        l.sort_by(|a, b| b.total_cmp(a));
+        let [l1, l2, l3] = l;
+        let sum = l1 + l2 + l3;
+        if neighbors < 3 || l1 <= 0. {
+            return Self {
+                neighbors,
+                ..Default::default()
+            };
+        }
+        Self {
+            neighbors,
+            linearity: (l1 - l2) / l1,
+            planarity: (l2 - l3) / l1,
+            sphericity: l3 / l1,
+            curvature: l3 / sum,
+        }
+    }
+}
+
+/// Uniform random sample of a fixed size drawn from a stream in a single pass (Algorithm R)
+#[derive(Debug, Clone)]
+pub struct Reservoir<T> {
+    capacity: usize,
+    seen: usize,
+    items: Vec<T>,
+    rng: StdRng,
+}
+
+impl<T> Reservoir<T> {
+    pub fn new(capacity: usize, seed: u64) -> Self {
+        Self {
+            capacity,
+            seen: 0,
+            items: Vec::with_capacity(capacity),
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// offer the next item of the stream
+    pub fn push(&mut self, item: T) {
+        if self.items.len() < self.capacity {
+            self.items.push(item);
+        } else {
+            let i = self.rng.gen_range(0..=self.seen);
+            if i < self.capacity {
+                self.items[i] = item;
+            }
+        }
+        self.seen += 1;
+    }
+
+    /// number of the items offered so far
+    pub fn seen(&self) -> usize {
+        self.seen
+    }
+
+    pub fn into_vec(self) -> Vec<T> {
+        self.items
+    }
+}
+
+/// PointCloud struct that holds a set of points
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PointCloud {
+    pub points: Vec<Point>,
+}
+
+impl PointCloud {
+    pub fn new(points: Vec<Point>) -> Self {
+        Self { points }
+    }
+
+    pub fn points(&self) -> &Vec<Point> {
+        &self.points
+    }
+
+    pub fn into_points(self) -> Vec<Point> {
+        self.points
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Compute the bounding box of the points
+    pub fn bounds(&self) -> BoundingBox {
+        BoundingBox::from_points_parallel(&self.points)
+    }
+
+    /// Compute statistics of the point cloud
+    pub fn statistics(&self) -> PointCloudStatistics {
+        let bounds = self.bounds();
+        let has_color = self.points.iter().any(|p| p.color.is_some());
+        let intensity_range = value_range(self.points.iter().filter_map(|p| p.intensity));
+        let time_range = value_range(self.points.iter().filter_map(|p| p.gps_time));
+        let volume = bounds.volume();
+        let density = if self.is_empty() || volume <= 0. {
+            None
+        } else {
+            Some(self.len() as f64 / volume)
+        };
+        PointCloudStatistics {
+            count: self.len(),
+            bounds,
+            has_color,
+            intensity_range,
+            time_range,
+            density,
+        }
+    }
+
+    /// Create a new point cloud with the points that satisfy the predicate.
+    /// The predicate is evaluated in parallel.
+    pub fn filter<F>(&self, pred: F) -> PointCloud
+    where
+        F: Fn(&Point) -> bool + Sync + Send,
+    {
+        let points = self
+            .points
+            .par_iter()
+            .filter(|p| pred(p))
+            .cloned()
+            .collect();
+        Self { points }
+    }
+
+    /// Create a new point cloud with the points inside the bounding box (boundaries included).
+    pub fn crop(&self, bbox: &BoundingBox) -> PointCloud {
+        self.filter(|p| bbox.contains(&p.position))
+    }
+
+    /// Create a new point cloud with the points inside the sphere (boundary included).
+    pub fn crop_sphere(&self, center: Point3<f64>, radius: f64) -> PointCloud {
+        let center = Point::from(center);
+        let radius_squared = radius * radius;
+        self.filter(|p| p.distance_squared(&center) <= radius_squared)
+    }
+
+    /// Create a new point cloud with the points whose coordinate on `axis` (0: x, 1: y, 2: z)
+    /// is within `[min, max]`, e.g. a horizontal section of a building with `axis` 2.
+    pub fn slice(&self, axis: usize, min: f64, max: f64) -> anyhow::Result<PointCloud> {
+        ensure!(axis < 3, "Axis must be 0, 1 or 2: {}", axis);
+        Ok(self.filter(|p| (min..=max).contains(&p.position[axis])))
+    }
+
+    /// Create a new point cloud with the coordinate on `axis` set to `value`,
+    /// e.g. to flatten a slice into a floor plan.
+    pub fn project(&self, axis: usize, value: f64) -> anyhow::Result<PointCloud> {
+        ensure!(axis < 3, "Axis must be 0, 1 or 2: {}", axis);
+        let points = self
+            .points
+            .par_iter()
+            .map(|p| {
+                let mut p = p.clone();
+                p.position[axis] = value;
+                p
+            })
+            .collect();
+        Ok(Self { points })
+    }
+
+    /// Create a new point cloud with the points whose classification is one of `classes`.
+    /// Points without classification are dropped.
+    pub fn filter_by_classification(&self, classes: &[u8]) -> PointCloud {
+        self.filter(|p| {
+            p.classification
+                .map(|c| classes.contains(&c))
+                .unwrap_or(false)
+        })
+    }
+
+    /// Merge points closer than `tolerance` into a single point.
+    /// Each cluster is replaced by a point with the averaged position, the averaged color in linear RGB and the averaged intensity.
+    /// Other attributes are taken from the first point of the cluster.
+    pub fn merge_near_duplicates(&self, tolerance: f64) -> PointCloud {
+        let tree = KdTree::new(&self.points);
+        let mut merged = vec![false; self.points.len()];
+        let mut points = vec![];
+
+        for (i, p) in self.points.iter().enumerate() {
+            if merged[i] {
+                continue;
+            }
+            let indices: Vec<usize> = tree
+                .radius_search(&p.position, tolerance)
+                .into_iter()
+                .filter(|j| !merged[*j])
+                .collect();
+            indices.iter().for_each(|j| merged[*j] = true);
+            let cluster: Vec<&Point> = indices.iter().map(|j| &self.points[*j]).collect();
+            let n = cluster.len() as f64;
+
+            let position = cluster
+                .iter()
+                .fold(nalgebra::Vector3::zeros(), |acc, q| acc + q.position.coords)
+                / n;
+            let colors: Vec<[f64; 3]> = cluster
+                .iter()
+                .filter_map(|q| q.color.map(|c| c.to_linear()))
+                .collect();
+            let color = (!colors.is_empty()).then(|| {
+                let sum = colors.iter().fold([0.; 3], |acc, c| {
+                    [acc[0] + c[0], acc[1] + c[1], acc[2] + c[2]]
+                });
+                Color::from_linear(sum.map(|c| c / colors.len() as f64))
+            });
+            let intensities: Vec<f64> = cluster.iter().filter_map(|q| q.intensity).collect();
+            let intensity = (!intensities.is_empty())
+                .then(|| intensities.iter().sum::<f64>() / intensities.len() as f64);
+
+            points.push(Point {
+                position: position.into(),
+                color,
+                intensity,
+                ..p.clone()
+            });
+        }
+
+        Self { points }
+    }
+
+    /// uniform random sample of `n` points (all points if fewer), reproducible with `seed`
+    pub fn reservoir_sample(&self, n: usize, seed: u64) -> PointCloud {
+        let mut reservoir = Reservoir::new(n, seed);
+        self.points.iter().for_each(|p| reservoir.push(p.clone()));
+        PointCloud::new(reservoir.into_vec())
+    }
+
+    /// uniform random sample of at most `side * side` points (reproducible),
+    /// so that `Encoder::encode_8bit` packs the cloud into a single image of `side x side` (smaller with fewer points)
+    pub fn downsample_to_fit(&self, side: u32) -> PointCloud {
+        let budget = side as usize * side as usize;
+        self.reservoir_sample(budget, 0)
+    }
+
+    /// Create a new point cloud with the points captured between `min` and `max` (inclusive) of GPS time.
+    /// Points without GPS time are dropped.
+    pub fn filter_by_time(&self, min: f64, max: f64) -> PointCloud {
+        self.filter(|p| p.gps_time.is_some_and(|t| (min..=max).contains(&t)))
+    }
+
+    /// Create a new point cloud with the first returns of each pulse.
+    /// Points without return number are dropped.
+    pub fn filter_first_returns(&self) -> PointCloud {
+        self.filter(|p| p.return_number == Some(1))
+    }
+
+    /// Apply the affine transform (e.g. an alignment from registration) to the points.
+    /// Normals are transformed by the inverse transpose and normalized.
+    pub fn transform(&mut self, matrix: &Matrix4<f64>) {
+        let normal_matrix = matrix
+            .fixed_view::<3, 3>(0, 0)
+            .try_inverse()
+            .map(|m| m.transpose());
+        self.points.par_iter_mut().for_each(|p| {
+            p.position = matrix.transform_point(&p.position);
+            p.normal = p
+                .normal
+                .zip(normal_matrix)
+                .and_then(|(n, m)| (m * n).try_normalize(f64::EPSILON));
+        });
+    }
+
+    /// Set the radius of each point to the mean distance to its k nearest neighbors
+    pub fn estimate_point_radii(&mut self, k: usize) {
+        let tree = KdTree::new(&self.points);
+        let radii: Vec<Option<f64>> = self
+            .points
+            .par_iter()
+            .enumerate()
+            .map(|(i, p)| {
+                let neighbors: Vec<f64> = tree
+                    .k_nearest(&p.position, k + 1)
+                    .into_iter()
+                    .filter(|(j, _)| *j != i)
+                    .take(k)
+                    .map(|(_, d)| d)
+                    .collect();
+                match neighbors.is_empty() {
+                    true => None,
+                    false => Some(neighbors.iter().sum::<f64>() / neighbors.len() as f64),
+                }
+            })
+            .collect();
+        self.points
+            .iter_mut()
+            .zip(radii)
+            .for_each(|(p, radius)| p.radius = radius);
+    }
+
+    /// Heuristic position of the scanner of a terrestrial scan (e.g. to orient normals).
+    /// The scanner sees the surrounding surfaces at similar distances, so this is the center of
+    /// the least squares sphere through the points. If the fit is degenerate (e.g. a flat cloud),
+    /// the centroid raised by the largest extent of the bounding box is used instead.
+    pub fn estimate_viewpoint(&self) -> Point3<f64> {
+        if self.is_empty() {
+            return Point3::origin();
+        }
+        let n = self.len() as f64;
+        let centroid = self
+            .points
+            .iter()
+            .fold(Vector3::zeros(), |acc, p| acc + p.position.coords)
+            / n;
+        let max_size = self.bounds().max_size();
+        let fallback = Point3::from(centroid + Vector3::z() * max_size);
+
+        // |p|^2 = 2 c . p + d around the centroid for the center c
+        let (ata, atb) =
+            self.points
+                .iter()
+                .fold((Matrix4::zeros(), Vector4::zeros()), |(ata, atb), p| {
+                    let q = p.position.coords - centroid;
+                    let row = Vector4::new(2. * q.x, 2. * q.y, 2. * q.z, 1.);
+                    (ata + row * row.transpose(), atb + row * q.norm_squared())
+                });
+        match ata.lu().solve(&atb) {
+            Some(u) if u.iter().all(|v| v.is_finite()) => {
+                let center = u.xyz();
+                // a nearly flat cloud fits a huge sphere
+                match center.norm() < max_size * 2. {
+                    true => Point3::from(centroid + center),
+                    false => fallback,
+                }
+            }
+            _ => fallback,
+        }
+    }
+
+    /// Set the normal of each point to the direction of least variance of its k nearest neighbors,
+    /// oriented toward the viewpoint (`estimate_viewpoint` if `None`)
+    pub fn estimate_normals(&mut self, k: usize, viewpoint: Option<Point3<f64>>) {
+        let viewpoint = viewpoint.unwrap_or_else(|| self.estimate_viewpoint());
+        let tree = KdTree::new(&self.points);
+        let normals: Vec<Option<Vector3<f64>>> = self
+            .points
+            .par_iter()
+            .map(|p| {
+                let neighbors = tree.k_nearest(&p.position, k);
+                if neighbors.len() < 3 {
+                    return None;
+                }
+                let indices: Vec<usize> = neighbors.iter().map(|(j, _)| *j).collect();
+                let eigen = covariance(&self.points, &indices).symmetric_eigen();
+                let normal = eigen
+                    .eigenvectors
+                    .column(eigen.eigenvalues.imin())
+                    .try_normalize(f64::EPSILON)?;
+                match normal.dot(&(viewpoint - p.position)) < 0. {
+                    true => Some(-normal),
+                    false => Some(normal),
+                }
+            })
+            .collect();
+        self.points
+            .iter_mut()
+            .zip(normals)
+            .for_each(|(p, normal)| p.normal = normal);
+    }
+
+    /// Geometric features of each point from its neighbors within `radius`,
+    /// call with several radii for the features of multiple scales
+    pub fn compute_features(&self, radius: f64) -> Vec<PointFeatures> {
+        let tree = KdTree::new(&self.points);
+        self.points
+            .par_iter()
+            .map(|p| {
+                let neighbors = tree.radius_search(&p.position, radius);
+                let eigenvalues = covariance(&self.points, &neighbors).symmetric_eigenvalues();
+                PointFeatures::from_eigenvalues(neighbors.len(), &eigenvalues)
+            })
+            .collect()
+    }
+
+    /// Create a new point cloud reordered so that any prefix is a spatially uniform subsample.
+    /// Points are sorted in Morton order, then interleaved by the bit-reversed index.
+    pub fn progressive_order(&self) -> PointCloud {
+        let n = self.points.len();
+        if n < 2 {
+            return self.clone();
+        }
+
+        let bounds = self.bounds();
+        let size = bounds.size().map(|v| if v > 0. { v } else { 1. });
+        let mut codes: Vec<(u64, usize)> = self
+            .points
+            .iter()
+            .enumerate()
+            .map(|(i, p)| {
+                let v = (p.position - bounds.min()).component_div(&size) * 1023.;
+                (morton_code(v.x as u64, v.y as u64, v.z as u64), i)
+            })
+            .collect();
+        codes.sort_unstable();
+
+        let bits = usize::BITS - (n - 1).leading_zeros();
+        let points = (0..1_usize << bits)
+            .map(|i| i.reverse_bits() >> (usize::BITS - bits))
+            .filter(|i| *i < n)
+            .map(|i| self.points[codes[i].1].clone())
+            .collect();
+        Self { points }
+    }
+}
+
+/// covariance of the positions of the points at the indices around their mean
+fn covariance(points: &[Point], indices: &[usize]) -> Matrix3<f64> {
+    let n = indices.len() as f64;
+    let mean = indices
+        .iter()
+        .fold(Vector3::zeros(), |acc, j| acc + points[*j].position.coords)
+        / n;
+    indices.iter().fold(Matrix3::zeros(), |acc, j| {
+        let d = points[*j].position.coords - mean;
+        acc + d * d.transpose()
+    })
+}
+
+/// (min, max) of the values (`None` if empty)
+fn value_range(values: impl Iterator<Item = f64>) -> Option<(f64, f64)> {
+    values.fold(None, |range, v| match range {
+        Some((min, max)) => Some((v.min(min), v.max(max))),
+        None => Some((v, v)),
+    })
+}
+
+/// interleave the lower 10 bits of x, y, z
+fn morton_code(x: u64, y: u64, z: u64) -> u64 {
+    (0..10).fold(0, |code, bit| {
+        code | ((x >> bit) & 1) << (bit * 3)
+            | ((y >> bit) & 1) << (bit * 3 + 1)
+            | ((z >> bit) & 1) << (bit * 3 + 2)
+    })
+}
+
+/// Set the color of each sample to the dominant color of the input points nearest to it,
+/// so that a sample represents the color of its region rather than its own color.
+pub fn assign_dominant_colors(samples: &mut [Point], inputs: &[Point]) {
+    if samples.is_empty() {
+        return;
+    }
+    let tree = KdTree::new(samples);
+    let nearest: Vec<Option<(usize, Color)>> = inputs
+        .par_iter()
+        .map(|p| {
+            let color = p.color?;
+            tree.nearest(&p.position).map(|(i, _)| (i, color))
+        })
+        .collect();
+
+    let mut regions = vec![vec![]; samples.len()];
+    for (i, color) in nearest.into_iter().flatten() {
+        regions[i].push(color);
+    }
+    samples
+        .iter_mut()
+        .zip(regions)
+        .filter(|(_, colors)| !colors.is_empty())
+        .for_each(|(sample, colors)| sample.color = Some(dominant_color(&colors)));
+}
+
+/// Average (in linear RGB) of the most frequent bin of the colors (16 levels per channel)
+fn dominant_color(colors: &[Color]) -> Color {
+    let mut bins: HashMap<(u8, u8, u8), Vec<&Color>> = HashMap::new();
+    for c in colors {
+        bins.entry((c.r() >> 4, c.g() >> 4, c.b() >> 4))
+            .or_default()
+            .push(c);
+    }
+    let (_, bin) = bins
+        .into_iter()
+        .max_by(|(k0, v0), (k1, v1)| v0.len().cmp(&v1.len()).then(k1.cmp(k0)))
+        .unwrap();
+    Color::linear_average(bin).unwrap()
+}
+
+/// Distance from each point of `a` to its nearest point in `b` (cloud-to-cloud distance),
+/// e.g. to measure how far a sampled cloud deviates from the original.
+/// All statistics are zero if `a` or `b` is empty.
+pub fn cloud_to_cloud_distance(a: &PointCloud, b: &PointCloud) -> CloudDistanceStats {
+    if a.is_empty() || b.is_empty() {
+        return CloudDistanceStats::default();
+    }
+    let tree = KdTree::new(b.points());
+    let mut distances: Vec<f64> = a
+        .points()
+        .par_iter()
+        .filter_map(|p| tree.nearest(&p.position).map(|(_, d)| d))
+        .collect();
+    distances.sort_by(|d0, d1| d0.total_cmp(d1));
+
+    let n = distances.len();
+    let median = if n.is_multiple_of(2) {
+        (distances[n / 2 - 1] + distances[n / 2]) * 0.5
+    } else {
+        distances[n / 2]
+    };
+    CloudDistanceStats {
+        mean: distances.iter().sum::<f64>() / n as f64,
+        median,
+        max: distances[n - 1],
+        rms: (distances.iter().map(|d| d * d).sum::<f64>() / n as f64).sqrt(),
+    }
+}
+
+impl From<Vec<Point>> for PointCloud {
+    fn from(points: Vec<Point>) -> Self {
+        Self::new(points)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid_cloud() -> PointCloud {
+        let mut points = vec![];
+        for z in 0..4 {
+            for y in 0..4 {
+                for x in 0..4 {
+                    let (r, g, b) = if x < 2 { (255, 0, 0) } else { (0, 0, 255) };
+                    let line = format!("{} {} {} {} {} {}", x, y, z, r, g, b);
+                    points.push(Point::try_parse(&line).unwrap());
+                }
+            }
+        }
+        PointCloud::new(points)
+    }
+
+    #[test]
+    fn crop_to_sub_box() {
+        let cloud = grid_cloud();
+        let bbox = BoundingBox::new(Point3::new(0., 0., 0.), Point3::new(1., 1., 1.));
+        let cropped = cloud.crop(&bbox);
+        assert_eq!(cropped.len(), 8);
+        assert!(cropped.points().iter().all(|p| bbox.contains(&p.position)));
+    }
+
+    #[test]
+    fn crop_cube_to_sphere() {
+        let cloud = grid_cloud();
+        let center = Point3::new(1.5, 1.5, 1.5);
+        let cropped = cloud.crop_sphere(center, 1.5);
+        assert!(!cropped.is_empty());
+        assert!(cropped.len() < cloud.len());
+        // corners of the cube are sqrt(3) * 1.5 away from the center
+        let corners = [0., 3.];
+        assert!(cropped.points().iter().all(|p| {
+            !(corners.contains(&p.position.x)
+                && corners.contains(&p.position.y)
+                && corners.contains(&p.position.z))
+        }));
+        assert!(cropped
+            .points()
+            .iter()
+            .all(|p| (p.position - center).norm() <= 1.5));
+    }
+
+    #[test]
+    fn slice_cube_to_band() {
+        let cloud = grid_cloud();
+        let band = cloud.slice(2, 0.5, 1.5).unwrap();
+        assert_eq!(band.len(), 16);
+        assert!(band.points().iter().all(|p| p.position.z == 1.));
+        assert_eq!(cloud.slice(0, 3., 3.).unwrap().len(), 16);
+        assert!(cloud.slice(1, 4., 5.).unwrap().is_empty());
+
+        let plan = cloud.slice(2, 0.5, 2.5).unwrap().project(2, 0.).unwrap();
+        assert_eq!(plan.len(), 32);
+        assert!(plan.points().iter().all(|p| p.position.z == 0.));
+
+        assert!(cloud.slice(3, 0., 1.).is_err());
+        assert!(cloud.project(3, 0.).is_err());
+    }
+
+    #[test]
+    fn statistics() {
+        let stats = grid_cloud().statistics();
+        assert_eq!(stats.count, 64);
+        assert_eq!(stats.bounds.min, Point3::new(0., 0., 0.));
+        assert_eq!(stats.bounds.max, Point3::new(3., 3., 3.));
+        assert!(stats.has_color);
+        assert!(stats.intensity_range.is_none());
+        assert!(stats.time_range.is_none());
+        assert_eq!(stats.density, Some(64. / 27.));
+    }
+
+    #[test]
+    fn merge_near_duplicates() {
+        let cloud = grid_cloud();
+        let mut points = cloud.points().clone();
+        // duplicate every point 1e-4 apart with a different color
+        points.extend(cloud.points().iter().map(|p| Point {
+            position: p.position + nalgebra::Vector3::new(1e-4, 0., 0.),
+            color: Some(Color::new(0, 255, 0)),
+            ..p.clone()
+        }));
+        let merged = PointCloud::new(points).merge_near_duplicates(1e-3);
+        assert_eq!(merged.len(), cloud.len());
+
+        let p = &merged.points()[0];
+        assert!((p.position.x - 0.5e-4).abs() < 1e-9);
+        // average of red and green in linear space is brighter than the sRGB average
+        let c = p.color.unwrap();
+        assert_eq!(c.r(), c.g());
+        assert!(c.r() > 128);
+    }
+
+    #[test]
+    fn filter_by_color() {
+        let cloud = grid_cloud();
+        let red = cloud.filter(|p| p.color.map(|c| c.r() == 255).unwrap_or(false));
+        assert_eq!(red.len(), 32);
+        assert!(red.points().iter().all(|p| p.position.x < 2.));
+    }
+
+    #[test]
+    fn filter_ground_only() {
+        let mut cloud = grid_cloud();
+        let classes = [Some(2), Some(5), Some(6), None];
+        cloud
+            .points
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, p)| p.classification = classes[i % classes.len()]);
+        let ground = cloud.filter_by_classification(&[2]);
+        assert_eq!(ground.len(), 16);
+        assert!(ground.points().iter().all(|p| p.classification == Some(2)));
+    }
+
+    #[test]
+    fn filter_first_returns() {
+        let mut cloud = grid_cloud();
+        // pulses with 1, 2 and 3 returns in turn
+        let returns = [(1, 1), (1, 2), (2, 2), (1, 3), (2, 3), (3, 3)];
+        cloud.points.iter_mut().enumerate().for_each(|(i, p)| {
+            let (n, count) = returns[i % returns.len()];
+            p.gps_time = Some(i as f64 * 1e-3);
+            p.return_number = Some(n);
+            p.number_of_returns = Some(count);
+        });
+        let first = cloud.filter_first_returns();
+        let expected = (0..cloud.len())
+            .filter(|i| returns[i % returns.len()].0 == 1)
+            .count();
+        assert_eq!(first.len(), expected);
+        assert!(first.points().iter().all(|p| p.return_number == Some(1)));
+    }
+
+    #[test]
+    fn filter_to_time_window() {
+        let mut cloud = grid_cloud();
+        cloud.points.iter_mut().enumerate().for_each(|(i, p)| {
+            // a point without time in every 10
+            p.gps_time = (i % 10 != 0).then_some(1000. + i as f64 * 0.5);
+        });
+        assert_eq!(cloud.statistics().time_range, Some((1000.5, 1031.5)));
+
+        let window = cloud.filter_by_time(1010., 1020.);
+        let expected = (20..=40).filter(|i| i % 10 != 0).count();
+        assert_eq!(window.len(), expected);
+        assert!(window
+            .points()
+            .iter()
+            .all(|p| (1010. ..=1020.).contains(&p.gps_time.unwrap())));
+        assert!(cloud.filter_by_time(2000., 3000.).is_empty());
+    }
+
+    #[test]
+    fn features_of_plane_and_line() {
+        let mut rng = StdRng::seed_from_u64(1);
+        // a plane of z = 0 with a little noise
+        let plane: Vec<Point> = (0..20)
+            .flat_map(|y| (0..20).map(move |x| (x, y)))
+            .map(|(x, y)| Point::new(x as f64 * 0.1, y as f64 * 0.1, rng.gen_range(-1e-3..1e-3)))
+            .collect();
+        let features = PointCloud::new(plane).compute_features(0.25);
+        assert_eq!(features.len(), 400);
+        for f in features.iter() {
+            assert!(f.neighbors >= 3);
+            assert!(f.curvature < 0.01, "{:?}", f);
+            assert!(f.sphericity < 0.05, "{:?}", f);
+        }
+        // the neighborhoods on the border are elongated along it
+        let interior = (3..17).flat_map(|y| (3..17).map(move |x| y * 20 + x));
+        for f in interior.map(|i| features[i]) {
+            assert!(f.planarity > 0.9, "{:?}", f);
+            assert!(f.linearity < 0.1, "{:?}", f);
+        }
+
+        // a line along x
+        let line: Vec<Point> = (0..50)
+            .map(|i| Point::new(i as f64 * 0.1, 2., rng.gen_range(-1e-3..1e-3)))
+            .collect();
+        let features = PointCloud::new(line).compute_features(0.35);
+        for f in features.iter() {
+            assert!(f.linearity > 0.95, "{:?}", f);
+            assert!(f.planarity < 0.05, "{:?}", f);
+        }
+
+        // an isolated point has no features
+        let features = PointCloud::new(vec![Point::new(0., 0., 0.)]).compute_features(1.);
+        assert_eq!(
+            features,
+            vec![PointFeatures {
+                neighbors: 1,
+                ..Default::default()
+            }]
+        );
+    }
+
+    #[test]
+    fn text_points_have_no_lidar_fields() {
+        let p = Point::try_parse("1 2 3 255 255 255 0.5").unwrap();
+        assert!(p.gps_time.is_none());
+        assert!(p.return_number.is_none());
+        assert!(p.number_of_returns.is_none());
+
+        // points serialized before these fields existed still deserialize
+        let json = r#"{"position":[1.0,2.0,3.0],"color":null,"intensity":null}"#;
+        let p: Point = serde_json::from_str(json).unwrap();
+        assert!(p.gps_time.is_none());
+        assert!(p.classification.is_none());
+    }
+
+    #[test]
+    fn progressive_order_is_permutation() {
+        let cloud = grid_cloud();
+        let ordered = cloud.progressive_order();
+        assert_eq!(ordered.len(), cloud.len());
+        for p in cloud.points().iter() {
+            assert!(ordered.points().iter().any(|q| q.position == p.position));
+        }
+        // the first 8 points of 4x4x4 grid fall into distinct octants
+        let octants: std::collections::HashSet<_> = ordered.points()[..8]
+            .iter()
+            .map(|p| p.position.map(|v| (v >= 2.) as u8))
+            .collect();
+        assert_eq!(octants.len(), 8);
+    }
+
+    /// dome of the radius 10 around (5, -3, 2)
+    fn hemisphere() -> (PointCloud, Point3<f64>) {
+        let center = Point3::new(5., -3., 2.);
+        let points = (0..17)
+            .flat_map(|i| {
+                let elevation = (i as f64 * 5.).to_radians();
+                (0..72).map(move |j| {
+                    let azimuth = (j as f64 * 5.).to_radians();
+                    let dir = Vector3::new(
+                        elevation.cos() * azimuth.cos(),
+                        elevation.cos() * azimuth.sin(),
+                        elevation.sin(),
+                    );
+                    Point::from(center + dir * 10.)
+                })
+            })
+            .collect::<Vec<_>>();
+        (PointCloud::new(points), center)
+    }
+
+    #[test]
+    fn viewpoint_of_hemisphere() {
+        let (cloud, center) = hemisphere();
+        let viewpoint = cloud.estimate_viewpoint();
+        assert!((viewpoint - center).norm() < 0.5, "{}", viewpoint);
+
+        // a flat cloud is seen from above
+        let flat = PointCloud::new(
+            (0..100)
+                .map(|i| Point::from(Point3::new((i % 10) as f64, (i / 10) as f64, 1.)))
+                .collect(),
+        );
+        assert!(flat.estimate_viewpoint().z > 1.);
+    }
+
+    #[test]
+    fn normals_face_viewpoint() {
+        let (mut cloud, center) = hemisphere();
+        cloud.estimate_normals(8, None);
+        for p in cloud.points() {
+            let normal = p.normal.unwrap();
+            let inward = (center - p.position).normalize();
+            assert!(normal.dot(&inward) > 0.9, "{} at {}", normal, p.position);
+        }
+    }
+
+    #[test]
+    fn denser_regions_have_smaller_radii() {
+        // 0.1 spacing for x < 1 and 0.5 spacing for x >= 2
+        let dense = (0..1000).map(|i| {
+            (
+                (i % 10) as f64 * 0.1,
+                (i / 10 % 10) as f64 * 0.1,
+                (i / 100) as f64 * 0.1,
+            )
+        });
+        let sparse = (0..1000).map(|i| {
+            (
+                2. + (i % 10) as f64 * 0.5,
+                (i / 10 % 10) as f64 * 0.5,
+                (i / 100) as f64 * 0.5,
+            )
+        });
+        let mut cloud = PointCloud::new(
+            dense
+                .chain(sparse)
+                .map(|(x, y, z)| Point::from(Point3::new(x, y, z)))
+                .collect(),
+        );
+        cloud.estimate_point_radii(6);
+
+        let mean = |pred: fn(&Point) -> bool| {
+            let radii: Vec<f64> = cloud
+                .points()
+                .iter()
+                .filter(|p| pred(p))
+                .map(|p| p.radius.unwrap())
+                .collect();
+            radii.iter().sum::<f64>() / radii.len() as f64
+        };
+        let dense = mean(|p| p.position.x < 1.);
+        let sparse = mean(|p| p.position.x >= 2.);
+        assert!((dense - 0.1).abs() < 0.05, "{}", dense);
+        assert!(sparse > dense * 4., "{} vs {}", sparse, dense);
+    }
+
+    #[test]
+    fn dominant_color_of_region() {
+        // 90% red and 10% blue points around a blue sample
+        let inputs: Vec<Point> = (0..100)
+            .map(|i| {
+                let (r, b) = if i % 10 == 0 { (0, 255) } else { (255, 0) };
+                let line = format!(
+                    "{} {} 0 {} 0 {}",
+                    (i % 10) as f64 * 0.1,
+                    (i / 10) as f64 * 0.1,
+                    r,
+                    b
+                );
+                Point::try_parse(&line).unwrap()
+            })
+            .collect();
+        let mut samples = vec![
+            inputs[0].clone(),
+            Point::try_parse("10 10 10 0 255 0").unwrap(),
+        ];
+        assert_eq!(samples[0].color, Some(Color::new(0, 0, 255)));
+
+        assign_dominant_colors(&mut samples, &inputs);
+        assert_eq!(samples[0].color, Some(Color::new(255, 0, 0)));
+        // samples without nearby points keep their own color
+        assert_eq!(samples[1].color, Some(Color::new(0, 255, 0)));
+    }
+
+    #[test]
+    fn distance_to_subset() {
+        let cloud = grid_cloud();
+        // every other column of the unit grid
+        let subset = cloud.filter(|p| p.position.x as i32 % 2 == 0);
+
+        let stats = cloud_to_cloud_distance(&cloud, &subset);
+        assert_eq!(stats.max, 1.);
+        assert_eq!(stats.mean, 0.5);
+        assert_eq!(stats.median, 0.5);
+        assert!((stats.rms - 0.5_f64.sqrt()).abs() < 1e-12);
+
+        // the subset lies on the original cloud
+        assert_eq!(
+            cloud_to_cloud_distance(&subset, &cloud),
+            CloudDistanceStats::default()
+        );
+    }
+
+    #[test]
+    fn reservoir_sample_is_reproducible() {
+        let cloud = grid_cloud();
+        let sample = cloud.reservoir_sample(10, 7);
+        assert_eq!(sample.len(), 10);
+        assert_eq!(cloud.reservoir_sample(100, 7).len(), cloud.len());
+
+        let positions = |c: &PointCloud| c.points().iter().map(|p| p.position).collect::<Vec<_>>();
+        assert_eq!(
+            positions(&sample),
+            positions(&cloud.reservoir_sample(10, 7))
+        );
+        assert_ne!(
+            positions(&sample),
+            positions(&cloud.reservoir_sample(10, 8))
+        );
+        // every sample is one of the points, without duplicates
+        let mut indices: Vec<usize> = sample
+            .points()
+            .iter()
+            .map(|p| {
+                cloud
+                    .points()
+                    .iter()
+                    .position(|q| q.position == p.position)
+                    .unwrap()
+            })
+            .collect();
+        indices.sort();
+        indices.dedup();
+        assert_eq!(indices.len(), 10);
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn downsample_to_fit_texture() {
+        use crate::prelude::Encoder;
+
+        let points: Vec<Point> = (0..10000)
+            .map(|i| {
+                let f = i as f64;
+                Point::new(f.sin() * 10., f.cos() * 10., f * 0.001)
+            })
+            .collect();
+        let cloud = PointCloud::new(points);
+
+        let fitted = cloud.downsample_to_fit(64);
+        assert_eq!(fitted.len(), 64 * 64);
+        let (position, color) = Encoder::new(fitted.points(), None).encode_8bit();
+        assert_eq!(position.dimensions(), (64, 64));
+        assert_eq!(color.dimensions(), (64, 64));
+
+        // a cloud under the budget is kept as is
+        assert_eq!(cloud.downsample_to_fit(128).len(), cloud.len());
+    }
+
+    #[test]
+    fn transform_positions_and_normals() {
+        let mut p = Point::new(1., 1., 1.);
+        p.normal = Some(nalgebra::Vector3::new(1., 1., 0.).normalize());
+        let mut cloud = PointCloud::new(vec![p]);
+        // stretch x by 2 and move up
+        let matrix = Matrix4::new_nonuniform_scaling(&nalgebra::Vector3::new(2., 1., 1.))
+            .append_translation(&nalgebra::Vector3::new(0., 0., 5.));
+        cloud.transform(&matrix);
+
+        let p = &cloud.points()[0];
+        assert_eq!(p.position, Point3::new(2., 1., 6.));
+        // the normal stays perpendicular to the stretched surface
+        let expected = nalgebra::Vector3::new(0.5, 1., 0.).normalize();
+        assert!((p.normal.unwrap() - expected).norm() < 1e-12);
+    }
+}
@@ -1,16 +1,33 @@
 use std::collections::HashMap;
 
-use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use nalgebra::Vector3;
 
 use crate::{
-    prelude::{BoundingBox, Point, PointCloudUnit},
+    prelude::{BoundingBox, Color, Point, PointCloudUnit},
     LODKey,
 };
 
+/// Bin the points into a grid of `grid_size` cells along each axis of their cubic bounds
+/// (the binning of `PointCloudMap::divide`), e.g. to process the chunks in parallel elsewhere
+pub fn partition(points: &[Point], grid_size: usize) -> HashMap<LODKey, Vec<Point>> {
+    let bounds = BoundingBox::from_iter(points.iter().map(|p| p.position));
+    PointCloudUnit {
+        points: points.to_vec(),
+    }
+    .split(&bounds, grid_size.max(1))
+    .into_iter()
+    .map(|(key, unit)| (key, unit.points))
+    .collect()
+}
+
 /// PointCloudMap struct that holds the octree of the point cloud data.
 pub struct PointCloudMap {
     lod: u32,
     bounds: BoundingBox,
+    /// number of the cells along each axis
+    divisions: [usize; 3],
+    /// divide each axis independently (see `next_divisions`) instead of the cubic cells of the max size
+    anisotropic: bool,
     octree: HashMap<LODKey, PointCloudUnit>,
 }
 
@@ -20,6 +37,8 @@ impl PointCloudMap {
         Self {
             lod: 0,
             bounds,
+            divisions: [1; 3],
+            anisotropic: false,
             octree: vec![(
                 (0, 0, 0),
                 PointCloudUnit {
@@ -31,58 +50,330 @@ impl PointCloudMap {
         }
     }
 
+    /// Create a map of `lod` with the points of the single cell of `key`,
+    /// whose bounds are the ones of the root, so that the cell is divided into the same keys as in the whole octree.
+    pub fn cell(bounds: BoundingBox, lod: u32, key: LODKey, points: Vec<Point>) -> Self {
+        Self {
+            lod,
+            bounds,
+            divisions: [2_usize.pow(lod); 3],
+            anisotropic: false,
+            octree: vec![(key, PointCloudUnit { points })].into_iter().collect(),
+        }
+    }
+
+    /// divide each axis only while its cells are longer than half of the longest side of the cells,
+    /// so that a long and thin cloud is divided along the long axis only (false by default)
+    pub fn anisotropic(mut self, anisotropic: bool) -> Self {
+        self.anisotropic = anisotropic;
+        self
+    }
+
     pub fn lod(&self) -> u32 {
         self.lod
     }
 
+    /// number of the cells along each axis
+    pub fn divisions(&self) -> [usize; 3] {
+        self.divisions
+    }
+
+    /// size of the cells along each axis (0 on a flat axis of the anisotropic octree)
+    pub fn cell_size(&self) -> Vector3<f64> {
+        match self.anisotropic {
+            true => {
+                let size = self.bounds.size();
+                Vector3::from_fn(|i, _| size[i] / self.divisions[i] as f64)
+            }
+            false => Vector3::repeat(self.bounds.max_size() / self.divisions[0] as f64),
+        }
+    }
+
     pub fn bounds(&self) -> &BoundingBox {
         &self.bounds
     }
 
     /// Divide the octree into 8 sub octrees.
     pub fn divide(&self, threshold: usize) -> Self {
+        self.divide_units(|_| true)
+    }
+
+    /// Divide only the units that need it by the point count or the color detail (see `needs_division`),
+    /// so that the regions of high color contrast get deeper than the flat ones.
+    /// The other units are the leaves of the pyramid and have no unit in the next level.
+    pub fn divide_detail(&self, threshold: usize, color_variance: f64) -> Self {
+        self.divide_units(|unit| needs_division(&unit.points, threshold, Some(color_variance)))
+    }
+
+    fn divide_units(&self, divide: impl Fn(&PointCloudUnit) -> bool) -> Self {
         let next_lod = self.lod + 1;
-        let div = 2_f64.powf(next_lod as f64);
-        let min = self.bounds.min();
-        let unit = self.bounds.max_size() / div;
-
-        let mut next = HashMap::new();
-
-        self.octree.iter().for_each(|(_k, v)| {
-            // if v.points.len() > threshold {
-            {
-                let pts: Vec<(LODKey, Point)> = v
-                    .points
-                    .par_iter()
-                    .map(|v| {
-                        let position = v.position;
-                        let u = (position - min) / unit;
-                        // let k = u.map(|v| v.floor().min(div) as i32);
-                        let k = u.map(|v| v.floor().min(div - 1.) as i32);
-                        let key = (k.x, k.y, k.z);
-                        (key, v.clone())
-                    })
-                    .collect();
-                for (key, v) in pts {
-                    next.entry(key).or_insert_with(Vec::new).push(v);
+        let mut divided = Self {
+            lod: next_lod,
+            bounds: self.bounds.clone(),
+            divisions: match self.anisotropic {
+                true => next_divisions(&self.bounds.size(), self.divisions),
+                false => [2_usize.pow(next_lod); 3],
+            },
+            anisotropic: self.anisotropic,
+            octree: HashMap::new(),
+        };
+        let unit = divided.cell_size();
+
+        let mut next: HashMap<LODKey, PointCloudUnit> = HashMap::new();
+
+        self.octree.values().filter(|v| divide(v)).for_each(|v| {
+            for (key, unit) in v.split_grid(&self.bounds, divided.divisions, unit) {
+                match next.get_mut(&key) {
+                    Some(u) => u.points.extend(unit.points),
+                    None => {
+                        next.insert(key, unit);
+                    }
                 }
             }
         });
 
-        Self {
-            lod: next_lod,
-            bounds: self.bounds.clone(),
-            octree: next
-                .into_iter()
-                .map(|pts| {
-                    let (key, points) = pts;
-                    (key, PointCloudUnit { points })
-                })
-                .collect(),
-        }
+        divided.octree = next;
+        divided
     }
 
     pub fn map(&self) -> &HashMap<LODKey, PointCloudUnit> {
         &self.octree
     }
+
+    /// Bounds of the cell of `key` in this level
+    pub fn cell_bounds(&self, key: &LODKey) -> BoundingBox {
+        let unit = self.cell_size();
+        let (x, y, z) = *key;
+        let min =
+            self.bounds.min() + Vector3::new(x as f64, y as f64, z as f64).component_mul(&unit);
+        BoundingBox::new(min, min + unit)
+    }
+
+    /// Fold the units with fewer than `min_points` into the nearest other unit
+    /// (by the distance between the cells, then the smallest key), smallest units first,
+    /// until every unit has `min_points` or only one unit is left.
+    /// A merged unit keeps the points outside of its cell, so its tile is bounded by the points, not by the cell.
+    /// The points are still divided by their own cells in the next level.
+    pub fn merge_small_units(&mut self, min_points: usize) {
+        while self.octree.len() > 1 {
+            let Some(small) = self
+                .octree
+                .iter()
+                .filter(|(_, u)| u.points.len() < min_points)
+                .min_by_key(|(k, u)| (u.points.len(), **k))
+                .map(|(k, _)| *k)
+            else {
+                break;
+            };
+            let distance = |(x, y, z): &LODKey| {
+                let d = [x - small.0, y - small.1, z - small.2];
+                d.iter().map(|v| (*v as i64).pow(2)).sum::<i64>()
+            };
+            let Some(target) = self
+                .octree
+                .keys()
+                .filter(|k| **k != small)
+                .min_by_key(|k| (distance(k), **k))
+                .copied()
+            else {
+                break;
+            };
+            if let Some(unit) = self.octree.remove(&small) {
+                if let Some(t) = self.octree.get_mut(&target) {
+                    t.points.extend(unit.points);
+                }
+            }
+        }
+    }
+
+    /// Points of the adjacent cells within `width` from the cell of `key`
+    pub fn halo_points(&self, key: &LODKey, width: f64) -> Vec<Point> {
+        let cell = self.cell_bounds(key);
+        let halo = BoundingBox::new(
+            cell.min() - Vector3::repeat(width),
+            cell.max() + Vector3::repeat(width),
+        );
+        neighbors(key, self.lod, false)
+            .iter()
+            .filter_map(|k| self.octree.get(k))
+            .flat_map(|unit| unit.points.iter())
+            .filter(|p| halo.contains(&p.position))
+            .cloned()
+            .collect()
+    }
+}
+
+/// The unit of the points is divided in the next level: it has `threshold` points or more,
+/// or its color variance (`Color::variance`) is over `color_variance` if given
+/// (unless all the points are at the same position, which no division separates)
+pub fn needs_division(points: &[Point], threshold: usize, color_variance: Option<f64>) -> bool {
+    if points.len() >= threshold {
+        return true;
+    }
+    match (color_variance, points.first()) {
+        (Some(max), Some(first)) => {
+            points.iter().any(|p| p.position != first.position)
+                && Color::variance(points.iter().filter_map(|p| p.color.as_ref())) > max
+        }
+        _ => false,
+    }
+}
+
+/// Divisions of the next level of the anisotropic octree:
+/// an axis is divided while its cells are longer than half of the longest side of the cells (never a flat axis),
+/// so the cells approach cubes and then all the axes are divided like the octree
+pub fn next_divisions(size: &Vector3<f64>, divisions: [usize; 3]) -> [usize; 3] {
+    let cell = Vector3::from_fn(|i, _| size[i] / divisions[i] as f64);
+    let longest = cell.max();
+    std::array::from_fn(|i| match cell[i] > longest / 2. {
+        true => divisions[i] * 2,
+        false => divisions[i],
+    })
+}
+
+/// Keys of the tiles adjacent to `key` in `lod` within the `2^lod` cells on each axis:
+/// the 6 sharing a face if `face_only`, otherwise the 26 sharing a face, an edge or a corner.
+pub fn neighbors(key: &LODKey, lod: u32, face_only: bool) -> Vec<LODKey> {
+    let div = 2_i32.pow(lod);
+    let (x, y, z) = *key;
+    (-1..=1)
+        .flat_map(|dz| (-1..=1).flat_map(move |dy| (-1..=1).map(move |dx| (dx, dy, dz))))
+        .filter(|d| *d != (0, 0, 0))
+        .filter(|(dx, dy, dz): &(i32, i32, i32)| !face_only || dx.abs() + dy.abs() + dz.abs() == 1)
+        .map(|(dx, dy, dz)| (x + dx, y + dy, z + dz))
+        .filter(|(x, y, z)| [x, y, z].iter().all(|v| (0..div).contains(*v)))
+        .collect()
+}
+
+/// Estimate the number of levels `process_lod` produces for a cloud, assuming roughly uniform density.
+/// A cloud under `threshold` points is a single root level; otherwise the root is divided at least once,
+/// and a level is divided again while its units have `threshold` points or more.
+pub fn estimate_lod_levels(bounds: &BoundingBox, point_count: usize, threshold: usize) -> u32 {
+    if point_count < threshold {
+        // the root tile holds all the points
+        return 1;
+    }
+    let size = bounds.size();
+    let max_size = bounds.max_size();
+    let mut lod = 1;
+    // guard against degenerate inputs (e.g. many coincident points)
+    while lod < 32 {
+        let unit = max_size / 2_f64.powi(lod as i32);
+        let occupied = size
+            .iter()
+            .map(|s| (s / unit).ceil().max(1.))
+            .product::<f64>();
+        if (point_count as f64) / occupied < threshold as f64 {
+            break;
+        }
+        lod += 1;
+    }
+    lod + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Point3;
+
+    use super::*;
+
+    #[test]
+    fn neighbors_of_corner_and_interior() {
+        // level 2 has 4 x 4 x 4 tiles
+        assert_eq!(neighbors(&(0, 0, 0), 2, false).len(), 7);
+        assert_eq!(neighbors(&(0, 0, 0), 2, true).len(), 3);
+        assert_eq!(neighbors(&(3, 3, 3), 2, true).len(), 3);
+
+        let interior = neighbors(&(1, 2, 1), 2, false);
+        assert_eq!(interior.len(), 26);
+        assert!(!interior.contains(&(1, 2, 1)));
+        let faces = neighbors(&(1, 2, 1), 2, true);
+        assert_eq!(faces.len(), 6);
+        assert!(faces.contains(&(1, 3, 1)) && faces.contains(&(0, 2, 1)));
+
+        // the root has no neighbors
+        assert!(neighbors(&(0, 0, 0), 0, false).is_empty());
+    }
+
+    #[test]
+    fn anisotropic_divides_long_axis_only() {
+        let points: Vec<Point> = (0..1000)
+            .map(|i| {
+                let t = i as f64 / 999.;
+                Point::from(Point3::new(t * 100., (t * 37.).fract(), (t * 53.).fract()))
+            })
+            .collect();
+        let bounds = BoundingBox::from_iter(points.iter().map(|p| p.position));
+        let size = bounds.size();
+
+        let mut map = PointCloudMap::root(bounds.clone(), &points).anisotropic(true);
+        for _ in 0..6 {
+            map = map.divide(0);
+        }
+        assert_eq!(map.divisions(), [64, 1, 1]);
+        assert!(map.map().keys().all(|(_, y, z)| *y == 0 && *z == 0));
+        assert_eq!(
+            map.map().values().map(|u| u.points.len()).sum::<usize>(),
+            points.len()
+        );
+        let cell = map.cell_size();
+        assert!((cell.x - size.x / 64.).abs() < 1e-9);
+        assert!((cell.y - size.y).abs() < 1e-9 && (cell.z - size.z).abs() < 1e-9);
+
+        // the short axes follow once the cells are nearly cubic
+        assert_eq!(next_divisions(&size, [64, 1, 1]), [128, 2, 2]);
+        assert_eq!(map.divide(0).divisions(), [128, 2, 2]);
+
+        // the regular octree divides all the axes
+        let map = PointCloudMap::root(bounds, &points).divide(0).divide(0);
+        assert_eq!(map.divisions(), [4, 4, 4]);
+        assert!((map.cell_size() - Vector3::repeat(size.x / 4.)).norm() < 1e-9);
+    }
+
+    #[test]
+    fn color_detail_divides_deeper() {
+        // a flat gray half (x < 32) and a black and white checkerboard half
+        let points: Vec<Point> = (0..64 * 64)
+            .map(|i| {
+                let (x, y) = (i % 64, i / 64);
+                let mut point = Point::from(Point3::new(x as f64, y as f64, 0.));
+                point.color = Some(match (x < 32, (x + y) % 2 == 0) {
+                    (true, _) => Color::new(128, 128, 128),
+                    (false, true) => Color::white(),
+                    (false, false) => Color::new(0, 0, 0),
+                });
+                point
+            })
+            .collect();
+        assert_eq!(
+            Color::variance(points[..32].iter().filter_map(|p| p.color.as_ref())),
+            0.
+        );
+        let checker = points[32..64].iter().filter_map(|p| p.color.as_ref());
+        assert!((Color::variance(checker) - 0.75).abs() < 1e-9);
+
+        let bounds = BoundingBox::from_iter(points.iter().map(|p| p.position));
+        let mut map = PointCloudMap::root(bounds, &points);
+        let (mut flat_depth, mut contrast_depth) = (0, 0);
+        while !map.map().is_empty() {
+            for unit in map.map().values() {
+                match unit.points[0].position.x < 32. {
+                    true => flat_depth = map.lod(),
+                    false => contrast_depth = map.lod(),
+                }
+            }
+            assert!(map.lod() < 10);
+            map = map.divide_detail(points.len(), 0.01);
+        }
+        assert_eq!(flat_depth, 1);
+        assert!(contrast_depth >= 5);
+
+        // the point count still divides without color detail
+        assert!(needs_division(&points[..32], 32, Some(0.01)));
+        assert!(!needs_division(&points[..32], 33, Some(0.01)));
+        assert!(!needs_division(&points[32..64], 33, None));
+        let mut stacked = vec![points[32].clone(), points[33].clone()];
+        stacked[1].position = stacked[0].position;
+        assert!(!needs_division(&stacked, 33, Some(0.01)));
+    }
 }
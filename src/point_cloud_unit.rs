@@ -1,4 +1,12 @@
-use crate::prelude::Point;
+use std::collections::HashMap;
+
+use nalgebra::Vector3;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+use crate::{
+    prelude::{BoundingBox, Point},
+    LODKey,
+};
 
 pub struct PointCloudUnit {
     pub points: Vec<Point>,
@@ -8,4 +16,67 @@ impl PointCloudUnit {
     pub fn points(&self) -> &Vec<Point> {
         &self.points
     }
+
+    /// Partition the points into a grid of `divisions` cubic cells per axis of the max size of `bounds`.
+    /// See `BoundingBox::cell` for the assignment of the points on the boundaries.
+    pub fn split(&self, bounds: &BoundingBox, divisions: usize) -> HashMap<LODKey, PointCloudUnit> {
+        let unit = bounds.max_size() / divisions as f64;
+        self.split_grid(bounds, [divisions; 3], Vector3::repeat(unit))
+    }
+
+    /// Partition the points into a grid of `divisions` cells of `unit` size along each axis from the min of `bounds`
+    /// (see `BoundingBox::grid_cell`)
+    pub fn split_grid(
+        &self,
+        bounds: &BoundingBox,
+        divisions: [usize; 3],
+        unit: Vector3<f64>,
+    ) -> HashMap<LODKey, PointCloudUnit> {
+        let pts: Vec<(LODKey, Point)> = self
+            .points
+            .par_iter()
+            .map(|v| (bounds.grid_cell(&v.position, divisions, unit), v.clone()))
+            .collect();
+
+        let mut units: HashMap<LODKey, PointCloudUnit> = HashMap::new();
+        for (key, v) in pts {
+            units
+                .entry(key)
+                .or_insert_with(|| PointCloudUnit { points: vec![] })
+                .points
+                .push(v);
+        }
+        units
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Point3;
+
+    use super::*;
+
+    #[test]
+    fn split_into_octants() {
+        let points: Vec<Point> = (0..1000)
+            .map(|i| {
+                let (x, y, z) = (i % 10, (i / 10) % 10, i / 100);
+                Point::from(Point3::new(x as f64, y as f64, z as f64))
+            })
+            .collect();
+        let unit = PointCloudUnit { points };
+        let bounds = BoundingBox::from_iter(unit.points().iter());
+
+        let split = unit.split(&bounds, 2);
+        assert_eq!(split.len(), 8);
+        let count: usize = split.values().map(|u| u.points.len()).sum();
+        assert_eq!(count, unit.points.len());
+        for ((x, y, z), u) in split.iter() {
+            assert_eq!(u.points.len(), 125);
+            for p in u.points() {
+                let k = p.position.map(|v| (v >= 4.5) as i32);
+                assert_eq!((k.x, k.y, k.z), (*x, *y, *z));
+            }
+        }
+    }
 }
@@ -1,38 +1,184 @@
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashMap, HashSet};
 
+use anyhow::bail;
 use itertools::Itertools;
 use nalgebra::{OPoint, RealField, U3};
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 
-use crate::grid::Grid;
+use crate::grid::{validate_grid, Cells, GridIndex, SparseGrid};
 use crate::has_position::HasPosition;
 use crate::misc::min_max;
 
+/// default limit of the grid cells allocated by the samplers (see `PoissonDiskSampling::max_grid_cells`), about 2.7e8.
+/// Only the occupied cells are allocated, so a tile of the pipeline has at most as many cells as its points.
+pub const DEFAULT_MAX_GRID_CELLS: u64 = 1 << 28;
+
 #[derive(Debug, Clone)]
 pub struct PoissonDiskSampling<T, P> {
+    max_grid_cells: u64,
     phantom: std::marker::PhantomData<(T, P)>,
 }
 
 impl<T, P> Default for PoissonDiskSampling<T, P> {
     fn default() -> Self {
         Self {
+            max_grid_cells: DEFAULT_MAX_GRID_CELLS,
             phantom: std::marker::PhantomData,
         }
     }
 }
 
+/// Sampling radius that keeps the samples at least `spacing` apart.
+/// A sample is only checked against the adjacent cells of the grid of `radius / sqrt(3)`,
+/// so the guaranteed (and the typically achieved) minimum distance between the samples is `radius / sqrt(3)`, not `radius`.
+pub fn radius_for_spacing(spacing: f64) -> f64 {
+    spacing * 3_f64.sqrt()
+}
+
+/// input point with its index, to tell the kept points from the discarded ones
+#[derive(Clone)]
+struct Indexed<'a, P> {
+    index: usize,
+    point: &'a P,
+}
+
+impl<'a, T: RealField, P: HasPosition<T, U3>> HasPosition<T, U3> for Indexed<'a, P> {
+    fn position(&self) -> &OPoint<T, U3> {
+        self.point.position()
+    }
+}
+
 impl<T, P> PoissonDiskSampling<T, P> {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// fail sampling when the grid would allocate more cells than this (the occupied cells, at most one per input)
+    pub fn max_grid_cells(mut self, max_grid_cells: u64) -> Self {
+        self.max_grid_cells = max_grid_cells;
+        self
+    }
 }
 
 impl<T: RealField + Copy + num_traits::ToPrimitive, P: HasPosition<T, U3> + Sync + Send>
     PoissonDiskSampling<T, P>
 {
-    pub fn sample(&self, inputs: &[P], radius: T) -> Vec<P> {
+    /// sample while keeping the `required` points (e.g. control points) in the output.
+    /// The other samples are at least `radius` away from every required point.
+    /// Fails if a required index is out of the inputs.
+    pub fn sample_with_required(
+        &self,
+        inputs: &[P],
+        radius: T,
+        required: &[usize],
+    ) -> anyhow::Result<Vec<P>> {
+        if let Some(i) = required.iter().find(|i| **i >= inputs.len()) {
+            bail!(
+                "Required point {} is out of the {} input points",
+                i,
+                inputs.len()
+            );
+        }
+        let required: HashSet<usize> = required.iter().copied().collect();
+        let anchors: Vec<&P> = required.iter().map(|i| &inputs[*i]).collect();
+
+        // hash the required points into cells of `radius` size
+        let cell = |p: &OPoint<T, U3>| {
+            p.coords
+                .map(|x| (x / radius).floor().to_i64().unwrap())
+                .into()
+        };
+        let mut cells: HashMap<(i64, i64, i64), Vec<&P>> = HashMap::new();
+        anchors.iter().for_each(|p| {
+            let c: [i64; 3] = cell(p.position());
+            cells.entry((c[0], c[1], c[2])).or_default().push(p);
+        });
+        let is_far = |p: &P| {
+            let c: [i64; 3] = cell(p.position());
+            (-1..=1).all(|dz| {
+                (-1..=1).all(|dy| {
+                    (-1..=1).all(|dx| {
+                        cells
+                            .get(&(c[0] + dx, c[1] + dy, c[2] + dz))
+                            .map(|qs| {
+                                qs.iter()
+                                    .all(|q| (p.position() - q.position()).norm() > radius)
+                            })
+                            .unwrap_or(true)
+                    })
+                })
+            })
+        };
+
+        let rest: Vec<P> = inputs
+            .iter()
+            .enumerate()
+            .filter(|(i, p)| !required.contains(i) && is_far(p))
+            .map(|(_, p)| p.clone())
+            .collect();
+
+        let mut samples: Vec<P> = anchors.into_iter().cloned().collect();
+        if !rest.is_empty() {
+            samples.extend(self.sample(&rest, radius)?);
+        }
+        Ok(samples)
+    }
+
+    /// Sample the points keeping them at least `spacing` apart (see `radius_for_spacing`)
+    pub fn sample_with_spacing(&self, inputs: &[P], spacing: T) -> anyhow::Result<Vec<P>> {
+        self.sample(inputs, spacing * T::from_usize(3).unwrap().sqrt())
+    }
+
+    /// Split the inputs into the points kept by `sample` and the discarded ones (both in the input order),
+    /// e.g. to store only the points added at a level
+    pub fn partition(&self, inputs: &[P], radius: T) -> anyhow::Result<(Vec<P>, Vec<P>)> {
+        if inputs.is_empty() {
+            return Ok((vec![], vec![]));
+        }
+        let indexed: Vec<Indexed<P>> = inputs
+            .iter()
+            .enumerate()
+            .map(|(index, point)| Indexed { index, point })
+            .collect();
+        let kept: HashSet<usize> = PoissonDiskSampling::<T, Indexed<P>>::new()
+            .max_grid_cells(self.max_grid_cells)
+            .sample(&indexed, radius)?
+            .into_iter()
+            .map(|p| p.index)
+            .collect();
+        let (kept, discarded): (Vec<_>, Vec<_>) = inputs
+            .iter()
+            .enumerate()
+            .partition(|(i, _)| kept.contains(i));
+        let points = |v: Vec<(usize, &P)>| v.into_iter().map(|(_, p)| p.clone()).collect();
+        Ok((points(kept), points(discarded)))
+    }
+
+    /// Sample the points at least `radius` apart (deterministic, the same inputs give the same samples)
+    pub fn sample(&self, inputs: &[P], radius: T) -> anyhow::Result<Vec<P>> {
+        let grid = self.sample_in(inputs, radius, |_| SparseGrid::new())?;
+        Ok(grid.representatives().into_iter().cloned().collect())
+    }
+
+    /// sample into the grid built by `new_grid` from the number of cells on each axis,
+    /// returning the grid with the samples as the representatives of its cells
+    pub(crate) fn sample_in<'a, G: Cells<'a, P> + Sync>(
+        &self,
+        inputs: &'a [P],
+        radius: T,
+        new_grid: impl FnOnce(GridIndex) -> G,
+    ) -> anyhow::Result<G> {
         let (min, max) = min_max(inputs.iter().map(|pt| pt.position()));
         let size = max - min;
+        validate_grid(
+            &size.map(|x| x.to_f64().unwrap()),
+            radius.to_f64().unwrap(),
+            (radius / T::from_usize(3).unwrap().sqrt())
+                .to_f64()
+                .unwrap(),
+            inputs.len(),
+            self.max_grid_cells,
+        )?;
 
         // `cell_size` refers following article
         // https://sighack.com/post/poisson-disk-sampling-bridsons-algorithm
@@ -44,79 +190,51 @@ impl<T: RealField + Copy + num_traits::ToPrimitive, P: HasPosition<T, U3> + Sync
 
         let grid_size = size.map(|x| (x / cell_size).ceil().max(T::one()));
         let u_grid_size = grid_size.map(|x| x.to_usize().unwrap());
-        // println!("grid_size: {:?}", u_grid_size);
-        let mut grid: Vec<Vec<Vec<Grid<'_, P>>>> = vec![];
-        for _ in 0..u_grid_size.z {
-            let mut gz = vec![];
-            for _ in 0..u_grid_size.y {
-                let mut gy = vec![];
-                for _ in 0..u_grid_size.x {
-                    gy.push(Grid::new());
-                }
-                gz.push(gy);
-            }
-            grid.push(gz);
-        }
+        let mut grid = new_grid((u_grid_size.x, u_grid_size.y, u_grid_size.z));
 
-        let index = |point: &OPoint<T, U3>| {
+        let index = |point: &OPoint<T, U3>| -> GridIndex {
             let n = point.coords - min;
-            n.map(|x| (x / cell_size).floor().to_usize().unwrap())
+            let i = n.map(|x| (x / cell_size).floor().to_usize().unwrap());
+            (i.x, i.y, i.z)
         };
 
         inputs.iter().for_each(|pt| {
-            let i = index(pt.position());
-            grid[i.z][i.y][i.x].insert(pt);
+            grid.insert(index(pt.position()), pt);
         });
 
-        let mut indices: HashSet<(usize, usize, usize)> = grid
-            .iter()
-            .enumerate()
-            .flat_map(|(iz, gz)| {
-                gz.iter()
-                    .enumerate()
-                    .flat_map(|(iy, gy)| {
-                        gy.iter()
-                            .enumerate()
-                            .filter_map(|(ix, g)| match !g.candidates().is_empty() {
-                                true => Some(ix),
-                                false => None,
-                            })
-                            .map(move |ix| (ix, iy))
-                    })
-                    .map(move |(ix, iy)| (ix, iy, iz))
-            })
-            .collect();
+        // occupied cells
+        // ordered so that the cells are visited in the same order on every run
+        let mut indices: BTreeSet<GridIndex> = grid.indices().into_iter().collect();
 
         // println!("indices: {:?}", indices.len());
 
         let mut actives = vec![];
 
-        let insert = |p: P,
-                      actives: &mut Vec<P>,
-                      grid: &mut Vec<Vec<Vec<Grid<'_, P>>>>,
-                      indices: &mut HashSet<(usize, usize, usize)>| {
-            actives.push(p.clone());
-            let i = index(p.position());
-            grid[i.z][i.y][i.x].set(p.clone());
-            indices.remove(&(i.x, i.y, i.z));
-        };
+        let insert =
+            |p: P, actives: &mut Vec<P>, grid: &mut G, indices: &mut BTreeSet<GridIndex>| {
+                actives.push(p.clone());
+                let i = index(p.position());
+                grid.set(i, p.clone());
+                indices.remove(&i);
+            };
 
-        let is_valid = |p: &P, grid: &Vec<Vec<Vec<Grid<'_, P>>>>| {
-            let i = index(p.position());
+        let is_valid = |p: &P, grid: &G| {
+            let (ix, iy, iz) = index(p.position());
             for dz in -1..=1 {
-                let z = i.z as isize + dz;
+                let z = iz as isize + dz;
                 if 0 <= z && z < u_grid_size.z as isize {
                     for dy in -1..=1 {
-                        let y = i.y as isize + dy;
+                        let y = iy as isize + dy;
                         if 0 <= y && y < u_grid_size.y as isize {
                             for dx in -1..=1 {
                                 if dz == 0 && dy == 0 && dx == 0 {
                                     continue;
                                 }
-                                let x = i.x as isize + dx;
+                                let x = ix as isize + dx;
                                 if 0 <= x && x < u_grid_size.x as isize {
-                                    if let Some(q) =
-                                        grid[z as usize][y as usize][x as usize].representative()
+                                    if let Some(q) = grid
+                                        .get(&(x as usize, y as usize, z as usize))
+                                        .and_then(|g| g.representative())
                                     {
                                         let dist = p.position() - q.position();
                                         if dist.norm() <= radius {
@@ -135,7 +253,7 @@ impl<T: RealField + Copy + num_traits::ToPrimitive, P: HasPosition<T, U3> + Sync
 
         let i = *indices.iter().next().unwrap();
         indices.remove(&i);
-        let start = grid[i.2][i.1][i.0].candidates().first().unwrap().clone();
+        let start = (*grid.get(&i).unwrap().candidates().first().unwrap()).clone();
         insert(start.clone(), &mut actives, &mut grid, &mut indices);
 
         while !indices.is_empty() {
@@ -143,7 +261,7 @@ impl<T: RealField + Copy + num_traits::ToPrimitive, P: HasPosition<T, U3> + Sync
                 true => {
                     let i = *indices.iter().next().unwrap();
                     indices.remove(&i);
-                    let next = grid[i.2][i.1][i.0].candidates().iter().find_map(|p| {
+                    let next = grid.get(&i).unwrap().candidates().iter().find_map(|p| {
                         if is_valid(p, &grid) {
                             Some(p)
                         } else {
@@ -163,14 +281,14 @@ impl<T: RealField + Copy + num_traits::ToPrimitive, P: HasPosition<T, U3> + Sync
                 }
                 false => actives.first().unwrap(),
             };
-            let i = index(current.position());
+            let (ix, iy, iz) = index(current.position());
             let neighbor_indices = (-1..=1)
                 .flat_map(|dz| {
-                    let z = i.z as isize + dz;
+                    let z = iz as isize + dz;
                     if 0 <= z && z < u_grid_size.z as isize {
                         (-1..=1)
                             .flat_map(|dy| {
-                                let y = i.y as isize + dy;
+                                let y = iy as isize + dy;
                                 if 0 <= y && y < u_grid_size.y as isize {
                                     (-1..=1)
                                         .filter_map(|dx| {
@@ -178,14 +296,11 @@ impl<T: RealField + Copy + num_traits::ToPrimitive, P: HasPosition<T, U3> + Sync
                                                 return None;
                                             }
 
-                                            let x = i.x as isize + dx;
+                                            let x = ix as isize + dx;
                                             if 0 <= x && x < u_grid_size.x as isize {
+                                                // only the occupied cells have candidates
                                                 let j = (x as usize, y as usize, z as usize);
-                                                if grid[j.2][j.1][j.0].visited() {
-                                                    None
-                                                } else {
-                                                    Some(j)
-                                                }
+                                                grid.get(&j).filter(|g| !g.visited()).map(|_| j)
                                             } else {
                                                 None
                                             }
@@ -202,10 +317,10 @@ impl<T: RealField + Copy + num_traits::ToPrimitive, P: HasPosition<T, U3> + Sync
                 })
                 .collect_vec();
 
-            let next = neighbor_indices.into_iter().find_map(|(x, y, z)| {
-                let cand = grid[z][y][x].candidates();
+            let next = neighbor_indices.into_iter().find_map(|j| {
+                let cand = grid.get(&j).unwrap().candidates();
                 cand.par_iter()
-                    .find_any(|q| {
+                    .find_first(|q| {
                         let dist = (current.position() - q.position()).norm();
                         // radius_squared <= dist_squared && dist_squared <= radius_2_squared
                         half_radius <= dist && dist <= radius && is_valid(q, &grid)
@@ -224,12 +339,215 @@ impl<T: RealField + Copy + num_traits::ToPrimitive, P: HasPosition<T, U3> + Sync
             };
         }
 
-        // collect result
-        grid.into_iter()
-            .flat_map(|gz| {
-                gz.into_iter()
-                    .flat_map(|gy| gy.into_iter().filter_map(|g| g.representative().cloned()))
+        Ok(grid)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use nalgebra::{Point3, Vector3};
+
+    use crate::{point::Point, prelude::ParallelPoissonDiskSampling};
+
+    use super::*;
+
+    #[test]
+    fn required_points_are_kept() {
+        let points: Vec<Point> = (0..1000)
+            .map(|i| {
+                let (x, y, z) = (i % 10, (i / 10) % 10, i / 100);
+                Point::from(Point3::new(x as f64, y as f64, z as f64) * 0.1)
+            })
+            .collect();
+        let required = [0, 1, 2, 555, 999];
+        let radius = 0.5;
+
+        let sampler = PoissonDiskSampling::<f64, Point>::new();
+        let samples = sampler
+            .sample_with_required(&points, radius, &required)
+            .unwrap();
+
+        for i in required {
+            assert!(samples.iter().any(|p| p.position == points[i].position));
+        }
+        // the other samples keep the radius around the required points
+        for p in samples.iter().skip(required.len()) {
+            for i in required {
+                assert!(p.distance(&points[i]) > radius);
+            }
+        }
+        assert!(samples.len() < points.len() / 10);
+
+        assert!(sampler
+            .sample_with_required(&points, radius, &[0, 1000])
+            .is_err());
+    }
+
+    /// two 5 x 5 x 5 lattices of `spacing` far apart (a dense grid over them would have about 1e17 cells for a radius about `spacing`)
+    pub(crate) fn sparse_clusters(spacing: f64) -> Vec<Point> {
+        [0., 1e4]
+            .into_iter()
+            .flat_map(|offset| {
+                (0..125).map(move |i| {
+                    let (x, y, z) = (i % 5, (i / 5) % 5, i / 25);
+                    Point::from(
+                        Point3::new(x as f64, y as f64, z as f64) * spacing
+                            + nalgebra::Vector3::repeat(offset),
+                    )
+                })
             })
             .collect()
     }
+
+    #[test]
+    fn sparse_cloud_keeps_separated_points() {
+        // the points are farther apart than the radius, so every point is a sample
+        let points = sparse_clusters(0.1);
+        let sampler = PoissonDiskSampling::<f64, Point>::new();
+        let samples = sampler.sample(&points, 0.05).unwrap();
+
+        let key = |p: &Point| (p.position.z, p.position.y, p.position.x);
+        let mut expected: Vec<_> = points.iter().map(key).collect();
+        let mut actual: Vec<_> = samples.iter().map(key).collect();
+        expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        actual.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn sparse_grid_matches_dense_grid() {
+        use crate::grid::DenseGrid;
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        // a random cube and a lattice apart from it, so that most of the cells of the bounding box are empty
+        let mut rng = StdRng::seed_from_u64(11);
+        let points: Vec<Point> = (0..5000)
+            .map(|_| Point::from(Point3::new(rng.gen(), rng.gen(), rng.gen())))
+            .chain(
+                sparse_clusters(0.1)
+                    .into_iter()
+                    .take(125)
+                    .map(|p| Point::from(p.position + Vector3::repeat(2.))),
+            )
+            .collect();
+        let sampler = PoissonDiskSampling::<f64, Point>::new();
+        for radius in [0.1, 0.25] {
+            let sparse = sampler
+                .sample_in(&points, radius, |_| SparseGrid::new())
+                .unwrap();
+            let dense = sampler.sample_in(&points, radius, DenseGrid::new).unwrap();
+
+            let positions = |samples: Vec<&Point>| samples.iter().map(|p| p.position).collect_vec();
+            let samples = positions(sparse.representatives());
+            assert!(samples.len() > 20);
+            assert_eq!(samples, positions(dense.representatives()));
+
+            // only the occupied cells are allocated
+            assert_eq!(sparse.cell_count(), dense.indices().len());
+            assert!(
+                sparse.cell_count() * 10 < dense.cell_count(),
+                "{} cells for {} dense cells at the radius {}",
+                sparse.cell_count(),
+                dense.cell_count(),
+                radius
+            );
+        }
+    }
+
+    #[test]
+    fn sparse_cloud_is_covered() {
+        let points = sparse_clusters(0.1);
+        let radius = 0.25;
+        let sampler = PoissonDiskSampling::<f64, Point>::new();
+        let samples = sampler.sample(&points, radius).unwrap();
+
+        assert!(samples.len() < points.len());
+        for p in points.iter() {
+            assert!(samples.iter().any(|q| p.distance(q) <= radius));
+        }
+    }
+
+    #[test]
+    fn achieved_spacing_is_close_to_requested() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let points: Vec<Point> = (0..20000)
+            .map(|_| Point::from(Point3::new(rng.gen(), rng.gen(), rng.gen())))
+            .collect();
+        for spacing in [0.03, 0.05, 0.1] {
+            assert_eq!(radius_for_spacing(spacing), spacing * 3_f64.sqrt());
+            let samples = PoissonDiskSampling::<f64, Point>::new()
+                .sample_with_spacing(&points, spacing)
+                .unwrap();
+            let tree = crate::prelude::KdTree::new(&samples);
+            let min = samples
+                .iter()
+                .enumerate()
+                .flat_map(|(i, p)| {
+                    tree.k_nearest(&p.position, 2)
+                        .into_iter()
+                        .filter(move |(j, _)| *j != i)
+                })
+                .map(|(_, d)| d)
+                .fold(f64::MAX, f64::min);
+            assert!(
+                spacing <= min && min < spacing * 1.25,
+                "{} for the spacing {}",
+                min,
+                spacing
+            );
+        }
+    }
+
+    #[test]
+    fn tiny_radius_is_rejected() {
+        let points = sparse_clusters(0.1);
+        let sampler = PoissonDiskSampling::<f64, Point>::new();
+
+        // the cell indices over the extent overflow
+        let err = sampler.sample(&points, 1e-20).unwrap_err().to_string();
+        assert!(err.contains("too small"), "{}", err);
+        assert!(ParallelPoissonDiskSampling::new(points.iter().collect(), 1e-20).is_err());
+        assert!(sampler.sample(&points, 0.).is_err());
+
+        // only the occupied cells are allocated, so a small radius over a large extent is fine
+        assert_eq!(sampler.sample(&points, 1e-9).unwrap().len(), points.len());
+        assert!(ParallelPoissonDiskSampling::new(points.iter().collect(), 1e-9).is_ok());
+
+        // the limit of the allocated cells is configurable
+        let cluster = &points[..125];
+        assert!(sampler.sample(cluster, 0.1).is_ok());
+        assert!(sampler
+            .clone()
+            .max_grid_cells(10)
+            .sample(cluster, 0.1)
+            .is_err());
+    }
+
+    #[test]
+    fn partition_into_kept_and_discarded() {
+        let points: Vec<Point> = (0..1000)
+            .map(|i| {
+                let (x, y, z) = (i % 10, (i / 10) % 10, i / 100);
+                Point::from(
+                    Point3::new(x as f64, y as f64, z as f64) * 0.1
+                        + Vector3::repeat(0.01 * (i % 7) as f64),
+                )
+            })
+            .collect();
+        let sampler = PoissonDiskSampling::<f64, Point>::new();
+        let (kept, discarded) = sampler.partition(&points, 0.3).unwrap();
+        assert!(!kept.is_empty() && !discarded.is_empty());
+
+        let key = |p: &Point| p.position.coords.iter().map(|v| v.to_bits()).collect_vec();
+        let sorted = |points: &[Point]| points.iter().map(key).sorted().collect_vec();
+        let mut all = kept.clone();
+        all.extend(discarded);
+        assert_eq!(sorted(&all), sorted(&points));
+        assert_eq!(
+            sorted(&kept),
+            sorted(&sampler.sample(&points, 0.3).unwrap())
+        );
+    }
 }
@@ -0,0 +1,108 @@
+use nalgebra::{Matrix4, Point3};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    prelude::{GlobalShift, Meta},
+    LODOptions,
+};
+
+/// Summary of a run of `build_lod` / `process_lod` (`report.json` of the CLI),
+/// to reproduce or debug the output
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LODReport {
+    pub version: String,
+    /// input files of `process_lod` (empty for `build_lod`)
+    pub input_files: Vec<String>,
+    /// number of the input points (before the crop)
+    pub point_count: usize,
+    /// number of the levels
+    pub levels: u32,
+    /// number of the tiles in each level
+    pub tiles_per_level: Vec<usize>,
+    pub parameters: ReportParameters,
+    pub timings: ReportTimings,
+    /// explicit global shift applied to the input, if any
+    pub global_shift: Option<GlobalShift>,
+}
+
+/// options of the run that change the output
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReportParameters {
+    /// point count threshold actually used to divide the octree
+    pub point_count_threshold: usize,
+    pub max_points: Option<usize>,
+    pub max_texture_side: Option<u32>,
+    pub transform: Option<Matrix4<f64>>,
+    pub crop_sphere: Option<(Point3<f64>, f64)>,
+    pub halo: Option<f64>,
+    pub min_points_per_tile: Option<usize>,
+    pub cubic_tile_bounds: bool,
+    pub progressive_order: bool,
+    pub carry_index: bool,
+    pub dominant_color: bool,
+    pub detail_tiles: bool,
+    pub deterministic: bool,
+    pub delta_levels: bool,
+    pub anisotropic: bool,
+    pub color_variance: Option<f64>,
+}
+
+impl ReportParameters {
+    pub fn new(options: &LODOptions) -> Self {
+        Self {
+            point_count_threshold: options.effective_point_count_threshold(),
+            max_points: options.max_points,
+            max_texture_side: options.max_texture_side,
+            transform: options.transform,
+            crop_sphere: options.crop_sphere,
+            halo: options.halo,
+            min_points_per_tile: options.min_points_per_tile,
+            cubic_tile_bounds: options.cubic_tile_bounds,
+            progressive_order: options.progressive_order,
+            carry_index: options.carry_index,
+            dominant_color: options.dominant_color,
+            detail_tiles: options.detail_tiles,
+            deterministic: options.deterministic,
+            delta_levels: options.delta_levels,
+            anisotropic: options.anisotropic,
+            color_variance: options.color_variance,
+        }
+    }
+}
+
+/// elapsed seconds of each step
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ReportTimings {
+    /// loading the input files (0 for `build_lod`)
+    pub load: f64,
+    /// building the levels, including the callbacks
+    pub build: f64,
+}
+
+impl LODReport {
+    pub fn new(options: &LODOptions) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            input_files: vec![],
+            point_count: 0,
+            levels: 0,
+            tiles_per_level: vec![],
+            parameters: ReportParameters::new(options),
+            timings: ReportTimings::default(),
+            global_shift: None,
+        }
+    }
+
+    /// record the levels and the tiles of the final meta
+    pub(crate) fn levels_of(mut self, meta: &Meta) -> Self {
+        self.levels = meta.lod();
+        self.tiles_per_level = (0..meta.lod())
+            .map(|level| {
+                meta.coordinates()
+                    .get(&level)
+                    .map_or(0, |units| units.len())
+            })
+            .collect();
+        self
+    }
+}
@@ -0,0 +1,91 @@
+use std::fmt::Debug;
+
+use crate::prelude::{ParallelPoissonDiskSampling, Point, PoissonDiskSampling};
+
+/// Algorithm sampling the points of each tile (e.g. voxel, farthest point or a custom sampling),
+/// given to `build_lod` / `process_lod` by `LODOptions::sampler`.
+pub trait Sampler: Send + Sync {
+    /// samples of the points spaced by about `radius` (the sampling radius of the level).
+    /// The radius grows until the samples fit in the tile image if the texture side is limited,
+    /// so a larger radius should give fewer samples
+    fn sample(&self, points: &[Point], radius: f64) -> anyhow::Result<Vec<Point>>;
+}
+
+impl Debug for dyn Sampler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Sampler")
+    }
+}
+
+/// Poisson disk sampling in parallel (`ParallelPoissonDiskSampling`), the default sampler of the pipeline
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ParallelPoissonDiskSampler {
+    seed: Option<u64>,
+}
+
+impl ParallelPoissonDiskSampler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// seed of the random order of the samples, so that the same points give the same samples
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+}
+
+impl Sampler for ParallelPoissonDiskSampler {
+    fn sample(&self, points: &[Point], radius: f64) -> anyhow::Result<Vec<Point>> {
+        let mut sampler = ParallelPoissonDiskSampling::new(points.iter().collect(), radius)?;
+        if let Some(seed) = self.seed {
+            sampler = sampler.seed(seed);
+        }
+        let _ = sampler.sample();
+        Ok(sampler.samples().into_iter().cloned().collect())
+    }
+}
+
+impl Sampler for PoissonDiskSampling<f64, Point> {
+    fn sample(&self, points: &[Point], radius: f64) -> anyhow::Result<Vec<Point>> {
+        PoissonDiskSampling::sample(self, points, radius)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Point3;
+
+    use super::*;
+
+    #[test]
+    fn builtin_samplers() {
+        let points: Vec<Point> = (0..1000)
+            .map(|i| {
+                Point::from(Point3::new(
+                    (i % 10) as f64,
+                    (i / 10 % 10) as f64,
+                    (i / 100) as f64,
+                ))
+            })
+            .collect();
+        let samplers: Vec<Box<dyn Sampler>> = vec![
+            Box::new(ParallelPoissonDiskSampler::new().seed(0)),
+            Box::new(PoissonDiskSampling::<f64, Point>::new()),
+        ];
+        for sampler in samplers {
+            let fine = sampler.sample(&points, 1.).unwrap();
+            let coarse = sampler.sample(&points, 3.).unwrap();
+            assert!(!coarse.is_empty() && coarse.len() < fine.len());
+            assert!(fine.len() <= points.len());
+        }
+
+        let sampler = ParallelPoissonDiskSampler::new().seed(7);
+        let positions =
+            |samples: Vec<Point>| samples.iter().map(|p| p.position).collect::<Vec<_>>();
+        assert_eq!(
+            positions(sampler.sample(&points, 2.).unwrap()),
+            positions(sampler.sample(&points, 2.).unwrap())
+        );
+    }
+}
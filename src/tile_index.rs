@@ -0,0 +1,186 @@
+use std::{collections::HashMap, fs::File, io::BufReader, path::Path};
+
+use nalgebra::Vector3;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    meta::parse_coordinate_key,
+    prelude::{BoundingBox, Meta},
+    LODKey,
+};
+
+/// Spatial index of the tiles (`index.json` next to `meta.json`) to find the tiles intersecting a box
+/// without scanning all the tiles of the meta.
+/// The tiles of each level are sorted by the Morton code of their keys,
+/// so a query only visits the range of codes of the cells covering the box.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TileIndex {
+    /// bounds of the octree (the root cell)
+    bounds: BoundingBox,
+    levels: HashMap<u32, LevelIndex>,
+}
+
+/// tiles of a level sorted by the Morton code
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct LevelIndex {
+    /// size of the cells along each axis
+    cell: Vector3<f64>,
+    /// number of the cells along each axis
+    divisions: [usize; 3],
+    /// max distance the bounds of a tile reach out of its cell (e.g. merged tiles, cubic bounds)
+    margin: f64,
+    tiles: Vec<(u64, LODKey, BoundingBox)>,
+}
+
+impl TileIndex {
+    /// build the index of the tiles in the meta
+    pub fn from_meta(meta: &Meta) -> Self {
+        let bounds = meta.bounds().clone();
+        let levels = meta
+            .coordinates()
+            .iter()
+            .map(|(level, units)| {
+                let mut index = LevelIndex {
+                    cell: meta.cell_size(*level),
+                    divisions: meta.divisions(*level),
+                    margin: 0.,
+                    tiles: vec![],
+                };
+                for (key, bbox) in units {
+                    let Some(key) = parse_coordinate_key(key) else {
+                        continue;
+                    };
+                    let cell_min = bounds.min() + key_vector(&key).component_mul(&index.cell);
+                    let cell_max = cell_min + index.cell;
+                    let out = (cell_min - bbox.min()).sup(&(bbox.max() - cell_max)).max();
+                    index.margin = index.margin.max(out);
+                    index.tiles.push((morton(&key), key, bbox.clone()));
+                }
+                index.tiles.sort_by_key(|(code, key, _)| (*code, *key));
+                (*level, index)
+            })
+            .collect();
+        Self { bounds, levels }
+    }
+
+    /// load the index saved as JSON
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    /// keys of the tiles in `level` whose bounds intersect the box (in the Morton order)
+    pub fn query(&self, bbox: &BoundingBox, level: u32) -> Vec<LODKey> {
+        let Some(index) = self.levels.get(&level) else {
+            return vec![];
+        };
+        // the range of the cells whose tiles may reach the box
+        let cell = |p: &Vector3<f64>, i: usize| {
+            let unit = index.cell[i];
+            let k = if unit > 0. {
+                (p[i] - self.bounds.min()[i]) / unit
+            } else {
+                0.
+            };
+            (k.floor() as i64).clamp(0, index.divisions[i] as i64 - 1) as i32
+        };
+        let corner = |p: Vector3<f64>| (cell(&p, 0), cell(&p, 1), cell(&p, 2));
+        let lo = corner(bbox.min().coords - Vector3::repeat(index.margin));
+        let hi = corner(bbox.max().coords + Vector3::repeat(index.margin));
+
+        let (lo_code, hi_code) = (morton(&lo), morton(&hi));
+        let start = index.tiles.partition_point(|(code, ..)| *code < lo_code);
+        index.tiles[start..]
+            .iter()
+            .take_while(|(code, ..)| *code <= hi_code)
+            .filter(|(_, (x, y, z), tile)| {
+                (lo.0..=hi.0).contains(x)
+                    && (lo.1..=hi.1).contains(y)
+                    && (lo.2..=hi.2).contains(z)
+                    && tile.intersects(bbox)
+            })
+            .map(|(_, key, _)| *key)
+            .collect()
+    }
+}
+
+fn key_vector(key: &LODKey) -> Vector3<f64> {
+    Vector3::new(key.0 as f64, key.1 as f64, key.2 as f64)
+}
+
+/// Morton code interleaving the lowest 21 bits of x, y and z
+fn morton(key: &LODKey) -> u64 {
+    let spread = |v: i32| {
+        let mut v = (v.max(0) as u64) & 0x1f_ffff;
+        v = (v | v << 32) & 0x1f_0000_0000_ffff;
+        v = (v | v << 16) & 0x1f_0000_ff00_00ff;
+        v = (v | v << 8) & 0x100f_00f0_0f00_f00f;
+        v = (v | v << 4) & 0x10c3_0c30_c30c_30c3;
+        v = (v | v << 2) & 0x1249_2492_4924_9249;
+        v
+    };
+    spread(key.0) | spread(key.1) << 1 | spread(key.2) << 2
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Point3;
+
+    use crate::prelude::Coordinates;
+
+    use super::*;
+
+    #[test]
+    fn morton_interleaves_bits() {
+        assert_eq!(morton(&(0, 0, 0)), 0);
+        assert_eq!(morton(&(1, 0, 0)), 1);
+        assert_eq!(morton(&(0, 1, 0)), 2);
+        assert_eq!(morton(&(0, 0, 1)), 4);
+        assert_eq!(morton(&(3, 3, 3)), 63);
+        assert_eq!(morton(&(0x1f_ffff, 0, 0)), 0x1249_2492_4924_9249);
+    }
+
+    #[test]
+    fn query_tiles_in_sub_region() {
+        let bounds = BoundingBox::new(Point3::origin(), Point3::new(8., 8., 8.));
+        let mut coordinates = Coordinates::new();
+        // every cell of level 3 (1 x 1 x 1) is a tile shrunk to its inner half
+        let level = coordinates.entry(3).or_default();
+        for x in 0..8 {
+            for y in 0..8 {
+                for z in 0..8 {
+                    let min = Point3::new(x as f64, y as f64, z as f64) + Vector3::repeat(0.25);
+                    let bbox = BoundingBox::new(min, min + Vector3::repeat(0.5));
+                    level.insert(format!("{}-{}-{}", x, y, z), bbox);
+                }
+            }
+        }
+        // a merged tile of level 2 reaching out of its cell
+        coordinates.entry(2).or_default().insert(
+            "0-0-0".to_string(),
+            BoundingBox::new(Point3::origin(), Point3::new(5., 2., 2.)),
+        );
+        let meta = Meta::new(4, bounds, coordinates);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.json");
+        std::fs::write(
+            &path,
+            serde_json::to_string(&TileIndex::from_meta(&meta)).unwrap(),
+        )
+        .unwrap();
+        let index = TileIndex::load(&path).unwrap();
+
+        let query = BoundingBox::new(Point3::new(2.5, 0.5, 6.), Point3::new(4.1, 1.1, 7.9));
+        let mut keys = index.query(&query, 3);
+        keys.sort();
+        assert_eq!(keys, vec![(2, 0, 6), (2, 0, 7), (3, 0, 6), (3, 0, 7)]);
+
+        // found through the margin although the box is outside of its cell
+        let query = BoundingBox::new(Point3::new(4.5, 1., 1.), Point3::new(4.6, 1.1, 1.1));
+        assert_eq!(index.query(&query, 2), vec![(0, 0, 0)]);
+        let query = BoundingBox::new(Point3::new(5.5, 1., 1.), Point3::new(5.6, 1.1, 1.1));
+        assert!(index.query(&query, 2).is_empty());
+        assert!(index.query(&query, 5).is_empty());
+    }
+}
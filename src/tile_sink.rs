@@ -0,0 +1,580 @@
+use std::{
+    collections::HashMap,
+    fs::{create_dir_all, rename, File},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use image::codecs::png::PngEncoder;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    prelude::{
+        pack_atlas, BoundingBox, Encoder, EncoderOptions, ImageKind, LODReport, Meta, Point,
+        TileIndex,
+    },
+    LODKey, LODUnit,
+};
+
+#[cfg(feature = "draco")]
+use crate::prelude::encode_draco;
+
+/// bounding box and number of points of a tile, stored next to its images (`<x>-<y>-<z>.json`)
+/// to recover the meta data from the tiles
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TileBounds {
+    pub bounding_box: BoundingBox,
+    pub count: usize,
+    /// geometric error of the level of the tile (missing in the sidecars of older versions)
+    #[serde(default)]
+    pub geometric_error: Option<f64>,
+}
+
+/// Destination of the encoded tiles and the meta data (e.g. file system, object storage, memory)
+pub trait TileSink {
+    /// write an encoded tile image of the unit at `key` in `level`
+    fn write_tile(
+        &self,
+        level: u32,
+        key: &LODKey,
+        bytes: &[u8],
+        kind: ImageKind,
+    ) -> anyhow::Result<()>;
+
+    /// write the meta data
+    fn write_meta(&self, meta: &Meta) -> anyhow::Result<()>;
+
+    /// write the bounds of the unit at `key` in `level` (ignored by default)
+    fn write_bounds(&self, _level: u32, _key: &LODKey, _bounds: &TileBounds) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// write the points of the unit at `key` in `level` (in `bounding_box`) in another format than the images
+    /// (ignored by default)
+    fn write_points(
+        &self,
+        _level: u32,
+        _key: &LODKey,
+        _bounding_box: &BoundingBox,
+        _points: &[Point],
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// write the summary report of the run (ignored by default)
+    fn write_report(&self, _report: &LODReport) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// encoded tiles of each level and image kind
+type PendingTiles = HashMap<(u32, ImageKind), Vec<(LODKey, Vec<u8>)>>;
+
+/// TileSink that writes `<level>/<x>-<y>-<z>.png`, `meta.json` (with the `index.json` of the tiles)
+/// and `report.json` under the root directory
+pub struct FileSystemSink {
+    root: PathBuf,
+    bounds_sidecar: bool,
+    atlas: bool,
+    /// quantization bits of the Draco point clouds written for each tile
+    #[cfg(feature = "draco")]
+    draco: Option<u8>,
+    /// tiles of the levels in progress, kept until the level is done when packing atlases
+    pending: Mutex<PendingTiles>,
+}
+
+impl FileSystemSink {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            bounds_sidecar: false,
+            atlas: false,
+            #[cfg(feature = "draco")]
+            draco: None,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// pack the tiles of each level into `<level>/atlas<suffix>.png`
+    /// with the sub-rectangles of the tiles in `<level>/atlas<suffix>.json`, instead of a file per tile
+    pub fn atlas(mut self, atlas: bool) -> Self {
+        self.atlas = atlas;
+        self
+    }
+
+    /// write `<level>/<x>-<y>-<z>.json` with the bounds of each tile
+    pub fn bounds_sidecar(mut self, bounds_sidecar: bool) -> Self {
+        self.bounds_sidecar = bounds_sidecar;
+        self
+    }
+
+    /// write `<level>/<x>-<y>-<z>.drc` with the points of each tile as a Draco point cloud,
+    /// the positions relative to the min of the tile bounds and quantized to the bits (see `encode_draco`)
+    #[cfg(feature = "draco")]
+    pub fn draco(mut self, bits: Option<u8>) -> Self {
+        self.draco = bits;
+        self
+    }
+
+    pub fn root(&self) -> &PathBuf {
+        &self.root
+    }
+
+    /// path of the tile file `<level>/<x>-<y>-<z><suffix>`, creating the level directory
+    fn tile_path(&self, level: u32, key: &LODKey, suffix: &str) -> anyhow::Result<PathBuf> {
+        let mut path = self.root.clone();
+        path.push(level.to_string());
+        create_dir_all(&path)?;
+
+        let (x, y, z) = key;
+        path.push(format!("{}-{}-{}{}", x, y, z, suffix));
+        Ok(path)
+    }
+
+    /// write the atlases of the levels before `lod`
+    fn write_atlases(&self, lod: u32) -> anyhow::Result<()> {
+        let done: Vec<_> = {
+            let mut pending = self.pending.lock().unwrap();
+            let keys: Vec<_> = pending.keys().filter(|(l, _)| *l < lod).copied().collect();
+            keys.into_iter()
+                .filter_map(|k| pending.remove_entry(&k))
+                .collect()
+        };
+
+        for ((level, kind), tiles) in done {
+            let images = tiles
+                .iter()
+                .map(|(key, bytes)| Ok((*key, image::load_from_memory(bytes)?)))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let (image, atlas) = pack_atlas(&images)?;
+
+            let mut dir = self.root.clone();
+            dir.push(level.to_string());
+            create_dir_all(&dir)?;
+            let name = format!("atlas{}", kind.suffix());
+            let mut png = vec![];
+            image.write_with_encoder(PngEncoder::new(&mut png))?;
+            write_atomically(&dir.join(format!("{}.png", name)), &png)?;
+            let json = serde_json::to_string(&atlas)?;
+            write_atomically(&dir.join(format!("{}.json", name)), json.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+impl TileSink for FileSystemSink {
+    fn write_tile(
+        &self,
+        level: u32,
+        key: &LODKey,
+        bytes: &[u8],
+        kind: ImageKind,
+    ) -> anyhow::Result<()> {
+        if self.atlas {
+            self.pending
+                .lock()
+                .unwrap()
+                .entry((level, kind))
+                .or_default()
+                .push((*key, bytes.to_vec()));
+            return Ok(());
+        }
+        let path = self.tile_path(level, key, &format!("{}.png", kind.suffix()))?;
+        write_atomically(&path, bytes)
+    }
+
+    fn write_meta(&self, meta: &Meta) -> anyhow::Result<()> {
+        #[cfg(feature = "draco")]
+        let meta = &{
+            let mut meta = meta.clone();
+            meta.draco_bits = self.draco;
+            meta
+        };
+        // through a value the keys of the hash maps are sorted, so the same meta gives the same bytes
+        let json = serde_json::to_string(&serde_json::to_value(meta)?)?;
+        write_atomically(&self.root.join("meta.json"), json.as_bytes())?;
+        let index = serde_json::to_string(&TileIndex::from_meta(meta))?;
+        write_atomically(&self.root.join("index.json"), index.as_bytes())?;
+
+        // meta is written when a level is done
+        if self.atlas {
+            self.write_atlases(meta.lod())?;
+        }
+        Ok(())
+    }
+
+    fn write_bounds(&self, level: u32, key: &LODKey, bounds: &TileBounds) -> anyhow::Result<()> {
+        if !self.bounds_sidecar {
+            return Ok(());
+        }
+        let json = serde_json::to_string(bounds)?;
+        write_atomically(&self.tile_path(level, key, ".json")?, json.as_bytes())
+    }
+
+    #[cfg(feature = "draco")]
+    fn write_points(
+        &self,
+        level: u32,
+        key: &LODKey,
+        bounding_box: &BoundingBox,
+        points: &[Point],
+    ) -> anyhow::Result<()> {
+        let Some(bits) = self.draco else {
+            return Ok(());
+        };
+        let bytes = encode_draco(points, bounding_box.min(), bits)?;
+        write_atomically(&self.tile_path(level, key, ".drc")?, &bytes)
+    }
+
+    fn write_report(&self, report: &LODReport) -> anyhow::Result<()> {
+        let json = serde_json::to_string_pretty(report)?;
+        write_atomically(&self.root.join("report.json"), json.as_bytes())
+    }
+}
+
+/// Write to `<path>.tmp` and rename it to `path`, so that a crash in the middle
+/// leaves the previous content of `path` (or no file) instead of a truncated file.
+/// A leftover `.tmp` of an interrupted run is overwritten.
+fn write_atomically(path: &Path, bytes: &[u8]) -> anyhow::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut f = File::create(&tmp)?;
+    f.write_all(bytes)?;
+    f.sync_all()?;
+    rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Encode the unit into PNG images
+pub fn encode_unit(
+    unit: &LODUnit,
+    options: &EncoderOptions,
+) -> anyhow::Result<Vec<(ImageKind, Vec<u8>)>> {
+    let scale = unit
+        .bounding_box
+        .extent_of(unit.points.iter().map(|p| &p.position));
+    let encoder = Encoder::new(&unit.points, Some(unit.bounding_box.clone()))
+        .position_scale(scale)
+        .default_color(options.get_default_color())
+        .intensity_in_alpha(options.has_intensity_in_alpha())
+        .intensity_in_position_alpha(options.has_intensity_in_position_alpha())
+        .color_by_time(options.get_color_by_time())
+        .pixel_layout(options.get_pixel_layout().clone())
+        .gamma(options.get_gamma());
+    encoder
+        .encode(options)?
+        .into_iter()
+        .map(|encoded| {
+            let mut bytes = vec![];
+            let (compression, filter) = options.png_quality(encoded.kind);
+            let png = PngEncoder::new_with_quality(&mut bytes, compression, filter);
+            encoded.image.write_with_encoder(png)?;
+            Ok((encoded.kind, bytes))
+        })
+        .collect()
+}
+
+/// Write the PNG images of the unit to the sink.
+/// The unit is encoded here unless it was already encoded in `process_lod`.
+pub fn write_unit<S: TileSink>(
+    sink: &S,
+    unit: &LODUnit,
+    options: &EncoderOptions,
+) -> anyhow::Result<()> {
+    let key = (unit.x, unit.y, unit.z);
+    let encoded;
+    let images = if unit.images.is_empty() {
+        encoded = encode_unit(unit, options)?;
+        &encoded
+    } else {
+        &unit.images
+    };
+    for (kind, bytes) in images {
+        sink.write_tile(unit.lod, &key, bytes, *kind)?;
+    }
+    let bounds = TileBounds {
+        bounding_box: unit.bounding_box.clone(),
+        count: unit.points.len(),
+        geometric_error: Some(unit.geometric_error),
+    };
+    sink.write_bounds(unit.lod, &key, &bounds)?;
+    sink.write_points(unit.lod, &key, &unit.bounding_box, &unit.points)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use nalgebra::Point3;
+
+    use crate::{build_lod_with_meta, prelude::Point, LODOptions};
+
+    use super::*;
+
+    /// TileSink that keeps the tiles in memory
+    #[derive(Default)]
+    struct MemorySink {
+        tiles: Mutex<HashMap<(u32, LODKey, ImageKind), Vec<u8>>>,
+        meta: Mutex<Option<Meta>>,
+    }
+
+    impl TileSink for MemorySink {
+        fn write_tile(
+            &self,
+            level: u32,
+            key: &LODKey,
+            bytes: &[u8],
+            kind: ImageKind,
+        ) -> anyhow::Result<()> {
+            self.tiles
+                .lock()
+                .unwrap()
+                .insert((level, *key, kind), bytes.to_vec());
+            Ok(())
+        }
+
+        fn write_meta(&self, meta: &Meta) -> anyhow::Result<()> {
+            *self.meta.lock().unwrap() = Some(meta.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn png_compression_levels_decode_identically() {
+        use image::codecs::png::{CompressionType, FilterType};
+
+        let points: Vec<Point> = (0..1000)
+            .map(|i| {
+                let f = i as f64;
+                let line = format!("{} {} {} {} 128 0", f.sin(), f.cos(), f * 0.01, i % 256);
+                Point::try_parse(&line).unwrap()
+            })
+            .collect();
+        let unit = LODUnit {
+            lod: 0,
+            bounding_box: crate::prelude::BoundingBox::from_iter(points.iter()),
+            points,
+            x: 0,
+            y: 0,
+            z: 0,
+            geometric_error: 1.,
+            images: vec![],
+        };
+        let fast = EncoderOptions::new()
+            .png_compression(CompressionType::Fast)
+            .position_png_filter(FilterType::NoFilter);
+        let best = EncoderOptions::new()
+            .png_compression(CompressionType::Best)
+            .position_png_filter(FilterType::Paeth)
+            .color_png_filter(FilterType::Sub);
+
+        let fast = encode_unit(&unit, &fast).unwrap();
+        let best = encode_unit(&unit, &best).unwrap();
+        assert_eq!(fast.len(), best.len());
+        for ((k0, b0), (k1, b1)) in fast.iter().zip(best.iter()) {
+            assert_eq!(k0, k1);
+            let i0 = image::load_from_memory(b0).unwrap();
+            let i1 = image::load_from_memory(b1).unwrap();
+            assert_eq!(i0.as_bytes(), i1.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn meta_is_valid_after_crash() {
+        let points: Vec<Point> = (0..8)
+            .flat_map(|z| (0..8).flat_map(move |y| (0..8).map(move |x| (x, y, z))))
+            .map(|(x, y, z)| Point::from(Point3::new(x as f64, y as f64, z as f64)))
+            .collect();
+        let dir = tempfile::tempdir().unwrap();
+        let sink = FileSystemSink::new(dir.path().to_path_buf());
+        let options = EncoderOptions::new();
+        let read_meta = || -> anyhow::Result<Meta> {
+            let json = std::fs::read_to_string(dir.path().join("meta.json"))?;
+            Ok(serde_json::from_str(&json)?)
+        };
+
+        let result = build_lod_with_meta(
+            points,
+            |unit| {
+                let r = write_unit(&sink, &unit, &options);
+                async { r }
+            },
+            |meta| {
+                let r = sink.write_meta(&meta).and_then(|_| {
+                    assert_eq!(read_meta()?.lod(), meta.lod());
+                    // the process dies after the second level
+                    anyhow::ensure!(meta.lod() < 2, "crash");
+                    Ok(())
+                });
+                async { r }
+            },
+            &LODOptions {
+                point_count_threshold: 32,
+                ..Default::default()
+            },
+        )
+        .await;
+        assert!(result.is_err());
+
+        // and in the middle of writing the meta of the next level
+        std::fs::write(dir.path().join("meta.json.tmp"), "{\"version\":").unwrap();
+        assert_eq!(read_meta().unwrap().lod(), 2);
+
+        // the next run overwrites the leftover
+        let meta = read_meta().unwrap();
+        sink.write_meta(&meta).unwrap();
+        assert_eq!(read_meta().unwrap().lod(), 2);
+        assert!(!dir.path().join("meta.json.tmp").exists());
+    }
+
+    #[test]
+    fn tile_is_absent_or_complete_after_interrupted_write() {
+        let points: Vec<Point> = (0..100)
+            .map(|i| Point::from(Point3::new(i as f64, (i % 7) as f64, (i % 3) as f64)))
+            .collect();
+        let unit = LODUnit {
+            lod: 0,
+            bounding_box: BoundingBox::from_iter(points.iter()),
+            points,
+            x: 0,
+            y: 0,
+            z: 0,
+            geometric_error: 1.,
+            images: vec![],
+        };
+        let (_, png) = encode_unit(&unit, &EncoderOptions::new())
+            .unwrap()
+            .into_iter()
+            .find(|(kind, _)| *kind == ImageKind::Position)
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let sink = FileSystemSink::new(dir.path().to_path_buf());
+        let path = dir.path().join("0").join("0-0-0.png");
+        let tmp = dir.path().join("0").join("0-0-0.png.tmp");
+
+        // the write is interrupted (the temporary file can not be completed)
+        std::fs::create_dir_all(&tmp).unwrap();
+        assert!(sink
+            .write_tile(0, &(0, 0, 0), &png, ImageKind::Position)
+            .is_err());
+        assert!(!path.exists());
+
+        // a truncated temporary file of the interrupted run is never seen as the tile
+        std::fs::remove_dir(&tmp).unwrap();
+        std::fs::write(&tmp, &png[..png.len() / 2]).unwrap();
+        assert!(!path.exists());
+        sink.write_tile(0, &(0, 0, 0), &png, ImageKind::Position)
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), png);
+        assert!(image::open(&path).is_ok());
+        assert!(!tmp.exists());
+
+        // an interrupted overwrite keeps the previous complete tile
+        std::fs::create_dir_all(&tmp).unwrap();
+        assert!(sink
+            .write_tile(0, &(0, 0, 0), &png[..10], ImageKind::Position)
+            .is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), png);
+    }
+
+    #[cfg(feature = "draco")]
+    #[tokio::test]
+    async fn write_draco_tiles() {
+        use crate::prelude::decode_draco;
+
+        let points: Vec<Point> = (0..8)
+            .flat_map(|z| (0..8).flat_map(move |y| (0..8).map(move |x| (x, y, z))))
+            .map(|(x, y, z)| {
+                let line = format!("{} {} {} {} {} 0", x, y, z, x * 30, y * 30);
+                Point::try_parse(&line).unwrap()
+            })
+            .collect();
+        let dir = tempfile::tempdir().unwrap();
+        let sink = FileSystemSink::new(dir.path().to_path_buf()).draco(Some(12));
+        let options = EncoderOptions::new();
+        let units = Mutex::new(vec![]);
+        build_lod_with_meta(
+            points,
+            |unit| {
+                let r = write_unit(&sink, &unit, &options);
+                units.lock().unwrap().push(unit);
+                async { r }
+            },
+            |meta| {
+                let r = sink.write_meta(&meta);
+                async { r }
+            },
+            &LODOptions {
+                point_count_threshold: 100,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let json = std::fs::read_to_string(dir.path().join("meta.json")).unwrap();
+        let meta: Meta = serde_json::from_str(&json).unwrap();
+        assert_eq!(meta.draco_bits, Some(12));
+        for unit in units.into_inner().unwrap() {
+            let path = dir
+                .path()
+                .join(unit.lod.to_string())
+                .join(format!("{}-{}-{}.drc", unit.x, unit.y, unit.z));
+            // the positions are relative to the min of the tile bounds in the meta
+            let origin = meta
+                .tile_bounds(unit.lod, &(unit.x, unit.y, unit.z))
+                .unwrap();
+            let decoded = decode_draco(&std::fs::read(path).unwrap(), origin.min()).unwrap();
+            assert_eq!(decoded.len(), unit.points.len());
+            for (p, q) in unit.points.iter().zip(decoded.iter()) {
+                assert!((p.position - q.position).norm() < 1e-2);
+                assert_eq!(p.color, q.color);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn write_tiles_to_memory() {
+        let points: Vec<Point> = (0..4)
+            .flat_map(|z| (0..4).flat_map(move |y| (0..4).map(move |x| (x, y, z))))
+            .map(|(x, y, z)| Point::from(Point3::new(x as f64, y as f64, z as f64)))
+            .collect();
+        let sink = MemorySink::default();
+        let options = EncoderOptions::new();
+        build_lod_with_meta(
+            points,
+            |unit| {
+                let r = write_unit(&sink, &unit, &options);
+                async { r }
+            },
+            |meta| {
+                let r = sink.write_meta(&meta);
+                async { r }
+            },
+            &LODOptions {
+                point_count_threshold: 32,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let meta = sink.meta.lock().unwrap().clone().unwrap();
+        let tiles = sink.tiles.lock().unwrap();
+        let expected: usize = meta.coordinates().values().map(|c| c.len()).sum();
+        // position and color image for each unit
+        assert_eq!(tiles.len(), expected * 2);
+        assert!(tiles.contains_key(&(0, (0, 0, 0), ImageKind::Position)));
+        assert!(tiles.contains_key(&(0, (0, 0, 0), ImageKind::Color)));
+        for (level, units) in meta.coordinates() {
+            for key in units.keys() {
+                let k: Vec<i32> = key.split('-').map(|v| v.parse().unwrap()).collect();
+                let key = (k[0], k[1], k[2]);
+                assert!(tiles.contains_key(&(*level, key, ImageKind::Position)));
+            }
+        }
+    }
+}
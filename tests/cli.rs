@@ -0,0 +1,52 @@
+#![cfg(feature = "image")]
+
+use std::process::Command;
+
+#[test]
+fn stats_prints_count_and_bounds() {
+    let output = Command::new(env!("CARGO_BIN_EXE_pcd-lod"))
+        .args(["stats", "--native", "-i", "tests/fixtures/small.xyz"])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert!(stdout.contains("points: 8"), "{}", stdout);
+    assert!(stdout.contains("bounds min: 0 0 0"), "{}", stdout);
+    assert!(stdout.contains("bounds max: 1 2 3"), "{}", stdout);
+    assert!(stdout.contains("intensity: 0.1 ~ 0.8"), "{}", stdout);
+}
+
+#[test]
+fn overwrite_only_with_force() {
+    let dir = tempfile::tempdir().unwrap();
+    let run = |force: bool| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_pcd-lod"));
+        command.args(["--native", "-i", "tests/fixtures/small.xyz", "-o"]);
+        command.arg(dir.path());
+        if force {
+            command.arg("--force");
+        }
+        String::from_utf8(command.output().unwrap().stdout).unwrap()
+    };
+
+    let stdout = run(false);
+    assert!(stdout.contains("success"), "{}", stdout);
+    assert!(dir.path().join("meta.json").exists());
+    assert!(dir.path().join("report.json").exists());
+    assert!(dir.path().join("index.json").exists());
+
+    // a tile left by a previous, deeper run
+    let stale = dir.path().join("5");
+    std::fs::create_dir(&stale).unwrap();
+    std::fs::write(stale.join("0-0-0.png"), b"").unwrap();
+
+    let stdout = run(false);
+    assert!(stdout.contains("not empty"), "{}", stdout);
+    assert!(stale.exists());
+
+    let stdout = run(true);
+    assert!(stdout.contains("success"), "{}", stdout);
+    assert!(!stale.exists());
+    assert!(dir.path().join("meta.json").exists());
+    assert!(dir.path().join("0").join("0-0-0.png").exists());
+}
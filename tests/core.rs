@@ -0,0 +1,23 @@
+use nalgebra::Point3;
+use pcd_lod::prelude::{BoundingBox, Point, PointCloudMap, PoissonDiskSampling};
+
+/// core types (point, sampling, octree) are usable without the `image` feature
+/// (`cargo test --no-default-features --test core`)
+#[test]
+fn core_without_image() {
+    let points: Vec<Point> = (0..1000)
+        .map(|i| {
+            let (x, y, z) = (i % 10, (i / 10) % 10, i / 100);
+            Point::from(Point3::new(x as f64, y as f64, z as f64))
+        })
+        .collect();
+
+    let sampler = PoissonDiskSampling::<f64, Point>::new();
+    let samples = sampler.sample(&points, 2.).unwrap();
+    assert!(!samples.is_empty() && samples.len() < points.len());
+
+    let bounds = BoundingBox::from_iter(points.iter().map(|p| p.position));
+    let map = PointCloudMap::root(bounds, &points).divide(100);
+    let count: usize = map.map().values().map(|u| u.points.len()).sum();
+    assert_eq!(count, points.len());
+}